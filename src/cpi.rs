@@ -43,6 +43,12 @@ enum VaultInstruction {
     SetPaused { _paused: bool },                                              // 10
     UpdateAdmin { _new_admin: [u8; 32] },                                     // 11
     SetFundProgram { _fund_program: [u8; 32] },                               // 12
+    CreditLiquidationReward { amount: u64 },                                  // 13
+    SettleFunding { amount: i64 },                                            // 14
+    TransferToInsuranceFund { amount: u64 },                                  // 15
+    CreditFeeRebate { amount: u64 },                                          // 16
+    CreditReferralReward { amount: u64 },                                     // 17
+    ChargeTradingFee { amount: u64 },                                         // 18
 }
 
 /// CPI: 锁定保证金 (Vault Program)
@@ -71,6 +77,35 @@ pub fn lock_margin<'a>(
     )
 }
 
+/// CPI: 开仓手续费从用户可用余额扣除 (Vault Program)
+///
+/// 与 LockMargin 分开调用，手续费不计入锁定保证金 / Position.margin_e6；
+/// Token 留在 Vault Token Account 中 (平仓手续费由 ClosePositionSettle 同样扣除)
+pub fn charge_trading_fee<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    user_account: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new(*user_account.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+        ],
+        data: VaultInstruction::ChargeTradingFee { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[vault_config, user_account, caller_program],
+        signer_seeds,
+    )
+}
+
 /// CPI: 释放保证金 (Vault Program)
 pub fn release_margin<'a>(
     vault_program_id: &Pubkey,
@@ -97,6 +132,64 @@ pub fn release_margin<'a>(
     )
 }
 
+/// CPI: 清算奖励记入清算人账户 (Vault Program)
+///
+/// 奖励来自被清算用户的清算罚金 (LiquidatePosition 中已从其保证金扣除但未转入 Insurance Fund 的部分)，
+/// Token 仍在 Vault Token Account 中，只增加清算人的可用余额
+pub fn credit_liquidation_reward<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    liquidator_account: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new(*liquidator_account.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+        ],
+        data: VaultInstruction::CreditLiquidationReward { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[vault_config, liquidator_account, caller_program],
+        signer_seeds,
+    )
+}
+
+/// CPI: 资金费实时结算 (Vault Program)
+///
+/// amount > 0: 用户支付，从锁定保证金扣除；amount < 0: 用户收取，增加锁定保证金。
+/// 多空双方的资金费在 Vault Token Account 内对冲，不产生 Token 转账
+pub fn settle_funding<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    user_account: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    amount: i64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new(*user_account.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+        ],
+        data: VaultInstruction::SettleFunding { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[vault_config, user_account, caller_program],
+        signer_seeds,
+    )
+}
+
 /// CPI: 平仓结算 (Vault Program - 仅用户账户部分)
 ///
 /// 注意: 手续费分配由单独调用 Fund Program 处理
@@ -132,6 +225,64 @@ pub fn close_position_settle<'a>(
     )
 }
 
+/// CPI: Maker 返佣记入用户账户 (Vault Program)
+///
+/// Vault 从已收取的手续费 (Token 仍在 Vault Token Account 中) 划转到用户可用余额，
+/// 不计入用户盈亏；返佣上限为同一批次拆分到 Maker 返佣池的手续费
+pub fn credit_fee_rebate<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    user_account: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new(*user_account.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+        ],
+        data: VaultInstruction::CreditFeeRebate { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[vault_config, user_account, caller_program],
+        signer_seeds,
+    )
+}
+
+/// CPI: 推荐人领取手续费分成 (Vault Program)
+///
+/// Vault 从已收取的手续费 (Token 仍在 Vault Token Account 中) 划转到推荐人可用余额，
+/// 不计入推荐人盈亏；金额为 ReferrerStats 中累计的推荐人分成 (未计入保险基金)
+pub fn credit_referral_reward<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    referrer_account: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new(*referrer_account.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+        ],
+        data: VaultInstruction::CreditReferralReward { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[vault_config, referrer_account, caller_program],
+        signer_seeds,
+    )
+}
+
 /// CPI: 清算用户账户 (Vault Program)
 ///
 /// 执行完整的清算资金处理:
@@ -182,6 +333,51 @@ pub fn liquidate_position<'a>(
     )
 }
 
+/// Vault Token Account -> Insurance Fund Vault 转账所需的 Token 账户
+pub struct InsuranceTransferAccounts<'a> {
+    pub vault_token_account: AccountInfo<'a>,
+    pub insurance_fund_vault: AccountInfo<'a>,
+    pub token_program: AccountInfo<'a>,
+}
+
+/// CPI: 从 Vault Token Account 转出资金到 Insurance Fund Vault (Vault Program)
+///
+/// 用于 ADL: 目标仓位按破产价格结算后让出的盈利仍在 Vault Token Account 中，
+/// 由此转入保险基金以补足穿仓
+pub fn transfer_to_insurance_fund<'a>(
+    vault_program_id: &Pubkey,
+    vault_config: AccountInfo<'a>,
+    caller_program: AccountInfo<'a>,
+    token_accounts: InsuranceTransferAccounts<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let InsuranceTransferAccounts { vault_token_account, insurance_fund_vault, token_program } = token_accounts;
+    let instruction = Instruction {
+        program_id: *vault_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*vault_config.key, false),
+            AccountMeta::new_readonly(*caller_program.key, false),
+            AccountMeta::new(*vault_token_account.key, false),
+            AccountMeta::new(*insurance_fund_vault.key, false),
+            AccountMeta::new_readonly(*token_program.key, false),
+        ],
+        data: VaultInstruction::TransferToInsuranceFund { amount }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[
+            vault_config,
+            caller_program,
+            vault_token_account,
+            insurance_fund_vault,
+            token_program,
+        ],
+        signer_seeds,
+    )
+}
+
 // =============================================================================
 // Fund Program CPI (Insurance Fund Operations)
 // =============================================================================
@@ -247,9 +443,37 @@ pub fn add_liquidation_income<'a>(
     )
 }
 
-/// CPI: 添加ADL盈余到保险基金 (Fund Program)
+/// CPI: 交易手续费计入保险基金 (Fund Program)
 ///
-/// 当ADL执行后有盈余时，转入保险基金
+/// 手续费已由 Vault 从用户账户扣除 (开仓 ChargeTradingFee / 平仓 ClosePositionSettle)，此处记入保险基金
+pub fn add_trading_fee<'a>(
+    fund_program_id: &Pubkey,
+    caller_program: AccountInfo<'a>,
+    fund_account: AccountInfo<'a>,
+    insurance_config: AccountInfo<'a>,
+    fee_e6: i64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *fund_program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*caller_program.key, false),
+            AccountMeta::new(*fund_account.key, false),
+            AccountMeta::new(*insurance_config.key, false),
+        ],
+        data: FundInstruction::AddTradingFee { fee_e6 }.try_to_vec()?,
+    };
+
+    invoke_signed(
+        &instruction,
+        &[caller_program, fund_account, insurance_config],
+        signer_seeds,
+    )
+}
+
+/// CPI: 添加ADL收益到保险基金 (Fund Program)
+///
+/// ADL 让出的盈利转入保险基金后 (transfer_to_insurance_fund)，此处记入保险基金统计
 pub fn add_adl_profit<'a>(
     fund_program_id: &Pubkey,
     caller_program: AccountInfo<'a>,
@@ -381,6 +605,13 @@ pub fn calculate_fee(size_e6: u64, price_e6: u64, fee_rate_e6: u64) -> Result<u6
     Ok(fee as u64)
 }
 
+/// 计算带符号手续费 (负费率 = maker 返佣，返回负值)
+pub fn calculate_signed_fee(size_e6: u64, price_e6: u64, fee_rate_e6: i64) -> Result<i64, crate::error::LedgerError> {
+    let fee = calculate_fee(size_e6, price_e6, fee_rate_e6.unsigned_abs())?;
+    let fee = i64::try_from(fee).map_err(|_| crate::error::LedgerError::Overflow)?;
+    Ok(if fee_rate_e6 < 0 { -fee } else { fee })
+}
+
 // =============================================================================
 // User Account Reading (Non-CPI, direct account read)
 // =============================================================================
@@ -389,6 +620,7 @@ pub fn calculate_fee(size_e6: u64, price_e6: u64, fee_rate_e6: u64) -> Result<u6
 /// 必须与 Vault 程序中的 UserAccount 布局一致！
 #[derive(Debug)]
 pub struct VaultUserAccount {
+    pub wallet: Pubkey,
     pub locked_margin_e6: i64,
 }
 
@@ -408,12 +640,18 @@ pub fn read_user_account(user_account_info: &AccountInfo) -> Result<VaultUserAcc
         return Err(crate::error::LedgerError::InvalidAccount);
     }
     
+    // 读取 wallet (offset 8-40)
+    let wallet = Pubkey::new_from_array(
+        data[8..40].try_into().map_err(|_| crate::error::LedgerError::InvalidAccount)?
+    );
+
     // 读取 locked_margin_e6 (offset 49-57)
     let locked_margin_e6 = i64::from_le_bytes(
         data[49..57].try_into().map_err(|_| crate::error::LedgerError::InvalidAccount)?
     );
     
     Ok(VaultUserAccount {
+        wallet,
         locked_margin_e6,
     })
 }
@@ -443,4 +681,26 @@ mod tests {
         let fee = calculate_fee(size_e6, price_e6, fee_rate_e6).unwrap();
         assert_eq!(fee, 50_000_000); // $50 in e6
     }
+
+    #[test]
+    fn test_calculate_signed_fee() {
+        // 1 BTC at $50,000: taker 0.05% = $25, maker -0.02% = $10 返佣
+        assert_eq!(calculate_signed_fee(1_000_000, 50_000_000_000, 500), Ok(25_000_000));
+        assert_eq!(calculate_signed_fee(1_000_000, 50_000_000_000, -200), Ok(-10_000_000));
+        assert_eq!(calculate_signed_fee(1_000_000, 50_000_000_000, 0), Ok(0));
+    }
+
+    #[test]
+    fn test_add_trading_fee_encoding() {
+        let data = FundInstruction::AddTradingFee { fee_e6: 50_000_000 }.try_to_vec().unwrap();
+        assert_eq!(data[0], 22);
+        assert_eq!(i64::from_le_bytes(data[1..9].try_into().unwrap()), 50_000_000);
+    }
+
+    #[test]
+    fn test_charge_trading_fee_encoding() {
+        let data = VaultInstruction::ChargeTradingFee { amount: 5_000_000 }.try_to_vec().unwrap();
+        assert_eq!(data[0], 18);
+        assert_eq!(u64::from_le_bytes(data[1..9].try_into().unwrap()), 5_000_000);
+    }
 }
@@ -147,6 +147,63 @@ pub enum LedgerError {
     // === Fee 相关 ===
     #[error("Fee rate exceeds maximum allowed (10000 = 1%)")]
     InvalidFeeRate,
+
+    // === Market 相关 ===
+    #[error("Invalid market config")]
+    InvalidMarketConfig,
+
+    // === Oracle 相关 ===
+    #[error("Invalid price feed")]
+    InvalidPriceFeed,
+
+    #[error("Oracle price is stale")]
+    StalePrice,
+
+    #[error("Oracle price confidence too wide")]
+    PriceConfidenceTooWide,
+
+    #[error("Trade price outside allowed band of reference price")]
+    PriceOutOfBand,
+
+    // === ADL 执行 ===
+    #[error("ADL targets not ranked by ADL score")]
+    ADLTargetsNotRanked,
+
+    #[error("ADL shortfall not fully covered")]
+    ADLShortfallNotCovered,
+
+    #[error("Shortfall record already consumed by ADL")]
+    ShortfallAlreadyConsumed,
+
+    // === 资金费率计算 ===
+    #[error("No premium samples since last funding rate publish")]
+    NoPremiumSamples,
+
+    // === 标记价格 ===
+    #[error("Invalid mark price config")]
+    InvalidMarkPrice,
+
+    // === 撮合成交 ===
+    #[error("Matched trade sides do not net to zero")]
+    UnbalancedTrade,
+
+    // === 手续费分配 ===
+    #[error("Invalid fee schedule")]
+    InvalidFeeSchedule,
+
+    // === 手续费档位 ===
+    #[error("Invalid fee tier table")]
+    InvalidFeeTier,
+    #[error("Fee rate above the user's fee tier")]
+    FeeRateAboveTier,
+
+    // === 推荐返佣 ===
+    #[error("Invalid referral code")]
+    InvalidReferralCode,
+    #[error("Cannot refer yourself")]
+    SelfReferral,
+    #[error("No referral rewards to claim")]
+    NoReferralRewards,
 }
 
 impl From<LedgerError> for ProgramError {
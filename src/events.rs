@@ -267,6 +267,12 @@ pub enum PositionEventType {
     Liquidated = 5,
     /// 被 ADL
     ADLed = 6,
+    /// 追加保证金
+    MarginAdded = 7,
+    /// 减少保证金
+    MarginRemoved = 8,
+    /// 调整杠杆
+    LeverageChanged = 9,
 }
 
 /// PositionEvent — 仓位变动事件
@@ -546,6 +552,12 @@ pub enum FeeType {
     InsuranceContribution = 3,
     /// Funding fee
     FundingFee = 4,
+    /// 分给协议金库的手续费
+    TreasuryShare = 5,
+    /// 分给推荐人的手续费
+    ReferrerShare = 6,
+    /// 划入 Maker 返佣池的手续费
+    MakerRebateShare = 7,
 }
 
 /// FeeEvent — 费用收取明细事件
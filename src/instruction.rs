@@ -7,10 +7,22 @@
 //! 4. 清算指令 - Liquidate, TriggerADL
 //! 5. 资金费率 - SettleFunding
 //! 6. 管理指令 - UpdateRelayers, Pause, UpdateAdmin
+//! 7. 市场配置 - CreateMarketConfig, UpdateMarketConfig, SetRiskTiers
+//! 8. 预言机 - CreatePriceFeed, UpdatePriceFeed, SetPriceFeedParams
+//! 9. 保证金管理 - AddMargin, RemoveMargin, SetLeverage
+//! 10. ADL 执行 - ExecuteADL, CompleteADL
+//! 11. 资金费率指数 - CreateFundingState, UpdateFundingIndex
+//! 12. 批量资金费 - SettleFundingBatch
+//! 13. 资金费率计算 - CreatePremiumSamples, SetFundingRateParams, SubmitPremiumSample, PublishFundingRate
+//! 14. 标记价格 - CreateMarkPrice, UpdateMarkPrice, CheckPositionHealth
+//! 15. 手续费分配 - CreateFeeSchedule, UpdateFeeSchedule
+//! 16. 手续费档位 - CreateFeeTierTable, UpdateFeeTierTable
+//! 17. 推荐返佣 - CreateReferrerCode, BindReferral, ClaimReferralRewards
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::pubkey::Pubkey;
-use crate::state::Side;
+use crate::error::LedgerError;
+use crate::state::{FeeTier, ReferralCode, RiskTier, Side};
 
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
 pub enum LedgerInstruction {
@@ -77,18 +89,42 @@ pub enum LedgerInstruction {
     /// 5. `[]` Vault Program
     /// 6. `[]` Ledger Program (self)
     /// 7. `[]` System Program
-    /// 8. `[writable]` Insurance Fund (for close positions, can be SystemProgram if no closes)
+    /// 8. `[writable]` Insurance Fund Account (Fund Program, 接收手续费)
+    /// 9. `[]` Fund Program
+    /// 10. `[writable]` InsuranceFundConfig (Fund Program)
+    /// 11. `[writable]` FeeSchedule PDA (手续费分配比例)
+    /// 12. `[]` FeeTierTable PDA (手续费档位)
     /// 
-    /// 然后每笔交易需要 3 个账户:
-    /// For trade i (starting from index 9):
-    ///   9 + i*3 + 0: `[writable]` Position PDA (seeds: ["position", user, market_index])
-    ///   9 + i*3 + 1: `[writable]` UserAccount (Vault)
-    ///   9 + i*3 + 2: `[writable]` UserStats PDA
+    /// 然后每笔撮合成交需要 6 个账户 (maker 3 个 + taker 3 个):
+    /// For trade i (starting from index 13):
+    ///   13 + i*6 + 0: `[writable]` Maker Position PDA (seeds: ["position", user, market_index])
+    ///   13 + i*6 + 1: `[writable]` Maker UserAccount (Vault)
+    ///   13 + i*6 + 2: `[writable]` Maker UserStats PDA (seeds: ["user_stats", user], 确定费率档位)
+    ///   13 + i*6 + 3: `[writable]` Taker Position PDA
+    ///   13 + i*6 + 4: `[writable]` Taker UserAccount (Vault)
+    ///   13 + i*6 + 5: `[writable]` Taker UserStats PDA
+    ///
+    /// 示例: 2 笔成交需要 13 + 12 = 25 个账户
+    ///
+    /// 之后追加批次中涉及的每个市场的 MarketConfig、PriceFeed、FundingState 和 MarkPrice PDA (每个市场各一个，顺序不限):
+    ///   13 + N*6 + j: `[]` MarketConfig PDA (seeds: ["market_config", market_index])
+    ///   13 + N*6 + j: `[]` PriceFeed PDA (seeds: ["price_feed", market_index], 成交价格带宽参考价)
+    ///   13 + N*6 + j: `[]` FundingState PDA (seeds: ["funding_state", market_index], 计提资金费)
+    ///   13 + N*6 + j: `[writable]` MarkPrice PDA (seeds: ["mark_price", market_index], 记录最新成交价)
     ///
-    /// 示例: 2 笔交易需要 9 + 6 = 15 个账户
+    /// 以及每个交易用户的 Referral PDA (seeds: ["referral", user]，未绑定时为空账户，必须提供)
+    /// 和已绑定推荐码的 ReferrerStats PDA (`[writable]`, seeds: ["referrer_stats", code])，顺序不限
+    ///
+    /// 每笔成交的 maker / taker 方向相反、数量相等 (UnbalancedTrade)，双方仓位原子更新，
+    /// 发出一个带双方订单号的 TradeEvent，双方手续费各发出一个 FeeEvent。
+    /// maker 负费率的返佣通过 Vault.CreditFeeRebate 记入 maker 账户，批次累计返佣不超过本批 Maker 返佣拆分部分。
+    /// 双方费率不得高于各自 30 天滚动成交量所在档位的费率 (FeeRateAboveTier)。
+    /// 被推荐用户的费率按 FeeSchedule 折扣，手续费的推荐人部分记入 ReferrerStats。
+    /// 手续费按 FeeSchedule 拆分，每个分配对象再发出一个 FeeEvent；
+    /// 批次保险基金部分合计后通过一次 CPI 调用 Fund.AddTradingFee 计入保险基金
     ExecuteTradeBatch {
         batch_id: u64,
-        trades: Vec<TradeData>,
+        trades: Vec<MatchedTrade>,
     },
 
     // ========================================================================
@@ -100,6 +136,9 @@ pub enum LedgerInstruction {
     /// 2. CPI 调用 Vault.lockMargin
     /// 3. 创建 TradeRecord
     ///
+    /// 已有反向仓位时按净额处理: 先减仓并实现已平部分 PnL (Vault.closePositionSettle)，
+    /// 剩余数量在新方向开仓，发出 PositionEventType::Flipped。ExecuteTradeBatch 的 OPEN 同理。
+    ///
     /// Accounts:
     /// 0. `[signer]` Relayer (or user for direct trades)
     /// 1. `[writable]` Position PDA
@@ -107,9 +146,23 @@ pub enum LedgerInstruction {
     /// 3. `[writable]` VaultConfig
     /// 4. `[writable]` LedgerConfig
     /// 5. `[writable]` UserStats PDA
-    /// 6. `[writable]` TradeRecord PDA
-    /// 7. `[]` Vault Program
+    /// 6. `[]` Vault Program
+    /// 7. `[]` Ledger Program (self)
     /// 8. `[]` System Program
+    /// 9. `[]` MarketConfig PDA
+    /// 10. `[]` PriceFeed PDA (成交价格带宽参考价)
+    /// 11. `[]` FundingState PDA (计提资金费)
+    /// 12. `[writable]` MarkPrice PDA (记录最新成交价)
+    /// 13. `[]` Fund Program
+    /// 14. `[writable]` Insurance Fund Account (Fund Program, 接收手续费)
+    /// 15. `[writable]` InsuranceFundConfig (Fund Program)
+    /// 16. `[writable]` FeeSchedule PDA (手续费分配比例)
+    /// 17. `[]` FeeTierTable PDA (费率不得高于用户档位费率)
+    /// 18. `[]` Referral PDA (seeds: ["referral", user], 未绑定推荐码时为空账户)
+    /// 19. `[writable]` ReferrerStats PDA (推荐人分成，未绑定时可传任意账户)
+    ///
+    /// 手续费按 FeeSchedule 拆分，保险基金部分通过 CPI 调用 Fund.AddTradingFee 计入保险基金，
+    /// 发出手续费 FeeEvent 及每个分配对象的 FeeEvent
     OpenPosition {
         user: Pubkey,
         market_index: u8,
@@ -118,7 +171,7 @@ pub enum LedgerInstruction {
         price_e6: u64,
         leverage: u8,
         batch_id: u64,
-        /// 0 = taker, 1 = maker (for logging/stats)
+        /// 1 = taker, 0 = maker (决定 FeeEvent 的 fee_type)
         is_taker: u8,
         /// Fee rate in e6 units (e.g., 500 = 5 bps = 0.05%). Passed by Relayer.
         fee_rate_e6: u64,
@@ -136,19 +189,30 @@ pub enum LedgerInstruction {
     /// 1. `[writable]` Position PDA
     /// 2. `[writable]` UserAccount (Vault)
     /// 3. `[writable]` VaultConfig
-    /// 4. `[writable]` InsuranceFund
+    /// 4. `[writable]` Insurance Fund Account (Fund Program, 接收手续费)
     /// 5. `[writable]` LedgerConfig
     /// 6. `[writable]` UserStats PDA
-    /// 7. `[writable]` TradeRecord PDA
-    /// 8. `[]` Vault Program
-    /// 9. `[]` System Program
+    /// 7. `[]` Vault Program
+    /// 8. `[]` MarketConfig PDA
+    /// 9. `[]` PriceFeed PDA (成交价格带宽参考价)
+    /// 10. `[]` FundingState PDA (计提资金费)
+    /// 11. `[writable]` MarkPrice PDA (记录最新成交价)
+    /// 12. `[]` Fund Program
+    /// 13. `[writable]` InsuranceFundConfig (Fund Program)
+    /// 14. `[writable]` FeeSchedule PDA (手续费分配比例)
+    /// 15. `[]` FeeTierTable PDA (费率不得高于用户档位费率)
+    /// 16. `[]` Referral PDA (seeds: ["referral", user], 未绑定推荐码时为空账户)
+    /// 17. `[writable]` ReferrerStats PDA (推荐人分成，未绑定时可传任意账户)
+    ///
+    /// 手续费按 FeeSchedule 拆分，保险基金部分通过 CPI 调用 Fund.AddTradingFee 计入保险基金，
+    /// 发出手续费 FeeEvent 及每个分配对象的 FeeEvent
     ClosePosition {
         user: Pubkey,
         market_index: u8,
         size_e6: u64,
         price_e6: u64,
         batch_id: u64,
-        /// 0 = taker, 1 = maker (for logging/stats)
+        /// 1 = taker, 0 = maker (决定 FeeEvent 的 fee_type)
         is_taker: u8,
         /// Fee rate in e6 units (e.g., 500 = 5 bps = 0.05%). Passed by Relayer.
         fee_rate_e6: u64,
@@ -159,7 +223,12 @@ pub enum LedgerInstruction {
     // ========================================================================
 
     /// 清算 (原子操作)
-    /// 1. 验证清算条件 (mark_price vs liquidation_price)
+    ///
+    /// 保证金率不低于 MarketConfig.full_liquidation_margin_ratio_e6 时执行部分清算:
+    /// 只平掉使保证金率回到 MMR + partial_liquidation_buffer_e6 的数量，
+    /// 亏损和罚金从仓位保证金扣除 (PositionEvent: Decreased)。
+    /// 否则 (或剩余仓位低于 min_liquidation_notional_e6) 全部清算 (PositionEvent: Liquidated):
+    /// 1. 验证清算条件 (PriceFeed 价格 vs liquidation_price)
     /// 2. CPI 调用 Vault.LiquidatePosition (更新用户账户 + 转移罚金到 Insurance Fund)
     /// 3. CPI 调用 Fund.AddLiquidationIncome (更新保险基金统计)
     /// 4. CPI 调用 Fund.CoverShortfall (如有穿仓)
@@ -181,25 +250,42 @@ pub enum LedgerInstruction {
     /// 11. `[writable]` Insurance Fund Vault (接收罚金)
     /// 12. `[writable]` Counterparty Vault (穿仓时接收覆盖)
     /// 13. `[]` Token Program
+    /// 14. `[]` MarketConfig PDA
+    /// 15. `[]` PriceFeed PDA (指数价格，检查延迟和置信区间)
+    /// 16. `[writable]` Liquidator UserAccount (Vault，接收清算奖励)
+    /// 17. `[writable]` LiquidatorStats PDA (不存在时自动创建，清算人付费)
+    /// 18. `[]` System Program
+    /// 19. `[writable]` ShortfallRecord PDA ["shortfall", user, market_index, shortfall_index]
+    ///     (穿仓时创建，清算人付费；shortfall_index 为清算前的 Position.shortfall_count)
+    /// 20. `[]` FundingState PDA (清算前计提资金费)
+    /// 21. `[writable]` MarkPrice PDA (按 PriceFeed 指数价格刷新后作为清算价格)
+    ///
+    /// 罚金按 MarketConfig.liquidator_reward_bps 拆分: 清算人奖励记入其 Vault 账户，其余进入保险基金
     Liquidate {
         user: Pubkey,
         market_index: u8,
-        mark_price_e6: u64,
     },
 
     /// 触发 ADL (自动减仓)
-    /// 当保险基金不足以覆盖穿仓时触发
+    /// 当保险基金不足以覆盖穿仓时触发，穿仓金额/方向取自 ShortfallRecord
+    ///
+    /// 目标仓位按 MarkPrice 标记价格计算 ADL 分数 (盈利率 × 有效杠杆)，
+    /// 必须按分数从高到低传入，否则返回 ADLTargetsNotRanked
     ///
     /// Accounts:
-    /// 0. `[signer]` Admin or Relayer
-    /// 1. `[]` InsuranceFund (或 InsuranceFundConfig in Fund Program)
-    /// 2. `[writable]` LedgerConfig
-    /// 3+ `[writable]` Target Position PDAs (按盈利排序)
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` LedgerConfig
+    /// 2. `[]` Fund Program
+    /// 3. `[writable]` InsuranceFundConfig
+    /// 4. `[]` Insurance Fund Vault (读取余额)
+    /// 5. `[]` PriceFeed PDA
+    /// 6. `[writable]` MarkPrice PDA (按 PriceFeed 指数价格刷新)
+    /// 7. `[writable]` ADLState PDA (不存在时自动创建，已有进行中的 ADL 时拒绝)
+    /// 8. `[]` System Program
+    /// 9. `[writable]` ShortfallRecord PDA (Liquidate 穿仓时创建，消费后不能再次触发)
+    /// 10+ `[]` Target Position PDAs (按 ADL 分数排序)
     TriggerADL {
         market_index: u8,
-        shortfall_e6: u64,
-        /// 穿仓方向 (需要 ADL 反向盈利仓位)
-        bankrupt_side: Side,
     },
 
     // ========================================================================
@@ -207,13 +293,26 @@ pub enum LedgerInstruction {
     // ========================================================================
 
     /// 结算资金费率
+    /// 按 FundingState 累计指数计提到最新 (size * (index - last_funding_index))，连同仓位此前计提
+    /// 未结算的部分实时计入仓位保证金，并通过 Vault.SettleFunding 同步用户锁定保证金；
+    /// 超过保证金的部分保留为未结算资金费
     ///
     /// Accounts:
     /// 0. `[signer]` Relayer
     /// 1. `[writable]` Position PDA
-    /// 2. `[writable]` UserAccount (Vault)
+    /// 2. `[writable]` UserAccount (Vault，wallet 必须为仓位用户)
     /// 3. `[writable]` VaultConfig
     /// 4. `[]` Vault Program
+    /// 5. `[writable]` LedgerConfig (CPI 调用者 / 事件序号)
+    /// 6. `[writable]` UserStats PDA ["user_stats", user] (未创建时跳过统计)
+    /// 7. `[]` MarketConfig PDA (刷新清算价格、资金费周期和费率上限)
+    /// 8. `[]` RelayerConfig (签名者必须是授权 Relayer)
+    /// 9. `[]` PremiumSamples PDA (费率和指数价格必须等于最近一次 PublishFundingRate 的结果)
+    /// 10. `[]` MarkPrice PDA (FundingEvent 中的标记价格，不得过期)
+    /// 11. `[]` FundingState PDA (累计资金费指数，费率和指数价格必须是最近一次 UpdateFundingIndex 计入的值)
+    ///
+    /// 距仓位上次结算不足 MarketConfig.funding_interval_secs 返回 FundingNotDue，
+    /// |funding_rate_e6| 超过 max_funding_rate_e6 返回 InvalidFundingRate
     SettleFunding {
         user: Pubkey,
         market_index: u8,
@@ -402,6 +501,489 @@ pub enum LedgerInstruction {
     RecordFundingEvents {
         events: Vec<FundingEventInput>,
     },
+
+    // ========================================================================
+    // 市场配置指令
+    // ========================================================================
+
+    /// 创建市场风控配置
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` MarketConfig PDA (seeds: ["market_config", market_index])
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreateMarketConfig {
+        market_index: u8,
+        params: MarketConfigParams,
+    },
+
+    /// 更新市场风控配置
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` MarketConfig PDA
+    /// 2. `[]` LedgerConfig
+    UpdateMarketConfig {
+        market_index: u8,
+        params: MarketConfigParams,
+    },
+
+    /// 设置市场风险档位 (按仓位名义价值分档的杠杆/维持保证金率)
+    ///
+    /// 传入空列表则回退到 MarketConfig 的基础参数。
+    /// 档位必须按 max_notional_e6 升序，最多 MAX_RISK_TIERS 个。
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` MarketConfig PDA
+    /// 2. `[]` LedgerConfig
+    SetRiskTiers {
+        market_index: u8,
+        tiers: Vec<RiskTier>,
+    },
+
+    // === 预言机指令 ===
+
+    /// 创建市场 PriceFeed PDA
+    ///
+    /// Accounts:
+    /// 0. `[signer, writable]` Admin
+    /// 1. `[writable]` PriceFeed PDA
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreatePriceFeed {
+        market_index: u8,
+        max_staleness_secs: i64,
+        max_confidence_e6: i64,
+    },
+
+    /// 更新预言机价格 (Relayer 多签)
+    ///
+    /// prices_e6[i] 为第 i 个签名 Relayer 的报价，写入价格取中位数，
+    /// 置信区间取 max(conf_e6, 报价离散度)。
+    /// 签名数必须 >= RelayerConfig.required_signatures，publish_ts 必须严格递增。
+    ///
+    /// Accounts:
+    /// 0. `[writable]` PriceFeed PDA
+    /// 1. `[]` RelayerConfig
+    /// 2. `[signer]` Relayers (共 prices_e6.len() 个，依次对应 prices_e6)
+    UpdatePriceFeed {
+        market_index: u8,
+        prices_e6: Vec<u64>,
+        conf_e6: u64,
+        publish_ts: i64,
+    },
+
+    /// 更新 PriceFeed 延迟 / 置信区间阈值
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` PriceFeed PDA
+    /// 2. `[]` LedgerConfig
+    SetPriceFeedParams {
+        market_index: u8,
+        max_staleness_secs: i64,
+        max_confidence_e6: i64,
+    },
+
+    // === 保证金管理 (逐仓) ===
+
+    /// 追加仓位保证金
+    /// 1. CPI 调用 Vault.lockMargin (从可用余额锁定 amount)
+    /// 2. 增加 Position.margin_e6，重新计算清算价格
+    ///
+    /// Accounts:
+    /// 0. `[signer]` User (仓位所有者) 或授权 Relayer
+    /// 1. `[writable]` Position PDA
+    /// 2. `[writable]` UserAccount (Vault)
+    /// 3. `[]` VaultConfig
+    /// 4. `[writable]` LedgerConfig
+    /// 5. `[]` RelayerConfig
+    /// 6. `[]` Vault Program
+    /// 7. `[]` MarketConfig PDA
+    /// 8. `[]` FundingState PDA (调整前计提资金费)
+    AddMargin {
+        user: Pubkey,
+        market_index: u8,
+        amount_e6: u64,
+    },
+
+    /// 减少仓位保证金
+    /// 1. 计提资金费后，按 MarkPrice 标记价格检查剩余保证金 (扣除未结算资金费) 满足初始保证金率 (浮盈不计入)
+    /// 2. 减少 Position.margin_e6，重新计算清算价格
+    /// 3. CPI 调用 Vault.releaseMargin
+    ///
+    /// Accounts:
+    /// 0. `[signer]` User (仓位所有者) 或授权 Relayer
+    /// 1. `[writable]` Position PDA
+    /// 2. `[writable]` UserAccount (Vault)
+    /// 3. `[]` VaultConfig
+    /// 4. `[writable]` LedgerConfig
+    /// 5. `[]` RelayerConfig
+    /// 6. `[]` Vault Program
+    /// 7. `[]` MarketConfig PDA
+    /// 8. `[]` FundingState PDA (调整前计提资金费)
+    /// 9. `[]` PriceFeed PDA
+    /// 10. `[writable]` MarkPrice PDA (按 PriceFeed 指数价格刷新)
+    RemoveMargin {
+        user: Pubkey,
+        market_index: u8,
+        amount_e6: u64,
+    },
+
+    /// 调整已有仓位的杠杆
+    /// 目标保证金 = 入场名义价值 / leverage，差额通过 Vault.lockMargin / releaseMargin 结算。
+    /// 提高杠杆 (释放保证金) 时与 RemoveMargin 相同，需满足当前价格下的初始保证金要求。
+    ///
+    /// Accounts: 同 RemoveMargin
+    SetLeverage {
+        user: Pubkey,
+        market_index: u8,
+        leverage: u8,
+    },
+
+    // ========================================================================
+    // ADL 执行
+    // ========================================================================
+
+    /// 执行 ADL (TriggerADL 之后，可分多次执行)
+    /// 按 ShortfallRecord 记录的破产价格依次减少目标仓位，直到让出的盈利 (相对 MarkPrice 标记价格) 覆盖
+    /// ADLState 中尚未覆盖的金额。让出的盈利由 Vault 转入保险基金 (Fund.AddADLProfit 记账)，
+    /// 并从 ShortfallRecord 的剩余穿仓金额中扣减。
+    ///
+    /// 破产价格需在标记价格的 liquidation_price_band_bps 以内，且对目标仓位不利于标记价格。
+    /// 目标仓位必须按 ADL 分数 (同 TriggerADL) 从高到低传入。
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` LedgerConfig
+    /// 2. `[]` VaultConfig
+    /// 3. `[]` Vault Program
+    /// 4. `[]` Fund Program
+    /// 5. `[writable]` Insurance Fund Account
+    /// 6. `[writable]` InsuranceFundConfig
+    /// 7. `[writable]` ADLState PDA (ADL 需已在进行中)
+    /// 8. `[]` MarketConfig PDA
+    /// 9. `[]` PriceFeed PDA
+    /// 10. `[]` FundingState PDA (减仓前计提资金费)
+    /// 11. `[writable]` MarkPrice PDA (按 PriceFeed 指数价格刷新)
+    /// 12. `[writable]` ShortfallRecord PDA (必须是 ADLState 记录的穿仓)
+    /// 13. `[writable]` Vault Token Account
+    /// 14. `[writable]` Insurance Fund Vault
+    /// 15. `[]` Token Program
+    /// 16. 每个目标仓位 2 个账户 (按 ADL 顺序):
+    ///     - `[writable]` Target Position PDA
+    ///     - `[writable]` Target UserAccount (Vault)
+    ExecuteADL {
+        market_index: u8,
+    },
+
+    /// 完成 ADL
+    /// ADLState 中的穿仓已完全覆盖后，CPI 调用 Fund.SetADLInProgress(false) 恢复 LP 赎回，
+    /// 并发出最终 ADLEvent (实际减仓数量和完成后保险基金余额)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` LedgerConfig
+    /// 2. `[]` Fund Program
+    /// 3. `[writable]` InsuranceFundConfig
+    /// 4. `[]` Insurance Fund Vault (读取余额)
+    /// 5. `[writable]` ADLState PDA
+    CompleteADL {
+        market_index: u8,
+    },
+
+    // ========================================================================
+    // 资金费率指数
+    // ========================================================================
+
+    /// 创建市场 FundingState (累计资金费指数)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` FundingState PDA
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreateFundingState {
+        market_index: u8,
+        funding_interval_secs: i64,
+    },
+
+    /// 按本周期资金费率更新累计指数 (每个周期一次)
+    /// 仓位不在此处逐个结算，而是在下次开/平仓、清算或 ADL 时按指数差额计提
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Relayer
+    /// 1. `[]` RelayerConfig
+    /// 2. `[writable]` FundingState PDA
+    /// 3. `[]` MarketConfig PDA (资金费率上限)
+    /// 4. `[]` PremiumSamples PDA (费率和指数价格必须等于最近一次 PublishFundingRate 的结果)
+    UpdateFundingIndex {
+        market_index: u8,
+        funding_rate_e6: i64,
+        index_price_e6: u64,
+    },
+
+    // ========================================================================
+    // 批量资金费
+    // ========================================================================
+
+    /// 批量结算资金费率 (同一市场、同一指数的多个仓位)
+    /// 每个仓位按 SettleFunding 规则结算并发出 FundingEvent，本轮汇总累加到 FundingSettlementSummary。
+    /// 同一 epoch 可拆分为多笔交易；未到结算周期或空仓位跳过。
+    ///
+    /// Accounts:
+    /// 0. `[signer, writable]` Relayer (汇总账户不存在时付费创建)
+    /// 1. `[]` RelayerConfig
+    /// 2. `[writable]` LedgerConfig (CPI 调用者 / 事件序号)
+    /// 3. `[writable]` VaultConfig
+    /// 4. `[]` Vault Program
+    /// 5. `[]` MarketConfig PDA
+    /// 6. `[writable]` FundingSettlementSummary PDA (seeds: ["funding_summary", market_index])
+    /// 7. `[]` System Program
+    /// 8. `[]` PremiumSamples PDA (费率和指数价格必须等于最近一次 PublishFundingRate 的结果)
+    /// 9. `[]` FundingState PDA (累计资金费指数，费率和指数价格必须是最近一次 UpdateFundingIndex 计入的值)
+    /// 10. `[]` MarkPrice PDA (FundingEvent 中的标记价格，不得过期)
+    /// 11. 每个仓位 3 个账户:
+    ///     - `[writable]` Position PDA
+    ///     - `[writable]` UserAccount (Vault)
+    ///     - `[writable]` UserStats PDA ["user_stats", user] (未创建时跳过统计)
+    SettleFundingBatch {
+        market_index: u8,
+        funding_rate_e6: i64,
+        index_price_e6: u64,
+        epoch: u64,
+    },
+
+    // ========================================================================
+    // 资金费率计算
+    // ========================================================================
+
+    /// 创建市场溢价采样缓冲区
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` PremiumSamples PDA (seeds: ["premium_samples", market_index])
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreatePremiumSamples {
+        market_index: u8,
+        interest_rate_e6: i64,
+        premium_clamp_e6: i64,
+    },
+
+    /// 更新利率分量和溢价钳制范围
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` PremiumSamples PDA
+    /// 2. `[]` LedgerConfig
+    SetFundingRateParams {
+        market_index: u8,
+        interest_rate_e6: i64,
+        premium_clamp_e6: i64,
+    },
+
+    /// 提交溢价采样 (采样时间取链上时钟)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Relayer
+    /// 1. `[]` RelayerConfig
+    /// 2. `[writable]` PremiumSamples PDA
+    SubmitPremiumSample {
+        market_index: u8,
+        mark_price_e6: u64,
+        index_price_e6: u64,
+    },
+
+    /// 发布本周期资金费率 (任何人可调用，每个 funding_interval_secs 一次)
+    /// rate = clamp(TWAP(premium) + clamp(interest - TWAP(premium), ±premium_clamp), ±max_funding_rate)，
+    /// TWAP 只使用上次发布之后的采样
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Caller
+    /// 1. `[writable]` PremiumSamples PDA
+    /// 2. `[]` MarketConfig PDA
+    PublishFundingRate {
+        market_index: u8,
+    },
+
+    // ========================================================================
+    // 标记价格
+    // ========================================================================
+
+    /// 创建市场标记价格
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` MarkPrice PDA (seeds: ["mark_price", market_index])
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreateMarkPrice {
+        market_index: u8,
+        ema_period_secs: i64,
+        max_staleness_secs: i64,
+    },
+
+    /// 刷新标记价格 (任何人可调用)
+    /// mark = median(index, index + EMA(last_trade - index), last_trade)，index 取 PriceFeed
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Caller
+    /// 1. `[writable]` MarkPrice PDA
+    /// 2. `[]` PriceFeed PDA
+    UpdateMarkPrice {
+        market_index: u8,
+    },
+
+    /// 按标记价格检查仓位健康度 (只读)
+    /// 结果以 PositionHealth (Borsh) 写入 return data，可通过模拟交易读取
+    ///
+    /// Accounts:
+    /// 0. `[]` Position PDA
+    /// 1. `[]` MarketConfig PDA
+    /// 2. `[]` FundingState PDA (计提未结算资金费)
+    /// 3. `[]` MarkPrice PDA (不得过期)
+    CheckPositionHealth {
+        user: Pubkey,
+        market_index: u8,
+    },
+
+    // ========================================================================
+    // 手续费分配
+    // ========================================================================
+
+    /// 创建全局手续费分配比例 (bps 之和必须为 10000，且在硬上限内) 和被推荐用户折扣
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` FeeSchedule PDA (seeds: ["fee_schedule"])
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreateFeeSchedule {
+        insurance_bps: u16,
+        treasury_bps: u16,
+        referrer_bps: u16,
+        maker_rebate_bps: u16,
+        /// 被推荐用户的手续费折扣 (bps)
+        referee_discount_bps: u16,
+    },
+
+    /// 更新手续费分配比例 (同 CreateFeeSchedule 的校验)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` FeeSchedule PDA
+    /// 2. `[]` LedgerConfig
+    UpdateFeeSchedule {
+        insurance_bps: u16,
+        treasury_bps: u16,
+        referrer_bps: u16,
+        maker_rebate_bps: u16,
+        /// 被推荐用户的手续费折扣 (bps)
+        referee_discount_bps: u16,
+    },
+
+    // ========================================================================
+    // 手续费档位
+    // ========================================================================
+
+    /// 创建手续费档位表 (按 30 天滚动成交量分档的 maker / taker 费率上限)
+    ///
+    /// 档位必须按 min_volume_e6 升序、第一档下限为 0、费率不递增，最多 MAX_FEE_TIERS 个。
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` FeeTierTable PDA (seeds: ["fee_tier_table"])
+    /// 2. `[]` LedgerConfig
+    /// 3. `[]` System Program
+    CreateFeeTierTable {
+        tiers: Vec<FeeTier>,
+    },
+
+    /// 更新手续费档位表 (同 CreateFeeTierTable 的校验)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Admin
+    /// 1. `[writable]` FeeTierTable PDA
+    /// 2. `[]` LedgerConfig
+    UpdateFeeTierTable {
+        tiers: Vec<FeeTier>,
+    },
+
+    // ========================================================================
+    // 推荐返佣
+    // ========================================================================
+
+    /// 注册推荐码 (推荐码非全零，每个推荐码一个 ReferrerStats)
+    ///
+    /// Accounts:
+    /// 0. `[signer, writable]` Referrer (payer)
+    /// 1. `[writable]` ReferrerStats PDA (seeds: ["referrer_stats", code])
+    /// 2. `[]` System Program
+    CreateReferrerCode {
+        code: ReferralCode,
+    },
+
+    /// 用户绑定推荐码 (一次性，不能绑定自己的推荐码)
+    ///
+    /// Accounts:
+    /// 0. `[signer, writable]` User (payer)
+    /// 1. `[writable]` Referral PDA (seeds: ["referral", user])
+    /// 2. `[writable]` ReferrerStats PDA
+    /// 3. `[]` System Program
+    BindReferral {
+        code: ReferralCode,
+    },
+
+    /// 推荐人领取累计的手续费分成 (CPI Vault.CreditReferralReward 从已收取的手续费中划转到推荐人账户)
+    ///
+    /// Accounts:
+    /// 0. `[signer]` Referrer
+    /// 1. `[writable]` ReferrerStats PDA
+    /// 2. `[writable]` Referrer UserAccount (Vault)
+    /// 3. `[]` VaultConfig
+    /// 4. `[]` LedgerConfig
+    /// 5. `[]` Vault Program
+    ClaimReferralRewards {
+        code: ReferralCode,
+    },
+}
+
+/// 市场风控参数 (CreateMarketConfig / UpdateMarketConfig)
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct MarketConfigParams {
+    /// 最大杠杆倍数
+    pub max_leverage: u8,
+    /// 初始保证金率 (e6)
+    pub initial_margin_rate_e6: i64,
+    /// 维持保证金率 (e6)
+    pub maintenance_margin_rate_e6: i64,
+    /// 清算罚金率 (e6)
+    pub liquidation_penalty_rate_e6: i64,
+    /// 部分清算目标保证金率 buffer (e6, 目标 = MMR + buffer)
+    pub partial_liquidation_buffer_e6: i64,
+    /// 全部清算保证金率阈值 (e6)
+    pub full_liquidation_margin_ratio_e6: i64,
+    /// 部分清算后最小剩余名义价值 (e6)
+    pub min_liquidation_notional_e6: u64,
+    /// 清算人奖励占罚金比例 (bps)
+    pub liquidator_reward_bps: u16,
+    /// 最小价格变动单位 (e6)
+    pub tick_size_e6: u64,
+    /// 最小数量变动单位 (e6)
+    pub lot_size_e6: u64,
+    /// 普通成交价格带宽 (bps)
+    pub price_band_bps: u16,
+    /// 清算 / ADL 成交价格带宽 (bps)
+    pub liquidation_price_band_bps: u16,
+    /// 资金费结算周期 (秒)
+    pub funding_interval_secs: i64,
+    /// 单期资金费率绝对值上限 (e6)
+    pub max_funding_rate_e6: i64,
+    /// 是否允许开仓
+    pub is_active: bool,
 }
 
 /// 订单事件输入数据（由 Relayer 提交）
@@ -481,10 +1063,90 @@ pub struct TradeData {
     pub price_e6: u64,
     /// 杠杆 (仅开仓)
     pub leverage: u8,
-    /// 0 = taker, 1 = maker (for logging/stats)
+    /// 1 = taker, 0 = maker (决定 FeeEvent 的 fee_type)
     pub is_taker: u8,
     /// Fee rate in e6 units (e.g., 500 = 5 bps). Passed by Relayer.
-    pub fee_rate_e6: u64,
+    /// 负值 = maker 返佣 (仅 ExecuteTradeBatch 的 maker)
+    pub fee_rate_e6: i64,
+}
+
+impl TradeData {
+    /// 实际收取的费率 (负费率的返佣另行结算，此处按 0 收取)
+    pub fn charged_fee_rate_e6(&self) -> u64 {
+        self.fee_rate_e6.max(0) as u64
+    }
+}
+
+/// 撮合成交的一方
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct TradeLeg {
+    /// 用户
+    pub user: Pubkey,
+    /// 订单 ID
+    pub order_id: [u8; 16],
+    /// 交易类型 (0=Open, 1=Close)
+    pub trade_type: u8,
+    /// 成交方向 (平仓时为原仓位的反方向)
+    pub side: Side,
+    /// 数量 (e6)
+    pub size_e6: u64,
+    /// 杠杆 (仅开仓)
+    pub leverage: u8,
+    /// Fee rate in e6 units (e.g., 500 = 5 bps). Passed by Relayer.
+    /// maker 可为负值 (返佣，不超过同批次 Maker 返佣拆分部分)，taker 必须 >= 0
+    pub fee_rate_e6: i64,
+}
+
+/// 撮合成交 (用于 ExecuteTradeBatch): 同一价格下 maker 与 taker 的配对
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct MatchedTrade {
+    /// 市场索引
+    pub market_index: u8,
+    /// 成交价格 (e6)
+    pub price_e6: u64,
+    /// Maker
+    pub maker: TradeLeg,
+    /// Taker
+    pub taker: TradeLeg,
+}
+
+impl MatchedTrade {
+    /// 校验双方数量净额为零 (一方 Long、一方 Short、数量相等；同一成交价下名义价值也相等)，
+    /// 展开为 [maker, taker] 两条单边交易
+    ///
+    /// 费率绝对值不超过 1% (InvalidFeeRate)，只有 maker 可以为负
+    pub fn legs(&self) -> Result<[TradeData; 2], LedgerError> {
+        let signed_size = |leg: &TradeLeg| match leg.side {
+            Side::Long => leg.size_e6 as i128,
+            Side::Short => -(leg.size_e6 as i128),
+        };
+        if self.maker.size_e6 == 0 || signed_size(&self.maker) + signed_size(&self.taker) != 0 {
+            return Err(LedgerError::UnbalancedTrade);
+        }
+        if self.taker.fee_rate_e6 < 0
+            || self.taker.fee_rate_e6 > 10_000
+            || self.maker.fee_rate_e6.unsigned_abs() > 10_000
+        {
+            return Err(LedgerError::InvalidFeeRate);
+        }
+        let to_trade = |leg: &TradeLeg, is_taker: u8| -> Result<TradeData, LedgerError> {
+            if leg.trade_type != trade_data_type::OPEN && leg.trade_type != trade_data_type::CLOSE {
+                return Err(LedgerError::InvalidTradeAmount);
+            }
+            Ok(TradeData {
+                user: leg.user,
+                market_index: self.market_index,
+                trade_type: leg.trade_type,
+                side: leg.side,
+                size_e6: leg.size_e6,
+                price_e6: self.price_e6,
+                leverage: leg.leverage,
+                is_taker,
+                fee_rate_e6: leg.fee_rate_e6,
+            })
+        };
+        Ok([to_trade(&self.maker, 0)?, to_trade(&self.taker, 1)?])
+    }
 }
 
 /// 交易数据类型常量
@@ -493,3 +1155,62 @@ pub mod trade_data_type {
     pub const CLOSE: u8 = 1;
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leg(side: Side, trade_type: u8, size_e6: u64) -> TradeLeg {
+        TradeLeg {
+            user: Pubkey::new_unique(),
+            order_id: [1u8; 16],
+            trade_type,
+            side,
+            size_e6,
+            leverage: 10,
+            fee_rate_e6: 500,
+        }
+    }
+
+    #[test]
+    fn test_matched_trade_legs() {
+        let fill = MatchedTrade {
+            market_index: 1,
+            price_e6: 50_000_000_000,
+            maker: leg(Side::Short, trade_data_type::OPEN, 2_000_000),
+            taker: leg(Side::Long, trade_data_type::CLOSE, 2_000_000),
+        };
+        let [maker, taker] = fill.legs().unwrap();
+        assert_eq!((maker.user, maker.side, maker.is_taker), (fill.maker.user, Side::Short, 0));
+        assert_eq!((taker.user, taker.trade_type, taker.is_taker), (fill.taker.user, trade_data_type::CLOSE, 1));
+        assert_eq!((maker.price_e6, taker.price_e6), (fill.price_e6, fill.price_e6));
+        assert_eq!(maker.market_index, 1);
+
+        // 同方向或数量不等不能对冲
+        let mut same_side = fill.clone();
+        same_side.taker.side = Side::Short;
+        assert_eq!(same_side.legs(), Err(LedgerError::UnbalancedTrade));
+        let mut uneven = fill.clone();
+        uneven.taker.size_e6 = 1_000_000;
+        assert_eq!(uneven.legs(), Err(LedgerError::UnbalancedTrade));
+        let mut empty = fill.clone();
+        empty.maker.size_e6 = 0;
+        empty.taker.size_e6 = 0;
+        assert_eq!(empty.legs(), Err(LedgerError::UnbalancedTrade));
+
+        let mut unknown = fill.clone();
+        unknown.maker.trade_type = 7;
+        assert_eq!(unknown.legs(), Err(LedgerError::InvalidTradeAmount));
+
+        // 只有 maker 可以使用负费率 (返佣)
+        let mut rebate = fill.clone();
+        rebate.maker.fee_rate_e6 = -200;
+        let [maker, _] = rebate.legs().unwrap();
+        assert_eq!((maker.fee_rate_e6, maker.charged_fee_rate_e6()), (-200, 0));
+        let mut negative_taker = fill.clone();
+        negative_taker.taker.fee_rate_e6 = -200;
+        assert_eq!(negative_taker.legs(), Err(LedgerError::InvalidFeeRate));
+        let mut excessive_rebate = fill;
+        excessive_rebate.maker.fee_rate_e6 = -10_001;
+        assert_eq!(excessive_rebate.legs(), Err(LedgerError::InvalidFeeRate));
+    }
+}
@@ -5,7 +5,7 @@ use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::invoke_signed,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
@@ -16,7 +16,7 @@ use solana_program::{
 use crate::{
     error::LedgerError,
     events::{self, OrderEvent, emit_order_event, event_discriminator, PositionEvent, TradeEvent, BatchEvent, FeeEvent, InsuranceFundEvent, emit_position_event, emit_trade_event, emit_batch_event, emit_fee_event, emit_insurance_fund_event},
-    instruction::{LedgerInstruction, TradeData, OrderEventInput, FundingEventInput, trade_data_type},
+    instruction::{LedgerInstruction, TradeData, MatchedTrade, OrderEventInput, FundingEventInput, MarketConfigParams, trade_data_type},
     state::*,
     utils::*,
     cpi,
@@ -29,462 +29,5147 @@ fn deserialize_account<T: BorshDeserialize>(data: &[u8]) -> Result<T, std::io::E
     T::deserialize(&mut slice)
 }
 
-/// 主处理函数
-pub fn process_instruction(
+/// 读取并验证 LedgerConfig PDA
+///
+/// 所有依赖 `admin` / `vault_program` / `fund_program` 的路径都必须经过此处，
+/// 否则调用方可以传入伪造的 LedgerConfig 冒充管理员。
+fn load_ledger_config(
     program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    instruction_data: &[u8],
-) -> ProgramResult {
-    let instruction = LedgerInstruction::try_from_slice(instruction_data)
-        .map_err(|_| LedgerError::InvalidInstructionData)?;
+    ledger_config_info: &AccountInfo,
+) -> Result<LedgerConfig, ProgramError> {
+    let (ledger_config_pda, _) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+    if ledger_config_info.key != &ledger_config_pda {
+        msg!("❌ Invalid LedgerConfig PDA");
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if ledger_config_info.owner != program_id || ledger_config_info.data_len() < LedgerConfig::SIZE {
+        msg!("❌ LedgerConfig not initialized");
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    if ledger_config.discriminator != LedgerConfig::DISCRIMINATOR {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(ledger_config)
+}
 
-    match instruction {
-        // 初始化
-        LedgerInstruction::Initialize => {
-            msg!("Instruction: Initialize");
-            process_initialize(program_id, accounts)
-        }
-        LedgerInstruction::InitializeRelayers { relayers, required_signatures } => {
-            msg!("Instruction: InitializeRelayers");
-            process_initialize_relayers(program_id, accounts, relayers, required_signatures)
-        }
-        LedgerInstruction::InitializeUserStats => {
-            msg!("Instruction: InitializeUserStats");
-            process_initialize_user_stats(program_id, accounts)
-        }
+/// 读取并验证 MarketConfig PDA
+///
+/// - 地址不匹配 -> InvalidAccount
+/// - 账户未创建 / 未初始化 (市场未配置) -> InvalidMarketIndex
+fn load_market_config(
+    program_id: &Pubkey,
+    market_config_info: &AccountInfo,
+    market_index: u8,
+) -> Result<MarketConfig, ProgramError> {
+    let (market_config_pda, _) = Pubkey::find_program_address(
+        &[MarketConfig::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if market_config_info.key != &market_config_pda {
+        msg!("❌ Invalid MarketConfig PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    read_market_config(program_id, market_config_info, market_index)
+}
 
-        // 多签
-        LedgerInstruction::SubmitTradeBatch { batch_id, data_hash } => {
-            msg!("Instruction: SubmitTradeBatch");
-            process_submit_trade_batch(program_id, accounts, batch_id, data_hash)
-        }
-        LedgerInstruction::ConfirmTradeBatch { batch_id, data_hash } => {
-            msg!("Instruction: ConfirmTradeBatch");
-            process_confirm_trade_batch(program_id, accounts, batch_id, data_hash)
-        }
-        LedgerInstruction::ExecuteTradeBatch { batch_id, trades } => {
-            msg!("Instruction: ExecuteTradeBatch");
-            process_execute_trade_batch(program_id, accounts, batch_id, trades)
-        }
+/// 在账户列表中按 PDA 地址查找 MarketConfig 并读取
+///
+/// 用于 ExecuteTradeBatch: 市场账户追加在每笔交易账户之后，顺序不限
+fn find_market_config(
+    program_id: &Pubkey,
+    market_accounts: &[AccountInfo],
+    market_index: u8,
+) -> Result<MarketConfig, ProgramError> {
+    let (market_config_pda, _) = Pubkey::find_program_address(
+        &[MarketConfig::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    let market_config_info = market_accounts
+        .iter()
+        .find(|a| a.key == &market_config_pda)
+        .ok_or_else(|| {
+            msg!("❌ Missing MarketConfig account for market {}", market_index);
+            LedgerError::InsufficientAccounts
+        })?;
+    read_market_config(program_id, market_config_info, market_index)
+}
 
-        // 交易
-        LedgerInstruction::OpenPosition {
-            user,
-            market_index,
-            side,
-            size_e6,
-            price_e6,
-            leverage,
-            batch_id,
-            is_taker: _,
-            fee_rate_e6,
-        } => {
-            msg!("Instruction: OpenPosition");
-            if fee_rate_e6 > 10_000 {
-                return Err(LedgerError::InvalidFeeRate.into());
-            }
-            process_open_position(
-                program_id, accounts, user, market_index, side, size_e6, price_e6, leverage, batch_id, fee_rate_e6,
-            )
-        }
-        LedgerInstruction::ClosePosition {
-            user,
-            market_index,
-            size_e6,
-            price_e6,
-            batch_id,
-            is_taker: _,
-            fee_rate_e6,
-        } => {
-            msg!("Instruction: ClosePosition");
-            if fee_rate_e6 > 10_000 {
-                return Err(LedgerError::InvalidFeeRate.into());
-            }
-            process_close_position(program_id, accounts, user, market_index, size_e6, price_e6, batch_id, fee_rate_e6)
-        }
+/// 读取并校验 PriceFeed PDA
+fn load_price_feed(
+    program_id: &Pubkey,
+    price_feed_info: &AccountInfo,
+    market_index: u8,
+) -> Result<PriceFeed, ProgramError> {
+    let (price_feed_pda, _) = Pubkey::find_program_address(
+        &[PriceFeed::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if price_feed_info.key != &price_feed_pda {
+        msg!("❌ Invalid PriceFeed PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if price_feed_info.owner != program_id || price_feed_info.data_len() < PriceFeed::SIZE {
+        msg!("❌ PriceFeed for market {} not initialized", market_index);
+        return Err(LedgerError::InvalidPriceFeed.into());
+    }
+    let price_feed = deserialize_account::<PriceFeed>(&price_feed_info.data.borrow())?;
+    if price_feed.discriminator != PriceFeed::DISCRIMINATOR || price_feed.market_index != market_index {
+        return Err(LedgerError::InvalidPriceFeed.into());
+    }
+    Ok(price_feed)
+}
 
-        // 清算
-        LedgerInstruction::Liquidate {
-            user,
-            market_index,
-            mark_price_e6,
-        } => {
-            msg!("Instruction: Liquidate");
-            process_liquidate(program_id, accounts, user, market_index, mark_price_e6)
-        }
-        LedgerInstruction::TriggerADL {
-            market_index,
-            shortfall_e6,
-            bankrupt_side,
-        } => {
-            msg!("Instruction: TriggerADL");
-            process_trigger_adl(program_id, accounts, market_index, shortfall_e6, bankrupt_side)
-        }
+/// 读取并验证 ADLState PDA
+fn load_adl_state(
+    program_id: &Pubkey,
+    adl_state_info: &AccountInfo,
+    market_index: u8,
+) -> Result<ADLState, ProgramError> {
+    let (adl_state_pda, _) = Pubkey::find_program_address(
+        &[ADLState::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if adl_state_info.key != &adl_state_pda {
+        msg!("❌ Invalid ADLState PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if adl_state_info.owner != program_id || adl_state_info.data_len() < ADLState::SIZE {
+        msg!("❌ ADLState for market {} not initialized", market_index);
+        return Err(LedgerError::ADLNotTriggered.into());
+    }
+    let adl_state = deserialize_account::<ADLState>(&adl_state_info.data.borrow())?;
+    if adl_state.discriminator != ADLState::DISCRIMINATOR || adl_state.market_index != market_index {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(adl_state)
+}
 
-        // 资金费率
-        LedgerInstruction::SettleFunding {
-            user,
-            market_index,
+/// 读取并验证 ShortfallRecord (必须由本程序在 Liquidate 中创建)
+fn load_shortfall_record(
+    program_id: &Pubkey,
+    shortfall_record_info: &AccountInfo,
+    market_index: u8,
+) -> Result<ShortfallRecord, ProgramError> {
+    if shortfall_record_info.owner != program_id || shortfall_record_info.data_len() < ShortfallRecord::SIZE {
+        msg!("❌ ShortfallRecord {} not initialized", shortfall_record_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let record = deserialize_account::<ShortfallRecord>(&shortfall_record_info.data.borrow())?;
+    if record.discriminator != ShortfallRecord::DISCRIMINATOR || record.market_index != market_index {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(record)
+}
+
+/// 读取并校验 FundingState PDA
+fn load_funding_state(
+    program_id: &Pubkey,
+    funding_state_info: &AccountInfo,
+    market_index: u8,
+) -> Result<FundingState, ProgramError> {
+    let (funding_state_pda, _) = Pubkey::find_program_address(
+        &[FundingState::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if funding_state_info.key != &funding_state_pda {
+        msg!("❌ Invalid FundingState PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if funding_state_info.owner != program_id || funding_state_info.data_len() < FundingState::SIZE {
+        msg!("❌ FundingState for market {} not initialized", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let funding_state = deserialize_account::<FundingState>(&funding_state_info.data.borrow())?;
+    if funding_state.discriminator != FundingState::DISCRIMINATOR || funding_state.market_index != market_index {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(funding_state)
+}
+
+/// 在账户列表中按 PDA 地址查找 FundingState 并读取
+fn find_funding_state(
+    program_id: &Pubkey,
+    market_accounts: &[AccountInfo],
+    market_index: u8,
+) -> Result<FundingState, ProgramError> {
+    let (funding_state_pda, _) = Pubkey::find_program_address(
+        &[FundingState::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    let funding_state_info = market_accounts
+        .iter()
+        .find(|a| a.key == &funding_state_pda)
+        .ok_or_else(|| {
+            msg!("❌ Missing FundingState account for market {}", market_index);
+            LedgerError::InsufficientAccounts
+        })?;
+    load_funding_state(program_id, funding_state_info, market_index)
+}
+
+/// 读取并校验 PremiumSamples PDA
+fn load_premium_samples(
+    program_id: &Pubkey,
+    premium_samples_info: &AccountInfo,
+    market_index: u8,
+) -> Result<PremiumSamples, ProgramError> {
+    let (premium_samples_pda, _) = Pubkey::find_program_address(
+        &[PremiumSamples::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if premium_samples_info.key != &premium_samples_pda {
+        msg!("❌ Invalid PremiumSamples PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if premium_samples_info.owner != program_id || premium_samples_info.data_len() < PremiumSamples::SIZE {
+        msg!("❌ PremiumSamples for market {} not initialized", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let premium_samples = deserialize_account::<PremiumSamples>(&premium_samples_info.data.borrow())?;
+    if premium_samples.discriminator != PremiumSamples::DISCRIMINATOR || premium_samples.market_index != market_index {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(premium_samples)
+}
+
+/// 校验 Relayer 提交的资金费率是否为链上最近一次发布的结果
+fn check_published_funding_rate(
+    program_id: &Pubkey,
+    premium_samples_info: &AccountInfo,
+    market_config: &MarketConfig,
+    funding_rate_e6: i64,
+    index_price_e6: u64,
+    current_ts: i64,
+) -> ProgramResult {
+    let premium_samples = load_premium_samples(program_id, premium_samples_info, market_config.market_index)?;
+    if let Err(e) = premium_samples.check_published(
+        funding_rate_e6,
+        index_price_e6,
+        market_config.funding_interval_secs,
+        current_ts,
+    ) {
+        msg!(
+            "❌ Funding rate {} / index {} does not match published rate {} / index {} (published_at={})",
             funding_rate_e6,
             index_price_e6,
-        } => {
-            msg!("Instruction: SettleFunding");
-            process_settle_funding(program_id, accounts, user, market_index, funding_rate_e6, index_price_e6)
-        }
+            premium_samples.last_funding_rate_e6,
+            premium_samples.last_index_price_e6,
+            premium_samples.last_published_ts
+        );
+        return Err(e.into());
+    }
+    Ok(())
+}
 
-        // 管理
-        LedgerInstruction::AddRelayer { relayer } => {
-            msg!("Instruction: AddRelayer");
-            process_add_relayer(accounts, relayer)
-        }
-        LedgerInstruction::RemoveRelayer { relayer } => {
-            msg!("Instruction: RemoveRelayer");
-            process_remove_relayer(accounts, relayer)
-        }
-        LedgerInstruction::UpdateRequiredSignatures { required_signatures } => {
-            msg!("Instruction: UpdateRequiredSignatures");
-            process_update_required_signatures(accounts, required_signatures)
-        }
-        LedgerInstruction::SetPaused { paused } => {
-            msg!("Instruction: SetPaused");
-            process_set_paused(accounts, paused)
-        }
-        LedgerInstruction::UpdateAdmin { new_admin } => {
-            msg!("Instruction: UpdateAdmin");
-            process_update_admin(accounts, new_admin)
-        }
-        LedgerInstruction::UpdateVaultProgram { new_vault_program } => {
-            msg!("Instruction: UpdateVaultProgram");
-            process_update_vault_program(accounts, new_vault_program)
-        }
-        LedgerInstruction::UpdateFundProgram { new_fund_program } => {
-            msg!("Instruction: UpdateFundProgram");
-            process_update_fund_program(accounts, new_fund_program)
-        }
-        LedgerInstruction::InitializeUserStats => {
-            msg!("Instruction: InitializeUserStats");
-            process_initialize_user_stats(program_id, accounts)
-        }
-        LedgerInstruction::AdminResetPosition { user, market_index } => {
-            msg!("Instruction: AdminResetPosition");
-            process_admin_reset_position(program_id, accounts, user, market_index)
-        }
-        
-        // Spot 交易指令
-        LedgerInstruction::RecordSpotTrade {
-            user,
-            market_index,
-            is_buy,
-            base_amount_e6,
-            quote_amount_e6,
-            price_e6,
+/// 在账户列表中按 PDA 地址查找 PriceFeed 并读取
+fn find_price_feed(
+    program_id: &Pubkey,
+    market_accounts: &[AccountInfo],
+    market_index: u8,
+) -> Result<PriceFeed, ProgramError> {
+    let (price_feed_pda, _) = Pubkey::find_program_address(
+        &[PriceFeed::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    let price_feed_info = market_accounts
+        .iter()
+        .find(|a| a.key == &price_feed_pda)
+        .ok_or_else(|| {
+            msg!("❌ Missing PriceFeed account for market {}", market_index);
+            LedgerError::InsufficientAccounts
+        })?;
+    load_price_feed(program_id, price_feed_info, market_index)
+}
+
+/// 读取并校验 MarkPrice PDA
+fn load_mark_price(
+    program_id: &Pubkey,
+    mark_price_info: &AccountInfo,
+    market_index: u8,
+) -> Result<MarkPrice, ProgramError> {
+    let (mark_price_pda, _) = Pubkey::find_program_address(
+        &[MarkPrice::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if mark_price_info.key != &mark_price_pda {
+        msg!("❌ Invalid MarkPrice PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if mark_price_info.owner != program_id || mark_price_info.data_len() < MarkPrice::SIZE {
+        msg!("❌ MarkPrice for market {} not initialized", market_index);
+        return Err(LedgerError::InvalidMarkPrice.into());
+    }
+    let mark_price = deserialize_account::<MarkPrice>(&mark_price_info.data.borrow())?;
+    if mark_price.discriminator != MarkPrice::DISCRIMINATOR || mark_price.market_index != market_index {
+        return Err(LedgerError::InvalidMarkPrice.into());
+    }
+    Ok(mark_price)
+}
+
+/// 将成交价格写入 MarkPrice (开/平仓和批量成交)
+fn record_trade_price(
+    program_id: &Pubkey,
+    mark_price_info: &AccountInfo,
+    market_index: u8,
+    price_e6: u64,
+    current_ts: i64,
+) -> ProgramResult {
+    assert_writable(mark_price_info)?;
+    let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+    mark_price.record_trade(price_e6, current_ts)?;
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// 开仓: CPI Vault 锁定保证金，再从用户可用余额扣除手续费
+///
+/// 手续费不计入锁定保证金 / Position.margin_e6，平仓时不会退还给用户
+fn lock_margin_and_charge_fee<'a>(
+    vault_program_id: &Pubkey,
+    vault_config_info: &AccountInfo<'a>,
+    user_account_info: &AccountInfo<'a>,
+    ledger_config_info: &AccountInfo<'a>,
+    margin_e6: u64,
+    fee_e6: u64,
+    ledger_config_bump: u8,
+) -> ProgramResult {
+    let signer_seeds: &[&[&[u8]]] = &[&[b"ledger_config", &[ledger_config_bump]]];
+    cpi::lock_margin(
+        vault_program_id,
+        vault_config_info.clone(),
+        user_account_info.clone(),
+        ledger_config_info.clone(),
+        margin_e6,
+        signer_seeds,
+    )?;
+    if fee_e6 > 0 {
+        cpi::charge_trading_fee(
+            vault_program_id,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
             fee_e6,
-            is_taker,
-            batch_id,
-        } => {
-            msg!("Instruction: RecordSpotTrade");
-            process_record_spot_trade(
-                program_id, accounts, user, market_index, is_buy,
-                base_amount_e6, quote_amount_e6, price_e6, fee_e6, is_taker, batch_id
-            )
-        }
-        LedgerInstruction::BatchRecordSpotTrades { trades, batch_id } => {
-            msg!("Instruction: BatchRecordSpotTrades");
-            process_batch_record_spot_trades(program_id, accounts, trades, batch_id)
+            signer_seeds,
+        )?;
+    }
+    Ok(())
+}
+
+/// 交易手续费的保险基金部分通过 Fund.AddTradingFee 计入保险基金 (fee 为 0 时跳过)
+///
+/// 调用前手续费必须已由 Vault 从用户账户扣除。金库 / 推荐人 / Maker 返佣部分留在 Vault Token Account
+/// 中并累计到 FeeSchedule: Maker 返佣和推荐人分成从这里通过 CreditFeeRebate / CreditReferralReward 支付，
+/// LedgerConfig 没有配置金库 Token 账户，金库部分由 FeeSchedule.total_treasury_e6 记账
+fn add_trading_fee_to_fund<'a>(
+    ledger_config: &LedgerConfig,
+    ledger_config_info: &AccountInfo<'a>,
+    fund_program: &AccountInfo<'a>,
+    insurance_fund_info: &AccountInfo<'a>,
+    insurance_config_info: &AccountInfo<'a>,
+    fee_e6: u64,
+    ledger_config_bump: u8,
+) -> ProgramResult {
+    if fee_e6 == 0 {
+        return Ok(());
+    }
+    if fund_program.key != &ledger_config.fund_program {
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+    cpi::add_trading_fee(
+        fund_program.key,
+        ledger_config_info.clone(),
+        insurance_fund_info.clone(),
+        insurance_config_info.clone(),
+        i64::try_from(fee_e6).map_err(|_| LedgerError::Overflow)?,
+        &[&[b"ledger_config", &[ledger_config_bump]]],
+    )
+}
+
+/// 发出交易手续费 FeeEvent (fee 为 0 时跳过)
+fn emit_trading_fee_event(
+    sequence: u64,
+    related_trade_sequence: u64,
+    user: Pubkey,
+    market_index: u8,
+    fee_e6: u64,
+    is_taker: bool,
+    current_ts: i64,
+) {
+    if fee_e6 == 0 {
+        return;
+    }
+    let fee_type = if is_taker { events::FeeType::TakerFee } else { events::FeeType::MakerFee };
+    emit_fee_event(&FeeEvent {
+        discriminator: event_discriminator::FEE,
+        sequence,
+        timestamp: current_ts,
+        user,
+        market_index,
+        fee_type: fee_type as u8,
+        amount_e6: fee_e6 as i64,
+        related_trade_sequence,
+    });
+}
+
+/// 读取并校验 FeeSchedule PDA
+fn load_fee_schedule(program_id: &Pubkey, fee_schedule_info: &AccountInfo) -> Result<FeeSchedule, ProgramError> {
+    let (fee_schedule_pda, _) = Pubkey::find_program_address(&[FeeSchedule::SEED_PREFIX], program_id);
+    if fee_schedule_info.key != &fee_schedule_pda {
+        msg!("❌ Invalid FeeSchedule PDA");
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if fee_schedule_info.owner != program_id || fee_schedule_info.data_len() < FeeSchedule::SIZE {
+        msg!("❌ FeeSchedule not initialized");
+        return Err(LedgerError::InvalidFeeSchedule.into());
+    }
+    let fee_schedule = deserialize_account::<FeeSchedule>(&fee_schedule_info.data.borrow())?;
+    if fee_schedule.discriminator != FeeSchedule::DISCRIMINATOR {
+        return Err(LedgerError::InvalidFeeSchedule.into());
+    }
+    Ok(fee_schedule)
+}
+
+/// 读取并校验 FeeTierTable PDA
+fn load_fee_tier_table(program_id: &Pubkey, fee_tier_table_info: &AccountInfo) -> Result<FeeTierTable, ProgramError> {
+    let (fee_tier_table_pda, _) = Pubkey::find_program_address(&[FeeTierTable::SEED_PREFIX], program_id);
+    if fee_tier_table_info.key != &fee_tier_table_pda {
+        msg!("❌ Invalid FeeTierTable PDA");
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if fee_tier_table_info.owner != program_id || fee_tier_table_info.data_len() < FeeTierTable::SIZE {
+        msg!("❌ FeeTierTable not initialized");
+        return Err(LedgerError::InvalidFeeTier.into());
+    }
+    let fee_tier_table = deserialize_account::<FeeTierTable>(&fee_tier_table_info.data.borrow())?;
+    if fee_tier_table.discriminator != FeeTierTable::DISCRIMINATOR {
+        return Err(LedgerError::InvalidFeeTier.into());
+    }
+    Ok(fee_tier_table)
+}
+
+/// 校验 Relayer 提交的费率不高于用户所在档位的费率
+///
+/// 档位按用户 UserStats 的 30 天滚动成交量确定 (UserStats 尚未创建时为 0)。
+/// UserStats 地址必须是该用户的 PDA，防止传入其他账户抬高档位费率。
+fn check_tier_fee_rate(
+    program_id: &Pubkey,
+    fee_tier_table: &FeeTierTable,
+    user_stats_info: &AccountInfo,
+    user: &Pubkey,
+    is_taker: bool,
+    fee_rate_e6: i64,
+    current_ts: i64,
+) -> ProgramResult {
+    let (user_stats_pda, _) = Pubkey::find_program_address(&[UserStats::SEED_PREFIX, user.as_ref()], program_id);
+    if user_stats_info.key != &user_stats_pda {
+        msg!("❌ Invalid UserStats PDA for {}", user);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let rolling_volume_e6 = if user_stats_info.owner == program_id && user_stats_info.data_len() >= UserStats::SIZE {
+        deserialize_account::<UserStats>(&user_stats_info.data.borrow())
+            .map(|stats| stats.rolling_volume_e6(current_ts))
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    fee_tier_table
+        .check_fee_rate(rolling_volume_e6, is_taker, fee_rate_e6)
+        .inspect_err(|_| {
+            msg!(
+                "❌ Fee rate {} above tier rate for {} (30d volume={}, taker={})",
+                fee_rate_e6, user, rolling_volume_e6, is_taker
+            );
+        })?;
+    Ok(())
+}
+
+/// 读取用户的推荐关系 (Referral PDA 尚未创建时返回 None)
+fn load_referral(program_id: &Pubkey, referral_info: &AccountInfo, user: &Pubkey) -> Result<Option<Referral>, ProgramError> {
+    let (referral_pda, _) = Pubkey::find_program_address(&[Referral::SEED_PREFIX, user.as_ref()], program_id);
+    if referral_info.key != &referral_pda {
+        msg!("❌ Invalid Referral PDA for {}", user);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if referral_info.owner != program_id || referral_info.data_len() < Referral::SIZE {
+        return Ok(None);
+    }
+    let referral = deserialize_account::<Referral>(&referral_info.data.borrow())?;
+    if referral.discriminator != Referral::DISCRIMINATOR || referral.user != *user {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    Ok(Some(referral))
+}
+
+/// 在批量账户中查找用户的 Referral PDA (必须提供，用于确定是否有推荐人)
+fn find_referral(
+    program_id: &Pubkey,
+    market_accounts: &[AccountInfo],
+    user: &Pubkey,
+) -> Result<Option<Referral>, ProgramError> {
+    let (referral_pda, _) = Pubkey::find_program_address(&[Referral::SEED_PREFIX, user.as_ref()], program_id);
+    let referral_info = market_accounts
+        .iter()
+        .find(|a| a.key == &referral_pda)
+        .ok_or_else(|| {
+            msg!("❌ Missing Referral account for {}", user);
+            LedgerError::InsufficientAccounts
+        })?;
+    load_referral(program_id, referral_info, user)
+}
+
+/// 读取并校验 ReferrerStats PDA
+fn load_referrer_stats(
+    program_id: &Pubkey,
+    referrer_stats_info: &AccountInfo,
+    code: &ReferralCode,
+) -> Result<ReferrerStats, ProgramError> {
+    let (referrer_stats_pda, _) = Pubkey::find_program_address(&[ReferrerStats::SEED_PREFIX, code], program_id);
+    if referrer_stats_info.key != &referrer_stats_pda {
+        msg!("❌ Invalid ReferrerStats PDA");
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if referrer_stats_info.owner != program_id || referrer_stats_info.data_len() < ReferrerStats::SIZE {
+        return Err(LedgerError::InvalidReferralCode.into());
+    }
+    let referrer_stats = deserialize_account::<ReferrerStats>(&referrer_stats_info.data.borrow())?;
+    if referrer_stats.discriminator != ReferrerStats::DISCRIMINATOR || referrer_stats.code != *code {
+        return Err(LedgerError::InvalidReferralCode.into());
+    }
+    Ok(referrer_stats)
+}
+
+/// 推荐人分成记入 ReferrerStats (amount 为 0 时跳过)
+fn credit_referrer(
+    program_id: &Pubkey,
+    referrer_stats_info: &AccountInfo,
+    referral: &Referral,
+    amount_e6: u64,
+    current_ts: i64,
+) -> ProgramResult {
+    if amount_e6 == 0 {
+        return Ok(());
+    }
+    assert_writable(referrer_stats_info)?;
+    let mut referrer_stats = load_referrer_stats(program_id, referrer_stats_info, &referral.code)?;
+    if referrer_stats.referrer != referral.referrer {
+        return Err(LedgerError::InvalidReferralCode.into());
+    }
+    referrer_stats.credit(amount_e6, current_ts)?;
+    referrer_stats.serialize(&mut &mut referrer_stats_info.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// 被推荐用户按 FeeSchedule 折扣后的费率 (开/平仓)
+fn referee_fee_rate(
+    program_id: &Pubkey,
+    fee_schedule_info: &AccountInfo,
+    referral: Option<&Referral>,
+    fee_rate: u64,
+) -> Result<u64, ProgramError> {
+    match referral {
+        Some(_) => Ok(load_fee_schedule(program_id, fee_schedule_info)?.referee_fee_rate_e6(fee_rate as i64) as u64),
+        None => Ok(fee_rate),
+    }
+}
+
+/// 按 FeeSchedule 拆分一笔手续费并累计到 FeeSchedule，推荐人部分记入 ReferrerStats (开/平仓)
+fn record_fee_split(
+    program_id: &Pubkey,
+    fee_schedule_info: &AccountInfo,
+    fee_e6: u64,
+    referral: Option<&Referral>,
+    referrer_stats_info: &AccountInfo,
+    current_ts: i64,
+) -> Result<FeeSplit, ProgramError> {
+    assert_writable(fee_schedule_info)?;
+    let mut fee_schedule = load_fee_schedule(program_id, fee_schedule_info)?;
+    let split = fee_schedule.split(fee_e6, referral.is_some());
+    fee_schedule.record(&split)?;
+    fee_schedule.serialize(&mut &mut fee_schedule_info.data.borrow_mut()[..])?;
+    if let Some(referral) = referral {
+        credit_referrer(program_id, referrer_stats_info, referral, split.referrer_e6, current_ts)?;
+    }
+    Ok(split)
+}
+
+/// 每个分配对象发出一个 FeeEvent，序号从 first_sequence 开始连续分配
+/// (调用方通过 `ledger_config.next_sequences(split.recipients().len())` 预留)
+fn emit_fee_split_events(
+    first_sequence: u64,
+    related_trade_sequence: u64,
+    user: Pubkey,
+    market_index: u8,
+    split: &FeeSplit,
+    current_ts: i64,
+) {
+    for (k, (fee_type, amount_e6)) in split.recipients().into_iter().enumerate() {
+        emit_fee_event(&FeeEvent {
+            discriminator: event_discriminator::FEE,
+            sequence: first_sequence + k as u64,
+            timestamp: current_ts,
+            user,
+            market_index,
+            fee_type: fee_type as u8,
+            amount_e6: amount_e6 as i64,
+            related_trade_sequence,
+        });
+    }
+}
+
+/// OpenPosition / ClosePosition 的成交归属参数
+struct FillParams {
+    batch_id: u64,
+    /// 决定 FeeEvent 的 fee_type
+    is_taker: bool,
+    /// Relayer 提交的手续费率 (e6)
+    fee_rate_e6: u64,
+}
+
+/// ExecuteTradeBatch 中每个市场只读取一次的上下文
+struct BatchMarketContext {
+    config: MarketConfig,
+    /// PriceFeed 参考价格 (已通过延迟 / 置信区间检查)
+    reference_price_e6: u64,
+    /// FundingState 累计资金费指数
+    funding_index_e6: i64,
+    /// 本批次该市场最后一笔成交价格 (批次结束后写入 MarkPrice)
+    last_trade_price_e6: u64,
+}
+
+/// 反向开仓的净额结果
+struct NettedOpen {
+    /// 原仓位被平掉的数量
+    close_size_e6: u64,
+    /// 已平部分实现的 PnL
+    realized_pnl_e6: i64,
+    /// 已平部分释放的保证金
+    margin_released_e6: u64,
+    /// 已平部分手续费
+    close_fee_e6: u64,
+    /// 反方向新开仓数量 (0 = 仅减仓/平仓)
+    open_size_e6: u64,
+    /// 新开仓所需保证金
+    open_margin_e6: u64,
+    /// 新开仓手续费
+    open_fee_e6: u64,
+}
+
+impl NettedOpen {
+    fn position_event_type(&self, size_after_e6: u64) -> events::PositionEventType {
+        if self.open_size_e6 > 0 && self.close_size_e6 > 0 {
+            events::PositionEventType::Flipped
+        } else if self.open_size_e6 > 0 {
+            events::PositionEventType::Opened
+        } else if size_after_e6 == 0 {
+            events::PositionEventType::Closed
+        } else {
+            events::PositionEventType::Decreased
         }
-        LedgerInstruction::RecordOrderEvents { events } => {
-            msg!("Instruction: RecordOrderEvents ({} events)", events.len());
-            process_record_order_events(program_id, accounts, events)
+    }
+}
+
+/// 检查账户中是否为与 side 反向的已有仓位 (空仓位也算，需要按新方向重新开仓)
+fn has_opposite_position(position_info: &AccountInfo, side: Side) -> bool {
+    if position_info.data_len() == 0 {
+        return false;
+    }
+    match deserialize_account::<Position>(&position_info.data.borrow()) {
+        Ok(position) => position.discriminator == Position::DISCRIMINATOR && position.side != side,
+        Err(_) => false,
+    }
+}
+
+/// 已有反向仓位时的开仓: 先减少现有仓位并实现已平部分 PnL，剩余数量在新方向开仓
+///
+/// 只修改 Position；平仓结算 / 保证金锁定 CPI 由调用方执行
+fn net_opposite_open(
+    position: &mut Position,
+    trade: &TradeData,
+    market_config: &MarketConfig,
+    current_ts: i64,
+) -> Result<NettedOpen, ProgramError> {
+    let (close_size_e6, realized_pnl_e6, margin_released_e6) = position.reduce(trade.size_e6, trade.price_e6)?;
+    let close_fee_e6 = cpi::calculate_fee(close_size_e6, trade.price_e6, trade.charged_fee_rate_e6())?;
+    let open_size_e6 = checked_sub_u64(trade.size_e6, close_size_e6)?;
+
+    let mut open_margin_e6 = 0;
+    let mut open_fee_e6 = 0;
+    if open_size_e6 > 0 {
+        if !market_config.is_active {
+            return Err(LedgerError::MarketNotActive.into());
         }
-        LedgerInstruction::RecordFundingEvents { events } => {
-            msg!("Instruction: RecordFundingEvents ({} events)", events.len());
-            process_record_funding_events(program_id, accounts, events)
+        open_margin_e6 = cpi::calculate_required_margin(open_size_e6, trade.price_e6, trade.leverage)?;
+        open_fee_e6 = cpi::calculate_fee(open_size_e6, trade.price_e6, trade.charged_fee_rate_e6())?;
+
+        position.side = trade.side;
+        position.size_e6 = open_size_e6;
+        position.entry_price_e6 = trade.price_e6;
+        position.margin_e6 = open_margin_e6;
+        position.leverage = trade.leverage;
+        position.unrealized_pnl_e6 = 0;
+        position.opened_at = current_ts;
+        market_config.check_position_tier(position.leverage, position.notional_value_e6()?)?;
+    }
+    if !position.is_empty() {
+        position.refresh_liquidation_price(market_config)?;
+    }
+    position.last_update_ts = current_ts;
+
+    Ok(NettedOpen {
+        close_size_e6,
+        realized_pnl_e6,
+        margin_released_e6,
+        close_fee_e6,
+        open_size_e6,
+        open_margin_e6,
+        open_fee_e6,
+    })
+}
+
+fn read_market_config(
+    program_id: &Pubkey,
+    market_config_info: &AccountInfo,
+    market_index: u8,
+) -> Result<MarketConfig, ProgramError> {
+    if market_config_info.owner != program_id || market_config_info.data_len() < MarketConfig::SIZE {
+        msg!("❌ Market {} not configured", market_index);
+        return Err(LedgerError::InvalidMarketIndex.into());
+    }
+    let market_config = deserialize_account::<MarketConfig>(&market_config_info.data.borrow())?;
+    if market_config.discriminator != MarketConfig::DISCRIMINATOR || market_config.market_index != market_index {
+        msg!("❌ Market {} not configured", market_index);
+        return Err(LedgerError::InvalidMarketIndex.into());
+    }
+    Ok(market_config)
+}
+
+/// 主处理函数
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction = LedgerInstruction::try_from_slice(instruction_data)
+        .map_err(|_| LedgerError::InvalidInstructionData)?;
+
+    match instruction {
+        // 初始化
+        LedgerInstruction::Initialize => {
+            msg!("Instruction: Initialize");
+            process_initialize(program_id, accounts)
+        }
+        LedgerInstruction::InitializeRelayers { relayers, required_signatures } => {
+            msg!("Instruction: InitializeRelayers");
+            process_initialize_relayers(program_id, accounts, relayers, required_signatures)
+        }
+        LedgerInstruction::InitializeUserStats => {
+            msg!("Instruction: InitializeUserStats");
+            process_initialize_user_stats(program_id, accounts)
+        }
+
+        // 多签
+        LedgerInstruction::SubmitTradeBatch { batch_id, data_hash } => {
+            msg!("Instruction: SubmitTradeBatch");
+            process_submit_trade_batch(program_id, accounts, batch_id, data_hash)
+        }
+        LedgerInstruction::ConfirmTradeBatch { batch_id, data_hash } => {
+            msg!("Instruction: ConfirmTradeBatch");
+            process_confirm_trade_batch(program_id, accounts, batch_id, data_hash)
+        }
+        LedgerInstruction::ExecuteTradeBatch { batch_id, trades } => {
+            msg!("Instruction: ExecuteTradeBatch");
+            process_execute_trade_batch(program_id, accounts, batch_id, trades)
+        }
+
+        // 交易
+        LedgerInstruction::OpenPosition {
+            user,
+            market_index,
+            side,
+            size_e6,
+            price_e6,
+            leverage,
+            batch_id,
+            is_taker,
+            fee_rate_e6,
+        } => {
+            msg!("Instruction: OpenPosition");
+            if fee_rate_e6 > 10_000 {
+                return Err(LedgerError::InvalidFeeRate.into());
+            }
+            let fill = FillParams { batch_id, is_taker: is_taker != 0, fee_rate_e6 };
+            process_open_position(program_id, accounts, user, market_index, side, size_e6, price_e6, leverage, fill)
+        }
+        LedgerInstruction::ClosePosition {
+            user,
+            market_index,
+            size_e6,
+            price_e6,
+            batch_id,
+            is_taker,
+            fee_rate_e6,
+        } => {
+            msg!("Instruction: ClosePosition");
+            if fee_rate_e6 > 10_000 {
+                return Err(LedgerError::InvalidFeeRate.into());
+            }
+            let fill = FillParams { batch_id, is_taker: is_taker != 0, fee_rate_e6 };
+            process_close_position(program_id, accounts, user, market_index, size_e6, price_e6, fill)
+        }
+
+        // 清算
+        LedgerInstruction::Liquidate {
+            user,
+            market_index,
+        } => {
+            msg!("Instruction: Liquidate");
+            process_liquidate(program_id, accounts, user, market_index)
+        }
+        LedgerInstruction::TriggerADL { market_index } => {
+            msg!("Instruction: TriggerADL");
+            process_trigger_adl(program_id, accounts, market_index)
+        }
+
+        // 资金费率
+        LedgerInstruction::SettleFunding {
+            user,
+            market_index,
+            funding_rate_e6,
+            index_price_e6,
+        } => {
+            msg!("Instruction: SettleFunding");
+            process_settle_funding(program_id, accounts, user, market_index, funding_rate_e6, index_price_e6)
+        }
+
+        // 管理
+        LedgerInstruction::AddRelayer { relayer } => {
+            msg!("Instruction: AddRelayer");
+            process_add_relayer(accounts, relayer)
+        }
+        LedgerInstruction::RemoveRelayer { relayer } => {
+            msg!("Instruction: RemoveRelayer");
+            process_remove_relayer(accounts, relayer)
+        }
+        LedgerInstruction::UpdateRequiredSignatures { required_signatures } => {
+            msg!("Instruction: UpdateRequiredSignatures");
+            process_update_required_signatures(accounts, required_signatures)
+        }
+        LedgerInstruction::SetPaused { paused } => {
+            msg!("Instruction: SetPaused");
+            process_set_paused(program_id, accounts, paused)
+        }
+        LedgerInstruction::UpdateAdmin { new_admin } => {
+            msg!("Instruction: UpdateAdmin");
+            process_update_admin(program_id, accounts, new_admin)
+        }
+        LedgerInstruction::UpdateVaultProgram { new_vault_program } => {
+            msg!("Instruction: UpdateVaultProgram");
+            process_update_vault_program(program_id, accounts, new_vault_program)
+        }
+        LedgerInstruction::UpdateFundProgram { new_fund_program } => {
+            msg!("Instruction: UpdateFundProgram");
+            process_update_fund_program(program_id, accounts, new_fund_program)
+        }
+        LedgerInstruction::InitializeUserStats => {
+            msg!("Instruction: InitializeUserStats");
+            process_initialize_user_stats(program_id, accounts)
+        }
+        LedgerInstruction::AdminResetPosition { user, market_index } => {
+            msg!("Instruction: AdminResetPosition");
+            process_admin_reset_position(program_id, accounts, user, market_index)
+        }
+        
+        // Spot 交易指令
+        LedgerInstruction::RecordSpotTrade {
+            user,
+            market_index,
+            is_buy,
+            base_amount_e6,
+            quote_amount_e6,
+            price_e6,
+            fee_e6,
+            is_taker,
+            batch_id,
+        } => {
+            msg!("Instruction: RecordSpotTrade");
+            process_record_spot_trade(
+                program_id, accounts, user, market_index, is_buy,
+                base_amount_e6, quote_amount_e6, price_e6, fee_e6, is_taker, batch_id
+            )
+        }
+        LedgerInstruction::BatchRecordSpotTrades { trades, batch_id } => {
+            msg!("Instruction: BatchRecordSpotTrades");
+            process_batch_record_spot_trades(program_id, accounts, trades, batch_id)
+        }
+        LedgerInstruction::RecordOrderEvents { events } => {
+            msg!("Instruction: RecordOrderEvents ({} events)", events.len());
+            process_record_order_events(program_id, accounts, events)
+        }
+        LedgerInstruction::RecordFundingEvents { events } => {
+            msg!("Instruction: RecordFundingEvents ({} events)", events.len());
+            process_record_funding_events(program_id, accounts, events)
+        }
+
+        // 市场配置
+        LedgerInstruction::CreateMarketConfig { market_index, params } => {
+            msg!("Instruction: CreateMarketConfig");
+            process_create_market_config(program_id, accounts, market_index, params)
+        }
+        LedgerInstruction::UpdateMarketConfig { market_index, params } => {
+            msg!("Instruction: UpdateMarketConfig");
+            process_update_market_config(program_id, accounts, market_index, params)
+        }
+        LedgerInstruction::SetRiskTiers { market_index, tiers } => {
+            msg!("Instruction: SetRiskTiers ({} tiers)", tiers.len());
+            process_set_risk_tiers(program_id, accounts, market_index, tiers)
+        }
+        LedgerInstruction::CreatePriceFeed { market_index, max_staleness_secs, max_confidence_e6 } => {
+            msg!("Instruction: CreatePriceFeed");
+            process_create_price_feed(program_id, accounts, market_index, max_staleness_secs, max_confidence_e6)
+        }
+        LedgerInstruction::UpdatePriceFeed { market_index, prices_e6, conf_e6, publish_ts } => {
+            msg!("Instruction: UpdatePriceFeed");
+            process_update_price_feed(program_id, accounts, market_index, prices_e6, conf_e6, publish_ts)
+        }
+        LedgerInstruction::SetPriceFeedParams { market_index, max_staleness_secs, max_confidence_e6 } => {
+            msg!("Instruction: SetPriceFeedParams");
+            process_set_price_feed_params(program_id, accounts, market_index, max_staleness_secs, max_confidence_e6)
+        }
+        LedgerInstruction::AddMargin { user, market_index, amount_e6 } => {
+            msg!("Instruction: AddMargin");
+            process_adjust_margin(program_id, accounts, user, market_index, MarginAdjustment::Add(amount_e6))
+        }
+        LedgerInstruction::RemoveMargin { user, market_index, amount_e6 } => {
+            msg!("Instruction: RemoveMargin");
+            process_adjust_margin(program_id, accounts, user, market_index, MarginAdjustment::Remove(amount_e6))
+        }
+        LedgerInstruction::SetLeverage { user, market_index, leverage } => {
+            msg!("Instruction: SetLeverage");
+            process_adjust_margin(program_id, accounts, user, market_index, MarginAdjustment::SetLeverage(leverage))
+        }
+        LedgerInstruction::ExecuteADL { market_index } => {
+            msg!("Instruction: ExecuteADL");
+            process_execute_adl(program_id, accounts, market_index)
+        }
+        LedgerInstruction::CompleteADL { market_index } => {
+            msg!("Instruction: CompleteADL");
+            process_complete_adl(program_id, accounts, market_index)
+        }
+        LedgerInstruction::CreateFundingState { market_index, funding_interval_secs } => {
+            msg!("Instruction: CreateFundingState");
+            process_create_funding_state(program_id, accounts, market_index, funding_interval_secs)
+        }
+        LedgerInstruction::UpdateFundingIndex { market_index, funding_rate_e6, index_price_e6 } => {
+            msg!("Instruction: UpdateFundingIndex");
+            process_update_funding_index(program_id, accounts, market_index, funding_rate_e6, index_price_e6)
+        }
+        LedgerInstruction::SettleFundingBatch { market_index, funding_rate_e6, index_price_e6, epoch } => {
+            msg!("Instruction: SettleFundingBatch");
+            process_settle_funding_batch(program_id, accounts, market_index, funding_rate_e6, index_price_e6, epoch)
+        }
+        LedgerInstruction::CreatePremiumSamples { market_index, interest_rate_e6, premium_clamp_e6 } => {
+            msg!("Instruction: CreatePremiumSamples");
+            process_create_premium_samples(program_id, accounts, market_index, interest_rate_e6, premium_clamp_e6)
+        }
+        LedgerInstruction::SetFundingRateParams { market_index, interest_rate_e6, premium_clamp_e6 } => {
+            msg!("Instruction: SetFundingRateParams");
+            process_set_funding_rate_params(program_id, accounts, market_index, interest_rate_e6, premium_clamp_e6)
+        }
+        LedgerInstruction::SubmitPremiumSample { market_index, mark_price_e6, index_price_e6 } => {
+            msg!("Instruction: SubmitPremiumSample");
+            process_submit_premium_sample(program_id, accounts, market_index, mark_price_e6, index_price_e6)
+        }
+        LedgerInstruction::PublishFundingRate { market_index } => {
+            msg!("Instruction: PublishFundingRate");
+            process_publish_funding_rate(program_id, accounts, market_index)
+        }
+        LedgerInstruction::CreateMarkPrice { market_index, ema_period_secs, max_staleness_secs } => {
+            msg!("Instruction: CreateMarkPrice");
+            process_create_mark_price(program_id, accounts, market_index, ema_period_secs, max_staleness_secs)
+        }
+        LedgerInstruction::UpdateMarkPrice { market_index } => {
+            msg!("Instruction: UpdateMarkPrice");
+            process_update_mark_price(program_id, accounts, market_index)
+        }
+        LedgerInstruction::CheckPositionHealth { user, market_index } => {
+            msg!("Instruction: CheckPositionHealth");
+            process_check_position_health(program_id, accounts, user, market_index)
+        }
+
+        // 手续费分配
+        LedgerInstruction::CreateFeeSchedule {
+            insurance_bps,
+            treasury_bps,
+            referrer_bps,
+            maker_rebate_bps,
+            referee_discount_bps,
+        } => {
+            msg!("Instruction: CreateFeeSchedule");
+            process_create_fee_schedule(
+                program_id, accounts, insurance_bps, treasury_bps, referrer_bps, maker_rebate_bps, referee_discount_bps,
+            )
+        }
+        LedgerInstruction::UpdateFeeSchedule {
+            insurance_bps,
+            treasury_bps,
+            referrer_bps,
+            maker_rebate_bps,
+            referee_discount_bps,
+        } => {
+            msg!("Instruction: UpdateFeeSchedule");
+            process_update_fee_schedule(
+                program_id, accounts, insurance_bps, treasury_bps, referrer_bps, maker_rebate_bps, referee_discount_bps,
+            )
+        }
+
+        // 手续费档位
+        LedgerInstruction::CreateFeeTierTable { tiers } => {
+            msg!("Instruction: CreateFeeTierTable");
+            process_create_fee_tier_table(program_id, accounts, tiers)
+        }
+        LedgerInstruction::UpdateFeeTierTable { tiers } => {
+            msg!("Instruction: UpdateFeeTierTable");
+            process_update_fee_tier_table(program_id, accounts, tiers)
+        }
+
+        // 推荐返佣
+        LedgerInstruction::CreateReferrerCode { code } => {
+            msg!("Instruction: CreateReferrerCode");
+            process_create_referrer_code(program_id, accounts, code)
+        }
+        LedgerInstruction::BindReferral { code } => {
+            msg!("Instruction: BindReferral");
+            process_bind_referral(program_id, accounts, code)
+        }
+        LedgerInstruction::ClaimReferralRewards { code } => {
+            msg!("Instruction: ClaimReferralRewards");
+            process_claim_referral_rewards(program_id, accounts, code)
+        }
+    }
+}
+
+// ============================================================================
+// 初始化指令处理
+// ============================================================================
+
+fn process_initialize(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+
+    // 派生 PDA
+    let (ledger_config_pda, bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+    if ledger_config_info.key != &ledger_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = LedgerConfig::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            ledger_config_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), ledger_config_info.clone(), system_program.clone()],
+        &[&[b"ledger_config", &[bump]]],
+    )?;
+
+    // 初始化数据
+    let current_ts = get_current_timestamp()?;
+    let ledger_config = LedgerConfig {
+        discriminator: LedgerConfig::DISCRIMINATOR,
+        admin: *admin.key,
+        vault_program: *vault_program.key,
+        fund_program: *fund_program.key,
+        global_sequence: 0,
+        total_positions_opened: 0,
+        total_positions_closed: 0,
+        total_volume_e6: 0,
+        total_fees_collected_e6: 0,
+        total_liquidations: 0,
+        total_adl_count: 0,
+        is_paused: false,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        reserved: [0u8; 65],
+    };
+
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    msg!("LedgerConfig initialized by {}", admin.key);
+    msg!("Vault Program: {}", vault_program.key);
+    msg!("Fund Program: {}", fund_program.key);
+
+    Ok(())
+}
+
+fn process_initialize_relayers(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    relayers: Vec<Pubkey>,
+    required_signatures: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+
+    // 验证参数
+    if relayers.is_empty() || relayers.len() > MAX_RELAYERS {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if required_signatures == 0 || required_signatures as usize > relayers.len() {
+        return Err(LedgerError::InsufficientSignatures.into());
+    }
+
+    // 派生 PDA
+    let (relayer_config_pda, bump) = Pubkey::find_program_address(&[b"relayer_config"], program_id);
+    if relayer_config_info.key != &relayer_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = RelayerConfig::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            relayer_config_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), relayer_config_info.clone(), system_program.clone()],
+        &[&[b"relayer_config", &[bump]]],
+    )?;
+
+    // 初始化数据
+    let relayer_config = RelayerConfig {
+        discriminator: RelayerConfig::DISCRIMINATOR,
+        admin: *admin.key,
+        authorized_relayers: relayers.clone(),
+        required_signatures,
+        bump,
+        last_update_ts: get_current_timestamp()?,
+    };
+
+    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+    msg!("RelayerConfig initialized with {} relayers, {} required", relayers.len(), required_signatures);
+
+    Ok(())
+}
+
+fn process_initialize_user_stats(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let user_stats_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(user)?;
+
+    // 派生 PDA
+    let (user_stats_pda, bump) = Pubkey::find_program_address(
+        &[b"user_stats", user.key.as_ref()],
+        program_id,
+    );
+    if user_stats_info.key != &user_stats_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = UserStats::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            user.key,
+            user_stats_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[user.clone(), user_stats_info.clone(), system_program.clone()],
+        &[&[b"user_stats", user.key.as_ref(), &[bump]]],
+    )?;
+
+    // 初始化数据
+    let user_stats = UserStats {
+        discriminator: UserStats::DISCRIMINATOR,
+        user: *user.key,
+        total_trades: 0,
+        total_volume_e6: 0,
+        total_realized_pnl_e6: 0,
+        total_fees_paid_e6: 0,
+        total_funding_paid_e6: 0,
+        total_liquidations: 0,
+        first_trade_at: 0,
+        last_trade_at: 0,
+        bump,
+        total_rebates_received_e6: 0,
+        last_volume_day: 0,
+        daily_volume_e6: [0; ROLLING_VOLUME_DAYS],
+    };
+
+    user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+    msg!("UserStats initialized for {}", user.key);
+
+    Ok(())
+}
+
+// ============================================================================
+// 辅助函数：自动创建 UserStats
+// ============================================================================
+
+/// 确保 UserStats 账户存在，如果不存在则自动创建
+/// 
+/// 返回: Ok(true) 如果创建了新账户，Ok(false) 如果已存在
+fn ensure_user_stats_exists<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    user_wallet: &Pubkey,
+    user_stats_info: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> Result<bool, ProgramError> {
+    // 检查账户是否已存在
+    let is_empty = user_stats_info.data_len() == 0 || {
+        let data = user_stats_info.data.borrow();
+        data.iter().all(|&x| x == 0)
+    };
+    
+    if !is_empty {
+        // 账户已存在，验证 discriminator
+        let exists = {
+            let data = user_stats_info.data.borrow();
+            data.len() >= 8 && &data[0..8] == UserStats::DISCRIMINATOR.as_slice()
+        };
+        if exists {
+            // 每日成交量分桶之前创建的账户较小，扩容后新增字段为 0
+            if user_stats_info.data_len() < UserStats::SIZE {
+                let rent_due = Rent::get()?.minimum_balance(UserStats::SIZE).saturating_sub(user_stats_info.lamports());
+                if rent_due > 0 {
+                    invoke(
+                        &system_instruction::transfer(payer.key, user_stats_info.key, rent_due),
+                        &[payer.clone(), user_stats_info.clone(), system_program.clone()],
+                    )?;
+                }
+                user_stats_info.realloc(UserStats::SIZE, true)?;
+                msg!("✅ UserStats resized for {}", user_wallet);
+            }
+            return Ok(false); // 已存在，无需创建
+        }
+    }
+    
+    // 验证 PDA
+    let (user_stats_pda, bump) = Pubkey::find_program_address(
+        &[b"user_stats", user_wallet.as_ref()],
+        program_id,
+    );
+    if user_stats_info.key != &user_stats_pda {
+        msg!("❌ Invalid UserStats PDA: expected {}, got {}", user_stats_pda, user_stats_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = UserStats::SIZE;
+    let lamports = rent.minimum_balance(space);
+    
+    msg!("✨ Auto-creating UserStats for user {}", user_wallet);
+    
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            user_stats_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[payer.clone(), user_stats_info.clone(), system_program.clone()],
+        &[&[b"user_stats", user_wallet.as_ref(), &[bump]]],
+    )?;
+    
+    // 初始化数据
+    let user_stats = UserStats {
+        discriminator: UserStats::DISCRIMINATOR,
+        user: *user_wallet,
+        total_trades: 0,
+        total_volume_e6: 0,
+        total_realized_pnl_e6: 0,
+        total_fees_paid_e6: 0,
+        total_funding_paid_e6: 0,
+        total_liquidations: 0,
+        first_trade_at: 0,
+        last_trade_at: 0,
+        bump,
+        total_rebates_received_e6: 0,
+        last_volume_day: 0,
+        daily_volume_e6: [0; ROLLING_VOLUME_DAYS],
+    };
+    
+    user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+    msg!("✅ UserStats auto-created for {}", user_wallet);
+    
+    Ok(true) // 新创建
+}
+
+// ============================================================================
+// 多签指令处理
+// ============================================================================
+
+fn process_submit_trade_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    batch_id: u64,
+    data_hash: [u8; 32],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let trade_batch_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+
+    // 验证 Relayer 授权
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(relayer.key) {
+        return Err(LedgerError::UnauthorizedRelayer.into());
+    }
+
+    // 派生 TradeBatch PDA
+    let (trade_batch_pda, bump) = Pubkey::find_program_address(
+        &[b"trade_batch", &batch_id.to_le_bytes()],
+        program_id,
+    );
+    if trade_batch_info.key != &trade_batch_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = TradeBatch::SIZE;
+    let lamports = rent.minimum_balance(space);
+    let current_ts = get_current_timestamp()?;
+
+    invoke_signed(
+        &system_instruction::create_account(
+            relayer.key,
+            trade_batch_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[relayer.clone(), trade_batch_info.clone(), system_program.clone()],
+        &[&[b"trade_batch", &batch_id.to_le_bytes(), &[bump]]],
+    )?;
+
+    // 初始化 TradeBatch
+    let mut trade_batch = TradeBatch {
+        discriminator: TradeBatch::DISCRIMINATOR,
+        batch_id,
+        data_hash,
+        signatures: vec![],
+        executed: false,
+        created_at: current_ts,
+        expires_at: current_ts + TRADE_BATCH_EXPIRY_SECONDS,
+        creator: *relayer.key,
+        bump,
+    };
+
+    // 添加第一个签名
+    trade_batch.add_signature(*relayer.key, current_ts)?;
+    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
+
+    // Emit BatchEvent (Submitted)
+    events::emit_batch_event(&events::BatchEvent {
+        discriminator: events::event_discriminator::BATCH,
+        batch_id,
+        timestamp: current_ts,
+        event_type: events::BatchStatus::Submitted as u8,
+        trade_count: 0,
+        total_notional_e6: 0,
+        relayer: *relayer.key,
+        data_hash,
+        chain_tx: [0u8; 64],
+        error_code: 0,
+    });
+
+    msg!("TradeBatch {} submitted by {}", batch_id, relayer.key);
+    Ok(())
+}
+
+fn process_confirm_trade_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    batch_id: u64,
+    data_hash: [u8; 32],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let trade_batch_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+    assert_writable(trade_batch_info)?;
+
+    // 验证 Relayer 授权
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(relayer.key) {
+        return Err(LedgerError::UnauthorizedRelayer.into());
+    }
+
+    // 验证 TradeBatch PDA
+    let (trade_batch_pda, _) = Pubkey::find_program_address(
+        &[b"trade_batch", &batch_id.to_le_bytes()],
+        program_id,
+    );
+    if trade_batch_info.key != &trade_batch_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    let mut trade_batch = deserialize_account::<TradeBatch>(&trade_batch_info.data.borrow())?;
+
+    // 验证状态
+    let current_ts = get_current_timestamp()?;
+    if trade_batch.is_expired(current_ts) {
+        return Err(LedgerError::TradeBatchExpired.into());
+    }
+    if trade_batch.executed {
+        return Err(LedgerError::TradeBatchAlreadyExecuted.into());
+    }
+
+    // 验证数据哈希
+    if trade_batch.data_hash != data_hash {
+        return Err(LedgerError::InvalidDataHash.into());
+    }
+
+    // 添加签名
+    trade_batch.add_signature(*relayer.key, current_ts)?;
+    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
+
+    emit_batch_event(&BatchEvent {
+        discriminator: event_discriminator::BATCH,
+        batch_id,
+        timestamp: current_ts,
+        event_type: 1,
+        trade_count: 0,
+        total_notional_e6: 0,
+        relayer: *relayer.key,
+        data_hash,
+        chain_tx: [0u8; 64],
+        error_code: 0,
+    });
+
+    msg!(
+        "TradeBatch {} confirmed by {}, signatures: {}",
+        batch_id,
+        relayer.key,
+        trade_batch.signature_count()
+    );
+    Ok(())
+}
+
+/// ExecuteTradeBatch 账户布局:
+/// 0. `[signer]` Relayer
+/// 1. `[writable]` TradeBatch PDA
+/// 2. `[]` RelayerConfig
+/// 3. `[writable]` LedgerConfig
+/// 4. `[]` VaultConfig
+/// 5. `[]` Vault Program
+/// 6. `[]` Ledger Program (self, for CPI caller verification)
+/// 7. `[]` System Program
+/// 8. `[writable]` Insurance Fund Account (Fund Program, 接收手续费)
+/// 9. `[]` Fund Program
+/// 10. `[writable]` InsuranceFundConfig (Fund Program)
+/// 
+/// 然后是每笔撮合成交的账户 (maker / taker 各 3 个账户):
+/// For trade i:
+///   11 + i*6 + 0..3: `[writable]` Maker Position PDA, UserAccount (Vault), UserStats PDA
+///   11 + i*6 + 3..6: `[writable]` Taker Position PDA, UserAccount (Vault), UserStats PDA
+///
+/// 最后是批次涉及的每个市场的 MarketConfig / PriceFeed / FundingState / MarkPrice PDA (按地址查找，顺序不限)
+fn process_execute_trade_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    batch_id: u64,
+    trades: Vec<MatchedTrade>,
+) -> ProgramResult {
+    // 解析共享账户
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let trade_batch_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let ledger_program_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let insurance_fund_info = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_config_info = next_account_info(account_info_iter)?;
+    let fee_schedule_info = next_account_info(account_info_iter)?;
+    let fee_tier_table_info = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+    assert_writable(trade_batch_info)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(fee_schedule_info)?;
+
+    // 验证 Ledger Program 地址
+    if ledger_program_info.key != program_id {
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+
+    // 验证 Relayer 授权
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(relayer.key) {
+        return Err(LedgerError::UnauthorizedRelayer.into());
+    }
+
+    // 验证 TradeBatch
+    let mut trade_batch = deserialize_account::<TradeBatch>(&trade_batch_info.data.borrow())?;
+    let current_ts = get_current_timestamp()?;
+
+    if trade_batch.is_expired(current_ts) {
+        return Err(LedgerError::TradeBatchExpired.into());
+    }
+    if trade_batch.executed {
+        return Err(LedgerError::TradeBatchAlreadyExecuted.into());
+    }
+    if !relayer_config.has_enough_signatures(trade_batch.signature_count()) {
+        return Err(LedgerError::InsufficientSignatures.into());
+    }
+
+    // 验证数据哈希 (使用 batch_id 防止重放攻击)
+    let trades_data = trades.try_to_vec()?;
+    if !verify_batch_hash(program_id, batch_id, &trades_data, &trade_batch.data_hash) {
+        return Err(LedgerError::InvalidDataHash.into());
+    }
+
+    // 标记已执行
+    trade_batch.executed = true;
+    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
+
+    // 读取 LedgerConfig
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+
+    // 验证 Vault Program
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
+    }
+
+    // 收集剩余账户 (每笔交易的账户)
+    let remaining_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect();
+    
+    // 验证账户数量
+    let expected_accounts = trades.len() * 6; // 每笔成交 maker / taker 各 3 个账户
+    if remaining_accounts.len() < expected_accounts {
+        msg!(
+            "❌ Insufficient accounts: expected {} for {} trades, got {}",
+            expected_accounts,
+            trades.len(),
+            remaining_accounts.len()
+        );
+        return Err(LedgerError::InsufficientAccounts.into());
+    }
+    let market_accounts = &remaining_accounts[expected_accounts..];
+    let mut market_contexts: Vec<BatchMarketContext> = Vec::new();
+
+    // 派生 Ledger Config PDA bump 用于 CPI
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+
+    let batch_accounts = BatchAccounts {
+        relayer,
+        vault_config_info,
+        vault_program,
+        ledger_config_info,
+        system_program,
+        ledger_config_bump,
+    };
+
+    msg!("📦 ExecuteTradeBatch: batch_id={}, trades={}", batch_id, trades.len());
+    let mut fee_schedule = load_fee_schedule(program_id, fee_schedule_info)?;
+    let fee_tier_table = load_fee_tier_table(program_id, fee_tier_table_info)?;
+    let mut batch_fee_split = FeeSplit::default();
+    let mut batch_rebates_e6: u64 = 0;
+
+    // 执行每笔撮合成交: maker 和 taker 仓位在同一交易中原子更新
+    for (i, fill) in trades.iter().enumerate() {
+        let mut legs = fill.legs().inspect_err(|_| {
+            msg!("❌ Trade {}: maker / taker sides do not net to zero", i);
+        })?;
+        let sequence = ledger_config.next_sequence();
+
+        // 读取市场风控配置和参考价格 (同一市场只读取一次)
+        let ctx_idx = match market_contexts.iter().position(|c| c.config.market_index == fill.market_index) {
+            Some(idx) => idx,
+            None => {
+                let config = find_market_config(program_id, market_accounts, fill.market_index)?;
+                let price_feed = find_price_feed(program_id, market_accounts, fill.market_index)?;
+                let reference_price_e6 = price_feed.checked_price(current_ts)?;
+                let funding_index_e6 = find_funding_state(program_id, market_accounts, fill.market_index)?
+                    .cumulative_funding_index_e6;
+                market_contexts.push(BatchMarketContext {
+                    config,
+                    reference_price_e6,
+                    funding_index_e6,
+                    last_trade_price_e6: 0,
+                });
+                market_contexts.len() - 1
+            }
+        };
+
+        // 获取此成交的账户: maker 3 个 + taker 3 个
+        let base_idx = i * 6;
+
+        // 双方费率不得高于各自 30 天成交量所在档位的费率
+        for (leg, stats_idx, is_taker) in [(&fill.maker, base_idx + 2, false), (&fill.taker, base_idx + 5, true)] {
+            check_tier_fee_rate(
+                program_id,
+                &fee_tier_table,
+                &remaining_accounts[stats_idx],
+                &leg.user,
+                is_taker,
+                leg.fee_rate_e6,
+                current_ts,
+            )?;
+        }
+
+        // 被推荐用户按 FeeSchedule 折扣费率
+        let referrals = [
+            find_referral(program_id, market_accounts, &fill.maker.user)?,
+            find_referral(program_id, market_accounts, &fill.taker.user)?,
+        ];
+        for (leg, referral) in legs.iter_mut().zip(referrals.iter()) {
+            if referral.is_some() {
+                leg.fee_rate_e6 = fee_schedule.referee_fee_rate_e6(leg.fee_rate_e6);
+            }
+        }
+
+        let fill_ctx = BatchFillContext {
+            market: &market_contexts[ctx_idx],
+            sequence,
+            current_ts,
+            index: i,
+        };
+        let maker = execute_trade_leg(
+            program_id,
+            &batch_accounts,
+            &mut ledger_config,
+            &remaining_accounts[base_idx..base_idx + 3],
+            &legs[0],
+            &fill_ctx,
+        )?;
+        let taker = execute_trade_leg(
+            program_id,
+            &batch_accounts,
+            &mut ledger_config,
+            &remaining_accounts[base_idx + 3..base_idx + 6],
+            &legs[1],
+            &fill_ctx,
+        )?;
+
+        // 双方实际成交数量必须等于撮合数量 (平仓数量不能超过原仓位)，多空持仓同增同减
+        let size_e6 = fill.maker.size_e6;
+        if maker.size_e6 != size_e6 || taker.size_e6 != size_e6 {
+            msg!(
+                "❌ Trade {}: executed size mismatch (maker={}, taker={}, matched={})",
+                i, maker.size_e6, taker.size_e6, size_e6
+            );
+            return Err(LedgerError::UnbalancedTrade.into());
+        }
+
+        // 按 FeeSchedule 拆分双方手续费，每个分配对象发出一个 FeeEvent
+        for ((leg, result), referral) in [(&fill.maker, &maker), (&fill.taker, &taker)].into_iter().zip(referrals.iter()) {
+            let split = fee_schedule.split(result.fee_e6, referral.is_some());
+            if let Some(referral) = referral {
+                let (referrer_stats_pda, _) =
+                    Pubkey::find_program_address(&[ReferrerStats::SEED_PREFIX, &referral.code], program_id);
+                if let Some(referrer_stats_info) = market_accounts.iter().find(|a| a.key == &referrer_stats_pda) {
+                    credit_referrer(program_id, referrer_stats_info, referral, split.referrer_e6, current_ts)?;
+                } else if split.referrer_e6 > 0 {
+                    msg!("❌ Missing ReferrerStats account for referrer {}", referral.referrer);
+                    return Err(LedgerError::InsufficientAccounts.into());
+                }
+            }
+            batch_fee_split.accumulate(&split)?;
+            let split_seq = ledger_config.next_sequences(split.recipients().len() as u64);
+            emit_fee_split_events(split_seq, sequence, leg.user, fill.market_index, &split, current_ts);
+        }
+
+        // Maker 返佣 (负费率): 本批次累计返佣不超过已拆分到 Maker 返佣池的手续费
+        let rebate_e6 = match cpi::calculate_signed_fee(size_e6, fill.price_e6, fill.maker.fee_rate_e6)? {
+            fee if fee < 0 => {
+                let budget_e6 = checked_sub_u64(batch_fee_split.maker_rebate_e6, batch_rebates_e6)?;
+                if fee.unsigned_abs() > budget_e6 {
+                    msg!("⚠️ Trade {}: maker rebate {} capped at {}", i, fee.unsigned_abs(), budget_e6);
+                }
+                fee.unsigned_abs().min(budget_e6)
+            }
+            _ => 0,
+        };
+        let maker_fee_e6 = if rebate_e6 > 0 {
+            pay_maker_rebate(&batch_accounts, &remaining_accounts[base_idx..base_idx + 3], rebate_e6)?;
+            batch_rebates_e6 = checked_add_u64(batch_rebates_e6, rebate_e6)?;
+            emit_fee_event(&FeeEvent {
+                discriminator: event_discriminator::FEE,
+                sequence: ledger_config.next_sequence(),
+                timestamp: current_ts,
+                user: fill.maker.user,
+                market_index: fill.market_index,
+                fee_type: events::FeeType::MakerFee as u8,
+                amount_e6: -(rebate_e6 as i64),
+                related_trade_sequence: sequence,
+            });
+            -(rebate_e6 as i64)
+        } else {
+            maker.fee_e6 as i64
+        };
+
+        // 更新交易量
+        let notional_e6 = (size_e6 as u128 * fill.price_e6 as u128 / 1_000_000) as u64;
+        ledger_config.total_volume_e6 = ledger_config.total_volume_e6.saturating_add(notional_e6);
+
+        // Emit TradeEvent
+        events::emit_trade_event(&events::TradeEvent {
+            discriminator: events::event_discriminator::TRADE,
+            sequence,
+            timestamp: current_ts,
+            batch_id,
+            market_index: fill.market_index,
+            market_type: 0, // Perp
+            trade_type: events::TradeType::Normal as u8,
+            maker: fill.maker.user,
+            maker_order_id: fill.maker.order_id,
+            maker_side: fill.maker.side as u8,
+            maker_fee_e6,
+            taker: fill.taker.user,
+            taker_order_id: fill.taker.order_id,
+            taker_side: fill.taker.side as u8,
+            taker_fee_e6: taker.fee_e6 as i64,
+            price_e6: fill.price_e6,
+            size_e6,
+            notional_e6,
+            maker_realized_pnl_e6: maker.realized_pnl_e6,
+            taker_realized_pnl_e6: taker.realized_pnl_e6,
+            maker_margin_delta_e6: maker.margin_delta_e6,
+            taker_margin_delta_e6: taker.margin_delta_e6,
+        });
+
+        market_contexts[ctx_idx].last_trade_price_e6 = fill.price_e6;
+        msg!("  📊 Sequence: {}", sequence);
+    }
+
+    // 每个市场的最后一笔成交价格写入 MarkPrice
+    for ctx in &market_contexts {
+        let (mark_price_pda, _) = Pubkey::find_program_address(
+            &[MarkPrice::SEED_PREFIX, &[ctx.config.market_index]],
+            program_id,
+        );
+        let mark_price_info = market_accounts
+            .iter()
+            .find(|a| a.key == &mark_price_pda)
+            .ok_or_else(|| {
+                msg!("❌ Missing MarkPrice account for market {}", ctx.config.market_index);
+                LedgerError::InsufficientAccounts
+            })?;
+        record_trade_price(program_id, mark_price_info, ctx.config.market_index, ctx.last_trade_price_e6, current_ts)?;
+    }
+
+    // 已支付的返佣从 Maker 返佣池中扣除，FeeSchedule 只累计未分发的部分
+    batch_fee_split.maker_rebate_e6 = checked_sub_u64(batch_fee_split.maker_rebate_e6, batch_rebates_e6)?;
+
+    // CPI: 本批次手续费的保险基金部分计入保险基金，其余部分累计到 FeeSchedule
+    add_trading_fee_to_fund(
+        &ledger_config,
+        ledger_config_info,
+        fund_program,
+        insurance_fund_info,
+        insurance_config_info,
+        batch_fee_split.insurance_e6,
+        ledger_config_bump,
+    )?;
+    fee_schedule.record(&batch_fee_split)?;
+    fee_schedule.serialize(&mut &mut fee_schedule_info.data.borrow_mut()[..])?;
+
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    emit_batch_event(&BatchEvent {
+        discriminator: event_discriminator::BATCH,
+        batch_id,
+        timestamp: current_ts,
+        event_type: 2,
+        trade_count: trades.len() as u16,
+        total_notional_e6: 0,
+        relayer: *relayer.key,
+        data_hash: trade_batch.data_hash,
+        chain_tx: [0u8; 64],
+        error_code: 0,
+    });
+
+    msg!("✅ TradeBatch {} executed successfully with {} trades", batch_id, trades.len());
+    Ok(())
+}
+
+/// ExecuteTradeBatch 中撮合成交一方的执行结果
+struct LegResult {
+    /// 实际成交数量
+    size_e6: u64,
+    /// 手续费
+    fee_e6: u64,
+    /// 已实现盈亏
+    realized_pnl_e6: i64,
+    /// 保证金变动 (正 = 锁定, 负 = 释放)
+    margin_delta_e6: i64,
+}
+
+/// ExecuteTradeBatch 中单笔撮合成交 (maker / taker 两方共用) 的上下文
+struct BatchFillContext<'a> {
+    market: &'a BatchMarketContext,
+    /// TradeEvent 序号
+    sequence: u64,
+    current_ts: i64,
+    /// 成交在批次中的下标 (日志用)
+    index: usize,
+}
+
+/// ExecuteTradeBatch 的共享账户
+struct BatchAccounts<'a, 'info> {
+    relayer: &'a AccountInfo<'info>,
+    vault_config_info: &'a AccountInfo<'info>,
+    vault_program: &'a AccountInfo<'info>,
+    ledger_config_info: &'a AccountInfo<'info>,
+    system_program: &'a AccountInfo<'info>,
+    ledger_config_bump: u8,
+}
+
+/// Maker 返佣: CPI Vault 从已收取的手续费中划转到 maker 账户，并累计到 maker 的 UserStats
+///
+/// leg_accounts: [Position PDA, UserAccount (Vault), UserStats PDA]
+fn pay_maker_rebate<'info>(
+    batch: &BatchAccounts<'_, 'info>,
+    leg_accounts: &[AccountInfo<'info>],
+    rebate_e6: u64,
+) -> ProgramResult {
+    cpi::credit_fee_rebate(
+        batch.vault_program.key,
+        batch.vault_config_info.clone(),
+        leg_accounts[1].clone(),
+        batch.ledger_config_info.clone(),
+        rebate_e6,
+        &[&[b"ledger_config", &[batch.ledger_config_bump]]],
+    )?;
+
+    let user_stats_info = &leg_accounts[2];
+    if user_stats_info.data_len() > 0 {
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_rebates_received_e6 = checked_add_u64(user_stats.total_rebates_received_e6, rebate_e6)?;
+            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+        }
+    }
+    Ok(())
+}
+
+/// 执行撮合成交的一方 (maker 或 taker): 更新仓位、Vault CPI、PositionEvent 和 UserStats
+///
+/// leg_accounts: [Position PDA, UserAccount (Vault), UserStats PDA]
+fn execute_trade_leg<'info>(
+    program_id: &Pubkey,
+    batch: &BatchAccounts<'_, 'info>,
+    ledger_config: &mut LedgerConfig,
+    leg_accounts: &[AccountInfo<'info>],
+    trade: &TradeData,
+    fill: &BatchFillContext,
+) -> Result<LegResult, ProgramError> {
+    let BatchFillContext { market, sequence, current_ts, index: i } = *fill;
+    let BatchAccounts {
+        relayer,
+        vault_config_info,
+        vault_program,
+        ledger_config_info,
+        system_program,
+        ledger_config_bump,
+    } = *batch;
+    let position_info = &leg_accounts[0];
+    let user_account_info = &leg_accounts[1];
+    let user_stats_info = &leg_accounts[2];
+
+    // 验证 Position PDA
+    let (expected_position_pda, position_bump) = Pubkey::find_program_address(
+        &[b"position", trade.user.as_ref(), &[trade.market_index]],
+        program_id,
+    );
+    if position_info.key != &expected_position_pda {
+        msg!("❌ Trade {}: Invalid position PDA for {}", i, trade.user);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    let market_config = &market.config;
+    let reference_price_e6 = market.reference_price_e6;
+    let funding_index_e6 = market.funding_index_e6;
+
+    let result = match trade.trade_type {
+        trade_data_type::OPEN if has_opposite_position(position_info, trade.side) => {
+            msg!(
+                "🔄 Trade {} OPEN (opposite): user={}, market={}, side={:?}, size={}, price={}, leverage={}",
+                i, trade.user, trade.market_index, trade.side, trade.size_e6, trade.price_e6, trade.leverage
+            );
+
+            // 验证参数
+            if trade.size_e6 == 0 {
+                return Err(LedgerError::InvalidTradeAmount.into());
+            }
+            if trade.price_e6 == 0 {
+                return Err(LedgerError::InvalidPrice.into());
+            }
+            market_config.check_leverage(trade.leverage)?;
+            market_config.check_order(trade.size_e6, trade.price_e6)?;
+            if let Err(e) = market_config.check_trade_price(trade.price_e6, reference_price_e6) {
+                msg!("❌ Trade {}: price {} out of band (reference={})", i, trade.price_e6, reference_price_e6);
+                return Err(e.into());
+            }
+            if trade.fee_rate_e6 > 10_000 {
+                return Err(LedgerError::InvalidFeeRate.into());
+            }
+
+            // 先减少现有仓位，剩余数量反向开仓
+            let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+            let side_before = position.side;
+            let size_before = position.size_e6;
+            let entry_before = position.entry_price_e6;
+            let margin_before = position.margin_e6;
+            position.accrue_funding(funding_index_e6)?;
+            let netted = net_opposite_open(&mut position, trade, market_config, current_ts)?;
+            position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+            // CPI: 已平部分结算
+            if netted.close_size_e6 > 0 {
+                cpi::close_position_settle(
+                    &ledger_config.vault_program,
+                    vault_config_info.clone(),
+                    user_account_info.clone(),
+                    ledger_config_info.clone(),
+                    netted.margin_released_e6,
+                    netted.realized_pnl_e6,
+                    netted.close_fee_e6,
+                    &[&[b"ledger_config", &[ledger_config_bump]]],
+                )?;
+                ledger_config.total_positions_closed += 1;
+                msg!(
+                    "  ✅ Closed {}: pnl={}, margin_released={}, fee={}",
+                    netted.close_size_e6, netted.realized_pnl_e6, netted.margin_released_e6, netted.close_fee_e6
+                );
+            }
+
+            // CPI: 反向新仓锁定保证金并扣除开仓手续费
+            if netted.open_size_e6 > 0 {
+                lock_margin_and_charge_fee(
+                    vault_program.key,
+                    vault_config_info,
+                    user_account_info,
+                    ledger_config_info,
+                    netted.open_margin_e6,
+                    netted.open_fee_e6,
+                    ledger_config_bump,
+                )?;
+                ledger_config.total_positions_opened += 1;
+                msg!(
+                    "  ✅ Opened {} {:?}: margin={}, fee={}",
+                    netted.open_size_e6, trade.side, netted.open_margin_e6, netted.open_fee_e6
+                );
+            }
+
+            let fee = checked_add_u64(netted.close_fee_e6, netted.open_fee_e6)?;
+            ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+
+            // Emit PositionEvent (Flipped / Decreased / Closed)
+            events::emit_position_event(&events::PositionEvent {
+                discriminator: events::event_discriminator::POSITION,
+                sequence,
+                timestamp: current_ts,
+                user: trade.user,
+                market_index: trade.market_index,
+                event_type: netted.position_event_type(position.size_e6) as u8,
+                side_before: side_before as u8,
+                size_before_e6: size_before,
+                entry_price_before_e6: entry_before,
+                margin_before_e6: margin_before,
+                side_after: position.side as u8,
+                size_after_e6: position.size_e6,
+                entry_price_after_e6: position.entry_price_e6,
+                margin_after_e6: position.margin_e6,
+                size_delta_e6: position.size_e6 as i64 - size_before as i64,
+                realized_pnl_e6: netted.realized_pnl_e6,
+                fee_e6: fee,
+                related_trade_sequence: sequence,
+            });
+
+            LegResult {
+                size_e6: trade.size_e6,
+                fee_e6: fee,
+                realized_pnl_e6: netted.realized_pnl_e6,
+                margin_delta_e6: netted.open_margin_e6 as i64 - netted.margin_released_e6 as i64,
+            }
+        }
+
+        trade_data_type::OPEN => {
+            msg!(
+                "🔵 Trade {} OPEN: user={}, market={}, side={:?}, size={}, price={}, leverage={}",
+                i, trade.user, trade.market_index, trade.side, trade.size_e6, trade.price_e6, trade.leverage
+            );
+
+            // 验证参数
+            if trade.size_e6 == 0 {
+                return Err(LedgerError::InvalidTradeAmount.into());
+            }
+            if trade.price_e6 == 0 {
+                return Err(LedgerError::InvalidPrice.into());
+            }
+            if !market_config.is_active {
+                msg!("❌ Trade {}: Market {} not active", i, trade.market_index);
+                return Err(LedgerError::MarketNotActive.into());
+            }
+            market_config.check_leverage(trade.leverage)?;
+            market_config.check_order(trade.size_e6, trade.price_e6)?;
+            if let Err(e) = market_config.check_trade_price(trade.price_e6, reference_price_e6) {
+                msg!("❌ Trade {}: price {} out of band (reference={})", i, trade.price_e6, reference_price_e6);
+                return Err(e.into());
+            }
+
+            // 计算所需保证金和手续费
+            let required_margin = cpi::calculate_required_margin(trade.size_e6, trade.price_e6, trade.leverage)?;
+            if trade.fee_rate_e6 > 10_000 {
+                return Err(LedgerError::InvalidFeeRate.into());
+            }
+            let fee = cpi::calculate_fee(trade.size_e6, trade.price_e6, trade.charged_fee_rate_e6())?;
+
+            // 检查是否是新仓位
+            let is_new_position = position_info.data_len() == 0 || {
+                let data = position_info.data.borrow();
+                data.iter().all(|&x| x == 0)
+            };
+
+            if is_new_position {
+                // 创建新仓位
+                let rent = Rent::get()?;
+                let space = Position::SIZE;
+                let lamports = rent.minimum_balance(space);
+
+                invoke_signed(
+                    &system_instruction::create_account(
+                        relayer.key,
+                        position_info.key,
+                        lamports,
+                        space as u64,
+                        program_id,
+                    ),
+                    &[relayer.clone(), position_info.clone(), system_program.clone()],
+                    &[&[b"position", trade.user.as_ref(), &[trade.market_index], &[position_bump]]],
+                )?;
+
+                let mut position = Position {
+                    discriminator: Position::DISCRIMINATOR,
+                    user: trade.user,
+                    market_index: trade.market_index,
+                    side: trade.side,
+                    size_e6: trade.size_e6,
+                    entry_price_e6: trade.price_e6,
+                    margin_e6: required_margin,
+                    leverage: trade.leverage,
+                    liquidation_price_e6: 0,
+                    unrealized_pnl_e6: 0,
+                    last_funding_ts: current_ts,
+                    cumulative_funding_e6: 0,
+                    open_order_count: 0,
+                    opened_at: current_ts,
+                    last_update_ts: current_ts,
+                    bump: position_bump,
+                    last_funding_index_e6: funding_index_e6,
+                    unsettled_funding_e6: 0,
+                    shortfall_count: 0,
+                    reserved: [0; 8],
+                };
+                market_config.check_position_tier(position.leverage, position.notional_value_e6()?)?;
+                position.refresh_liquidation_price(market_config)?;
+                position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+                msg!("  ✅ New position created");
+            } else {
+                // 加仓
+                let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+                if position.side != trade.side {
+                    msg!("❌ Trade {}: Side mismatch (existing: {:?}, new: {:?})", i, position.side, trade.side);
+                    return Err(LedgerError::InvalidPositionSide.into());
+                }
+                // 加仓前按原仓位数量计提资金费
+                position.accrue_funding(funding_index_e6)?;
+                // 先累加保证金，清算价格按加仓后的实际保证金率计算
+                position.margin_e6 = checked_add_u64(position.margin_e6, required_margin)?;
+                position.update_entry_price(trade.size_e6, trade.price_e6, market_config)?;
+                // 按加仓后的实际 (混合) 杠杆检查风险档位，而非本笔成交的杠杆
+                market_config.check_position_tier(position.leverage, position.notional_value_e6()?)?;
+                position.last_update_ts = current_ts;
+                position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+                msg!("  ✅ Position increased");
+            }
+
+            // CPI: 锁定保证金并扣除手续费 (使用 LedgerConfig PDA 作为 caller)
+            lock_margin_and_charge_fee(
+                vault_program.key,
+                vault_config_info,
+                user_account_info,
+                ledger_config_info,
+                required_margin,
+                fee,
+                ledger_config_bump,
+            )?;
+            msg!("  ✅ Margin locked: {}, fee charged: {}", required_margin, fee);
+
+            // 更新统计
+            ledger_config.total_positions_opened += 1;
+            ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+
+            // Emit PositionEvent (OPEN)
+            let side_u8 = match trade.side { Side::Long => 0u8, Side::Short => 1u8 };
+            events::emit_position_event(&events::PositionEvent {
+                discriminator: events::event_discriminator::POSITION,
+                sequence,
+                timestamp: current_ts,
+                user: trade.user,
+                market_index: trade.market_index,
+                event_type: if is_new_position {
+                    events::PositionEventType::Opened as u8
+                } else {
+                    events::PositionEventType::Increased as u8
+                },
+                side_before: side_u8,
+                size_before_e6: if is_new_position { 0 } else { trade.size_e6 }, // approximate
+                entry_price_before_e6: 0,
+                margin_before_e6: if is_new_position { 0 } else { required_margin },
+                side_after: side_u8,
+                size_after_e6: trade.size_e6,
+                entry_price_after_e6: trade.price_e6,
+                margin_after_e6: required_margin,
+                size_delta_e6: trade.size_e6 as i64,
+                realized_pnl_e6: 0,
+                fee_e6: fee,
+                related_trade_sequence: sequence,
+            });
+
+            LegResult {
+                size_e6: trade.size_e6,
+                fee_e6: fee,
+                realized_pnl_e6: 0,
+                margin_delta_e6: required_margin as i64,
+            }
+        }
+
+        trade_data_type::CLOSE => {
+            msg!(
+                "🔴 Trade {} CLOSE: user={}, market={}, size={}, price={}",
+                i, trade.user, trade.market_index, trade.size_e6, trade.price_e6
+            );
+
+            // 验证参数
+            if trade.size_e6 == 0 {
+                return Err(LedgerError::InvalidTradeAmount.into());
+            }
+            if trade.price_e6 == 0 {
+                return Err(LedgerError::InvalidPrice.into());
+            }
+            market_config.check_order(trade.size_e6, trade.price_e6)?;
+            if let Err(e) = market_config.check_trade_price(trade.price_e6, reference_price_e6) {
+                msg!("❌ Trade {}: price {} out of band (reference={})", i, trade.price_e6, reference_price_e6);
+                return Err(e.into());
+            }
+
+            // 读取仓位
+            let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+            if position.user != trade.user || position.market_index != trade.market_index {
+                return Err(LedgerError::PositionNotFound.into());
+            }
+            if position.is_empty() {
+                return Err(LedgerError::PositionNotFound.into());
+            }
+            // 平仓方向必须与原仓位相反，撮合双方才能对冲
+            if position.side == trade.side {
+                msg!("❌ Trade {}: Close side {:?} matches position side", i, trade.side);
+                return Err(LedgerError::InvalidPositionSide.into());
+            }
+            position.accrue_funding(funding_index_e6)?;
+
+            let close_size = trade.size_e6.min(position.size_e6);
+            let close_ratio = div_e6(close_size as i64, position.size_e6 as i64)?;
+            let pnl = position.calculate_unrealized_pnl(trade.price_e6)?;
+            let mut realized_pnl = mul_e6(pnl, close_ratio)?;
+            let original_margin = position.margin_e6;
+            let mut margin_to_release = mul_e6(position.margin_e6 as i64, close_ratio)? as u64;
+            if trade.fee_rate_e6 > 10_000 {
+                return Err(LedgerError::InvalidFeeRate.into());
+            }
+            let fee = cpi::calculate_fee(close_size, trade.price_e6, trade.charged_fee_rate_e6())?;
+            // 已平部分的未结算资金费计入已实现盈亏
+            realized_pnl = checked_sub(realized_pnl, position.take_unsettled_funding(close_ratio)?)?;
+
+            if close_size >= position.size_e6 {
+                margin_to_release = position.margin_e6;
+                position.size_e6 = 0;
+                position.margin_e6 = 0;
+                position.entry_price_e6 = 0;
+                position.liquidation_price_e6 = 0;
+                position.unrealized_pnl_e6 = 0;
+            } else {
+                position.size_e6 = checked_sub_u64(position.size_e6, close_size)?;
+                position.margin_e6 = checked_sub_u64(position.margin_e6, margin_to_release)?;
+                position.refresh_liquidation_price(market_config)?;
+                if position.size_e6 == 0 {
+                    margin_to_release = original_margin;
+                    position.margin_e6 = 0;
+                    position.entry_price_e6 = 0;
+                    position.liquidation_price_e6 = 0;
+                    position.unrealized_pnl_e6 = 0;
+                }
+            }
+            position.last_update_ts = current_ts;
+            position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+            // CPI: 平仓结算
+            cpi::close_position_settle(
+                &ledger_config.vault_program,
+                vault_config_info.clone(),
+                user_account_info.clone(),
+                ledger_config_info.clone(),
+                margin_to_release,
+                realized_pnl,
+                fee,
+                &[&[b"ledger_config", &[ledger_config_bump]]],
+            )?;
+            msg!("  ✅ Position closed: pnl={}, margin_released={}, fee={}", realized_pnl, margin_to_release, fee);
+
+            // 更新统计
+            ledger_config.total_positions_closed += 1;
+            ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+
+            // Emit PositionEvent (CLOSE)
+            let side_u8 = match position.side { Side::Long => 0u8, Side::Short => 1u8 };
+            let is_full_close = position.size_e6 == 0;
+            events::emit_position_event(&events::PositionEvent {
+                discriminator: events::event_discriminator::POSITION,
+                sequence,
+                timestamp: current_ts,
+                user: trade.user,
+                market_index: trade.market_index,
+                event_type: if is_full_close {
+                    events::PositionEventType::Closed as u8
+                } else {
+                    events::PositionEventType::Decreased as u8
+                },
+                side_before: side_u8,
+                size_before_e6: close_size + position.size_e6,
+                entry_price_before_e6: position.entry_price_e6,
+                margin_before_e6: original_margin,
+                side_after: side_u8,
+                size_after_e6: position.size_e6,
+                entry_price_after_e6: position.entry_price_e6,
+                margin_after_e6: position.margin_e6,
+                size_delta_e6: -(close_size as i64),
+                realized_pnl_e6: realized_pnl,
+                fee_e6: fee,
+                related_trade_sequence: sequence,
+            });
+
+            LegResult {
+                size_e6: close_size,
+                fee_e6: fee,
+                realized_pnl_e6: realized_pnl,
+                margin_delta_e6: -(margin_to_release as i64),
+            }
+        }
+
+        _ => {
+            msg!("❌ Trade {}: Unknown trade type {}", i, trade.trade_type);
+            return Err(LedgerError::InvalidTradeAmount.into());
+        }
+    };
+
+    let fee_event_seq = ledger_config.next_sequence();
+    emit_trading_fee_event(
+        fee_event_seq,
+        sequence,
+        trade.user,
+        trade.market_index,
+        result.fee_e6,
+        trade.is_taker != 0,
+        current_ts,
+    );
+
+    // 自动创建 UserStats (如果不存在)
+    let _ = ensure_user_stats_exists(
+        program_id,
+        relayer,
+        &trade.user,
+        user_stats_info,
+        system_program,
+    );
+
+    // 更新用户统计 (现在保证存在)
+    if user_stats_info.data_len() > 0 {
+        // 先读取数据到局部变量，释放借用
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_trades += 1;
+            let notional_e6 = (trade.size_e6 as u128 * trade.price_e6 as u128 / 1_000_000) as u64;
+            user_stats.total_volume_e6 = user_stats.total_volume_e6.saturating_add(notional_e6);
+            user_stats.record_rolling_volume(notional_e6, current_ts);
+            if user_stats.first_trade_at == 0 {
+                user_stats.first_trade_at = current_ts;
+            }
+            user_stats.last_trade_at = current_ts;
+            let _ = user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..]);
+        }
+    }
+
+    Ok(result)
+}
+
+// ============================================================================
+// 交易指令处理
+// ============================================================================
+
+fn process_open_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
+    side: Side,
+    size_e6: u64,
+    price_e6: u64,
+    leverage: u8,
+    fill: FillParams,
+) -> ProgramResult {
+    let FillParams { batch_id, is_taker, fee_rate_e6: fee_rate } = fill;
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let position_info = next_account_info(account_info_iter)?;
+    let user_account_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let user_stats_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let ledger_program_info = next_account_info(account_info_iter)?; // Ledger Program itself for CPI caller
+    let system_program = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_fund_info = next_account_info(account_info_iter)?;
+    let insurance_config_info = next_account_info(account_info_iter)?;
+    let fee_schedule_info = next_account_info(account_info_iter)?;
+    let fee_tier_table_info = next_account_info(account_info_iter)?;
+    let referral_info = next_account_info(account_info_iter)?;
+    let referrer_stats_info = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+    assert_writable(position_info)?;
+    assert_writable(user_account_info)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(user_stats_info)?;
+    
+    // 验证 Ledger Program 地址正确
+    if ledger_program_info.key != program_id {
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+
+    // 验证参数
+    if size_e6 == 0 {
+        return Err(LedgerError::InvalidTradeAmount.into());
+    }
+    if price_e6 == 0 {
+        return Err(LedgerError::InvalidPrice.into());
+    }
+
+    // 读取配置
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+
+    // 市场风控检查
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    if !market_config.is_active {
+        return Err(LedgerError::MarketNotActive.into());
+    }
+    market_config.check_leverage(leverage)?;
+    market_config.check_order(size_e6, price_e6)?;
+
+    // 成交价格必须在参考价格带宽内
+    let current_ts = get_current_timestamp()?;
+    let reference_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    market_config.check_trade_price(price_e6, reference_price_e6)?;
+    record_trade_price(program_id, mark_price_info, market_index, price_e6, current_ts)?;
+
+    // 费率不得高于用户 30 天成交量所在档位的费率
+    let fee_tier_table = load_fee_tier_table(program_id, fee_tier_table_info)?;
+    check_tier_fee_rate(program_id, &fee_tier_table, user_stats_info, &user, is_taker, fee_rate as i64, current_ts)?;
+
+    // 被推荐用户按 FeeSchedule 折扣费率
+    let referral = load_referral(program_id, referral_info, &user)?;
+    let fee_rate = referee_fee_rate(program_id, fee_schedule_info, referral.as_ref(), fee_rate)?;
+    let funding_index_e6 = load_funding_state(program_id, funding_state_info, market_index)?.cumulative_funding_index_e6;
+
+    // 验证 Vault Program
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
+    }
+
+    // 计算所需保证金
+    let required_margin = cpi::calculate_required_margin(size_e6, price_e6, leverage)?;
+    let fee = cpi::calculate_fee(size_e6, price_e6, fee_rate)?; // P1B: dynamic fee rate
+
+    // 派生 Position PDA
+    let (position_pda, position_bump) = Pubkey::find_program_address(
+        &[b"position", user.as_ref(), &[market_index]],
+        program_id,
+    );
+    if position_info.key != &position_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 检查是否是新仓位
+    let is_new_position = position_info.data_len() == 0 || {
+        let data = position_info.data.borrow();
+        data.iter().all(|&x| x == 0)
+    };
+
+    // 已有反向仓位: 减仓并实现 PnL，剩余数量反向开仓
+    if !is_new_position && has_opposite_position(position_info, side) {
+        let trade = TradeData {
+            user,
+            market_index,
+            trade_type: trade_data_type::OPEN,
+            side,
+            size_e6,
+            price_e6,
+            leverage,
+            is_taker: is_taker as u8,
+            fee_rate_e6: fee_rate as i64,
+        };
+        let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+        let side_before = position.side;
+        let size_before = position.size_e6;
+        let entry_before = position.entry_price_e6;
+        let margin_before = position.margin_e6;
+        position.accrue_funding(funding_index_e6)?;
+        let netted = net_opposite_open(&mut position, &trade, &market_config, current_ts)?;
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+        let (_, ledger_config_bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+
+        // CPI: 已平部分结算
+        if netted.close_size_e6 > 0 {
+            cpi::close_position_settle(
+                &ledger_config.vault_program,
+                vault_config_info.clone(),
+                user_account_info.clone(),
+                ledger_config_info.clone(),
+                netted.margin_released_e6,
+                netted.realized_pnl_e6,
+                netted.close_fee_e6,
+                &[&[b"ledger_config", &[ledger_config_bump]]],
+            )?;
+            ledger_config.total_positions_closed += 1;
+        }
+
+        // CPI: 反向新仓锁定保证金并扣除开仓手续费
+        if netted.open_size_e6 > 0 {
+            lock_margin_and_charge_fee(
+                vault_program.key,
+                vault_config_info,
+                user_account_info,
+                ledger_config_info,
+                netted.open_margin_e6,
+                netted.open_fee_e6,
+                ledger_config_bump,
+            )?;
+            ledger_config.total_positions_opened += 1;
+        }
+
+        let fee = checked_add_u64(netted.close_fee_e6, netted.open_fee_e6)?;
+        // CPI: 手续费的保险基金部分计入保险基金
+        let fee_split = record_fee_split(program_id, fee_schedule_info, fee, referral.as_ref(), referrer_stats_info, current_ts)?;
+        add_trading_fee_to_fund(
+            &ledger_config,
+            ledger_config_info,
+            fund_program,
+            insurance_fund_info,
+            insurance_config_info,
+            fee_split.insurance_e6,
+            ledger_config_bump,
+        )?;
+
+        let notional = (size_e6 as u128 * price_e6 as u128 / 1_000_000) as u64;
+        ledger_config.total_volume_e6 = checked_add_u64(ledger_config.total_volume_e6, notional)?;
+        ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+        let position_event_seq = ledger_config.next_sequence();
+        let fee_event_seq = ledger_config.next_sequence();
+        let fee_split_seq = ledger_config.next_sequences(fee_split.recipients().len() as u64);
+        ledger_config.last_update_ts = current_ts;
+        ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+        let _ = ensure_user_stats_exists(program_id, relayer, &user, user_stats_info, system_program);
+        if user_stats_info.data_len() > 0 {
+            let user_stats_result = {
+                let data = user_stats_info.data.borrow();
+                deserialize_account::<UserStats>(&data)
+            };
+            if let Ok(mut user_stats) = user_stats_result {
+                user_stats.total_trades += 1;
+                user_stats.total_volume_e6 = checked_add_u64(user_stats.total_volume_e6, notional)?;
+                user_stats.record_rolling_volume(notional, current_ts);
+                user_stats.total_fees_paid_e6 = checked_add_u64(user_stats.total_fees_paid_e6, fee)?;
+                user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, netted.realized_pnl_e6)?;
+                if user_stats.first_trade_at == 0 {
+                    user_stats.first_trade_at = current_ts;
+                }
+                user_stats.last_trade_at = current_ts;
+                user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+            }
+        }
+
+        msg!(
+            "OpenPosition netted: user={}, closed={}, pnl={}, opened={} {:?}, fee={}",
+            user, netted.close_size_e6, netted.realized_pnl_e6, netted.open_size_e6, side, fee
+        );
+
+        emit_position_event(&PositionEvent {
+            discriminator: event_discriminator::POSITION,
+            sequence: position_event_seq,
+            timestamp: current_ts,
+            user,
+            market_index,
+            event_type: netted.position_event_type(position.size_e6) as u8,
+            side_before: side_before as u8,
+            size_before_e6: size_before,
+            entry_price_before_e6: entry_before,
+            margin_before_e6: margin_before,
+            side_after: position.side as u8,
+            size_after_e6: position.size_e6,
+            entry_price_after_e6: position.entry_price_e6,
+            margin_after_e6: position.margin_e6,
+            size_delta_e6: position.size_e6 as i64 - size_before as i64,
+            realized_pnl_e6: netted.realized_pnl_e6,
+            fee_e6: fee,
+            related_trade_sequence: 0,
+        });
+        emit_trading_fee_event(fee_event_seq, position_event_seq, user, market_index, fee, is_taker, current_ts);
+        emit_fee_split_events(fee_split_seq, position_event_seq, user, market_index, &fee_split, current_ts);
+        return Ok(());
+    }
+
+    let mut pre_side: u8 = 0;
+    let mut pre_size: u64 = 0;
+    let mut pre_entry: u64 = 0;
+    let mut pre_margin: u64 = 0;
+    let mut post_side: u8 = 0;
+    let mut post_size: u64 = 0;
+    let mut post_entry: u64 = 0;
+    let mut post_margin: u64 = 0;
+
+    if is_new_position {
+        // 创建新仓位
+        let rent = Rent::get()?;
+        let space = Position::SIZE;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                relayer.key,
+                position_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[relayer.clone(), position_info.clone(), system_program.clone()],
+            &[&[b"position", user.as_ref(), &[market_index], &[position_bump]]],
+        )?;
+
+        let mut position = Position {
+            discriminator: Position::DISCRIMINATOR,
+            user,
+            market_index,
+            side,
+            size_e6,
+            entry_price_e6: price_e6,
+            margin_e6: required_margin,
+            leverage,
+            liquidation_price_e6: 0, // 计算后设置
+            unrealized_pnl_e6: 0,
+            last_funding_ts: current_ts,
+            cumulative_funding_e6: 0,
+            open_order_count: 0,
+            opened_at: current_ts,
+            last_update_ts: current_ts,
+            bump: position_bump,
+            last_funding_index_e6: funding_index_e6,
+            unsettled_funding_e6: 0,
+            shortfall_count: 0,
+            reserved: [0; 8],
+        };
+
+        // 检查风险档位并计算清算价格
+        market_config.check_position_tier(position.leverage, position.notional_value_e6()?)?;
+        position.refresh_liquidation_price(&market_config)?;
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+        post_side = position.side as u8;
+        post_size = position.size_e6;
+        post_entry = position.entry_price_e6;
+        post_margin = position.margin_e6;
+
+        msg!(
+            "New position opened: user={}, market={}, side={:?}, size={}, entry={}, margin={}",
+            user, market_index, side, size_e6, price_e6, required_margin
+        );
+    } else {
+        // 加仓
+        let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+
+        // 验证方向一致
+        if position.side != side {
+            return Err(LedgerError::InvalidPositionSide.into());
+        }
+
+        pre_side = position.side as u8;
+        pre_size = position.size_e6;
+        pre_entry = position.entry_price_e6;
+        pre_margin = position.margin_e6;
+
+        // 加仓前按原仓位数量计提资金费
+        position.accrue_funding(funding_index_e6)?;
+
+        // 更新仓位
+        position.margin_e6 = checked_add_u64(position.margin_e6, required_margin)?;
+        position.update_entry_price(size_e6, price_e6, &market_config)?;
+        // 按加仓后的实际 (混合) 杠杆检查风险档位，而非本笔成交的杠杆
+        market_config.check_position_tier(position.leverage, position.notional_value_e6()?)?;
+        position.last_update_ts = current_ts;
+
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+        post_side = position.side as u8;
+        post_size = position.size_e6;
+        post_entry = position.entry_price_e6;
+        post_margin = position.margin_e6;
+
+        msg!(
+            "Position increased: user={}, market={}, new_size={}, new_margin={}",
+            user, market_index, position.size_e6, position.margin_e6
+        );
+    }
+
+    // CPI: 锁定保证金 + 扣除手续费
+    // 派生 Ledger Config PDA 用于 CPI 签名
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+
+    lock_margin_and_charge_fee(
+        vault_program.key,
+        vault_config_info,
+        user_account_info,
+        ledger_config_info,  // 使用 LedgerConfig PDA 作为 caller
+        required_margin,
+        fee,
+        ledger_config_bump,
+    )?;
+
+    msg!("CPI: Locked margin {}, charged fee {}", required_margin, fee);
+
+    // CPI: 手续费的保险基金部分计入保险基金
+    let fee_split = record_fee_split(program_id, fee_schedule_info, fee, referral.as_ref(), referrer_stats_info, current_ts)?;
+    add_trading_fee_to_fund(
+        &ledger_config,
+        ledger_config_info,
+        fund_program,
+        insurance_fund_info,
+        insurance_config_info,
+        fee_split.insurance_e6,
+        ledger_config_bump,
+    )?;
+
+    // 更新统计
+    ledger_config.total_positions_opened += 1;
+    ledger_config.total_volume_e6 = checked_add_u64(
+        ledger_config.total_volume_e6,
+        (size_e6 as u128 * price_e6 as u128 / 1_000_000) as u64,
+    )?;
+    ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+    let position_event_seq = ledger_config.next_sequence();
+    let fee_event_seq = ledger_config.next_sequence();
+    let fee_split_seq = ledger_config.next_sequences(fee_split.recipients().len() as u64);
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    // 自动创建 UserStats (如果不存在)
+    let _ = ensure_user_stats_exists(
+        program_id,
+        relayer,
+        &user,
+        user_stats_info,
+        system_program,
+    );
+
+    // 更新用户统计 (现在保证存在)
+    if user_stats_info.data_len() > 0 {
+        // 先读取数据到局部变量，释放借用
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_trades += 1;
+            let notional_e6 = (size_e6 as u128 * price_e6 as u128 / 1_000_000) as u64;
+            user_stats.total_volume_e6 = checked_add_u64(user_stats.total_volume_e6, notional_e6)?;
+            user_stats.record_rolling_volume(notional_e6, current_ts);
+            user_stats.total_fees_paid_e6 = checked_add_u64(user_stats.total_fees_paid_e6, fee)?;
+            if user_stats.first_trade_at == 0 {
+                user_stats.first_trade_at = current_ts;
+            }
+            user_stats.last_trade_at = current_ts;
+            // 现在可以安全地可变借用
+            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+        }
+    }
+
+    msg!("OpenPosition completed: batch_id={}, margin_locked={}, fee={}", batch_id, required_margin, fee);
+
+    emit_position_event(&PositionEvent {
+        discriminator: event_discriminator::POSITION,
+        sequence: position_event_seq,
+        timestamp: current_ts,
+        user,
+        market_index,
+        event_type: if is_new_position { 0 } else { 1 },
+        side_before: pre_side,
+        size_before_e6: pre_size,
+        entry_price_before_e6: pre_entry,
+        margin_before_e6: pre_margin,
+        side_after: post_side,
+        size_after_e6: post_size,
+        entry_price_after_e6: post_entry,
+        margin_after_e6: post_margin,
+        size_delta_e6: size_e6 as i64,
+        realized_pnl_e6: 0,
+        fee_e6: fee,
+        related_trade_sequence: 0,
+    });
+    emit_trading_fee_event(fee_event_seq, position_event_seq, user, market_index, fee, is_taker, current_ts);
+    emit_fee_split_events(fee_split_seq, position_event_seq, user, market_index, &fee_split, current_ts);
+
+    Ok(())
+}
+
+fn process_close_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
+    size_e6: u64,
+    price_e6: u64,
+    fill: FillParams,
+) -> ProgramResult {
+    let FillParams { batch_id, is_taker, fee_rate_e6: fee_rate } = fill;
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let position_info = next_account_info(account_info_iter)?;
+    let user_account_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let insurance_fund_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let user_stats_info = next_account_info(account_info_iter)?;
+    let _vault_program = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_config_info = next_account_info(account_info_iter)?;
+    let fee_schedule_info = next_account_info(account_info_iter)?;
+    let fee_tier_table_info = next_account_info(account_info_iter)?;
+    let referral_info = next_account_info(account_info_iter)?;
+    let referrer_stats_info = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+    assert_writable(position_info)?;
+    assert_writable(user_account_info)?;
+    assert_writable(insurance_fund_info)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(user_stats_info)?;
+
+    // 验证参数
+    if size_e6 == 0 {
+        return Err(LedgerError::InvalidTradeAmount.into());
+    }
+    if price_e6 == 0 {
+        return Err(LedgerError::InvalidPrice.into());
+    }
+
+    // 读取配置
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+
+    // 平仓不要求市场处于 active 状态 (下架市场仍允许减仓)
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    market_config.check_order(size_e6, price_e6)?;
+
+    // 成交价格必须在参考价格带宽内
+    let current_ts = get_current_timestamp()?;
+    let reference_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    market_config.check_trade_price(price_e6, reference_price_e6)?;
+    record_trade_price(program_id, mark_price_info, market_index, price_e6, current_ts)?;
+
+    // 费率不得高于用户 30 天成交量所在档位的费率
+    let fee_tier_table = load_fee_tier_table(program_id, fee_tier_table_info)?;
+    check_tier_fee_rate(program_id, &fee_tier_table, user_stats_info, &user, is_taker, fee_rate as i64, current_ts)?;
+
+    // 被推荐用户按 FeeSchedule 折扣费率
+    let referral = load_referral(program_id, referral_info, &user)?;
+    let fee_rate = referee_fee_rate(program_id, fee_schedule_info, referral.as_ref(), fee_rate)?;
+
+    // 读取仓位
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    if position.user != user || position.market_index != market_index {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+    if position.is_empty() {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+    let funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    position.accrue_funding(funding_state.cumulative_funding_index_e6)?;
+
+    let close_pre_side = position.side as u8;
+    let close_pre_size = position.size_e6;
+    let close_pre_entry = position.entry_price_e6;
+    let close_pre_margin = position.margin_e6;
+
+    // 验证平仓数量
+    let close_size = size_e6.min(position.size_e6);
+    let close_ratio = div_e6(close_size as i64, position.size_e6 as i64)?;
+
+    // 计算盈亏 (已平部分的未结算资金费计入已实现盈亏)
+    let pnl = position.calculate_unrealized_pnl(price_e6)?;
+    let funding_share = position.take_unsettled_funding(close_ratio)?;
+    let realized_pnl = checked_sub(mul_e6(pnl, close_ratio)?, funding_share)?;
+
+    // S0-4 fix: capture original margin before any mutation, so partial-to-full-close
+    // releases the correct total amount instead of only the rounding remainder.
+    let original_margin = position.margin_e6;
+
+    let mut margin_to_release = mul_e6(position.margin_e6 as i64, close_ratio)? as u64;
+
+    let fee = cpi::calculate_fee(close_size, price_e6, fee_rate)?;
+
+    let is_full_close = close_size >= position.size_e6;
+    if is_full_close {
+        margin_to_release = position.margin_e6;
+    }
+
+    if is_full_close {
+        position.size_e6 = 0;
+        position.margin_e6 = 0;
+        position.entry_price_e6 = 0;
+        position.liquidation_price_e6 = 0;
+        position.unrealized_pnl_e6 = 0;
+    } else {
+        position.size_e6 = checked_sub_u64(position.size_e6, close_size)?;
+        position.margin_e6 = checked_sub_u64(position.margin_e6, margin_to_release)?;
+        position.refresh_liquidation_price(&market_config)?;
+        
+        if position.size_e6 == 0 {
+            // S0-4: partial close resulted in full close due to precision —
+            // release the ENTIRE original margin, not just the remainder after proportional subtraction.
+            margin_to_release = original_margin;
+            position.margin_e6 = 0;
+            position.entry_price_e6 = 0;
+            position.liquidation_price_e6 = 0;
+            position.unrealized_pnl_e6 = 0;
+        }
+    }
+    position.last_update_ts = current_ts;
+    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+    // CPI 调用 Vault.closePositionSettle
+    // 派生 Ledger Config PDA 用于 CPI 签名
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+    
+    cpi::close_position_settle(
+        &ledger_config.vault_program,
+        vault_config_info.clone(),
+        user_account_info.clone(),
+        ledger_config_info.clone(),
+        margin_to_release,
+        realized_pnl,
+        fee,
+        &[&[b"ledger_config", &[ledger_config_bump]]],
+    )?;
+    
+    msg!("CPI: Close position settle - margin={}, pnl={}, fee={}", margin_to_release, realized_pnl, fee);
+
+    // CPI: 手续费的保险基金部分计入保险基金
+    let fee_split = record_fee_split(program_id, fee_schedule_info, fee, referral.as_ref(), referrer_stats_info, current_ts)?;
+    add_trading_fee_to_fund(
+        &ledger_config,
+        ledger_config_info,
+        fund_program,
+        insurance_fund_info,
+        insurance_config_info,
+        fee_split.insurance_e6,
+        ledger_config_bump,
+    )?;
+
+    // 更新统计
+    ledger_config.total_positions_closed += 1;
+    ledger_config.total_volume_e6 = checked_add_u64(
+        ledger_config.total_volume_e6,
+        (close_size as u128 * price_e6 as u128 / 1_000_000) as u64,
+    )?;
+    ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
+    let close_pos_event_seq = ledger_config.next_sequence();
+    let close_trade_event_seq = ledger_config.next_sequence();
+    let close_fee_event_seq = ledger_config.next_sequence();
+    let close_fee_split_seq = ledger_config.next_sequences(fee_split.recipients().len() as u64);
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    // 更新用户统计
+    if user_stats_info.data_len() > 0 {
+        // 先读取数据到局部变量，释放借用
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_trades += 1;
+            let notional_e6 = (close_size as u128 * price_e6 as u128 / 1_000_000) as u64;
+            user_stats.total_volume_e6 = checked_add_u64(user_stats.total_volume_e6, notional_e6)?;
+            user_stats.record_rolling_volume(notional_e6, current_ts);
+            user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, realized_pnl)?;
+            user_stats.total_fees_paid_e6 = checked_add_u64(user_stats.total_fees_paid_e6, fee)?;
+            user_stats.total_funding_paid_e6 = checked_add(user_stats.total_funding_paid_e6, funding_share)?;
+            user_stats.last_trade_at = current_ts;
+            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+        }
+    }
+
+    msg!(
+        "ClosePosition completed: batch_id={}, size={}, pnl={}, margin_released={}, fee={}",
+        batch_id, close_size, realized_pnl, margin_to_release, fee
+    );
+
+    let close_event_type = if position.size_e6 == 0 { 2u8 } else { 3u8 };
+    emit_position_event(&PositionEvent {
+        discriminator: event_discriminator::POSITION,
+        sequence: close_pos_event_seq,
+        timestamp: current_ts,
+        user,
+        market_index,
+        event_type: close_event_type,
+        side_before: close_pre_side,
+        size_before_e6: close_pre_size,
+        entry_price_before_e6: close_pre_entry,
+        margin_before_e6: close_pre_margin,
+        side_after: position.side as u8,
+        size_after_e6: position.size_e6,
+        entry_price_after_e6: position.entry_price_e6,
+        margin_after_e6: position.margin_e6,
+        size_delta_e6: -(close_size as i64),
+        realized_pnl_e6: realized_pnl,
+        fee_e6: fee,
+        related_trade_sequence: close_trade_event_seq,
+    });
+
+    emit_trade_event(&TradeEvent {
+        discriminator: event_discriminator::TRADE,
+        sequence: close_trade_event_seq,
+        timestamp: current_ts,
+        batch_id,
+        market_index,
+        market_type: 0,
+        trade_type: 0,
+        maker: Pubkey::default(),
+        maker_order_id: [0u8; 16],
+        maker_side: 0,
+        maker_fee_e6: 0,
+        taker: user,
+        taker_order_id: [0u8; 16],
+        taker_side: close_pre_side,
+        taker_fee_e6: fee as i64,
+        price_e6,
+        size_e6: close_size,
+        notional_e6: (close_size as u128 * price_e6 as u128 / 1_000_000) as u64,
+        maker_realized_pnl_e6: 0,
+        taker_realized_pnl_e6: realized_pnl,
+        maker_margin_delta_e6: 0,
+        taker_margin_delta_e6: -(margin_to_release as i64),
+    });
+    emit_trading_fee_event(close_fee_event_seq, close_trade_event_seq, user, market_index, fee, is_taker, current_ts);
+    emit_fee_split_events(close_fee_split_seq, close_trade_event_seq, user, market_index, &fee_split, current_ts);
+
+    Ok(())
+}
+
+// ============================================================================
+// 清算指令处理
+// ============================================================================
+
+fn process_liquidate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let liquidator = next_account_info(account_info_iter)?;
+    let position_info = next_account_info(account_info_iter)?;
+    let user_account_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let user_stats_info = next_account_info(account_info_iter)?;
+    let _vault_program = next_account_info(account_info_iter)?;
+    // Vault Token Account for liquidation penalty transfer
+    let vault_token_account = next_account_info(account_info_iter)?;
+    // Fund Program accounts for insurance fund operations
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_fund_account = next_account_info(account_info_iter)?;
+    let insurance_config = next_account_info(account_info_iter)?;
+    let insurance_vault = next_account_info(account_info_iter)?;
+    let counterparty_vault = next_account_info(account_info_iter)?; // For shortfall coverage
+    let token_program = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let liquidator_account_info = next_account_info(account_info_iter)?;
+    let liquidator_stats_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let shortfall_record_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+
+    assert_signer(liquidator)?;
+    assert_writable(position_info)?;
+    assert_writable(user_account_info)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(user_stats_info)?;
+    assert_writable(vault_token_account)?;
+    assert_writable(insurance_vault)?;
+    assert_writable(liquidator_account_info)?;
+    assert_writable(liquidator_stats_info)?;
+
+    // 清算奖励只能记入清算人自己的 Vault 账户
+    let liquidator_vault_account = cpi::read_user_account(liquidator_account_info)?;
+    if liquidator_vault_account.wallet != *liquidator.key {
+        msg!("❌ Liquidator UserAccount wallet mismatch: expected {}, got {}", liquidator.key, liquidator_vault_account.wallet);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    // 读取配置
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    // 验证 Fund Program
+    if fund_program.key != &ledger_config.fund_program {
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+
+    // 读取仓位
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    if position.user != user || position.market_index != market_index {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+    if position.is_empty() {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+
+    // 计提资金费后使用当前市场风险档位重新计算清算价格
+    let current_ts = get_current_timestamp()?;
+    let funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    position.accrue_funding(funding_state.cumulative_funding_index_e6)?;
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    position.refresh_liquidation_price(&market_config)?;
+
+    // 清算价格取链上标记价格，先按 Ledger 信任的 PriceFeed 指数价格刷新
+    let index_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    assert_writable(mark_price_info)?;
+    let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+    let mark_price_e6 = mark_price.update(index_price_e6, current_ts)?;
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
+
+    // 验证清算条件
+    if !position.should_liquidate(mark_price_e6) {
+        return Err(LedgerError::PositionNotLiquidatable.into());
+    }
+
+    // 派生 Ledger Config PDA 用于 CPI 签名
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+    let bump_slice = [ledger_config_bump];
+    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
+
+    let pnl = position.calculate_unrealized_pnl(mark_price_e6)?;
+    let margin = position.margin_e6;
+    let margin_ratio_e6 = position.margin_ratio_e6(mark_price_e6)?;
+    let side_u8 = position.side as u8;
+
+    // 部分清算: 只平掉使保证金率回到 MMR + buffer 所需的数量
+    // 保证金率低于全部清算阈值、已穿仓/盈利、或剩余仓位过小时全部清算
+    // 自我清算不发放奖励，罚金全部进入保险基金
+    let liquidator_reward_bps = if *liquidator.key == user { 0 } else { market_config.liquidator_reward_bps };
+    ensure_liquidator_stats_exists(program_id, liquidator, liquidator_stats_info, system_program)?;
+
+    let mmr = market_config.risk_tier_for(position.notional_value_e6()?)?.maintenance_margin_rate_e6;
+    let partial_close_size = if pnl < 0 && margin_ratio_e6 >= market_config.full_liquidation_margin_ratio_e6 {
+        position
+            .partial_liquidation_size(
+                mark_price_e6,
+                mmr + market_config.partial_liquidation_buffer_e6,
+                market_config.liquidation_penalty_rate_e6,
+                market_config.lot_size_e6,
+            )?
+            .filter(|&close_size| {
+                let remaining = (position.size_e6 - close_size) as u128 * mark_price_e6 as u128 / 1_000_000;
+                close_size > 0 && remaining >= market_config.min_liquidation_notional_e6 as u128
+            })
+    } else {
+        None
+    };
+
+    if let Some(close_size) = partial_close_size {
+        let pre_size = position.size_e6;
+        let close_ratio = div_e6(close_size as i64, pre_size as i64)?;
+        let trade_pnl = mul_e6(pnl, close_ratio)?;
+        let funding_e6 = position.take_unsettled_funding(close_ratio)?;
+        let realized_pnl = checked_sub(trade_pnl, funding_e6)?;
+        let close_notional = (close_size as u128 * mark_price_e6 as u128 / 1_000_000) as i64;
+        let liquidation_penalty = mul_e6(close_notional, market_config.liquidation_penalty_rate_e6)?.max(0) as u64;
+
+        // 已实现亏损和罚金从仓位保证金扣除，剩余保证金留在仓位中
+        let new_margin = checked_sub(checked_add(margin as i64, realized_pnl)?, liquidation_penalty as i64)?;
+        if new_margin <= 0 {
+            return Err(LedgerError::InsufficientMargin.into());
+        }
+        let new_margin = new_margin as u64;
+        // 部分清算仅在浮亏时发生: 交易亏损 + 罚金从锁定保证金扣除，资金费单独结算
+        let margin_consumed = checked_add_u64(trade_pnl.unsigned_abs(), liquidation_penalty)?;
+        // 应收资金费可能超过亏损与罚金 (new_margin > margin): 盈余释放给用户，不计入仓位保证金
+        let released_surplus = new_margin.saturating_sub(margin);
+        let remaining_margin = checked_sub_u64(new_margin, released_surplus)?;
+
+        position.size_e6 = checked_sub_u64(pre_size, close_size)?;
+        position.margin_e6 = remaining_margin;
+        position.refresh_liquidation_price(&market_config)?;
+        position.last_update_ts = current_ts;
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+        let (insurance_share, liquidator_reward) =
+            split_liquidation_penalty(liquidation_penalty, liquidator_reward_bps);
+
+        // CPI 0: 平仓部分的未结算资金费同步到锁定保证金
+        if funding_e6 != 0 {
+            cpi::settle_funding(
+                &ledger_config.vault_program,
+                vault_config_info.clone(),
+                user_account_info.clone(),
+                ledger_config_info.clone(),
+                funding_e6,
+                signer_seeds,
+            )?;
+        }
+
+        // CPI 1: 从锁定保证金中扣除已实现亏损 + 罚金，保险基金份额转入 Insurance Fund (不返还用户)
+        cpi::liquidate_position(
+            &ledger_config.vault_program,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
+            vault_token_account.clone(),
+            insurance_vault.clone(),
+            token_program.clone(),
+            margin_consumed,
+            0,
+            insurance_share,
+            signer_seeds,
+        )?;
+
+        if released_surplus > 0 {
+            cpi::release_margin(
+                &ledger_config.vault_program,
+                vault_config_info.clone(),
+                user_account_info.clone(),
+                ledger_config_info.clone(),
+                released_surplus,
+                signer_seeds,
+            )?;
+        }
+
+        // CPI 2: 记录清算罚金到保险基金统计
+        if insurance_share > 0 {
+            cpi::add_liquidation_income(
+                fund_program.key,
+                ledger_config_info.clone(),
+                insurance_fund_account.clone(),
+                insurance_config.clone(),
+                insurance_share as i64,
+                signer_seeds,
+            )?;
+        }
+
+        // CPI 3: 清算人奖励
+        if liquidator_reward > 0 {
+            cpi::credit_liquidation_reward(
+                &ledger_config.vault_program,
+                vault_config_info.clone(),
+                liquidator_account_info.clone(),
+                ledger_config_info.clone(),
+                liquidator_reward,
+                signer_seeds,
+            )?;
+        }
+        record_liquidation(liquidator_stats_info, close_notional as u64, liquidator_reward, true, current_ts)?;
+
+        ledger_config.total_liquidations += 1;
+        ledger_config.last_update_ts = current_ts;
+        let sequence = ledger_config.next_sequence();
+        let liq_pos_seq = ledger_config.next_sequence();
+        let liq_fee_seq = ledger_config.next_sequence();
+        ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+        if user_stats_info.data_len() > 0 {
+            let user_stats_result = {
+                let data = user_stats_info.data.borrow();
+                deserialize_account::<UserStats>(&data)
+            };
+            if let Ok(mut user_stats) = user_stats_result {
+                user_stats.total_liquidations += 1;
+                user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, realized_pnl)?;
+                user_stats.last_trade_at = current_ts;
+                user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+            }
+        }
+
+        events::emit_liquidation_event(&events::LiquidationEvent {
+            discriminator: events::event_discriminator::LIQUIDATION,
+            sequence,
+            timestamp: current_ts,
+            user,
+            market_index,
+            side: side_u8,
+            position_size_e6: close_size,
+            entry_price_e6: position.entry_price_e6,
+            mark_price_e6,
+            liquidation_price_e6: position.liquidation_price_e6,
+            margin_e6: margin,
+            margin_ratio_e6: margin_ratio_e6.max(0) as u64,
+            penalty_e6: liquidation_penalty,
+            insurance_payout_e6: 0,
+            remaining_margin_e6: remaining_margin as i64,
+            is_bankruptcy: false,
+            realized_pnl_e6: realized_pnl,
+            related_trade_sequence: sequence,
+        });
+
+        emit_position_event(&PositionEvent {
+            discriminator: event_discriminator::POSITION,
+            sequence: liq_pos_seq,
+            timestamp: current_ts,
+            user,
+            market_index,
+            event_type: events::PositionEventType::Decreased as u8,
+            side_before: side_u8,
+            size_before_e6: pre_size,
+            entry_price_before_e6: position.entry_price_e6,
+            margin_before_e6: margin,
+            side_after: side_u8,
+            size_after_e6: position.size_e6,
+            entry_price_after_e6: position.entry_price_e6,
+            margin_after_e6: position.margin_e6,
+            size_delta_e6: -(close_size as i64),
+            realized_pnl_e6: realized_pnl,
+            fee_e6: liquidation_penalty,
+            related_trade_sequence: sequence,
+        });
+
+        if liquidation_penalty > 0 {
+            emit_fee_event(&FeeEvent {
+                discriminator: event_discriminator::FEE,
+                sequence: liq_fee_seq,
+                timestamp: current_ts,
+                user,
+                market_index,
+                fee_type: 2,
+                amount_e6: liquidation_penalty as i64,
+                related_trade_sequence: sequence,
+            });
+        }
+
+        msg!(
+            "Partial liquidation: user={}, market={}, mark_price={}, closed={}/{}, pnl={}, penalty={}, reward={}, margin_ratio={}",
+            user, market_index, mark_price_e6, close_size, pre_size, realized_pnl, liquidation_penalty, liquidator_reward, margin_ratio_e6
+        );
+        return Ok(());
+    }
+
+    // 全部清算
+    let pre_liq_size = position.size_e6;
+    let pre_liq_entry = position.entry_price_e6;
+    let pre_liq_liq_price = position.liquidation_price_e6;
+    let bankruptcy_price_e6 = position.bankruptcy_price_e6();
+    // 未结算资金费全部计入已实现盈亏
+    let pnl = checked_sub(pnl, position.take_unsettled_funding(1_000_000)?)?;
+
+    // 计算各方分配
+    let (user_remainder, liquidation_penalty, shortfall) =
+        calculate_liquidation_result(margin, pnl, market_config.liquidation_penalty_rate_e6);
+    let (insurance_share, liquidator_reward) =
+        split_liquidation_penalty(liquidation_penalty, liquidator_reward_bps);
+    let liquidated_notional = (pre_liq_size as u128 * mark_price_e6 as u128 / 1_000_000) as u64;
+
+    // 关闭仓位 (穿仓时递增本仓位的穿仓计数，作为下一条 ShortfallRecord 的 seed)
+    let shortfall_index = position.shortfall_count;
+    if shortfall > 0 {
+        position.shortfall_count = shortfall_index.checked_add(1).ok_or(LedgerError::Overflow)?;
+    }
+    position.size_e6 = 0;
+    position.margin_e6 = 0;
+    position.entry_price_e6 = 0;
+    position.liquidation_price_e6 = 0;
+    position.unrealized_pnl_e6 = 0;
+    position.last_update_ts = current_ts;
+    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+    // CPI 1: 更新用户账户 + 转移罚金的保险基金份额到 Insurance Fund (Vault Program)
+    // 这个 CPI 会执行实际的 Token Transfer: Vault Token Account -> Insurance Fund Vault
+    // 清算人奖励部分留在 Vault Token Account 中，由 CPI 3 记入清算人账户
+    cpi::liquidate_position(
+        &ledger_config.vault_program,
+        vault_config_info.clone(),
+        user_account_info.clone(),
+        ledger_config_info.clone(),
+        vault_token_account.clone(),
+        insurance_vault.clone(),
+        token_program.clone(),
+        margin,
+        user_remainder,
+        insurance_share,
+        signer_seeds,
+    )?;
+    
+    msg!(
+        "CPI: Liquidate user account - margin={}, remainder={}, penalty={}, insurance_share={}",
+        margin,
+        user_remainder,
+        liquidation_penalty,
+        insurance_share
+    );
+    
+    // CPI 2: 记录清算罚金到保险基金统计 (Fund Program)
+    // 注意: Token 已经在 CPI 1 中转移完成，这里只是更新统计
+    if insurance_share > 0 {
+        cpi::add_liquidation_income(
+            fund_program.key,
+            ledger_config_info.clone(),
+            insurance_fund_account.clone(),
+            insurance_config.clone(),
+            insurance_share as i64,
+            signer_seeds,
+        )?;
+        msg!("CPI: Liquidation penalty {} recorded in insurance fund stats", insurance_share);
+    }
+
+    // CPI 3: 清算人奖励 (Vault Program)
+    if liquidator_reward > 0 {
+        cpi::credit_liquidation_reward(
+            &ledger_config.vault_program,
+            vault_config_info.clone(),
+            liquidator_account_info.clone(),
+            ledger_config_info.clone(),
+            liquidator_reward,
+            signer_seeds,
+        )?;
+        msg!("CPI: Liquidator reward {} credited to {}", liquidator_reward, liquidator.key);
+    }
+    record_liquidation(liquidator_stats_info, liquidated_notional, liquidator_reward, false, current_ts)?;
+    
+    // CPI 4: 覆盖穿仓 (Fund Program)
+    if shortfall > 0 {
+        cpi::cover_shortfall(
+            fund_program.key,
+            ledger_config_info.clone(),
+            insurance_fund_account.clone(),
+            insurance_config.clone(),
+            insurance_vault.clone(),
+            counterparty_vault.clone(),
+            token_program.clone(),
+            shortfall as i64,
+            signer_seeds,
+        )?;
+        msg!("CPI: Shortfall {} coverage requested from insurance fund", shortfall);
+    }
+
+    // 更新统计
+    ledger_config.total_liquidations += 1;
+    ledger_config.last_update_ts = current_ts;
+    let sequence = ledger_config.next_sequence();
+    let liq_pos_seq = ledger_config.next_sequence();
+    let liq_fee_seq = ledger_config.next_sequence();
+    let liq_ins_seq = ledger_config.next_sequence();
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    // 穿仓记录: TriggerADL 只能基于真实穿仓触发，且每条记录只能消费一次
+    if shortfall > 0 {
+        let shortfall_record = ShortfallRecord {
+            discriminator: ShortfallRecord::DISCRIMINATOR,
+            user,
+            market_index,
+            side: position.side,
+            size_e6: pre_liq_size,
+            entry_price_e6: pre_liq_entry,
+            bankruptcy_price_e6,
+            shortfall_e6: shortfall,
+            liquidation_sequence: sequence,
+            shortfall_index,
+            is_consumed: false,
+            created_at: current_ts,
+            consumed_at: 0,
+            bump: 0,
+            reserved: [0u8; 24],
+        };
+        create_shortfall_record(
+            program_id,
+            liquidator,
+            shortfall_record_info,
+            system_program,
+            shortfall_record,
+        )?;
+    }
+
+    // 更新用户统计
+    if user_stats_info.data_len() > 0 {
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_liquidations += 1;
+            user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, pnl)?;
+            user_stats.last_trade_at = current_ts;
+            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
+        }
+    }
+
+    // Emit LiquidationEvent
+    events::emit_liquidation_event(&events::LiquidationEvent {
+        discriminator: events::event_discriminator::LIQUIDATION,
+        sequence,
+        timestamp: current_ts,
+        user,
+        market_index,
+        side: side_u8,
+        position_size_e6: pre_liq_size,
+        entry_price_e6: pre_liq_entry,
+        mark_price_e6,
+        liquidation_price_e6: pre_liq_liq_price,
+        margin_e6: margin,
+        margin_ratio_e6: margin_ratio_e6.max(0) as u64,
+        penalty_e6: liquidation_penalty,
+        insurance_payout_e6: shortfall,
+        remaining_margin_e6: user_remainder as i64,
+        is_bankruptcy: shortfall > 0,
+        realized_pnl_e6: pnl,
+        related_trade_sequence: sequence,
+    });
+
+    emit_position_event(&PositionEvent {
+        discriminator: event_discriminator::POSITION,
+        sequence: liq_pos_seq,
+        timestamp: current_ts,
+        user,
+        market_index,
+        event_type: events::PositionEventType::Liquidated as u8,
+        side_before: side_u8,
+        size_before_e6: pre_liq_size,
+        entry_price_before_e6: pre_liq_entry,
+        margin_before_e6: margin,
+        side_after: side_u8,
+        size_after_e6: 0,
+        entry_price_after_e6: 0,
+        margin_after_e6: 0,
+        size_delta_e6: -(pre_liq_size as i64),
+        realized_pnl_e6: pnl,
+        fee_e6: liquidation_penalty,
+        related_trade_sequence: sequence,
+    });
+
+    if liquidation_penalty > 0 {
+        emit_fee_event(&FeeEvent {
+            discriminator: event_discriminator::FEE,
+            sequence: liq_fee_seq,
+            timestamp: current_ts,
+            user,
+            market_index,
+            fee_type: 2,
+            amount_e6: liquidation_penalty as i64,
+            related_trade_sequence: sequence,
+        });
+
+        emit_insurance_fund_event(&InsuranceFundEvent {
+            discriminator: event_discriminator::INSURANCE_FUND,
+            sequence: liq_ins_seq,
+            timestamp: current_ts,
+            event_type: 0,
+            market_index,
+            amount_e6: insurance_share as i64,
+            balance_before_e6: 0,
+            balance_after_e6: 0,
+            related_user: user,
+            reason: 0,
+        });
+    }
+
+    msg!(
+        "Liquidation completed: user={}, market={}, mark_price={}, pnl={}, remainder={}, penalty={}, shortfall={}",
+        user, market_index, mark_price_e6, pnl, user_remainder, liquidation_penalty, shortfall
+    );
+
+    if shortfall > 0 {
+        msg!("⚠️ Shortfall detected: {}, ADL may be required if insurance fund insufficient", shortfall);
+    }
+
+    Ok(())
+}
+
+/// 确保 LiquidatorStats 存在，不存在则由清算人付费创建
+fn ensure_liquidator_stats_exists<'a>(
+    program_id: &Pubkey,
+    liquidator: &AccountInfo<'a>,
+    liquidator_stats_info: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> Result<bool, ProgramError> {
+    let (liquidator_stats_pda, bump) = Pubkey::find_program_address(
+        &[LiquidatorStats::SEED_PREFIX, liquidator.key.as_ref()],
+        program_id,
+    );
+    if liquidator_stats_info.key != &liquidator_stats_pda {
+        msg!("❌ Invalid LiquidatorStats PDA: expected {}, got {}", liquidator_stats_pda, liquidator_stats_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    let is_empty = liquidator_stats_info.data_len() == 0 || {
+        let data = liquidator_stats_info.data.borrow();
+        data.iter().all(|&x| x == 0)
+    };
+
+    if !is_empty {
+        let data = liquidator_stats_info.data.borrow();
+        if data.len() >= 8 && &data[0..8] == LiquidatorStats::DISCRIMINATOR.as_slice() {
+            return Ok(false);
+        }
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    let rent = Rent::get()?;
+    let space = LiquidatorStats::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            liquidator.key,
+            liquidator_stats_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[liquidator.clone(), liquidator_stats_info.clone(), system_program.clone()],
+        &[&[LiquidatorStats::SEED_PREFIX, liquidator.key.as_ref(), &[bump]]],
+    )?;
+
+    let liquidator_stats = LiquidatorStats {
+        discriminator: LiquidatorStats::DISCRIMINATOR,
+        liquidator: *liquidator.key,
+        total_liquidations: 0,
+        partial_liquidations: 0,
+        total_liquidated_notional_e6: 0,
+        total_rewards_e6: 0,
+        last_liquidation_ts: 0,
+        created_at: get_current_timestamp()?,
+        bump,
+        reserved: [0u8; 32],
+    };
+    liquidator_stats.serialize(&mut &mut liquidator_stats_info.data.borrow_mut()[..])?;
+    msg!("✅ LiquidatorStats auto-created for {}", liquidator.key);
+
+    Ok(true)
+}
+
+/// 更新清算人统计
+fn record_liquidation(
+    liquidator_stats_info: &AccountInfo,
+    notional_e6: u64,
+    reward_e6: u64,
+    is_partial: bool,
+    current_ts: i64,
+) -> ProgramResult {
+    let mut liquidator_stats = deserialize_account::<LiquidatorStats>(&liquidator_stats_info.data.borrow())?;
+    liquidator_stats.record(notional_e6, reward_e6, is_partial, current_ts);
+    liquidator_stats.serialize(&mut &mut liquidator_stats_info.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// 创建 ShortfallRecord PDA (清算人付费)
+fn create_shortfall_record<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    shortfall_record_info: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    mut record: ShortfallRecord,
+) -> ProgramResult {
+    let index_bytes = record.shortfall_index.to_le_bytes();
+    let (record_pda, bump) = Pubkey::find_program_address(
+        &[ShortfallRecord::SEED_PREFIX, record.user.as_ref(), &[record.market_index], &index_bytes],
+        program_id,
+    );
+    if shortfall_record_info.key != &record_pda {
+        msg!("❌ Invalid ShortfallRecord PDA: expected {}, got {}", record_pda, shortfall_record_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    assert_writable(shortfall_record_info)?;
+
+    let rent = Rent::get()?;
+    let space = ShortfallRecord::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            shortfall_record_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[payer.clone(), shortfall_record_info.clone(), system_program.clone()],
+        &[&[ShortfallRecord::SEED_PREFIX, record.user.as_ref(), &[record.market_index], &index_bytes, &[bump]]],
+    )?;
+
+    record.bump = bump;
+    record.serialize(&mut &mut shortfall_record_info.data.borrow_mut()[..])?;
+    msg!("✅ ShortfallRecord created: user={}, shortfall={}", record.user, record.shortfall_e6);
+
+    Ok(())
+}
+
+/// 计算清算结果
+/// 返回 (user_remainder, liquidation_penalty, shortfall)
+fn calculate_liquidation_result(margin: u64, pnl: i64, penalty_rate_e6: i64) -> (u64, u64, u64) {
+    let margin_i = margin as i64;
+    let total = margin_i + pnl;
+
+    if total <= 0 {
+        // 穿仓: 用户没有剩余，有穿仓损失
+        let shortfall = (-total) as u64;
+        (0, 0, shortfall)
+    } else {
+        // 有剩余: 计算罚金和用户剩余
+        let total_u = total as u64;
+        let penalty = mul_e6(total, penalty_rate_e6).unwrap_or(0) as u64;
+        let user_remainder = total_u.saturating_sub(penalty);
+        (user_remainder, penalty, 0)
+    }
+}
+
+/// 拆分清算罚金
+/// 返回 (insurance_share, liquidator_reward)，reward = penalty * reward_bps / 10000
+fn split_liquidation_penalty(penalty: u64, liquidator_reward_bps: u16) -> (u64, u64) {
+    let reward = (penalty as u128 * liquidator_reward_bps.min(10_000) as u128 / 10_000) as u64;
+    (penalty - reward, reward)
+}
+
+/// P0-2/NEW-1 实现: 完整的 ADL 链上触发逻辑 + Fund Program CPI 集成
+/// 
+/// ADL (Auto-Deleveraging) 流程:
+/// 1. 消费 Liquidate 写入的 ShortfallRecord，验证保险基金确实不足以覆盖穿仓
+/// 2. 验证目标仓位是有效的反向盈利仓位
+/// 3. CPI 调用 Fund Program 设置 ADL 状态
+/// 4. 标记 ADL 状态并记录事件
+/// 5. 实际的减仓由 ExecuteADL 执行
+/// 
+/// 账户顺序:
+/// 0. admin (signer) - 管理员/Relayer
+/// 1. ledger_config_info (writable) - Ledger 全局配置
+/// 2. fund_program - Fund Program ID
+/// 3. insurance_config (writable) - InsuranceFundConfig PDA
+/// 4. fund_vault - Insurance Fund Vault (Token Account)
+/// 5. price_feed_info - PriceFeed PDA (刷新标记价格的指数价格)
+/// 6. mark_price_info (writable) - MarkPrice PDA (ADL 排序使用的标记价格，与 ExecuteADL 一致)
+/// 7. adl_state_info (writable) - ADLState PDA (不存在时自动创建，管理员付费)
+/// 8. system_program
+/// 9. shortfall_record_info (writable) - ShortfallRecord PDA
+/// 10..n. target_position_infos - 目标仓位账户 (按 ADL 分数从高到低)
+fn process_trigger_adl(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_config = next_account_info(account_info_iter)?;
+    let fund_vault = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let adl_state_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let shortfall_record_info = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(insurance_config)?;
+    assert_writable(mark_price_info)?;
+    assert_writable(adl_state_info)?;
+    assert_writable(shortfall_record_info)?;
+
+    // 读取配置
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    // NEW-1: 验证 Fund Program
+    if fund_program.key != &ledger_config.fund_program {
+        msg!("❌ Invalid Fund Program: expected {}, got {}", ledger_config.fund_program, fund_program.key);
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+
+    // P0-2: 验证是管理员或授权 Relayer
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    // P0-2: 验证程序未暂停
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+
+    let current_ts = get_current_timestamp()?;
+
+    // 穿仓金额和方向只取 Liquidate 写入的 ShortfallRecord，每条记录只能触发一次
+    let mut shortfall_record = load_shortfall_record(program_id, shortfall_record_info, market_index)?;
+    shortfall_record.consume(current_ts)?;
+    let shortfall_e6 = shortfall_record.shortfall_e6;
+    let bankrupt_side = shortfall_record.side;
+
+    // ADL 排序使用按指数价格刷新后的 MarkPrice，与 ExecuteADL 的排序价格一致
+    let index_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+    let mark_price_e6 = mark_price.update(index_price_e6, current_ts)?;
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
+
+    // NEW-1: 从 Fund Program 的 InsuranceFundConfig 读取保险基金余额
+    // InsuranceFundConfig 结构:
+    // - discriminator: u64 (8 bytes)
+    // - fund: Pubkey (32 bytes)
+    // - bump: u8 (1 byte)
+    // - total_liquidation_income_e6: i64 (8 bytes)
+    // - total_adl_profit_e6: i64 (8 bytes)
+    // - total_shortfall_payout_e6: i64 (8 bytes)
+    // - adl_trigger_threshold_e6: i64 (8 bytes)
+    // - adl_trigger_count: u64 (8 bytes)
+    // - balance_1h_ago_e6: i64 (8 bytes)
+    // ... 
+    // 我们需要从 fund_vault 读取实际余额
+    let insurance_balance_e6 = read_insurance_fund_balance_from_vault(fund_vault)?;
+    let insurance_config_data = read_insurance_fund_config(insurance_config)?;
+
+    msg!(
+        "NEW-1 ADL Check: shortfall={}, insurance_balance={}, threshold={}",
+        shortfall_e6,
+        insurance_balance_e6,
+        insurance_config_data.adl_trigger_threshold_e6
+    );
+
+    // P0-2 步骤2: 验证保险基金确实不足
+    if insurance_balance_e6 >= shortfall_e6 as i64 {
+        msg!("⚠️ Insurance fund sufficient, ADL not required");
+        return Err(LedgerError::ADLNotRequired.into());
+    }
+
+    // P0-2 步骤3: 计算需要 ADL 覆盖的金额
+    let adl_required = shortfall_e6 as i64 - insurance_balance_e6;
+    msg!(
+        "NEW-1 ADL Required: {} (shortfall={}, insurance={})",
+        adl_required,
+        shortfall_e6,
+        insurance_balance_e6
+    );
+
+    // P0-2 步骤4: 验证目标仓位
+    // 收集剩余的账户作为目标仓位，盈亏和 ADL 分数按标记价格链上计算
+    let mut validated_targets: Vec<Pubkey> = Vec::new();
+    let mut total_available_pnl: i64 = 0;
+    let mut last_score = u64::MAX;
+    let counterparty_side = bankrupt_side.opposite();
+
+    for target_info in account_info_iter {
+        // 尝试反序列化为 Position
+        if let Ok(position) = deserialize_account::<Position>(&target_info.data.borrow()) {
+            // 验证: 必须是同市场
+            if position.market_index != market_index {
+                msg!("⚠️ Position {} wrong market, skipping", target_info.key);
+                continue;
+            }
+
+            // 验证: 必须是本程序的 Position PDA
+            let (position_pda, _) = Pubkey::find_program_address(
+                &[b"position", position.user.as_ref(), &[market_index]],
+                program_id,
+            );
+            if target_info.key != &position_pda {
+                msg!("⚠️ Position {} invalid PDA, skipping", target_info.key);
+                continue;
+            }
+
+            // 验证: 必须是反向方向
+            if position.side != counterparty_side {
+                msg!("⚠️ Position {} wrong side, skipping", target_info.key);
+                continue;
+            }
+
+            // 验证: 按标记价格必须有盈利
+            let pnl = position.calculate_unrealized_pnl(mark_price_e6)?;
+            if pnl <= 0 {
+                msg!("⚠️ Position {} no profit, skipping", target_info.key);
+                continue;
+            }
+
+            // 验证: 必须按 ADL 分数从高到低排列
+            let score = position.adl_score_e6(mark_price_e6)?;
+            if score > last_score {
+                msg!("❌ ADL target {} score {} ranks above previous target {}", target_info.key, score, last_score);
+                return Err(LedgerError::ADLTargetsNotRanked.into());
+            }
+            last_score = score;
+
+            // 验证通过
+            validated_targets.push(*target_info.key);
+            total_available_pnl += pnl;
+
+            msg!(
+                "✅ ADL Target validated: {}, pnl={}, score={}",
+                target_info.key,
+                pnl,
+                score
+            );
+        }
+    }
+
+    // P0-2: 验证是否有足够的目标仓位
+    if validated_targets.is_empty() {
+        msg!("❌ No valid ADL targets found");
+        return Err(LedgerError::NoOpposingPositionsForADL.into());
+    }
+
+    msg!(
+        "NEW-1 ADL Targets: {} positions, total_pnl={}",
+        validated_targets.len(),
+        total_available_pnl
+    );
+
+    // 记录本轮 ADL (已有进行中的 ADL 时拒绝)
+    ensure_adl_state_exists(program_id, admin, adl_state_info, system_program, market_index)?;
+    let mut adl_state = load_adl_state(program_id, adl_state_info, market_index)?;
+    adl_state.start(*shortfall_record_info.key, &shortfall_record, insurance_balance_e6, current_ts)?;
+    adl_state.serialize(&mut &mut adl_state_info.data.borrow_mut()[..])?;
+    shortfall_record.serialize(&mut &mut shortfall_record_info.data.borrow_mut()[..])?;
+
+    // NEW-1 步骤5: CPI 调用 Fund Program SetADLInProgress
+    // 派生 Ledger Config PDA 用于 CPI 签名
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+    let bump_slice = [ledger_config_bump];
+    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
+    
+    cpi::set_adl_in_progress(
+        fund_program.key,
+        ledger_config_info.clone(),
+        insurance_config.clone(),
+        true, // Set ADL in progress = true
+        signer_seeds,
+    )?;
+    
+    msg!("✅ NEW-1: CPI SetADLInProgress(true) - LP redemptions paused");
+
+    // P0-2 步骤6: 更新 ADL 状态
+    ledger_config.total_adl_count += 1;
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    // P0-2 步骤7: 发出 ADL 触发事件
+    msg!("🚨 ADL_TRIGGERED_EVENT:");
+    msg!("  market_index: {}", market_index);
+    msg!("  shortfall_e6: {}", shortfall_e6);
+    msg!("  insurance_balance_e6: {}", insurance_balance_e6);
+    msg!("  adl_required_e6: {}", adl_required);
+    msg!("  bankrupt_side: {:?}", bankrupt_side);
+    msg!("  target_count: {}", validated_targets.len());
+    msg!("  total_available_pnl_e6: {}", total_available_pnl);
+    msg!("  timestamp: {}", current_ts);
+    msg!("  adl_count: {}", ledger_config.total_adl_count);
+
+    // Emit structured ADL event
+    let adl_event = events::ADLEvent {
+        discriminator: events::event_discriminator::ADL,
+        sequence: ledger_config.total_adl_count,
+        timestamp: current_ts,
+        market_index,
+        trigger_reason: events::ADLTriggerReason::Bankruptcy as u8,
+        shortfall_e6,
+        insurance_balance_before_e6: insurance_balance_e6,
+        insurance_balance_after_e6: insurance_balance_e6,
+        bankrupt_user: shortfall_record.user,
+        bankrupt_side: match bankrupt_side { Side::Long => 0, Side::Short => 1 },
+        bankrupt_size_e6: shortfall_record.size_e6,
+        counterparty_user: if validated_targets.is_empty() { Pubkey::default() } else { validated_targets[0] },
+        counterparty_side: match bankrupt_side { Side::Long => 1, Side::Short => 0 },
+        counterparty_size_reduced_e6: 0,
+        counterparty_pnl_e6: total_available_pnl,
+        related_trade_sequence: 0,
+    };
+    events::emit_adl_event(&adl_event);
+
+    Ok(())
+}
+
+/// 执行 ADL: 按破产价格减少反向盈利仓位，覆盖保险基金不足的穿仓部分
+///
+/// 每个目标仓位按破产价格平仓 (而非标记价格)，让出的盈利 |mark - bankruptcy| * size
+/// 由 Vault 转入保险基金并从 ShortfallRecord 中扣减；覆盖完成后剩余目标不再处理。
+fn process_execute_adl(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_fund_account = next_account_info(account_info_iter)?;
+    let insurance_config = next_account_info(account_info_iter)?;
+    let adl_state_info = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let shortfall_record_info = next_account_info(account_info_iter)?;
+    let vault_token_account = next_account_info(account_info_iter)?;
+    let insurance_vault = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(insurance_config)?;
+    assert_writable(adl_state_info)?;
+    assert_writable(mark_price_info)?;
+    assert_writable(shortfall_record_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if vault_program.key != &ledger_config.vault_program {
+        msg!("❌ Invalid Vault Program: expected {}, got {}", ledger_config.vault_program, vault_program.key);
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+    if fund_program.key != &ledger_config.fund_program {
+        msg!("❌ Invalid Fund Program: expected {}, got {}", ledger_config.fund_program, fund_program.key);
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+
+    // ADL 必须已由 TriggerADL 启动，覆盖目标取自 ADLState
+    let mut adl_state = load_adl_state(program_id, adl_state_info, market_index)?;
+    if !adl_state.is_active {
+        msg!("❌ ADL not in progress, call TriggerADL first");
+        return Err(LedgerError::ADLNotTriggered.into());
+    }
+    let adl_required = adl_state.remaining_e6();
+    if adl_required == 0 {
+        msg!("⚠️ ADL shortfall already covered, call CompleteADL");
+        return Err(LedgerError::ADLNotRequired.into());
+    }
+    let bankrupt_side = adl_state.bankrupt_side;
+    let bankruptcy_price_e6 = adl_state.bankruptcy_price_e6;
+
+    // 覆盖的穿仓必须是 TriggerADL 消费的那条记录
+    if shortfall_record_info.key != &adl_state.shortfall_record {
+        msg!("❌ ShortfallRecord {} does not match ADLState", shortfall_record_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let mut shortfall_record = load_shortfall_record(program_id, shortfall_record_info, market_index)?;
+
+    let current_ts = get_current_timestamp()?;
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+
+    // 排序与价格带校验取链上标记价格，先按 Ledger 信任的 PriceFeed 指数价格刷新
+    let index_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+    let mark_price_e6 = mark_price.update(index_price_e6, current_ts)?;
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
+    market_config.check_liquidation_price(bankruptcy_price_e6, mark_price_e6)?;
+    let funding_index_e6 = load_funding_state(program_id, funding_state_info, market_index)?.cumulative_funding_index_e6;
+
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+    let bump_slice = [ledger_config_bump];
+    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
+
+    let counterparty_side = bankrupt_side.opposite();
+    let bankrupt_side_u8 = bankrupt_side as u8;
+    let mut remaining = adl_required;
+    let mut total_covered: u64 = 0;
+    let mut total_reduced: u64 = 0;
+    let mut target_count: u32 = 0;
+
+    // 所有传入目标 (含本次未减仓的) 必须按 ADL 分数从高到低排列
+    let target_accounts = account_info_iter.as_slice();
+    if target_accounts.len().checked_rem(2) != Some(0) {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let mut last_score = u64::MAX;
+    for pair in target_accounts.chunks(2) {
+        let position = deserialize_account::<Position>(&pair[0].data.borrow())?;
+        let score = position.adl_score_e6(mark_price_e6)?;
+        if score > last_score {
+            msg!("❌ ADL target {} score {} ranks above previous target {}", pair[0].key, score, last_score);
+            return Err(LedgerError::ADLTargetsNotRanked.into());
+        }
+        last_score = score;
+    }
+
+    for pair in target_accounts.chunks(2) {
+        if remaining == 0 {
+            break;
+        }
+        let (position_info, user_account_info) = (&pair[0], &pair[1]);
+        assert_writable(position_info)?;
+        assert_writable(user_account_info)?;
+
+        let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+        let (position_pda, _) = Pubkey::find_program_address(
+            &[b"position", position.user.as_ref(), &[market_index]],
+            program_id,
+        );
+        if position_info.key != &position_pda || position.is_empty() {
+            msg!("❌ Invalid ADL target position {}", position_info.key);
+            return Err(LedgerError::InvalidADLTarget.into());
+        }
+        if position.side != counterparty_side {
+            msg!("❌ ADL target {} is on the bankrupt side", position_info.key);
+            return Err(LedgerError::InvalidADLTarget.into());
+        }
+        // 按破产价格平仓后仍需盈利，否则不是有效的 ADL 对象
+        if position.calculate_unrealized_pnl(bankruptcy_price_e6)? <= 0 {
+            msg!("❌ ADL target {} not profitable at bankruptcy price", position_info.key);
+            return Err(LedgerError::InvalidADLTarget.into());
+        }
+        if cpi::read_user_account(user_account_info)?.wallet != position.user {
+            msg!("❌ UserAccount does not belong to ADL target {}", position.user);
+            return Err(LedgerError::InvalidAccount.into());
+        }
+
+        let (adl_size, covered) = position.adl_close_size(
+            remaining,
+            mark_price_e6,
+            bankruptcy_price_e6,
+            market_config.lot_size_e6,
+        )?;
+
+        let side_u8 = position.side as u8;
+        let size_before = position.size_e6;
+        let entry_before = position.entry_price_e6;
+        let margin_before = position.margin_e6;
+
+        position.accrue_funding(funding_index_e6)?;
+        let (close_size, realized_pnl, margin_released) = position.reduce(adl_size, bankruptcy_price_e6)?;
+        if !position.is_empty() {
+            position.refresh_liquidation_price(&market_config)?;
+        }
+        position.last_update_ts = current_ts;
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+        // 按破产价格结算已实现盈亏，释放对应保证金
+        cpi::close_position_settle(
+            &ledger_config.vault_program,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
+            margin_released,
+            realized_pnl,
+            0,
+            signer_seeds,
+        )?;
+
+        adl_state.record_reduction(covered, close_size);
+        remaining = remaining.saturating_sub(covered);
+        total_covered = total_covered.saturating_add(covered);
+        total_reduced = total_reduced.saturating_add(close_size);
+        target_count += 1;
+
+        let trade_seq = ledger_config.next_sequence();
+        let position_seq = ledger_config.next_sequence();
+
+        emit_trade_event(&TradeEvent {
+            discriminator: event_discriminator::TRADE,
+            sequence: trade_seq,
+            timestamp: current_ts,
+            batch_id: 0,
+            market_index,
+            market_type: 0,
+            trade_type: events::TradeType::ADL as u8,
+            maker: position.user,
+            maker_order_id: [0u8; 16],
+            maker_side: side_u8,
+            maker_fee_e6: 0,
+            taker: adl_state.bankrupt_user,
+            taker_order_id: [0u8; 16],
+            taker_side: bankrupt_side_u8,
+            taker_fee_e6: 0,
+            price_e6: bankruptcy_price_e6,
+            size_e6: close_size,
+            notional_e6: (close_size as u128 * bankruptcy_price_e6 as u128 / 1_000_000) as u64,
+            maker_realized_pnl_e6: realized_pnl,
+            taker_realized_pnl_e6: 0,
+            maker_margin_delta_e6: -(margin_released as i64),
+            taker_margin_delta_e6: 0,
+        });
+
+        emit_position_event(&PositionEvent {
+            discriminator: event_discriminator::POSITION,
+            sequence: position_seq,
+            timestamp: current_ts,
+            user: position.user,
+            market_index,
+            event_type: events::PositionEventType::ADLed as u8,
+            side_before: side_u8,
+            size_before_e6: size_before,
+            entry_price_before_e6: entry_before,
+            margin_before_e6: margin_before,
+            side_after: side_u8,
+            size_after_e6: position.size_e6,
+            entry_price_after_e6: position.entry_price_e6,
+            margin_after_e6: position.margin_e6,
+            size_delta_e6: -(close_size as i64),
+            realized_pnl_e6: realized_pnl,
+            fee_e6: 0,
+            related_trade_sequence: trade_seq,
+        });
+
+        msg!(
+            "ADL target {}: reduced={}/{}, pnl={}, covered={}",
+            position.user, close_size, size_before, realized_pnl, covered
+        );
+    }
+
+    if target_count == 0 {
+        msg!("❌ No valid ADL targets found");
+        return Err(LedgerError::NoOpposingPositionsForADL.into());
+    }
+
+    // 让出的盈利 (含 lot_size 取整的超额部分) 从 Vault 转入保险基金，补足穿仓
+    cpi::transfer_to_insurance_fund(
+        &ledger_config.vault_program,
+        vault_config_info.clone(),
+        ledger_config_info.clone(),
+        cpi::InsuranceTransferAccounts {
+            vault_token_account: vault_token_account.clone(),
+            insurance_fund_vault: insurance_vault.clone(),
+            token_program: token_program.clone(),
+        },
+        total_covered,
+        signer_seeds,
+    )?;
+    cpi::add_adl_profit(
+        fund_program.key,
+        ledger_config_info.clone(),
+        insurance_fund_account.clone(),
+        insurance_config.clone(),
+        i64::try_from(total_covered).map_err(|_| LedgerError::Overflow)?,
+        signer_seeds,
+    )?;
+    let surplus = total_covered.saturating_sub(adl_required);
+    msg!("CPI: ADL proceeds {} transferred to insurance fund (surplus {})", total_covered, surplus);
+
+    shortfall_record.apply_coverage(total_covered);
+    shortfall_record.serialize(&mut &mut shortfall_record_info.data.borrow_mut()[..])?;
+    adl_state.serialize(&mut &mut adl_state_info.data.borrow_mut()[..])?;
+
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "ADL executed: market={}, required={}, covered={}, reduced={}, targets={}, remaining={}, shortfall_left={}",
+        market_index, adl_required, total_covered, total_reduced, target_count, remaining,
+        shortfall_record.shortfall_e6
+    );
+
+    Ok(())
+}
+
+/// 确保 ADLState 存在，不存在则由管理员付费创建
+fn ensure_adl_state_exists<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    adl_state_info: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    market_index: u8,
+) -> ProgramResult {
+    if adl_state_info.data_len() > 0 {
+        return Ok(());
+    }
+
+    let (adl_state_pda, bump) = Pubkey::find_program_address(
+        &[ADLState::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if adl_state_info.key != &adl_state_pda {
+        msg!("❌ Invalid ADLState PDA: expected {}, got {}", adl_state_pda, adl_state_info.key);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    let rent = Rent::get()?;
+    let space = ADLState::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            adl_state_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[payer.clone(), adl_state_info.clone(), system_program.clone()],
+        &[&[ADLState::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
+
+    let adl_state = ADLState {
+        discriminator: ADLState::DISCRIMINATOR,
+        market_index,
+        is_active: false,
+        shortfall_record: Pubkey::default(),
+        bankrupt_user: Pubkey::default(),
+        bankrupt_side: Side::Long,
+        bankrupt_size_e6: 0,
+        bankruptcy_price_e6: 0,
+        shortfall_e6: 0,
+        insurance_balance_before_e6: 0,
+        adl_required_e6: 0,
+        covered_e6: 0,
+        counterparty_size_reduced_e6: 0,
+        target_count: 0,
+        triggered_at: 0,
+        completed_at: 0,
+        completed_count: 0,
+        bump,
+        reserved: [0u8; 32],
+    };
+    adl_state.serialize(&mut &mut adl_state_info.data.borrow_mut()[..])?;
+    msg!("✅ ADLState created for market {}", market_index);
+
+    Ok(())
+}
+
+/// 完成 ADL: 穿仓已完全覆盖后恢复 LP 赎回
+///
+/// 账户顺序:
+/// 0. admin (signer)
+/// 1. ledger_config_info (writable)
+/// 2. fund_program
+/// 3. insurance_config (writable) - InsuranceFundConfig PDA
+/// 4. fund_vault - Insurance Fund Vault (读取完成后余额)
+/// 5. adl_state_info (writable) - ADLState PDA
+fn process_complete_adl(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let fund_program = next_account_info(account_info_iter)?;
+    let insurance_config = next_account_info(account_info_iter)?;
+    let fund_vault = next_account_info(account_info_iter)?;
+    let adl_state_info = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(insurance_config)?;
+    assert_writable(adl_state_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if fund_program.key != &ledger_config.fund_program {
+        msg!("❌ Invalid Fund Program: expected {}, got {}", ledger_config.fund_program, fund_program.key);
+        return Err(LedgerError::InvalidProgramId.into());
+    }
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    let current_ts = get_current_timestamp()?;
+    let mut adl_state = load_adl_state(program_id, adl_state_info, market_index)?;
+    if let Err(e) = adl_state.complete(current_ts) {
+        msg!(
+            "❌ Cannot complete ADL: required={}, covered={}",
+            adl_state.adl_required_e6,
+            adl_state.covered_e6
+        );
+        return Err(e.into());
+    }
+    adl_state.serialize(&mut &mut adl_state_info.data.borrow_mut()[..])?;
+
+    let (_, ledger_config_bump) = Pubkey::find_program_address(
+        &[b"ledger_config"],
+        program_id,
+    );
+    let bump_slice = [ledger_config_bump];
+    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
+
+    cpi::set_adl_in_progress(
+        fund_program.key,
+        ledger_config_info.clone(),
+        insurance_config.clone(),
+        false,
+        signer_seeds,
+    )?;
+    msg!("✅ CPI SetADLInProgress(false) - LP redemptions resumed");
+
+    let insurance_balance_after_e6 = read_insurance_fund_balance_from_vault(fund_vault)?;
+
+    ledger_config.last_update_ts = current_ts;
+    let sequence = ledger_config.next_sequence();
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    events::emit_adl_event(&events::ADLEvent {
+        discriminator: events::event_discriminator::ADL,
+        sequence,
+        timestamp: current_ts,
+        market_index,
+        trigger_reason: events::ADLTriggerReason::Bankruptcy as u8,
+        shortfall_e6: adl_state.shortfall_e6,
+        insurance_balance_before_e6: adl_state.insurance_balance_before_e6,
+        insurance_balance_after_e6,
+        bankrupt_user: adl_state.bankrupt_user,
+        bankrupt_side: adl_state.bankrupt_side as u8,
+        bankrupt_size_e6: adl_state.bankrupt_size_e6,
+        counterparty_user: Pubkey::default(),
+        counterparty_side: adl_state.bankrupt_side.opposite() as u8,
+        counterparty_size_reduced_e6: adl_state.counterparty_size_reduced_e6,
+        counterparty_pnl_e6: adl_state.covered_e6 as i64,
+        related_trade_sequence: 0,
+    });
+
+    msg!(
+        "ADL completed: market={}, shortfall={}, covered={}, reduced={}, targets={}",
+        market_index,
+        adl_state.shortfall_e6,
+        adl_state.covered_e6,
+        adl_state.counterparty_size_reduced_e6,
+        adl_state.target_count
+    );
+
+    Ok(())
+}
+
+/// NEW-1: InsuranceFundConfig 数据 (用于读取)
+struct InsuranceFundConfigData {
+    #[allow(dead_code)]
+    discriminator: u64,
+    #[allow(dead_code)]
+    fund: Pubkey,
+    #[allow(dead_code)]
+    bump: u8,
+    #[allow(dead_code)]
+    total_liquidation_income_e6: i64,
+    #[allow(dead_code)]
+    total_adl_profit_e6: i64,
+    #[allow(dead_code)]
+    total_shortfall_payout_e6: i64,
+    adl_trigger_threshold_e6: i64,
+    #[allow(dead_code)]
+    adl_trigger_count: u64,
+    #[allow(dead_code)]
+    balance_1h_ago_e6: i64,
+    #[allow(dead_code)]
+    last_snapshot_ts: i64,
+    #[allow(dead_code)]
+    withdrawal_delay_secs: i64,
+    #[allow(dead_code)]
+    is_adl_in_progress: bool,
+}
+
+/// NEW-1: 从 Fund Program 的 InsuranceFundConfig 读取配置
+fn read_insurance_fund_config(insurance_config: &AccountInfo) -> Result<InsuranceFundConfigData, ProgramError> {
+    let data = insurance_config.data.borrow();
+    
+    // InsuranceFundConfig 最小大小检查
+    // discriminator(8) + fund(32) + bump(1) + 6*i64(48) + u64(8) + i64(8) + i64(8) + bool(1) + pubkey(32) + i64(8)
+    // = 8 + 32 + 1 + 48 + 8 + 8 + 8 + 1 + 32 + 8 = 154 bytes minimum
+    if data.len() < 154 {
+        msg!("InsuranceFundConfig account too small: {}", data.len());
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    
+    // 读取 discriminator
+    let discriminator = u64::from_le_bytes(data[0..8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    
+    // 验证 discriminator (0x1024_1024_0004 for InsuranceFundConfig)
+    // 这里我们跳过严格验证，因为可能有不同的 discriminator 值
+    
+    let mut offset = 8;
+    
+    // fund: Pubkey (32 bytes)
+    let fund = Pubkey::try_from(&data[offset..offset+32]).map_err(|_| LedgerError::InvalidAccount)?;
+    offset += 32;
+    
+    // bump: u8 (1 byte)
+    let bump = data[offset];
+    offset += 1;
+    
+    // total_liquidation_income_e6: i64
+    let total_liquidation_income_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // total_adl_profit_e6: i64
+    let total_adl_profit_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // total_shortfall_payout_e6: i64
+    let total_shortfall_payout_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // adl_trigger_threshold_e6: i64
+    let adl_trigger_threshold_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // adl_trigger_count: u64
+    let adl_trigger_count = u64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // balance_1h_ago_e6: i64
+    let balance_1h_ago_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // last_snapshot_ts: i64
+    let last_snapshot_ts = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // withdrawal_delay_secs: i64
+    let withdrawal_delay_secs = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    offset += 8;
+    
+    // is_adl_in_progress: bool
+    let is_adl_in_progress = data[offset] != 0;
+    
+    Ok(InsuranceFundConfigData {
+        discriminator,
+        fund,
+        bump,
+        total_liquidation_income_e6,
+        total_adl_profit_e6,
+        total_shortfall_payout_e6,
+        adl_trigger_threshold_e6,
+        adl_trigger_count,
+        balance_1h_ago_e6,
+        last_snapshot_ts,
+        withdrawal_delay_secs,
+        is_adl_in_progress,
+    })
+}
+
+/// NEW-1: 从 Fund Vault (SPL Token Account) 读取实际余额
+fn read_insurance_fund_balance_from_vault(fund_vault: &AccountInfo) -> Result<i64, ProgramError> {
+    // SPL Token Account 结构:
+    // - mint: Pubkey (32 bytes)
+    // - owner: Pubkey (32 bytes)
+    // - amount: u64 (8 bytes) <- 我们需要这个
+    // - ...
+    
+    let data = fund_vault.data.borrow();
+    if data.len() < 72 {
+        msg!("Fund vault account too small: {}", data.len());
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    
+    // 读取 amount (offset 64)
+    let amount = u64::from_le_bytes(data[64..72].try_into().map_err(|_| LedgerError::InvalidAccount)?);
+    
+    // 转换为 i64 (安全，因为余额不会超过 i64::MAX)
+    Ok(amount as i64)
+}
+
+// ============================================================================
+// 资金费率处理
+// ============================================================================
+
+fn process_settle_funding(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
+    funding_rate_e6: i64,
+    index_price_e6: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let position_info = next_account_info(account_info_iter)?;
+    let user_account_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let user_stats_info = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+
+    assert_authorized_relayer(program_id, relayer, relayer_config_info)?;
+    assert_writable(position_info)?;
+    assert_writable(user_account_info)?;
+    assert_writable(ledger_config_info)?;
+    assert_writable(user_stats_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
+    }
+
+    // 读取仓位
+    let (position_pda, _) = Pubkey::find_program_address(
+        &[b"position", user.as_ref(), &[market_index]],
+        program_id,
+    );
+    if position_info.key != &position_pda || position_info.owner != program_id {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    if position.user != user || position.market_index != market_index {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+    if position.is_empty() {
+        return Err(LedgerError::PositionNotFound.into());
+    }
+    if cpi::read_user_account(user_account_info)?.wallet != position.user {
+        msg!("❌ UserAccount does not belong to {}", position.user);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    market_config.check_funding_rate(funding_rate_e6)?;
+    if index_price_e6 == 0 {
+        return Err(LedgerError::InvalidPrice.into());
+    }
+
+    let current_ts = get_current_timestamp()?;
+    check_published_funding_rate(program_id, premium_samples_info, &market_config, funding_rate_e6, index_price_e6, current_ts)?;
+    let mark_price_e6 = load_mark_price(program_id, mark_price_info, market_index)?.checked_price(current_ts)?;
+    let funding_index_e6 = load_applied_funding_index(program_id, funding_state_info, market_index, funding_rate_e6, index_price_e6)?;
+    let (actual_payment, settled, period_start) =
+        apply_position_funding(&mut position, &market_config, funding_index_e6, current_ts)?;
+    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+
+    // CPI: Vault 同步锁定保证金
+    if settled != 0 {
+        let (_, ledger_config_bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+        cpi::settle_funding(
+            vault_program.key,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
+            settled,
+            &[&[b"ledger_config", &[ledger_config_bump]]],
+        )?;
+    }
+
+    let sequence = ledger_config.next_sequence();
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    record_user_funding_paid(program_id, user_stats_info, &position.user, settled)?;
+
+    // Emit FundingEvent
+    let side_u8 = match position.side { Side::Long => 0u8, Side::Short => 1u8 };
+    events::emit_funding_event(&events::FundingEvent {
+        discriminator: events::event_discriminator::FUNDING,
+        sequence,
+        timestamp: current_ts,
+        user,
+        market_index,
+        side: side_u8,
+        position_size_e6: position.size_e6,
+        funding_rate_e9: funding_rate_e6 * 1000, // e6 to e9
+        payment_e6: actual_payment,
+        mark_price_e6,
+        period_start,
+        period_end: current_ts,
+    });
+
+    msg!(
+        "Funding settled: user={}, market={}, rate={}, payment={}, settled={}, margin={}",
+        user, market_index, funding_rate_e6, actual_payment, settled, position.margin_e6
+    );
+
+    Ok(())
+}
+
+/// SettleFunding / SettleFundingBatch 共用: 检查结算周期后结算单个仓位按 FundingState 指数计提的资金费并刷新清算价格
+///
+/// 资金费只通过累计指数计提 (与开/平仓、清算相同)，结算后 last_funding_index_e6 已推进，不会重复收取。
+/// 返回 (本次计提的资金费, 实际计入保证金的金额, 周期开始时间)，调用方负责写回仓位和 Vault CPI
+fn apply_position_funding(
+    position: &mut Position,
+    market_config: &MarketConfig,
+    funding_index_e6: i64,
+    current_ts: i64,
+) -> Result<(i64, i64, i64), ProgramError> {
+    // 同一仓位每个资金费周期只能结算一次
+    let period_start = position.last_funding_ts;
+    if let Err(e) = market_config.check_funding_due(period_start, current_ts) {
+        msg!(
+            "❌ Funding not due: user={}, last={}, interval={}s, now={}",
+            position.user, period_start, market_config.funding_interval_secs, current_ts
+        );
+        return Err(e.into());
+    }
+
+    // 计提到最新指数，连同此前计提未结算的部分全部取出并实时计入保证金
+    let payment = position.accrue_funding(funding_index_e6)?;
+    let pending = position.take_unsettled_funding(1_000_000)?;
+    let settled = position.settle_funding_to_margin(pending)?;
+    position.refresh_liquidation_price(market_config)?;
+    position.last_funding_ts = current_ts;
+    position.last_update_ts = current_ts;
+    if position.unsettled_funding_e6 > 0 {
+        msg!("⚠️ Funding exceeds margin for {}, {} left unsettled", position.user, position.unsettled_funding_e6);
+    }
+    Ok((payment, settled, period_start))
+}
+
+/// SettleFunding / SettleFundingBatch 共用: 读取 FundingState 累计指数
+///
+/// 指令中的费率和指数价格必须已由 UpdateFundingIndex 计入指数 (该处检查费率上限和周期)，
+/// 结算金额只来自指数，费率仅用于事件和汇总
+fn load_applied_funding_index(
+    program_id: &Pubkey,
+    funding_state_info: &AccountInfo,
+    market_index: u8,
+    funding_rate_e6: i64,
+    index_price_e6: u64,
+) -> Result<i64, ProgramError> {
+    let funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    if let Err(e) = funding_state.check_applied_rate(funding_rate_e6, index_price_e6) {
+        msg!(
+            "❌ Funding rate {} / index {} not applied to FundingState (last rate {} / index {})",
+            funding_rate_e6,
+            index_price_e6,
+            funding_state.last_funding_rate_e6,
+            funding_state.last_index_price_e6
+        );
+        return Err(e.into());
+    }
+    Ok(funding_state.cumulative_funding_index_e6)
+}
+
+/// 累加 UserStats.total_funding_paid_e6 (UserStats 未创建时跳过)
+///
+/// user_stats_info 必须是仓位用户的 UserStats PDA，否则返回 InvalidAccount
+fn record_user_funding_paid(
+    program_id: &Pubkey,
+    user_stats_info: &AccountInfo,
+    user: &Pubkey,
+    settled: i64,
+) -> ProgramResult {
+    let (user_stats_pda, _) = Pubkey::find_program_address(&[UserStats::SEED_PREFIX, user.as_ref()], program_id);
+    if user_stats_info.key != &user_stats_pda {
+        msg!("❌ Invalid UserStats PDA for {}", user);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if user_stats_info.owner == program_id && user_stats_info.data_len() > 0 {
+        let user_stats_result = {
+            let data = user_stats_info.data.borrow();
+            deserialize_account::<UserStats>(&data)
+        };
+        if let Ok(mut user_stats) = user_stats_result {
+            user_stats.total_funding_paid_e6 = checked_add(user_stats.total_funding_paid_e6, settled)?;
+            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
         }
     }
+    Ok(())
 }
 
 // ============================================================================
-// 初始化指令处理
+// 管理指令处理
 // ============================================================================
 
-fn process_initialize(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-) -> ProgramResult {
+fn process_add_relayer(accounts: &[AccountInfo], relayer: Pubkey) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let admin = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
-    let vault_program = next_account_info(account_info_iter)?;
-    let fund_program = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
 
     assert_signer(admin)?;
+    assert_writable(relayer_config_info)?;
 
-    // 派生 PDA
-    let (ledger_config_pda, bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
-    if ledger_config_info.key != &ledger_config_pda {
+    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+
+    if relayer_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    if relayer_config.authorized_relayers.len() >= MAX_RELAYERS {
         return Err(LedgerError::InvalidAccount.into());
     }
 
-    // 创建账户
-    let rent = Rent::get()?;
-    let space = LedgerConfig::SIZE;
-    let lamports = rent.minimum_balance(space);
+    if !relayer_config.authorized_relayers.contains(&relayer) {
+        relayer_config.authorized_relayers.push(relayer);
+        relayer_config.last_update_ts = get_current_timestamp()?;
+        relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+        msg!("Added relayer: {}", relayer);
+    }
 
-    invoke_signed(
-        &system_instruction::create_account(
-            admin.key,
-            ledger_config_info.key,
-            lamports,
-            space as u64,
-            program_id,
-        ),
-        &[admin.clone(), ledger_config_info.clone(), system_program.clone()],
-        &[&[b"ledger_config", &[bump]]],
-    )?;
+    Ok(())
+}
 
-    // 初始化数据
-    let current_ts = get_current_timestamp()?;
-    let ledger_config = LedgerConfig {
-        discriminator: LedgerConfig::DISCRIMINATOR,
-        admin: *admin.key,
-        vault_program: *vault_program.key,
-        fund_program: *fund_program.key,
-        global_sequence: 0,
-        total_positions_opened: 0,
-        total_positions_closed: 0,
-        total_volume_e6: 0,
-        total_fees_collected_e6: 0,
-        total_liquidations: 0,
-        total_adl_count: 0,
-        is_paused: false,
-        bump,
-        created_at: current_ts,
-        last_update_ts: current_ts,
-        reserved: [0u8; 65],
-    };
+fn process_remove_relayer(accounts: &[AccountInfo], relayer: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
 
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
-    msg!("LedgerConfig initialized by {}", admin.key);
-    msg!("Vault Program: {}", vault_program.key);
-    msg!("Fund Program: {}", fund_program.key);
+    assert_signer(admin)?;
+    assert_writable(relayer_config_info)?;
 
+    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+
+    if relayer_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    relayer_config.authorized_relayers.retain(|&r| r != relayer);
+    relayer_config.last_update_ts = get_current_timestamp()?;
+    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+
+    msg!("Removed relayer: {}", relayer);
     Ok(())
 }
 
-fn process_initialize_relayers(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    relayers: Vec<Pubkey>,
-    required_signatures: u8,
-) -> ProgramResult {
+fn process_update_required_signatures(accounts: &[AccountInfo], required_signatures: u8) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let admin = next_account_info(account_info_iter)?;
     let relayer_config_info = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
 
     assert_signer(admin)?;
+    assert_writable(relayer_config_info)?;
 
-    // 验证参数
-    if relayers.is_empty() || relayers.len() > MAX_RELAYERS {
-        return Err(LedgerError::InvalidAccount.into());
+    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+
+    if relayer_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
-    if required_signatures == 0 || required_signatures as usize > relayers.len() {
+
+    if required_signatures == 0 || required_signatures as usize > relayer_config.authorized_relayers.len() {
         return Err(LedgerError::InsufficientSignatures.into());
     }
 
-    // 派生 PDA
-    let (relayer_config_pda, bump) = Pubkey::find_program_address(&[b"relayer_config"], program_id);
-    if relayer_config_info.key != &relayer_config_pda {
-        return Err(LedgerError::InvalidAccount.into());
+    relayer_config.required_signatures = required_signatures;
+    relayer_config.last_update_ts = get_current_timestamp()?;
+    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+
+    msg!("Updated required signatures to: {}", required_signatures);
+    Ok(())
+}
+
+fn process_set_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    // 创建账户
-    let rent = Rent::get()?;
-    let space = RelayerConfig::SIZE;
-    let lamports = rent.minimum_balance(space);
+    ledger_config.is_paused = paused;
+    ledger_config.last_update_ts = get_current_timestamp()?;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
 
-    invoke_signed(
-        &system_instruction::create_account(
-            admin.key,
-            relayer_config_info.key,
-            lamports,
-            space as u64,
-            program_id,
-        ),
-        &[admin.clone(), relayer_config_info.clone(), system_program.clone()],
-        &[&[b"relayer_config", &[bump]]],
-    )?;
+    msg!("Ledger {}", if paused { "paused" } else { "resumed" });
+    Ok(())
+}
 
-    // 初始化数据
-    let relayer_config = RelayerConfig {
-        discriminator: RelayerConfig::DISCRIMINATOR,
-        admin: *admin.key,
-        authorized_relayers: relayers.clone(),
-        required_signatures,
-        bump,
-        last_update_ts: get_current_timestamp()?,
-    };
+fn process_update_admin(program_id: &Pubkey, accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let current_admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
-    msg!("RelayerConfig initialized with {} relayers, {} required", relayers.len(), required_signatures);
+    assert_signer(current_admin)?;
+    assert_writable(ledger_config_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    if ledger_config.admin != *current_admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    ledger_config.admin = new_admin;
+    ledger_config.last_update_ts = get_current_timestamp()?;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
 
+    msg!("Admin updated to: {}", new_admin);
     Ok(())
 }
 
-fn process_initialize_user_stats(
+fn process_update_vault_program(program_id: &Pubkey, accounts: &[AccountInfo], new_vault_program: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    ledger_config.vault_program = new_vault_program;
+    ledger_config.last_update_ts = get_current_timestamp()?;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    msg!("Vault program updated to: {}", new_vault_program);
+    Ok(())
+}
+
+fn process_update_fund_program(program_id: &Pubkey, accounts: &[AccountInfo], new_fund_program: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(ledger_config_info)?;
+
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    ledger_config.fund_program = new_fund_program;
+    ledger_config.last_update_ts = get_current_timestamp()?;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    msg!("Fund program updated to: {}", new_fund_program);
+    Ok(())
+}
+
+// ============================================================================
+// Admin 工具指令
+// ============================================================================
+
+/// 🔧 Admin 重置 Position（仅测试网使用）
+/// 
+/// 将 Position 的 size 和其他字段重置为 0，用于清理累积的测试仓位
+fn process_admin_reset_position(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let user = next_account_info(account_info_iter)?;
-    let user_stats_info = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
+    let admin = next_account_info(account_info_iter)?;
+    let position_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    assert_signer(user)?;
+    assert_signer(admin)?;
+    assert_writable(position_info)?;
 
-    // 派生 PDA
-    let (user_stats_pda, bump) = Pubkey::find_program_address(
-        &[b"user_stats", user.key.as_ref()],
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        msg!("❌ Invalid admin: expected {}, got {}", ledger_config.admin, admin.key);
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    // 验证 Position PDA
+    let (position_pda, _) = Pubkey::find_program_address(
+        &[b"position", user.as_ref(), &[market_index]],
         program_id,
     );
-    if user_stats_info.key != &user_stats_pda {
+    if position_info.key != &position_pda {
+        msg!("❌ Invalid Position PDA");
         return Err(LedgerError::InvalidAccount.into());
     }
 
-    // 创建账户
-    let rent = Rent::get()?;
-    let space = UserStats::SIZE;
-    let lamports = rent.minimum_balance(space);
-
-    invoke_signed(
-        &system_instruction::create_account(
-            user.key,
-            user_stats_info.key,
-            lamports,
-            space as u64,
-            program_id,
-        ),
-        &[user.clone(), user_stats_info.clone(), system_program.clone()],
-        &[&[b"user_stats", user.key.as_ref(), &[bump]]],
-    )?;
+    // 读取并重置 Position
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    
+    let reset_pre_side = position.side as u8;
+    let reset_pre_size = position.size_e6;
+    let reset_pre_entry = position.entry_price_e6;
+    let reset_pre_margin = position.margin_e6;
 
-    // 初始化数据
-    let user_stats = UserStats {
-        discriminator: UserStats::DISCRIMINATOR,
-        user: *user.key,
-        total_trades: 0,
-        total_volume_e6: 0,
-        total_realized_pnl_e6: 0,
-        total_fees_paid_e6: 0,
-        total_funding_paid_e6: 0,
-        total_liquidations: 0,
-        first_trade_at: 0,
-        last_trade_at: 0,
-        bump,
-    };
+    msg!("🔧 Resetting Position: user={}, market={}, current_size={}", 
+         user, market_index, position.size_e6);
+    
+    // 重置所有字段
+    position.size_e6 = 0;
+    position.margin_e6 = 0;
+    position.entry_price_e6 = 0;
+    position.liquidation_price_e6 = 0;
+    position.unrealized_pnl_e6 = 0;
+    position.unsettled_funding_e6 = 0;
+    let reset_ts = get_current_timestamp()?;
+    position.last_update_ts = reset_ts;
+    
+    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
 
-    user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
-    msg!("UserStats initialized for {}", user.key);
+    emit_position_event(&PositionEvent {
+        discriminator: event_discriminator::POSITION,
+        sequence: 0,
+        timestamp: reset_ts,
+        user,
+        market_index,
+        event_type: 5,
+        side_before: reset_pre_side,
+        size_before_e6: reset_pre_size,
+        entry_price_before_e6: reset_pre_entry,
+        margin_before_e6: reset_pre_margin,
+        side_after: reset_pre_side,
+        size_after_e6: 0,
+        entry_price_after_e6: 0,
+        margin_after_e6: 0,
+        size_delta_e6: -(reset_pre_size as i64),
+        realized_pnl_e6: 0,
+        fee_e6: 0,
+        related_trade_sequence: 0,
+    });
 
+    msg!("✅ Position reset to zero");
     Ok(())
 }
 
 // ============================================================================
-// 辅助函数：自动创建 UserStats
+// Spot 交易指令处理
 // ============================================================================
 
-/// 确保 UserStats 账户存在，如果不存在则自动创建
-/// 
-/// 返回: Ok(true) 如果创建了新账户，Ok(false) 如果已存在
-fn ensure_user_stats_exists<'a>(
+use crate::state::{SpotTradeRecord, SpotSide, spot_fee_type};
+use crate::instruction::SpotTradeData;
+
+/// 记录单笔 Spot 成交
+fn process_record_spot_trade(
     program_id: &Pubkey,
-    payer: &AccountInfo<'a>,
-    user_wallet: &Pubkey,
-    user_stats_info: &AccountInfo<'a>,
-    system_program: &AccountInfo<'a>,
-) -> Result<bool, ProgramError> {
-    // 检查账户是否已存在
-    let is_empty = user_stats_info.data_len() == 0 || {
-        let data = user_stats_info.data.borrow();
-        data.iter().all(|&x| x == 0)
-    };
-    
-    if !is_empty {
-        // 账户已存在，验证 discriminator
-        let data = user_stats_info.data.borrow();
-        if data.len() >= 8 && &data[0..8] == UserStats::DISCRIMINATOR.as_slice() {
-            return Ok(false); // 已存在，无需创建
-        }
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u16,
+    is_buy: bool,
+    base_amount_e6: u64,
+    quote_amount_e6: u64,
+    price_e6: u64,
+    fee_e6: u64,
+    is_taker: bool,
+    batch_id: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let spot_trade_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(relayer)?;
+    assert_writable(spot_trade_info)?;
+    assert_writable(ledger_config_info)?;
+
+    // 验证 Relayer 授权
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(relayer.key) {
+        msg!("❌ Unauthorized relayer: {}", relayer.key);
+        return Err(LedgerError::UnauthorizedRelayer.into());
     }
-    
-    // 验证 PDA
-    let (user_stats_pda, bump) = Pubkey::find_program_address(
-        &[b"user_stats", user_wallet.as_ref()],
+
+    // 获取下一个序列号
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    let sequence = ledger_config.next_sequence();
+
+    // 派生 SpotTradeRecord PDA
+    let (spot_trade_pda, spot_trade_bump) = Pubkey::find_program_address(
+        &[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes()],
         program_id,
     );
-    if user_stats_info.key != &user_stats_pda {
-        msg!("❌ Invalid UserStats PDA: expected {}, got {}", user_stats_pda, user_stats_info.key);
+
+    if spot_trade_info.key != &spot_trade_pda {
+        msg!("❌ Invalid SpotTradeRecord PDA");
         return Err(LedgerError::InvalidAccount.into());
     }
-    
+
     // 创建账户
     let rent = Rent::get()?;
-    let space = UserStats::SIZE;
+    let space = SpotTradeRecord::SIZE;
     let lamports = rent.minimum_balance(space);
-    
-    msg!("✨ Auto-creating UserStats for user {}", user_wallet);
-    
+
     invoke_signed(
         &system_instruction::create_account(
-            payer.key,
-            user_stats_info.key,
+            relayer.key,
+            spot_trade_info.key,
             lamports,
             space as u64,
             program_id,
         ),
-        &[payer.clone(), user_stats_info.clone(), system_program.clone()],
-        &[&[b"user_stats", user_wallet.as_ref(), &[bump]]],
+        &[relayer.clone(), spot_trade_info.clone(), system_program.clone()],
+        &[&[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes(), &[spot_trade_bump]]],
     )?;
-    
-    // 初始化数据
-    let user_stats = UserStats {
-        discriminator: UserStats::DISCRIMINATOR,
-        user: *user_wallet,
-        total_trades: 0,
-        total_volume_e6: 0,
-        total_realized_pnl_e6: 0,
-        total_fees_paid_e6: 0,
-        total_funding_paid_e6: 0,
-        total_liquidations: 0,
-        first_trade_at: 0,
-        last_trade_at: 0,
-        bump,
+
+    // 初始化 SpotTradeRecord
+    let current_ts = get_current_timestamp()?;
+    let spot_trade = SpotTradeRecord {
+        discriminator: SpotTradeRecord::DISCRIMINATOR,
+        sequence,
+        user,
+        market_index,
+        side: if is_buy { SpotSide::Buy } else { SpotSide::Sell },
+        base_amount_e6,
+        quote_amount_e6,
+        price_e6,
+        fee_e6,
+        fee_type: if is_taker { spot_fee_type::TAKER } else { spot_fee_type::MAKER },
+        timestamp: current_ts,
+        batch_id,
+        bump: spot_trade_bump,
+        reserved: [0u8; 32],
     };
-    
-    user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
-    msg!("✅ UserStats auto-created for {}", user_wallet);
-    
-    Ok(true) // 新创建
-}
 
-// ============================================================================
-// 多签指令处理
-// ============================================================================
+    spot_trade.serialize(&mut &mut spot_trade_info.data.borrow_mut()[..])?;
+
+    // 更新 LedgerConfig 统计
+    ledger_config.total_volume_e6 = ledger_config.total_volume_e6.saturating_add(quote_amount_e6);
+    ledger_config.total_fees_collected_e6 = ledger_config.total_fees_collected_e6.saturating_add(fee_e6);
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    msg!("✅ SpotTradeRecord created: seq={}, user={}, market={}, side={}, base={}, quote={}, fee={}",
+         sequence, user, market_index, if is_buy { "Buy" } else { "Sell" },
+         base_amount_e6, quote_amount_e6, fee_e6);
+
+    emit_trade_event(&TradeEvent {
+        discriminator: event_discriminator::TRADE,
+        sequence,
+        timestamp: current_ts,
+        batch_id,
+        market_index: market_index as u8,
+        market_type: 1,
+        trade_type: 0,
+        maker: Pubkey::default(),
+        maker_order_id: [0u8; 16],
+        maker_side: 0,
+        maker_fee_e6: 0,
+        taker: user,
+        taker_order_id: [0u8; 16],
+        taker_side: if is_buy { 0 } else { 1 },
+        taker_fee_e6: fee_e6 as i64,
+        price_e6,
+        size_e6: base_amount_e6,
+        notional_e6: quote_amount_e6,
+        maker_realized_pnl_e6: 0,
+        taker_realized_pnl_e6: 0,
+        maker_margin_delta_e6: 0,
+        taker_margin_delta_e6: 0,
+    });
+
+    Ok(())
+}
 
-fn process_submit_trade_batch(
+/// 批量记录 Spot 成交
+fn process_batch_record_spot_trades(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
+    trades: Vec<SpotTradeData>,
     batch_id: u64,
-    data_hash: [u8; 32],
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let relayer = next_account_info(account_info_iter)?;
-    let trade_batch_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
     let relayer_config_info = next_account_info(account_info_iter)?;
     let system_program = next_account_info(account_info_iter)?;
 
     assert_signer(relayer)?;
+    assert_writable(ledger_config_info)?;
 
     // 验证 Relayer 授权
     let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
@@ -492,2343 +5177,3467 @@ fn process_submit_trade_batch(
         return Err(LedgerError::UnauthorizedRelayer.into());
     }
 
-    // 派生 TradeBatch PDA
-    let (trade_batch_pda, bump) = Pubkey::find_program_address(
-        &[b"trade_batch", &batch_id.to_le_bytes()],
-        program_id,
-    );
-    if trade_batch_info.key != &trade_batch_pda {
-        return Err(LedgerError::InvalidAccount.into());
-    }
-
-    // 创建账户
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    let current_ts = get_current_timestamp()?;
     let rent = Rent::get()?;
-    let space = TradeBatch::SIZE;
+    let space = SpotTradeRecord::SIZE;
     let lamports = rent.minimum_balance(space);
-    let current_ts = get_current_timestamp()?;
 
-    invoke_signed(
-        &system_instruction::create_account(
-            relayer.key,
-            trade_batch_info.key,
-            lamports,
-            space as u64,
+    for trade in trades.iter() {
+        let spot_trade_info = next_account_info(account_info_iter)?;
+        assert_writable(spot_trade_info)?;
+
+        let sequence = ledger_config.next_sequence();
+
+        // 派生 PDA
+        let (spot_trade_pda, spot_trade_bump) = Pubkey::find_program_address(
+            &[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes()],
             program_id,
-        ),
-        &[relayer.clone(), trade_batch_info.clone(), system_program.clone()],
-        &[&[b"trade_batch", &batch_id.to_le_bytes(), &[bump]]],
-    )?;
+        );
 
-    // 初始化 TradeBatch
-    let mut trade_batch = TradeBatch {
-        discriminator: TradeBatch::DISCRIMINATOR,
-        batch_id,
-        data_hash,
-        signatures: vec![],
-        executed: false,
-        created_at: current_ts,
-        expires_at: current_ts + TRADE_BATCH_EXPIRY_SECONDS,
-        creator: *relayer.key,
-        bump,
-    };
+        if spot_trade_info.key != &spot_trade_pda {
+            msg!("❌ Invalid SpotTradeRecord PDA for sequence {}", sequence);
+            return Err(LedgerError::InvalidAccount.into());
+        }
 
-    // 添加第一个签名
-    trade_batch.add_signature(*relayer.key, current_ts)?;
-    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
+        // 创建账户
+        invoke_signed(
+            &system_instruction::create_account(
+                relayer.key,
+                spot_trade_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[relayer.clone(), spot_trade_info.clone(), system_program.clone()],
+            &[&[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes(), &[spot_trade_bump]]],
+        )?;
 
-    // Emit BatchEvent (Submitted)
-    events::emit_batch_event(&events::BatchEvent {
-        discriminator: events::event_discriminator::BATCH,
-        batch_id,
-        timestamp: current_ts,
-        event_type: events::BatchStatus::Submitted as u8,
-        trade_count: 0,
-        total_notional_e6: 0,
-        relayer: *relayer.key,
-        data_hash,
-        chain_tx: [0u8; 64],
-        error_code: 0,
-    });
+        // 初始化
+        let spot_trade = SpotTradeRecord {
+            discriminator: SpotTradeRecord::DISCRIMINATOR,
+            sequence,
+            user: trade.user,
+            market_index: trade.market_index,
+            side: if trade.is_buy { SpotSide::Buy } else { SpotSide::Sell },
+            base_amount_e6: trade.base_amount_e6,
+            quote_amount_e6: trade.quote_amount_e6,
+            price_e6: trade.price_e6,
+            fee_e6: trade.fee_e6,
+            fee_type: if trade.is_taker { spot_fee_type::TAKER } else { spot_fee_type::MAKER },
+            timestamp: current_ts,
+            batch_id,
+            bump: spot_trade_bump,
+            reserved: [0u8; 32],
+        };
 
-    msg!("TradeBatch {} submitted by {}", batch_id, relayer.key);
+        spot_trade.serialize(&mut &mut spot_trade_info.data.borrow_mut()[..])?;
+
+        emit_trade_event(&TradeEvent {
+            discriminator: event_discriminator::TRADE,
+            sequence,
+            timestamp: current_ts,
+            batch_id,
+            market_index: trade.market_index as u8,
+            market_type: 1,
+            trade_type: 0,
+            maker: Pubkey::default(),
+            maker_order_id: [0u8; 16],
+            maker_side: 0,
+            maker_fee_e6: 0,
+            taker: trade.user,
+            taker_order_id: [0u8; 16],
+            taker_side: if trade.is_buy { 0 } else { 1 },
+            taker_fee_e6: trade.fee_e6 as i64,
+            price_e6: trade.price_e6,
+            size_e6: trade.base_amount_e6,
+            notional_e6: trade.quote_amount_e6,
+            maker_realized_pnl_e6: 0,
+            taker_realized_pnl_e6: 0,
+            maker_margin_delta_e6: 0,
+            taker_margin_delta_e6: 0,
+        });
+
+        // 累加统计
+        ledger_config.total_volume_e6 = ledger_config.total_volume_e6.saturating_add(trade.quote_amount_e6);
+        ledger_config.total_fees_collected_e6 = ledger_config.total_fees_collected_e6.saturating_add(trade.fee_e6);
+    }
+
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+
+    msg!("✅ BatchRecordSpotTrades: {} trades recorded, batch_id={}", trades.len(), batch_id);
     Ok(())
 }
 
-fn process_confirm_trade_batch(
-    program_id: &Pubkey,
+// ============================================================================
+// 订单事件存证
+// ============================================================================
+
+fn process_record_order_events(
+    _program_id: &Pubkey,
     accounts: &[AccountInfo],
-    batch_id: u64,
-    data_hash: [u8; 32],
+    events: Vec<OrderEventInput>,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let relayer = next_account_info(account_info_iter)?;
-    let trade_batch_info = next_account_info(account_info_iter)?;
+    let _ledger_config_info = next_account_info(account_info_iter)?;
     let relayer_config_info = next_account_info(account_info_iter)?;
 
     assert_signer(relayer)?;
-    assert_writable(trade_batch_info)?;
 
-    // 验证 Relayer 授权
     let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
     if !relayer_config.is_authorized(relayer.key) {
+        msg!("❌ Unauthorized relayer for RecordOrderEvents: {}", relayer.key);
         return Err(LedgerError::UnauthorizedRelayer.into());
     }
 
-    // 验证 TradeBatch PDA
-    let (trade_batch_pda, _) = Pubkey::find_program_address(
-        &[b"trade_batch", &batch_id.to_le_bytes()],
-        program_id,
-    );
-    if trade_batch_info.key != &trade_batch_pda {
-        return Err(LedgerError::InvalidAccount.into());
-    }
-
-    let mut trade_batch = deserialize_account::<TradeBatch>(&trade_batch_info.data.borrow())?;
-
-    // 验证状态
-    let current_ts = get_current_timestamp()?;
-    if trade_batch.is_expired(current_ts) {
-        return Err(LedgerError::TradeBatchExpired.into());
-    }
-    if trade_batch.executed {
-        return Err(LedgerError::TradeBatchAlreadyExecuted.into());
-    }
-
-    // 验证数据哈希
-    if trade_batch.data_hash != data_hash {
-        return Err(LedgerError::InvalidDataHash.into());
+    for input in &events {
+        let order_event = OrderEvent {
+            discriminator: event_discriminator::ORDER,
+            sequence: 0,
+            timestamp: input.timestamp,
+            order_id: input.order_id,
+            client_order_id: input.client_order_id,
+            user: input.user,
+            market_index: input.market_index,
+            market_type: input.market_type,
+            side: input.side,
+            order_type: input.order_type,
+            time_in_force: 0,
+            reduce_only: false,
+            post_only: false,
+            price_e6: input.price_e6,
+            size_e6: input.size_e6,
+            filled_size_e6: 0,
+            remaining_size_e6: input.size_e6,
+            trigger_price_e6: 0,
+            avg_fill_price_e6: 0,
+            status: input.status,
+            status_reason: input.status_reason,
+        };
+        emit_order_event(&order_event);
     }
 
-    // 添加签名
-    trade_batch.add_signature(*relayer.key, current_ts)?;
-    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
-
-    emit_batch_event(&BatchEvent {
-        discriminator: event_discriminator::BATCH,
-        batch_id,
-        timestamp: current_ts,
-        event_type: 1,
-        trade_count: 0,
-        total_notional_e6: 0,
-        relayer: *relayer.key,
-        data_hash,
-        chain_tx: [0u8; 64],
-        error_code: 0,
-    });
-
-    msg!(
-        "TradeBatch {} confirmed by {}, signatures: {}",
-        batch_id,
-        relayer.key,
-        trade_batch.signature_count()
-    );
+    msg!("✅ RecordOrderEvents: {} events emitted", events.len());
     Ok(())
-}
-
-/// ExecuteTradeBatch 账户布局:
-/// 0. `[signer]` Relayer
-/// 1. `[writable]` TradeBatch PDA
-/// 2. `[]` RelayerConfig
-/// 3. `[writable]` LedgerConfig
-/// 4. `[]` VaultConfig
-/// 5. `[]` Vault Program
-/// 6. `[]` Ledger Program (self, for CPI caller verification)
-/// 7. `[]` System Program
-/// 8. `[writable]` Insurance Fund (for close positions - optional, can be SystemProgram if no closes)
-/// 
-/// 然后是每笔交易的账户 (每笔交易 3 个账户):
-/// For trade i:
-///   9 + i*3 + 0: `[writable]` Position PDA
-///   9 + i*3 + 1: `[writable]` UserAccount (Vault)
-///   9 + i*3 + 2: `[writable]` UserStats PDA
-fn process_execute_trade_batch(
-    program_id: &Pubkey,
+}
+
+// ============================================================================
+// 资金费率事件存证
+// ============================================================================
+
+fn process_record_funding_events(
+    _program_id: &Pubkey,
     accounts: &[AccountInfo],
-    batch_id: u64,
-    trades: Vec<TradeData>,
+    events: Vec<FundingEventInput>,
 ) -> ProgramResult {
-    // 解析共享账户
     let account_info_iter = &mut accounts.iter();
     let relayer = next_account_info(account_info_iter)?;
-    let trade_batch_info = next_account_info(account_info_iter)?;
+    let _ledger_config_info = next_account_info(account_info_iter)?;
     let relayer_config_info = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
-    let vault_config_info = next_account_info(account_info_iter)?;
-    let vault_program = next_account_info(account_info_iter)?;
-    let ledger_program_info = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
-    let insurance_fund_info = next_account_info(account_info_iter)?;
 
     assert_signer(relayer)?;
-    assert_writable(trade_batch_info)?;
-    assert_writable(ledger_config_info)?;
-
-    // 验证 Ledger Program 地址
-    if ledger_program_info.key != program_id {
-        return Err(LedgerError::InvalidProgramId.into());
-    }
 
-    // 验证 Relayer 授权
     let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
     if !relayer_config.is_authorized(relayer.key) {
+        msg!("❌ Unauthorized relayer for RecordFundingEvents: {}", relayer.key);
         return Err(LedgerError::UnauthorizedRelayer.into());
     }
 
-    // 验证 TradeBatch
-    let mut trade_batch = deserialize_account::<TradeBatch>(&trade_batch_info.data.borrow())?;
-    let current_ts = get_current_timestamp()?;
-
-    if trade_batch.is_expired(current_ts) {
-        return Err(LedgerError::TradeBatchExpired.into());
-    }
-    if trade_batch.executed {
-        return Err(LedgerError::TradeBatchAlreadyExecuted.into());
-    }
-    if !relayer_config.has_enough_signatures(trade_batch.signature_count()) {
-        return Err(LedgerError::InsufficientSignatures.into());
+    for input in &events {
+        msg!(
+            "EVENT:FundingSettlement:market={},rate={},price={},accounts={},total_paid={},epoch={},ts={}",
+            input.market_index,
+            input.funding_rate_e6,
+            input.index_price_e6,
+            input.accounts_settled,
+            input.total_funding_paid_e6,
+            input.epoch,
+            input.timestamp,
+        );
     }
 
-    // 验证数据哈希 (使用 batch_id 防止重放攻击)
-    let trades_data = trades.try_to_vec()?;
-    if !verify_batch_hash(program_id, batch_id, &trades_data, &trade_batch.data_hash) {
-        return Err(LedgerError::InvalidDataHash.into());
-    }
+    msg!("✅ RecordFundingEvents: {} events emitted", events.len());
+    Ok(())
+}
 
-    // 标记已执行
-    trade_batch.executed = true;
-    trade_batch.serialize(&mut &mut trade_batch_info.data.borrow_mut()[..])?;
+// ============================================================================
+// 市场配置指令处理
+// ============================================================================
 
-    // 读取 LedgerConfig
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-    
-    if ledger_config.is_paused {
-        return Err(LedgerError::LedgerPaused.into());
-    }
+fn process_create_market_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    params: MarketConfigParams,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
-    // 验证 Vault Program
-    if vault_program.key != &ledger_config.vault_program {
-        return Err(LedgerError::InvalidVaultProgram.into());
-    }
+    assert_signer(admin)?;
+    assert_writable(market_config_info)?;
 
-    // 收集剩余账户 (每笔交易的账户)
-    let remaining_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect();
-    
-    // 验证账户数量
-    let expected_accounts = trades.len() * 3; // 每笔交易 3 个账户
-    if remaining_accounts.len() < expected_accounts {
-        msg!(
-            "❌ Insufficient accounts: expected {} for {} trades, got {}",
-            expected_accounts,
-            trades.len(),
-            remaining_accounts.len()
-        );
-        return Err(LedgerError::InsufficientAccounts.into());
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    // 派生 Ledger Config PDA bump 用于 CPI
-    let (_, ledger_config_bump) = Pubkey::find_program_address(
-        &[b"ledger_config"],
+    // 派生 PDA
+    let (market_config_pda, bump) = Pubkey::find_program_address(
+        &[MarketConfig::SEED_PREFIX, &[market_index]],
         program_id,
     );
+    if market_config_info.key != &market_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if market_config_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
+    }
 
-    msg!("📦 ExecuteTradeBatch: batch_id={}, trades={}", batch_id, trades.len());
-
-    // 执行每笔交易
-    for (i, trade) in trades.iter().enumerate() {
-        let sequence = ledger_config.next_sequence();
-        
-        // 获取此交易的账户
-        let base_idx = i * 3;
-        let position_info = &remaining_accounts[base_idx];
-        let user_account_info = &remaining_accounts[base_idx + 1];
-        let user_stats_info = &remaining_accounts[base_idx + 2];
-
-        // 验证 Position PDA
-        let (expected_position_pda, position_bump) = Pubkey::find_program_address(
-            &[b"position", trade.user.as_ref(), &[trade.market_index]],
-            program_id,
-        );
-        if position_info.key != &expected_position_pda {
-            msg!("❌ Trade {}: Invalid position PDA", i);
-            return Err(LedgerError::InvalidAccount.into());
-        }
-
-        match trade.trade_type {
-            trade_data_type::OPEN => {
-                msg!(
-                    "🔵 Trade {} OPEN: user={}, market={}, side={:?}, size={}, price={}, leverage={}",
-                    i, trade.user, trade.market_index, trade.side, trade.size_e6, trade.price_e6, trade.leverage
-                );
-
-                // 验证参数
-                if trade.size_e6 == 0 {
-                    return Err(LedgerError::InvalidTradeAmount.into());
-                }
-                if trade.price_e6 == 0 {
-                    return Err(LedgerError::InvalidPrice.into());
-                }
-                if trade.leverage == 0 || trade.leverage > MAX_LEVERAGE {
-                    return Err(LedgerError::InvalidLeverage.into());
-                }
-
-                // 计算所需保证金和手续费
-                let required_margin = cpi::calculate_required_margin(trade.size_e6, trade.price_e6, trade.leverage)?;
-                if trade.fee_rate_e6 > 10_000 {
-                    return Err(LedgerError::InvalidFeeRate.into());
-                }
-                let fee = cpi::calculate_fee(trade.size_e6, trade.price_e6, trade.fee_rate_e6)?;
-
-                // 检查是否是新仓位
-                let is_new_position = position_info.data_len() == 0 || {
-                    let data = position_info.data.borrow();
-                    data.iter().all(|&x| x == 0)
-                };
-
-                if is_new_position {
-                    // 创建新仓位
-                    let rent = Rent::get()?;
-                    let space = Position::SIZE;
-                    let lamports = rent.minimum_balance(space);
-
-                    invoke_signed(
-                        &system_instruction::create_account(
-                            relayer.key,
-                            position_info.key,
-                            lamports,
-                            space as u64,
-                            program_id,
-                        ),
-                        &[relayer.clone(), position_info.clone(), system_program.clone()],
-                        &[&[b"position", trade.user.as_ref(), &[trade.market_index], &[position_bump]]],
-                    )?;
-
-                    let mut position = Position {
-                        discriminator: Position::DISCRIMINATOR,
-                        user: trade.user,
-                        market_index: trade.market_index,
-                        side: trade.side.clone(),
-                        size_e6: trade.size_e6,
-                        entry_price_e6: trade.price_e6,
-                        margin_e6: required_margin,
-                        leverage: trade.leverage,
-                        liquidation_price_e6: 0,
-                        unrealized_pnl_e6: 0,
-                        last_funding_ts: current_ts,
-                        cumulative_funding_e6: 0,
-                        open_order_count: 0,
-                        opened_at: current_ts,
-                        last_update_ts: current_ts,
-                        bump: position_bump,
-                        reserved: [0; 32],
-                    };
-                    position.liquidation_price_e6 = position.calculate_liquidation_price()?;
-                    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
-
-                    msg!("  ✅ New position created");
-                } else {
-                    // 加仓
-                    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-                    if position.side != trade.side {
-                        msg!("❌ Trade {}: Side mismatch (existing: {:?}, new: {:?})", i, position.side, trade.side);
-                        return Err(LedgerError::InvalidPositionSide.into());
-                    }
-                    position.update_entry_price(trade.size_e6, trade.price_e6)?;
-                    position.margin_e6 = checked_add_u64(position.margin_e6, required_margin)?;
-                    position.last_update_ts = current_ts;
-                    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
-
-                    msg!("  ✅ Position increased");
-                }
-
-                // CPI: 锁定保证金 (使用 LedgerConfig PDA 作为 caller)
-                let total_to_lock = checked_add_u64(required_margin, fee)?;
-                cpi::lock_margin(
-                    vault_program.key,
-                    vault_config_info.clone(),
-                    user_account_info.clone(),
-                    ledger_config_info.clone(),  // 使用 LedgerConfig PDA 作为 caller
-                    total_to_lock,
-                    &[&[b"ledger_config", &[ledger_config_bump]]],  // PDA 签名
-                )?;
-                msg!("  ✅ Margin locked: {} (margin) + {} (fee)", required_margin, fee);
-
-                // 更新统计
-                ledger_config.total_positions_opened += 1;
-                ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
-
-                // Emit PositionEvent (OPEN)
-                let side_u8 = match trade.side { Side::Long => 0u8, Side::Short => 1u8 };
-                events::emit_position_event(&events::PositionEvent {
-                    discriminator: events::event_discriminator::POSITION,
-                    sequence,
-                    timestamp: current_ts,
-                    user: trade.user,
-                    market_index: trade.market_index,
-                    event_type: if is_new_position {
-                        events::PositionEventType::Opened as u8
-                    } else {
-                        events::PositionEventType::Increased as u8
-                    },
-                    side_before: side_u8,
-                    size_before_e6: if is_new_position { 0 } else { trade.size_e6 }, // approximate
-                    entry_price_before_e6: 0,
-                    margin_before_e6: if is_new_position { 0 } else { required_margin },
-                    side_after: side_u8,
-                    size_after_e6: trade.size_e6,
-                    entry_price_after_e6: trade.price_e6,
-                    margin_after_e6: required_margin,
-                    size_delta_e6: trade.size_e6 as i64,
-                    realized_pnl_e6: 0,
-                    fee_e6: fee,
-                    related_trade_sequence: sequence,
-                });
-            }
-            
-            trade_data_type::CLOSE => {
-                msg!(
-                    "🔴 Trade {} CLOSE: user={}, market={}, size={}, price={}",
-                    i, trade.user, trade.market_index, trade.size_e6, trade.price_e6
-                );
-
-                // 验证参数
-                if trade.size_e6 == 0 {
-                    return Err(LedgerError::InvalidTradeAmount.into());
-                }
-                if trade.price_e6 == 0 {
-                    return Err(LedgerError::InvalidPrice.into());
-                }
-
-                // 读取仓位
-                let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-                if position.user != trade.user || position.market_index != trade.market_index {
-                    return Err(LedgerError::PositionNotFound.into());
-                }
-                if position.is_empty() {
-                    return Err(LedgerError::PositionNotFound.into());
-                }
-
-                let close_size = trade.size_e6.min(position.size_e6);
-                let close_ratio = div_e6(close_size as i64, position.size_e6 as i64)?;
-                let pnl = position.calculate_unrealized_pnl(trade.price_e6)?;
-                let realized_pnl = mul_e6(pnl, close_ratio)?;
-                let original_margin = position.margin_e6;
-                let mut margin_to_release = mul_e6(position.margin_e6 as i64, close_ratio)? as u64;
-                if trade.fee_rate_e6 > 10_000 {
-                    return Err(LedgerError::InvalidFeeRate.into());
-                }
-                let fee = cpi::calculate_fee(close_size, trade.price_e6, trade.fee_rate_e6)?;
-
-                if close_size >= position.size_e6 {
-                    margin_to_release = position.margin_e6;
-                    position.size_e6 = 0;
-                    position.margin_e6 = 0;
-                    position.entry_price_e6 = 0;
-                    position.liquidation_price_e6 = 0;
-                    position.unrealized_pnl_e6 = 0;
-                } else {
-                    position.size_e6 = checked_sub_u64(position.size_e6, close_size)?;
-                    position.margin_e6 = checked_sub_u64(position.margin_e6, margin_to_release)?;
-                    position.liquidation_price_e6 = position.calculate_liquidation_price()?;
-                    if position.size_e6 == 0 {
-                        margin_to_release = original_margin;
-                        position.margin_e6 = 0;
-                        position.entry_price_e6 = 0;
-                        position.liquidation_price_e6 = 0;
-                        position.unrealized_pnl_e6 = 0;
-                    }
-                }
-                position.last_update_ts = current_ts;
-                position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+    let current_ts = get_current_timestamp()?;
+    let market_config = MarketConfig {
+        discriminator: MarketConfig::DISCRIMINATOR,
+        market_index,
+        max_leverage: params.max_leverage,
+        initial_margin_rate_e6: params.initial_margin_rate_e6,
+        maintenance_margin_rate_e6: params.maintenance_margin_rate_e6,
+        liquidation_penalty_rate_e6: params.liquidation_penalty_rate_e6,
+        partial_liquidation_buffer_e6: params.partial_liquidation_buffer_e6,
+        full_liquidation_margin_ratio_e6: params.full_liquidation_margin_ratio_e6,
+        min_liquidation_notional_e6: params.min_liquidation_notional_e6,
+        liquidator_reward_bps: params.liquidator_reward_bps,
+        tick_size_e6: params.tick_size_e6,
+        lot_size_e6: params.lot_size_e6,
+        price_band_bps: params.price_band_bps,
+        liquidation_price_band_bps: params.liquidation_price_band_bps,
+        funding_interval_secs: params.funding_interval_secs,
+        max_funding_rate_e6: params.max_funding_rate_e6,
+        is_active: params.is_active,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        risk_tiers: Vec::new(),
+        reserved: [0u8; 64],
+    };
+    market_config.validate()?;
 
-                // CPI: 平仓结算
-                cpi::close_position_settle(
-                    &ledger_config.vault_program,
-                    vault_config_info.clone(),
-                    user_account_info.clone(),
-                    ledger_config_info.clone(),
-                    margin_to_release,
-                    realized_pnl,
-                    fee,
-                    &[&[b"ledger_config", &[ledger_config_bump]]],
-                )?;
-                msg!("  ✅ Position closed: pnl={}, margin_released={}, fee={}", realized_pnl, margin_to_release, fee);
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = MarketConfig::SIZE;
+    let lamports = rent.minimum_balance(space);
 
-                // 更新统计
-                ledger_config.total_positions_closed += 1;
-                ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
-
-                // Emit TradeEvent (CLOSE)
-                let side_u8 = match position.side { Side::Long => 0u8, Side::Short => 1u8 };
-                events::emit_trade_event(&events::TradeEvent {
-                    discriminator: events::event_discriminator::TRADE,
-                    sequence,
-                    timestamp: current_ts,
-                    batch_id,
-                    market_index: trade.market_index,
-                    market_type: 0, // Perp
-                    trade_type: events::TradeType::Normal as u8,
-                    maker: trade.user,
-                    maker_order_id: [0u8; 16],
-                    maker_side: side_u8,
-                    maker_fee_e6: 0,
-                    taker: trade.user,
-                    taker_order_id: [0u8; 16],
-                    taker_side: side_u8,
-                    taker_fee_e6: fee as i64,
-                    price_e6: trade.price_e6,
-                    size_e6: close_size,
-                    notional_e6: (close_size as u128 * trade.price_e6 as u128 / 1_000_000) as u64,
-                    maker_realized_pnl_e6: realized_pnl,
-                    taker_realized_pnl_e6: 0,
-                    maker_margin_delta_e6: -(margin_to_release as i64),
-                    taker_margin_delta_e6: 0,
-                });
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            market_config_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), market_config_info.clone(), system_program.clone()],
+        &[&[MarketConfig::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
 
-                // Emit PositionEvent (CLOSE)
-                let is_full_close = position.size_e6 == 0;
-                events::emit_position_event(&events::PositionEvent {
-                    discriminator: events::event_discriminator::POSITION,
-                    sequence,
-                    timestamp: current_ts,
-                    user: trade.user,
-                    market_index: trade.market_index,
-                    event_type: if is_full_close {
-                        events::PositionEventType::Closed as u8
-                    } else {
-                        events::PositionEventType::Decreased as u8
-                    },
-                    side_before: side_u8,
-                    size_before_e6: close_size + position.size_e6,
-                    entry_price_before_e6: position.entry_price_e6,
-                    margin_before_e6: original_margin,
-                    side_after: side_u8,
-                    size_after_e6: position.size_e6,
-                    entry_price_after_e6: position.entry_price_e6,
-                    margin_after_e6: position.margin_e6,
-                    size_delta_e6: -(close_size as i64),
-                    realized_pnl_e6: realized_pnl,
-                    fee_e6: fee,
-                    related_trade_sequence: sequence,
-                });
-            }
-            
-            _ => {
-                msg!("⚠️ Trade {}: Unknown trade type {}", i, trade.trade_type);
-            }
-        }
+    market_config.serialize(&mut &mut market_config_info.data.borrow_mut()[..])?;
 
-        // 更新交易量
-        ledger_config.total_volume_e6 = ledger_config
-            .total_volume_e6
-            .saturating_add((trade.size_e6 as u128 * trade.price_e6 as u128 / 1_000_000) as u64);
+    msg!(
+        "MarketConfig created: market={}, max_leverage={}, imr={}, mmr={}, penalty={}",
+        market_index,
+        market_config.max_leverage,
+        market_config.initial_margin_rate_e6,
+        market_config.maintenance_margin_rate_e6,
+        market_config.liquidation_penalty_rate_e6
+    );
+    Ok(())
+}
 
-        // 自动创建 UserStats (如果不存在)
-        let _ = ensure_user_stats_exists(
-            program_id,
-            relayer,
-            &trade.user,
-            user_stats_info,
-            system_program,
-        );
+fn process_update_market_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    params: MarketConfigParams,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-        // 更新用户统计 (现在保证存在)
-        if user_stats_info.data_len() > 0 {
-            // 先读取数据到局部变量，释放借用
-            let user_stats_result = {
-                let data = user_stats_info.data.borrow();
-                deserialize_account::<UserStats>(&data)
-            };
-            
-            if let Ok(mut user_stats) = user_stats_result {
-                user_stats.total_trades += 1;
-                user_stats.total_volume_e6 = user_stats.total_volume_e6.saturating_add(
-                    (trade.size_e6 as u128 * trade.price_e6 as u128 / 1_000_000) as u64
-                );
-                if user_stats.first_trade_at == 0 {
-                    user_stats.first_trade_at = current_ts;
-                }
-                user_stats.last_trade_at = current_ts;
-                let _ = user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..]);
-            }
-        }
+    assert_signer(admin)?;
+    assert_writable(market_config_info)?;
 
-        msg!("  📊 Sequence: {}", sequence);
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    ledger_config.last_update_ts = current_ts;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    let mut market_config = load_market_config(program_id, market_config_info, market_index)?;
+
+    market_config.max_leverage = params.max_leverage;
+    market_config.initial_margin_rate_e6 = params.initial_margin_rate_e6;
+    market_config.maintenance_margin_rate_e6 = params.maintenance_margin_rate_e6;
+    market_config.liquidation_penalty_rate_e6 = params.liquidation_penalty_rate_e6;
+    market_config.partial_liquidation_buffer_e6 = params.partial_liquidation_buffer_e6;
+    market_config.full_liquidation_margin_ratio_e6 = params.full_liquidation_margin_ratio_e6;
+    market_config.min_liquidation_notional_e6 = params.min_liquidation_notional_e6;
+    market_config.liquidator_reward_bps = params.liquidator_reward_bps;
+    market_config.tick_size_e6 = params.tick_size_e6;
+    market_config.lot_size_e6 = params.lot_size_e6;
+    market_config.price_band_bps = params.price_band_bps;
+    market_config.liquidation_price_band_bps = params.liquidation_price_band_bps;
+    market_config.funding_interval_secs = params.funding_interval_secs;
+    market_config.max_funding_rate_e6 = params.max_funding_rate_e6;
+    market_config.is_active = params.is_active;
+    market_config.validate()?;
+
+    market_config.last_update_ts = get_current_timestamp()?;
+    market_config.serialize(&mut &mut market_config_info.data.borrow_mut()[..])?;
 
-    emit_batch_event(&BatchEvent {
-        discriminator: event_discriminator::BATCH,
-        batch_id,
-        timestamp: current_ts,
-        event_type: 2,
-        trade_count: trades.len() as u16,
-        total_notional_e6: 0,
-        relayer: *relayer.key,
-        data_hash: trade_batch.data_hash,
-        chain_tx: [0u8; 64],
-        error_code: 0,
-    });
+    msg!(
+        "MarketConfig updated: market={}, max_leverage={}, imr={}, mmr={}, penalty={}, active={}",
+        market_index,
+        market_config.max_leverage,
+        market_config.initial_margin_rate_e6,
+        market_config.maintenance_margin_rate_e6,
+        market_config.liquidation_penalty_rate_e6,
+        market_config.is_active
+    );
+    Ok(())
+}
+
+fn process_set_risk_tiers(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    tiers: Vec<RiskTier>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    msg!("✅ TradeBatch {} executed successfully with {} trades", batch_id, trades.len());
+    assert_signer(admin)?;
+    assert_writable(market_config_info)?;
+
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    let mut market_config = load_market_config(program_id, market_config_info, market_index)?;
+    market_config.risk_tiers = tiers;
+    market_config.validate()?;
+
+    market_config.last_update_ts = get_current_timestamp()?;
+    market_config.serialize(&mut &mut market_config_info.data.borrow_mut()[..])?;
+
+    for (i, tier) in market_config.risk_tiers.iter().enumerate() {
+        msg!(
+            "  Tier {}: notional<={}, max_leverage={}, mmr={}",
+            i, tier.max_notional_e6, tier.max_leverage, tier.maintenance_margin_rate_e6
+        );
+    }
+    msg!("RiskTiers updated: market={}, tiers={}", market_index, market_config.risk_tiers.len());
     Ok(())
 }
 
 // ============================================================================
-// 交易指令处理
+// 预言机指令处理
 // ============================================================================
 
-fn process_open_position(
+fn process_create_price_feed(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    user: Pubkey,
     market_index: u8,
-    side: Side,
-    size_e6: u64,
-    price_e6: u64,
-    leverage: u8,
-    batch_id: u64,
-    fee_rate: u64,
+    max_staleness_secs: i64,
+    max_confidence_e6: i64,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let position_info = next_account_info(account_info_iter)?;
-    let user_account_info = next_account_info(account_info_iter)?;
-    let vault_config_info = next_account_info(account_info_iter)?;
+    let admin = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
     let ledger_config_info = next_account_info(account_info_iter)?;
-    let user_stats_info = next_account_info(account_info_iter)?;
-    let vault_program = next_account_info(account_info_iter)?;
-    let ledger_program_info = next_account_info(account_info_iter)?; // Ledger Program itself for CPI caller
     let system_program = next_account_info(account_info_iter)?;
 
-    assert_signer(relayer)?;
-    assert_writable(position_info)?;
-    assert_writable(user_account_info)?;
-    assert_writable(ledger_config_info)?;
-    assert_writable(user_stats_info)?;
-    
-    // 验证 Ledger Program 地址正确
-    if ledger_program_info.key != program_id {
-        return Err(LedgerError::InvalidProgramId.into());
-    }
-
-    // 验证参数
-    if size_e6 == 0 {
-        return Err(LedgerError::InvalidTradeAmount.into());
-    }
-    if price_e6 == 0 {
-        return Err(LedgerError::InvalidPrice.into());
-    }
-    if leverage == 0 || leverage > MAX_LEVERAGE {
-        return Err(LedgerError::InvalidLeverage.into());
-    }
-
-    // 读取配置
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-    if ledger_config.is_paused {
-        return Err(LedgerError::LedgerPaused.into());
-    }
+    assert_signer(admin)?;
+    assert_writable(price_feed_info)?;
 
-    // 验证 Vault Program
-    if vault_program.key != &ledger_config.vault_program {
-        return Err(LedgerError::InvalidVaultProgram.into());
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    // 计算所需保证金
-    let required_margin = cpi::calculate_required_margin(size_e6, price_e6, leverage)?;
-    let fee = cpi::calculate_fee(size_e6, price_e6, fee_rate)?; // P1B: dynamic fee rate
-
-    // 派生 Position PDA
-    let (position_pda, position_bump) = Pubkey::find_program_address(
-        &[b"position", user.as_ref(), &[market_index]],
+    // 派生 PDA
+    let (price_feed_pda, bump) = Pubkey::find_program_address(
+        &[PriceFeed::SEED_PREFIX, &[market_index]],
         program_id,
     );
-    if position_info.key != &position_pda {
+    if price_feed_info.key != &price_feed_pda {
         return Err(LedgerError::InvalidAccount.into());
     }
+    if price_feed_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
+    }
 
     let current_ts = get_current_timestamp()?;
-
-    // 检查是否是新仓位
-    let is_new_position = position_info.data_len() == 0 || {
-        let data = position_info.data.borrow();
-        data.iter().all(|&x| x == 0)
+    let price_feed = PriceFeed {
+        discriminator: PriceFeed::DISCRIMINATOR,
+        market_index,
+        price_e6: 0,
+        conf_e6: 0,
+        publish_ts: 0,
+        max_staleness_secs,
+        max_confidence_e6,
+        signer_count: 0,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        reserved: [0u8; 64],
     };
+    price_feed.validate_params()?;
 
-    let mut pre_side: u8 = 0;
-    let mut pre_size: u64 = 0;
-    let mut pre_entry: u64 = 0;
-    let mut pre_margin: u64 = 0;
-    let mut post_side: u8 = 0;
-    let mut post_size: u64 = 0;
-    let mut post_entry: u64 = 0;
-    let mut post_margin: u64 = 0;
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = PriceFeed::SIZE;
+    let lamports = rent.minimum_balance(space);
 
-    if is_new_position {
-        // 创建新仓位
-        let rent = Rent::get()?;
-        let space = Position::SIZE;
-        let lamports = rent.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            price_feed_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), price_feed_info.clone(), system_program.clone()],
+        &[&[PriceFeed::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
 
-        invoke_signed(
-            &system_instruction::create_account(
-                relayer.key,
-                position_info.key,
-                lamports,
-                space as u64,
-                program_id,
-            ),
-            &[relayer.clone(), position_info.clone(), system_program.clone()],
-            &[&[b"position", user.as_ref(), &[market_index], &[position_bump]]],
-        )?;
+    price_feed.serialize(&mut &mut price_feed_info.data.borrow_mut()[..])?;
 
-        let mut position = Position {
-            discriminator: Position::DISCRIMINATOR,
-            user,
-            market_index,
-            side,
-            size_e6,
-            entry_price_e6: price_e6,
-            margin_e6: checked_add_u64(required_margin, fee)?,
-            leverage,
-            liquidation_price_e6: 0, // 计算后设置
-            unrealized_pnl_e6: 0,
-            last_funding_ts: current_ts,
-            cumulative_funding_e6: 0,
-            open_order_count: 0,
-            opened_at: current_ts,
-            last_update_ts: current_ts,
-            bump: position_bump,
-            reserved: [0; 32],
-        };
+    msg!(
+        "PriceFeed created: market={}, max_staleness={}s, max_conf={}",
+        market_index,
+        max_staleness_secs,
+        max_confidence_e6
+    );
+    Ok(())
+}
 
-        // 计算清算价格
-        position.liquidation_price_e6 = position.calculate_liquidation_price()?;
-        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+fn process_update_price_feed(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    prices_e6: Vec<u64>,
+    conf_e6: u64,
+    publish_ts: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
 
-        post_side = position.side as u8;
-        post_size = position.size_e6;
-        post_entry = position.entry_price_e6;
-        post_margin = position.margin_e6;
+    assert_writable(price_feed_info)?;
 
-        msg!(
-            "New position opened: user={}, market={}, side={:?}, size={}, entry={}, margin={}",
-            user, market_index, side, size_e6, price_e6, required_margin
-        );
-    } else {
-        // 加仓
-        let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    // 验证 RelayerConfig PDA
+    let (relayer_config_pda, _) = Pubkey::find_program_address(&[b"relayer_config"], program_id);
+    if relayer_config_info.key != &relayer_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
 
-        // 验证方向一致
-        if position.side != side {
-            return Err(LedgerError::InvalidPositionSide.into());
+    // 验证签名 Relayers: 每个报价对应一个不重复的授权签名者
+    let mut signers: Vec<Pubkey> = Vec::with_capacity(prices_e6.len());
+    for _ in 0..prices_e6.len() {
+        let relayer = next_account_info(account_info_iter)?;
+        assert_signer(relayer)?;
+        if !relayer_config.is_authorized(relayer.key) {
+            return Err(LedgerError::UnauthorizedRelayer.into());
         }
+        if signers.contains(relayer.key) {
+            return Err(LedgerError::DuplicateSignature.into());
+        }
+        signers.push(*relayer.key);
+    }
+    if !relayer_config.has_enough_signatures(signers.len() as u8) {
+        return Err(LedgerError::InsufficientSignatures.into());
+    }
 
-        pre_side = position.side as u8;
-        pre_size = position.size_e6;
-        pre_entry = position.entry_price_e6;
-        pre_margin = position.margin_e6;
-
-        // 更新仓位
-        position.update_entry_price(size_e6, price_e6)?;
-        position.margin_e6 = checked_add_u64(position.margin_e6, checked_add_u64(required_margin, fee)?)?;
-        position.last_update_ts = current_ts;
-
-        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+    let (price_e6, spread_e6) = PriceFeed::aggregate_quorum(&prices_e6)
+        .ok_or(LedgerError::InvalidPrice)?;
 
-        post_side = position.side as u8;
-        post_size = position.size_e6;
-        post_entry = position.entry_price_e6;
-        post_margin = position.margin_e6;
+    let mut price_feed = load_price_feed(program_id, price_feed_info, market_index)?;
+    let current_ts = get_current_timestamp()?;
 
-        msg!(
-            "Position increased: user={}, market={}, new_size={}, new_margin={}",
-            user, market_index, position.size_e6, position.margin_e6
-        );
+    // 拒绝未来时间戳和乱序/重放的旧报价
+    if publish_ts > current_ts || publish_ts <= price_feed.publish_ts {
+        msg!("❌ Invalid publish_ts {} (last={}, now={})", publish_ts, price_feed.publish_ts, current_ts);
+        return Err(LedgerError::StalePrice.into());
     }
 
-    // CPI: 锁定保证金 + 扣除手续费
-    let total_to_lock = checked_add_u64(required_margin, fee)?;
-    
-    // 调用 Vault Program 锁定保证金
-    // 派生 Ledger Config PDA 用于 CPI 签名
-    let (ledger_config_pda, ledger_config_bump) = Pubkey::find_program_address(
-        &[b"ledger_config"],
-        program_id,
+    price_feed.price_e6 = price_e6;
+    price_feed.conf_e6 = conf_e6.max(spread_e6);
+    price_feed.publish_ts = publish_ts;
+    price_feed.signer_count = signers.len() as u8;
+    price_feed.last_update_ts = current_ts;
+    price_feed.serialize(&mut &mut price_feed_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "PriceFeed updated: market={}, price={}, conf={}, signers={}",
+        market_index,
+        price_feed.price_e6,
+        price_feed.conf_e6,
+        price_feed.signer_count
     );
-    
-    cpi::lock_margin(
-        vault_program.key,
-        vault_config_info.clone(),
-        user_account_info.clone(),
-        ledger_config_info.clone(),  // 使用 LedgerConfig PDA 作为 caller
-        total_to_lock,
-        &[&[b"ledger_config", &[ledger_config_bump]]],  // PDA 签名
-    )?;
-    
-    msg!("CPI: Locked margin {} + fee {}", required_margin, fee);
+    Ok(())
+}
 
-    // 更新统计
-    ledger_config.total_positions_opened += 1;
-    ledger_config.total_volume_e6 = checked_add_u64(
-        ledger_config.total_volume_e6,
-        (size_e6 as u128 * price_e6 as u128 / 1_000_000) as u64,
-    )?;
-    ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
-    let position_event_seq = ledger_config.next_sequence();
-    ledger_config.last_update_ts = current_ts;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+fn process_set_price_feed_params(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    max_staleness_secs: i64,
+    max_confidence_e6: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    // 自动创建 UserStats (如果不存在)
-    let _ = ensure_user_stats_exists(
-        program_id,
-        relayer,
-        &user,
-        user_stats_info,
-        system_program,
-    );
+    assert_signer(admin)?;
+    assert_writable(price_feed_info)?;
 
-    // 更新用户统计 (现在保证存在)
-    if user_stats_info.data_len() > 0 {
-        // 先读取数据到局部变量，释放借用
-        let user_stats_result = {
-            let data = user_stats_info.data.borrow();
-            deserialize_account::<UserStats>(&data)
-        };
-        
-        if let Ok(mut user_stats) = user_stats_result {
-            user_stats.total_trades += 1;
-            user_stats.total_volume_e6 = checked_add_u64(
-                user_stats.total_volume_e6,
-                (size_e6 as u128 * price_e6 as u128 / 1_000_000) as u64,
-            )?;
-            user_stats.total_fees_paid_e6 = checked_add_u64(user_stats.total_fees_paid_e6, fee)?;
-            if user_stats.first_trade_at == 0 {
-                user_stats.first_trade_at = current_ts;
-            }
-            user_stats.last_trade_at = current_ts;
-            // 现在可以安全地可变借用
-            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
-        }
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    msg!("OpenPosition completed: batch_id={}, margin_locked={}, fee={}", batch_id, total_to_lock, fee);
-
-    emit_position_event(&PositionEvent {
-        discriminator: event_discriminator::POSITION,
-        sequence: position_event_seq,
-        timestamp: current_ts,
-        user,
+    let mut price_feed = load_price_feed(program_id, price_feed_info, market_index)?;
+    price_feed.max_staleness_secs = max_staleness_secs;
+    price_feed.max_confidence_e6 = max_confidence_e6;
+    price_feed.validate_params()?;
+
+    price_feed.last_update_ts = get_current_timestamp()?;
+    price_feed.serialize(&mut &mut price_feed_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "PriceFeed params updated: market={}, max_staleness={}s, max_conf={}",
         market_index,
-        event_type: if is_new_position { 0 } else { 1 },
-        side_before: pre_side,
-        size_before_e6: pre_size,
-        entry_price_before_e6: pre_entry,
-        margin_before_e6: pre_margin,
-        side_after: post_side,
-        size_after_e6: post_size,
-        entry_price_after_e6: post_entry,
-        margin_after_e6: post_margin,
-        size_delta_e6: size_e6 as i64,
-        realized_pnl_e6: 0,
-        fee_e6: fee,
-        related_trade_sequence: 0,
-    });
+        max_staleness_secs,
+        max_confidence_e6
+    );
+    Ok(())
+}
 
+// ============================================================================
+// 保证金管理指令处理
+// ============================================================================
+
+/// 验证签名者是仓位所有者或授权 Relayer
+fn assert_user_or_relayer(
+    program_id: &Pubkey,
+    signer: &AccountInfo,
+    user: &Pubkey,
+    relayer_config_info: &AccountInfo,
+) -> ProgramResult {
+    assert_signer(signer)?;
+    if signer.key == user {
+        return Ok(());
+    }
+    let (relayer_config_pda, _) = Pubkey::find_program_address(&[b"relayer_config"], program_id);
+    if relayer_config_info.key != &relayer_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(signer.key) {
+        return Err(LedgerError::UnauthorizedRelayer.into());
+    }
     Ok(())
 }
 
-fn process_close_position(
+/// 保证金调整方式
+enum MarginAdjustment {
+    Add(u64),
+    Remove(u64),
+    /// 按入场名义价值 / 新杠杆重设保证金
+    SetLeverage(u8),
+}
+
+/// AddMargin / RemoveMargin / SetLeverage
+fn process_adjust_margin(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     user: Pubkey,
     market_index: u8,
-    size_e6: u64,
-    price_e6: u64,
-    batch_id: u64,
-    fee_rate: u64,
+    adjustment: MarginAdjustment,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
+    let signer = next_account_info(account_info_iter)?;
     let position_info = next_account_info(account_info_iter)?;
     let user_account_info = next_account_info(account_info_iter)?;
     let vault_config_info = next_account_info(account_info_iter)?;
-    let insurance_fund_info = next_account_info(account_info_iter)?;
     let ledger_config_info = next_account_info(account_info_iter)?;
-    let user_stats_info = next_account_info(account_info_iter)?;
-    let _vault_program = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
 
-    assert_signer(relayer)?;
+    assert_user_or_relayer(program_id, signer, &user, relayer_config_info)?;
     assert_writable(position_info)?;
     assert_writable(user_account_info)?;
-    assert_writable(insurance_fund_info)?;
     assert_writable(ledger_config_info)?;
-    assert_writable(user_stats_info)?;
 
-    // 验证参数
-    if size_e6 == 0 {
-        return Err(LedgerError::InvalidTradeAmount.into());
-    }
-    if price_e6 == 0 {
-        return Err(LedgerError::InvalidPrice.into());
-    }
-
-    // 读取配置
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
     if ledger_config.is_paused {
         return Err(LedgerError::LedgerPaused.into());
     }
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
+    }
 
     // 读取仓位
-    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-    if position.user != user || position.market_index != market_index {
-        return Err(LedgerError::PositionNotFound.into());
+    let (position_pda, _) = Pubkey::find_program_address(
+        &[b"position", user.as_ref(), &[market_index]],
+        program_id,
+    );
+    if position_info.key != &position_pda {
+        return Err(LedgerError::InvalidAccount.into());
     }
-    if position.is_empty() {
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    if position.user != user || position.market_index != market_index || position.is_empty() {
         return Err(LedgerError::PositionNotFound.into());
     }
 
-    let close_pre_side = position.side as u8;
-    let close_pre_size = position.size_e6;
-    let close_pre_entry = position.entry_price_e6;
-    let close_pre_margin = position.margin_e6;
-
-    // 验证平仓数量
-    let close_size = size_e6.min(position.size_e6);
-    let close_ratio = div_e6(close_size as i64, position.size_e6 as i64)?;
-
-    // 计算盈亏
-    let pnl = position.calculate_unrealized_pnl(price_e6)?;
-    let realized_pnl = mul_e6(pnl, close_ratio)?;
-
-    // S0-4 fix: capture original margin before any mutation, so partial-to-full-close
-    // releases the correct total amount instead of only the rounding remainder.
-    let original_margin = position.margin_e6;
-
-    let mut margin_to_release = mul_e6(position.margin_e6 as i64, close_ratio)? as u64;
-
-    let fee = cpi::calculate_fee(close_size, price_e6, fee_rate)?;
-
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
     let current_ts = get_current_timestamp()?;
+    let margin_before = position.margin_e6;
+    let leverage_before = position.leverage;
+
+    // 先计提资金费，可提取保证金按扣除未结算资金费后的权益计算
+    let funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    position.accrue_funding(funding_state.cumulative_funding_index_e6)?;
+
+    // 计算目标保证金
+    let target_margin = match adjustment {
+        MarginAdjustment::Add(amount) => {
+            if amount == 0 {
+                return Err(LedgerError::InvalidTradeAmount.into());
+            }
+            checked_add_u64(position.margin_e6, amount)?
+        }
+        MarginAdjustment::Remove(amount) => {
+            if amount == 0 {
+                return Err(LedgerError::InvalidTradeAmount.into());
+            }
+            checked_sub_u64(position.margin_e6, amount).map_err(|_| LedgerError::InsufficientMargin)?
+        }
+        MarginAdjustment::SetLeverage(leverage) => {
+            market_config.check_leverage(leverage)?;
+            market_config.check_position_tier(leverage, position.notional_value_e6()?)?;
+            position.leverage = leverage;
+            cpi::calculate_required_margin(position.size_e6, position.entry_price_e6, leverage)?
+        }
+    };
 
-    let is_full_close = close_size >= position.size_e6;
-    if is_full_close {
-        margin_to_release = position.margin_e6;
-    }
-
-    if is_full_close {
-        position.size_e6 = 0;
-        position.margin_e6 = 0;
-        position.entry_price_e6 = 0;
-        position.liquidation_price_e6 = 0;
-        position.unrealized_pnl_e6 = 0;
-    } else {
-        position.size_e6 = checked_sub_u64(position.size_e6, close_size)?;
-        position.margin_e6 = checked_sub_u64(position.margin_e6, margin_to_release)?;
-        position.liquidation_price_e6 = position.calculate_liquidation_price()?;
-        
-        if position.size_e6 == 0 {
-            // S0-4: partial close resulted in full close due to precision —
-            // release the ENTIRE original margin, not just the remainder after proportional subtraction.
-            margin_to_release = original_margin;
-            position.margin_e6 = 0;
-            position.entry_price_e6 = 0;
-            position.liquidation_price_e6 = 0;
-            position.unrealized_pnl_e6 = 0;
+    // 减少保证金: 按标记价格检查初始保证金要求，标记价格先按 PriceFeed 指数价格刷新
+    if target_margin < position.margin_e6 {
+        let amount = position.margin_e6 - target_margin;
+        let price_feed_info = next_account_info(account_info_iter)?;
+        let mark_price_info = next_account_info(account_info_iter)?;
+        let index_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+        assert_writable(mark_price_info)?;
+        let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+        let mark_price_e6 = mark_price.update(index_price_e6, current_ts)?;
+        mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
+        let mark_notional = (position.size_e6 as u128 * mark_price_e6 as u128 / 1_000_000) as u64;
+        let imr = market_config.initial_margin_rate_for(mark_notional)?;
+        let max_removable = position.max_removable_margin(mark_price_e6, imr)?;
+        if amount > max_removable {
+            msg!("❌ Margin release {} exceeds max removable {} (mark={}, imr={})", amount, max_removable, mark_price_e6, imr);
+            return Err(LedgerError::InsufficientMargin.into());
         }
     }
+    position.margin_e6 = target_margin;
+    position.refresh_liquidation_price(&market_config)?;
     position.last_update_ts = current_ts;
     position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
 
-    // CPI 调用 Vault.closePositionSettle
-    // 派生 Ledger Config PDA 用于 CPI 签名
-    let (_, ledger_config_bump) = Pubkey::find_program_address(
-        &[b"ledger_config"],
-        program_id,
-    );
-    
-    cpi::close_position_settle(
-        &ledger_config.vault_program,
-        vault_config_info.clone(),
-        user_account_info.clone(),
-        ledger_config_info.clone(),
-        margin_to_release,
-        realized_pnl,
-        fee,
-        &[&[b"ledger_config", &[ledger_config_bump]]],
-    )?;
-    
-    msg!("CPI: Close position settle - margin={}, pnl={}, fee={}", margin_to_release, realized_pnl, fee);
+    // CPI: 锁定 / 释放保证金差额
+    let (_, ledger_config_bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+    if target_margin > margin_before {
+        cpi::lock_margin(
+            vault_program.key,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
+            target_margin - margin_before,
+            &[&[b"ledger_config", &[ledger_config_bump]]],
+        )?;
+    } else if target_margin < margin_before {
+        cpi::release_margin(
+            vault_program.key,
+            vault_config_info.clone(),
+            user_account_info.clone(),
+            ledger_config_info.clone(),
+            margin_before - target_margin,
+            &[&[b"ledger_config", &[ledger_config_bump]]],
+        )?;
+    }
 
-    // 更新统计
-    ledger_config.total_positions_closed += 1;
-    ledger_config.total_volume_e6 = checked_add_u64(
-        ledger_config.total_volume_e6,
-        (close_size as u128 * price_e6 as u128 / 1_000_000) as u64,
-    )?;
-    ledger_config.total_fees_collected_e6 = checked_add_u64(ledger_config.total_fees_collected_e6, fee)?;
-    let close_pos_event_seq = ledger_config.next_sequence();
-    let close_trade_event_seq = ledger_config.next_sequence();
+    let sequence = ledger_config.next_sequence();
     ledger_config.last_update_ts = current_ts;
     ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
 
-    // 更新用户统计
-    if user_stats_info.data_len() > 0 {
-        // 先读取数据到局部变量，释放借用
-        let user_stats_result = {
-            let data = user_stats_info.data.borrow();
-            deserialize_account::<UserStats>(&data)
-        };
-        
-        if let Ok(mut user_stats) = user_stats_result {
-            user_stats.total_trades += 1;
-            user_stats.total_volume_e6 = checked_add_u64(
-                user_stats.total_volume_e6,
-                (close_size as u128 * price_e6 as u128 / 1_000_000) as u64,
-            )?;
-            user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, realized_pnl)?;
-            user_stats.total_fees_paid_e6 = checked_add_u64(user_stats.total_fees_paid_e6, fee)?;
-            user_stats.last_trade_at = current_ts;
-            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
-        }
-    }
-
     msg!(
-        "ClosePosition completed: batch_id={}, size={}, pnl={}, margin_released={}, fee={}",
-        batch_id, close_size, realized_pnl, margin_to_release, fee
+        "Margin adjusted: user={}, market={}, margin {} -> {}, leverage {} -> {}, liq_price={}",
+        user, market_index, margin_before, position.margin_e6,
+        leverage_before, position.leverage, position.liquidation_price_e6
     );
 
-    let close_event_type = if position.size_e6 == 0 { 2u8 } else { 3u8 };
+    let event_type = match adjustment {
+        MarginAdjustment::SetLeverage(_) => events::PositionEventType::LeverageChanged,
+        _ if target_margin > margin_before => events::PositionEventType::MarginAdded,
+        _ => events::PositionEventType::MarginRemoved,
+    };
+    let side_u8 = position.side as u8;
     emit_position_event(&PositionEvent {
         discriminator: event_discriminator::POSITION,
-        sequence: close_pos_event_seq,
+        sequence,
         timestamp: current_ts,
         user,
         market_index,
-        event_type: close_event_type,
-        side_before: close_pre_side,
-        size_before_e6: close_pre_size,
-        entry_price_before_e6: close_pre_entry,
-        margin_before_e6: close_pre_margin,
-        side_after: position.side as u8,
+        event_type: event_type as u8,
+        side_before: side_u8,
+        size_before_e6: position.size_e6,
+        entry_price_before_e6: position.entry_price_e6,
+        margin_before_e6: margin_before,
+        side_after: side_u8,
         size_after_e6: position.size_e6,
         entry_price_after_e6: position.entry_price_e6,
         margin_after_e6: position.margin_e6,
-        size_delta_e6: -(close_size as i64),
-        realized_pnl_e6: realized_pnl,
-        fee_e6: fee,
-        related_trade_sequence: close_trade_event_seq,
+        size_delta_e6: 0,
+        realized_pnl_e6: 0,
+        fee_e6: 0,
+        related_trade_sequence: 0,
     });
 
-    emit_trade_event(&TradeEvent {
-        discriminator: event_discriminator::TRADE,
-        sequence: close_trade_event_seq,
-        timestamp: current_ts,
-        batch_id,
+    Ok(())
+}
+
+// ============================================================================
+// 资金费率指数指令处理
+// ============================================================================
+
+/// 验证签名者是授权 Relayer
+fn assert_authorized_relayer(
+    program_id: &Pubkey,
+    signer: &AccountInfo,
+    relayer_config_info: &AccountInfo,
+) -> ProgramResult {
+    assert_signer(signer)?;
+    let (relayer_config_pda, _) = Pubkey::find_program_address(&[b"relayer_config"], program_id);
+    if relayer_config_info.key != &relayer_config_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    if !relayer_config.is_authorized(signer.key) {
+        return Err(LedgerError::UnauthorizedRelayer.into());
+    }
+    Ok(())
+}
+
+fn process_create_funding_state(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    funding_interval_secs: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(funding_state_info)?;
+
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    // 派生 PDA
+    let (funding_state_pda, bump) = Pubkey::find_program_address(
+        &[FundingState::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if funding_state_info.key != &funding_state_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if funding_state_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
+    }
+
+    let funding_state = FundingState {
+        discriminator: FundingState::DISCRIMINATOR,
+        market_index,
+        cumulative_funding_index_e6: 0,
+        funding_interval_secs,
+        last_funding_ts: 0,
+        last_funding_rate_e6: 0,
+        last_index_price_e6: 0,
+        update_count: 0,
+        bump,
+        created_at: get_current_timestamp()?,
+        reserved: [0u8; 64],
+    };
+    funding_state.validate_params()?;
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = FundingState::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            funding_state_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), funding_state_info.clone(), system_program.clone()],
+        &[&[FundingState::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
+
+    funding_state.serialize(&mut &mut funding_state_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "FundingState created: market={}, interval={}s",
         market_index,
-        market_type: 0,
-        trade_type: 0,
-        maker: Pubkey::default(),
-        maker_order_id: [0u8; 16],
-        maker_side: 0,
-        maker_fee_e6: 0,
-        taker: user,
-        taker_order_id: [0u8; 16],
-        taker_side: close_pre_side,
-        taker_fee_e6: fee as i64,
-        price_e6,
-        size_e6: close_size,
-        notional_e6: (close_size as u128 * price_e6 as u128 / 1_000_000) as u64,
-        maker_realized_pnl_e6: 0,
-        taker_realized_pnl_e6: realized_pnl,
-        maker_margin_delta_e6: 0,
-        taker_margin_delta_e6: -(margin_to_release as i64),
-    });
+        funding_interval_secs
+    );
+    Ok(())
+}
+
+fn process_update_funding_index(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    funding_rate_e6: i64,
+    index_price_e6: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+
+    assert_authorized_relayer(program_id, relayer, relayer_config_info)?;
+    assert_writable(funding_state_info)?;
 
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    market_config.check_funding_rate(funding_rate_e6)?;
+    let current_ts = get_current_timestamp()?;
+    check_published_funding_rate(program_id, premium_samples_info, &market_config, funding_rate_e6, index_price_e6, current_ts)?;
+
+    let mut funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    funding_state.apply_rate(funding_rate_e6, index_price_e6, current_ts)?;
+    funding_state.serialize(&mut &mut funding_state_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "Funding index updated: market={}, rate={}, index_price={}, cumulative_index={}",
+        market_index,
+        funding_rate_e6,
+        index_price_e6,
+        funding_state.cumulative_funding_index_e6
+    );
     Ok(())
 }
 
 // ============================================================================
-// 清算指令处理
+// 批量资金费指令处理
 // ============================================================================
 
-fn process_liquidate(
+fn process_settle_funding_batch(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    user: Pubkey,
     market_index: u8,
-    mark_price_e6: u64,
+    funding_rate_e6: i64,
+    index_price_e6: u64,
+    epoch: u64,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let liquidator = next_account_info(account_info_iter)?;
-    let position_info = next_account_info(account_info_iter)?;
-    let user_account_info = next_account_info(account_info_iter)?;
-    let vault_config_info = next_account_info(account_info_iter)?;
+    let relayer = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
     let ledger_config_info = next_account_info(account_info_iter)?;
-    let user_stats_info = next_account_info(account_info_iter)?;
-    let _vault_program = next_account_info(account_info_iter)?;
-    // Vault Token Account for liquidation penalty transfer
-    let vault_token_account = next_account_info(account_info_iter)?;
-    // Fund Program accounts for insurance fund operations
-    let fund_program = next_account_info(account_info_iter)?;
-    let insurance_fund_account = next_account_info(account_info_iter)?;
-    let insurance_config = next_account_info(account_info_iter)?;
-    let insurance_vault = next_account_info(account_info_iter)?;
-    let counterparty_vault = next_account_info(account_info_iter)?; // For shortfall coverage
-    let token_program = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let summary_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
 
-    assert_signer(liquidator)?;
-    assert_writable(position_info)?;
-    assert_writable(user_account_info)?;
+    assert_authorized_relayer(program_id, relayer, relayer_config_info)?;
     assert_writable(ledger_config_info)?;
-    assert_writable(user_stats_info)?;
-    assert_writable(vault_token_account)?;
-    assert_writable(insurance_vault)?;
-
-    // 读取配置
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    assert_writable(summary_info)?;
 
-    // 验证 Fund Program
-    if fund_program.key != &ledger_config.fund_program {
-        return Err(LedgerError::InvalidProgramId.into());
+    let mut ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
     }
-
-    // 读取仓位
-    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-    if position.user != user || position.market_index != market_index {
-        return Err(LedgerError::PositionNotFound.into());
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
     }
-    if position.is_empty() {
-        return Err(LedgerError::PositionNotFound.into());
+
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    market_config.check_funding_rate(funding_rate_e6)?;
+    if index_price_e6 == 0 {
+        return Err(LedgerError::InvalidPrice.into());
     }
 
-    // 验证清算条件
-    if !position.should_liquidate(mark_price_e6) {
-        return Err(LedgerError::PositionNotLiquidatable.into());
+    let position_accounts = account_info_iter.as_slice();
+    if position_accounts.is_empty() || position_accounts.len().checked_rem(3) != Some(0) {
+        msg!("❌ Expected Position / UserAccount / UserStats triples, got {} accounts", position_accounts.len());
+        return Err(LedgerError::InsufficientAccounts.into());
     }
 
     let current_ts = get_current_timestamp()?;
+    check_published_funding_rate(program_id, premium_samples_info, &market_config, funding_rate_e6, index_price_e6, current_ts)?;
+    let funding_index_e6 = load_applied_funding_index(program_id, funding_state_info, market_index, funding_rate_e6, index_price_e6)?;
+    let mark_price_e6 = load_mark_price(program_id, mark_price_info, market_index)?.checked_price(current_ts)?;
+    let mut summary = ensure_funding_summary_exists(program_id, relayer, summary_info, system_program, market_index)?;
+    summary.begin_epoch(epoch, funding_rate_e6, index_price_e6, current_ts)?;
+
+    let (_, ledger_config_bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+    let signer_seeds: &[&[&[u8]]] = &[&[b"ledger_config", &[ledger_config_bump]]];
+    let funding_rate_e9 = funding_rate_e6.saturating_mul(1000);
+    let mut settled_count: u32 = 0;
+
+    for triple in position_accounts.chunks(3) {
+        let (position_info, user_account_info, user_stats_info) = (&triple[0], &triple[1], &triple[2]);
+        assert_writable(position_info)?;
+        assert_writable(user_account_info)?;
 
-    // 计算清算结果
-    let pnl = position.calculate_unrealized_pnl(mark_price_e6)?;
-    let margin = position.margin_e6;
-    let pre_liq_side = position.side.clone();
-    let pre_liq_size = position.size_e6;
-    let pre_liq_entry = position.entry_price_e6;
-    let pre_liq_liq_price = position.liquidation_price_e6;
+        let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+        let (position_pda, _) = Pubkey::find_program_address(
+            &[b"position", position.user.as_ref(), &[market_index]],
+            program_id,
+        );
+        if position_info.key != &position_pda || position.market_index != market_index {
+            msg!("❌ Invalid Position PDA {} for market {}", position_info.key, market_index);
+            return Err(LedgerError::InvalidAccount.into());
+        }
+        if cpi::read_user_account(user_account_info)?.wallet != position.user {
+            msg!("❌ UserAccount does not belong to {}", position.user);
+            return Err(LedgerError::InvalidAccount.into());
+        }
+        if position.is_empty()
+            || market_config.check_funding_due(position.last_funding_ts, current_ts).is_err()
+        {
+            msg!("⚠️ Skip {}: empty or funding not due", position.user);
+            continue;
+        }
 
-    // 计算各方分配
-    let (user_remainder, liquidation_penalty, shortfall) = calculate_liquidation_result(margin, pnl);
+        let (payment, settled, period_start) =
+            apply_position_funding(&mut position, &market_config, funding_index_e6, current_ts)?;
+        position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
 
-    // 关闭仓位
-    position.size_e6 = 0;
-    position.margin_e6 = 0;
-    position.entry_price_e6 = 0;
-    position.liquidation_price_e6 = 0;
-    position.unrealized_pnl_e6 = 0;
-    position.last_update_ts = current_ts;
-    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+        if settled != 0 {
+            cpi::settle_funding(
+                vault_program.key,
+                vault_config_info.clone(),
+                user_account_info.clone(),
+                ledger_config_info.clone(),
+                settled,
+                signer_seeds,
+            )?;
+        }
+        record_user_funding_paid(program_id, user_stats_info, &position.user, settled)?;
+        summary.record(position.side, payment);
+        settled_count += 1;
 
-    // 派生 Ledger Config PDA 用于 CPI 签名
-    let (_, ledger_config_bump) = Pubkey::find_program_address(
-        &[b"ledger_config"],
+        events::emit_funding_event(&events::FundingEvent {
+            discriminator: events::event_discriminator::FUNDING,
+            sequence: ledger_config.next_sequence(),
+            timestamp: current_ts,
+            user: position.user,
+            market_index,
+            side: position.side as u8,
+            position_size_e6: position.size_e6,
+            funding_rate_e9,
+            payment_e6: payment,
+            mark_price_e6,
+            period_start,
+            period_end: current_ts,
+        });
+    }
+
+    ledger_config.last_update_ts = current_ts;
+    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    summary.serialize(&mut &mut summary_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "EVENT:FundingSettlement:market={},rate={},price={},accounts={},total_paid={},epoch={},ts={}",
+        summary.market_index,
+        summary.funding_rate_e6,
+        summary.index_price_e6,
+        summary.accounts_settled,
+        summary.total_funding_paid_e6,
+        summary.epoch,
+        summary.last_settled_at,
+    );
+    msg!(
+        "✅ SettleFundingBatch: market={}, epoch={}, settled={}/{}",
+        market_index,
+        epoch,
+        settled_count,
+        position_accounts.len() / 3
+    );
+    Ok(())
+}
+
+/// 读取 FundingSettlementSummary，不存在时创建 (Relayer 付费)
+fn ensure_funding_summary_exists<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    summary_info: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    market_index: u8,
+) -> Result<FundingSettlementSummary, ProgramError> {
+    let (summary_pda, bump) = Pubkey::find_program_address(
+        &[FundingSettlementSummary::SEED_PREFIX, &[market_index]],
         program_id,
     );
-    let bump_slice = [ledger_config_bump];
-    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
-    
-    // CPI 1: 更新用户账户 + 转移清算罚金到 Insurance Fund (Vault Program)
-    // 这个 CPI 会执行实际的 Token Transfer: Vault Token Account -> Insurance Fund Vault
-    cpi::liquidate_position(
-        &ledger_config.vault_program,
-        vault_config_info.clone(),
-        user_account_info.clone(),
-        ledger_config_info.clone(),
-        vault_token_account.clone(),
-        insurance_vault.clone(),
-        token_program.clone(),
-        margin,
-        user_remainder,
-        liquidation_penalty,
-        signer_seeds,
+    if summary_info.key != &summary_pda {
+        msg!("❌ Invalid FundingSettlementSummary PDA for market {}", market_index);
+        return Err(LedgerError::InvalidAccount.into());
+    }
+
+    if summary_info.data_len() > 0 {
+        if summary_info.owner != program_id {
+            return Err(LedgerError::InvalidAccount.into());
+        }
+        let summary = deserialize_account::<FundingSettlementSummary>(&summary_info.data.borrow())?;
+        if summary.discriminator != FundingSettlementSummary::DISCRIMINATOR || summary.market_index != market_index {
+            return Err(LedgerError::InvalidAccount.into());
+        }
+        return Ok(summary);
+    }
+
+    let rent = Rent::get()?;
+    let space = FundingSettlementSummary::SIZE;
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            summary_info.key,
+            rent.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[payer.clone(), summary_info.clone(), system_program.clone()],
+        &[&[FundingSettlementSummary::SEED_PREFIX, &[market_index], &[bump]]],
     )?;
-    
-    msg!(
-        "CPI: Liquidate user account - margin={}, remainder={}, penalty={}",
-        margin,
-        user_remainder,
-        liquidation_penalty
+
+    Ok(FundingSettlementSummary {
+        discriminator: FundingSettlementSummary::DISCRIMINATOR,
+        market_index,
+        epoch: 0,
+        funding_rate_e6: 0,
+        index_price_e6: 0,
+        accounts_settled: 0,
+        total_funding_paid_e6: 0,
+        net_funding_e6: 0,
+        first_settled_at: 0,
+        last_settled_at: 0,
+        bump,
+        reserved: [0u8; 32],
+    })
+}
+
+// ============================================================================
+// 资金费率计算指令处理
+// ============================================================================
+
+fn process_create_premium_samples(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    interest_rate_e6: i64,
+    premium_clamp_e6: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(premium_samples_info)?;
+
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
+    }
+
+    // 派生 PDA
+    let (premium_samples_pda, bump) = Pubkey::find_program_address(
+        &[PremiumSamples::SEED_PREFIX, &[market_index]],
+        program_id,
     );
-    
-    // CPI 2: 记录清算罚金到保险基金统计 (Fund Program)
-    // 注意: Token 已经在 CPI 1 中转移完成，这里只是更新统计
-    if liquidation_penalty > 0 {
-        cpi::add_liquidation_income(
-            fund_program.key,
-            ledger_config_info.clone(),
-            insurance_fund_account.clone(),
-            insurance_config.clone(),
-            liquidation_penalty as i64,
-            signer_seeds,
-        )?;
-        msg!("CPI: Liquidation penalty {} recorded in insurance fund stats", liquidation_penalty);
+    if premium_samples_info.key != &premium_samples_pda {
+        return Err(LedgerError::InvalidAccount.into());
     }
-    
-    // CPI 3: 覆盖穿仓 (Fund Program)
-    if shortfall > 0 {
-        cpi::cover_shortfall(
-            fund_program.key,
-            ledger_config_info.clone(),
-            insurance_fund_account.clone(),
-            insurance_config.clone(),
-            insurance_vault.clone(),
-            counterparty_vault.clone(),
-            token_program.clone(),
-            shortfall as i64,
-            signer_seeds,
-        )?;
-        msg!("CPI: Shortfall {} coverage requested from insurance fund", shortfall);
+    if premium_samples_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
     }
 
-    // 更新统计
-    ledger_config.total_liquidations += 1;
-    ledger_config.last_update_ts = current_ts;
-    let sequence = ledger_config.next_sequence();
-    let liq_pos_seq = ledger_config.next_sequence();
-    let liq_fee_seq = ledger_config.next_sequence();
-    let liq_ins_seq = ledger_config.next_sequence();
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    let premium_samples = PremiumSamples {
+        discriminator: PremiumSamples::DISCRIMINATOR,
+        market_index,
+        interest_rate_e6,
+        premium_clamp_e6,
+        head: 0,
+        count: 0,
+        last_funding_rate_e6: 0,
+        last_premium_twap_e6: 0,
+        last_index_price_e6: 0,
+        last_published_ts: 0,
+        published_count: 0,
+        bump,
+        created_at: get_current_timestamp()?,
+        reserved: [0u8; 32],
+        samples: [PremiumSample::default(); PREMIUM_SAMPLE_CAPACITY],
+    };
+    premium_samples.validate_params()?;
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = PremiumSamples::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            premium_samples_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), premium_samples_info.clone(), system_program.clone()],
+        &[&[PremiumSamples::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
 
-    // 更新用户统计
-    if user_stats_info.data_len() > 0 {
-        let user_stats_result = {
-            let data = user_stats_info.data.borrow();
-            deserialize_account::<UserStats>(&data)
-        };
-        
-        if let Ok(mut user_stats) = user_stats_result {
-            user_stats.total_liquidations += 1;
-            user_stats.total_realized_pnl_e6 = checked_add(user_stats.total_realized_pnl_e6, pnl)?;
-            user_stats.last_trade_at = current_ts;
-            user_stats.serialize(&mut &mut user_stats_info.data.borrow_mut()[..])?;
-        }
-    }
+    premium_samples.serialize(&mut &mut premium_samples_info.data.borrow_mut()[..])?;
 
-    // Emit LiquidationEvent
-    let side_u8 = match pre_liq_side { Side::Long => 0u8, Side::Short => 1u8 };
-    events::emit_liquidation_event(&events::LiquidationEvent {
-        discriminator: events::event_discriminator::LIQUIDATION,
-        sequence,
-        timestamp: current_ts,
-        user,
+    msg!(
+        "PremiumSamples created: market={}, interest={}, clamp={}",
         market_index,
-        side: side_u8,
-        position_size_e6: pre_liq_size,
-        entry_price_e6: pre_liq_entry,
-        mark_price_e6,
-        liquidation_price_e6: pre_liq_liq_price,
-        margin_e6: margin,
-        margin_ratio_e6: 0,
-        penalty_e6: liquidation_penalty,
-        insurance_payout_e6: shortfall,
-        remaining_margin_e6: user_remainder as i64,
-        is_bankruptcy: shortfall > 0,
-        realized_pnl_e6: pnl,
-        related_trade_sequence: sequence,
-    });
+        interest_rate_e6,
+        premium_clamp_e6
+    );
+    Ok(())
+}
 
-    emit_position_event(&PositionEvent {
-        discriminator: event_discriminator::POSITION,
-        sequence: liq_pos_seq,
-        timestamp: current_ts,
-        user,
-        market_index,
-        event_type: 4,
-        side_before: side_u8,
-        size_before_e6: pre_liq_size,
-        entry_price_before_e6: pre_liq_entry,
-        margin_before_e6: margin,
-        side_after: side_u8,
-        size_after_e6: 0,
-        entry_price_after_e6: 0,
-        margin_after_e6: 0,
-        size_delta_e6: -(pre_liq_size as i64),
-        realized_pnl_e6: pnl,
-        fee_e6: liquidation_penalty,
-        related_trade_sequence: sequence,
-    });
+fn process_set_funding_rate_params(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    interest_rate_e6: i64,
+    premium_clamp_e6: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    if liquidation_penalty > 0 {
-        emit_fee_event(&FeeEvent {
-            discriminator: event_discriminator::FEE,
-            sequence: liq_fee_seq,
-            timestamp: current_ts,
-            user,
-            market_index,
-            fee_type: 2,
-            amount_e6: liquidation_penalty as i64,
-            related_trade_sequence: sequence,
-        });
+    assert_signer(admin)?;
+    assert_writable(premium_samples_info)?;
 
-        emit_insurance_fund_event(&InsuranceFundEvent {
-            discriminator: event_discriminator::INSURANCE_FUND,
-            sequence: liq_ins_seq,
-            timestamp: current_ts,
-            event_type: 0,
-            market_index,
-            amount_e6: liquidation_penalty as i64,
-            balance_before_e6: 0,
-            balance_after_e6: 0,
-            related_user: user,
-            reason: 0,
-        });
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
+    let mut premium_samples = load_premium_samples(program_id, premium_samples_info, market_index)?;
+    premium_samples.interest_rate_e6 = interest_rate_e6;
+    premium_samples.premium_clamp_e6 = premium_clamp_e6;
+    premium_samples.validate_params()?;
+    premium_samples.serialize(&mut &mut premium_samples_info.data.borrow_mut()[..])?;
+
     msg!(
-        "Liquidation completed: user={}, market={}, mark_price={}, pnl={}, remainder={}, penalty={}, shortfall={}",
-        user, market_index, mark_price_e6, pnl, user_remainder, liquidation_penalty, shortfall
+        "Funding rate params updated: market={}, interest={}, clamp={}",
+        market_index,
+        interest_rate_e6,
+        premium_clamp_e6
     );
+    Ok(())
+}
 
-    if shortfall > 0 {
-        msg!("⚠️ Shortfall detected: {}, ADL may be required if insurance fund insufficient", shortfall);
-    }
+fn process_submit_premium_sample(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+    mark_price_e6: u64,
+    index_price_e6: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let relayer = next_account_info(account_info_iter)?;
+    let relayer_config_info = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
 
+    assert_authorized_relayer(program_id, relayer, relayer_config_info)?;
+    assert_writable(premium_samples_info)?;
+
+    let mut premium_samples = load_premium_samples(program_id, premium_samples_info, market_index)?;
+    let current_ts = get_current_timestamp()?;
+    premium_samples.push(mark_price_e6, index_price_e6, current_ts)?;
+    premium_samples.serialize(&mut &mut premium_samples_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "Premium sample: market={}, mark={}, index={}, samples={}",
+        market_index,
+        mark_price_e6,
+        index_price_e6,
+        premium_samples.count
+    );
     Ok(())
 }
 
-/// 计算清算结果
-/// 返回 (user_remainder, liquidation_penalty, shortfall)
-fn calculate_liquidation_result(margin: u64, pnl: i64) -> (u64, u64, u64) {
-    let margin_i = margin as i64;
-    let total = margin_i + pnl;
+fn process_publish_funding_rate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let caller = next_account_info(account_info_iter)?;
+    let premium_samples_info = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
 
-    if total <= 0 {
-        // 穿仓: 用户没有剩余，有穿仓损失
-        let shortfall = (-total) as u64;
-        (0, 0, shortfall)
-    } else {
-        // 有剩余: 计算罚金和用户剩余
-        let total_u = total as u64;
-        let penalty = mul_e6(total as i64, LIQUIDATION_PENALTY_RATE).unwrap_or(0) as u64; // 1% 罚金
-        let user_remainder = total_u.saturating_sub(penalty);
-        (user_remainder, penalty, 0)
+    assert_signer(caller)?;
+    assert_writable(premium_samples_info)?;
+
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    let mut premium_samples = load_premium_samples(program_id, premium_samples_info, market_index)?;
+    let current_ts = get_current_timestamp()?;
+
+    // 每个资金费周期发布一次
+    if premium_samples.published_count > 0 {
+        market_config.check_funding_due(premium_samples.last_published_ts, current_ts)?;
     }
+
+    let (premium_twap_e6, index_price_e6) = premium_samples
+        .twap_since(premium_samples.last_published_ts, current_ts)
+        .ok_or_else(|| {
+            msg!("❌ No premium samples since {}", premium_samples.last_published_ts);
+            LedgerError::NoPremiumSamples
+        })?;
+    let funding_rate_e6 = premium_samples.compute_funding_rate(premium_twap_e6, market_config.max_funding_rate_e6);
+
+    premium_samples.last_funding_rate_e6 = funding_rate_e6;
+    premium_samples.last_premium_twap_e6 = premium_twap_e6;
+    premium_samples.last_index_price_e6 = index_price_e6;
+    premium_samples.last_published_ts = current_ts;
+    premium_samples.published_count = premium_samples.published_count.saturating_add(1);
+    premium_samples.serialize(&mut &mut premium_samples_info.data.borrow_mut()[..])?;
+
+    msg!(
+        "Funding rate published: market={}, premium_twap={}, interest={}, rate={}, index_price={}",
+        market_index,
+        premium_twap_e6,
+        premium_samples.interest_rate_e6,
+        funding_rate_e6,
+        index_price_e6
+    );
+    Ok(())
 }
 
-/// P0-2/NEW-1 实现: 完整的 ADL 链上触发逻辑 + Fund Program CPI 集成
-/// 
-/// ADL (Auto-Deleveraging) 流程:
-/// 1. 验证保险基金确实不足以覆盖穿仓
-/// 2. 验证目标仓位是有效的反向盈利仓位
-/// 3. CPI 调用 Fund Program 设置 ADL 状态
-/// 4. 标记 ADL 状态并记录事件
-/// 5. 实际的平仓操作由链下引擎执行
-/// 
-/// 账户顺序:
-/// 0. admin (signer) - 管理员/Relayer
-/// 1. ledger_config_info (writable) - Ledger 全局配置
-/// 2. fund_program - Fund Program ID
-/// 3. insurance_config (writable) - InsuranceFundConfig PDA
-/// 4. fund_vault - Insurance Fund Vault (Token Account)
-/// 5..n. target_position_infos - 目标仓位账户
-fn process_trigger_adl(
+// ============================================================================
+// 标记价格指令处理
+// ============================================================================
+
+fn process_create_mark_price(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     market_index: u8,
-    shortfall_e6: u64,
-    bankrupt_side: Side,
+    ema_period_secs: i64,
+    max_staleness_secs: i64,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let admin = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
     let ledger_config_info = next_account_info(account_info_iter)?;
-    let fund_program = next_account_info(account_info_iter)?;
-    let insurance_config = next_account_info(account_info_iter)?;
-    let fund_vault = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
     assert_signer(admin)?;
-    assert_writable(ledger_config_info)?;
-    assert_writable(insurance_config)?;
-
-    // 读取配置
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-
-    // NEW-1: 验证 Fund Program
-    if fund_program.key != &ledger_config.fund_program {
-        msg!("❌ Invalid Fund Program: expected {}, got {}", ledger_config.fund_program, fund_program.key);
-        return Err(LedgerError::InvalidProgramId.into());
-    }
+    assert_writable(mark_price_info)?;
 
-    // P0-2: 验证是管理员或授权 Relayer
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
     if ledger_config.admin != *admin.key {
         return Err(LedgerError::InvalidAdmin.into());
     }
 
-    // P0-2: 验证程序未暂停
-    if ledger_config.is_paused {
-        return Err(LedgerError::LedgerPaused.into());
+    // 派生 PDA
+    let (mark_price_pda, bump) = Pubkey::find_program_address(
+        &[MarkPrice::SEED_PREFIX, &[market_index]],
+        program_id,
+    );
+    if mark_price_info.key != &mark_price_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if mark_price_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
     }
 
     let current_ts = get_current_timestamp()?;
+    let mark_price = MarkPrice {
+        discriminator: MarkPrice::DISCRIMINATOR,
+        market_index,
+        mark_price_e6: 0,
+        index_price_e6: 0,
+        basis_ema_e6: 0,
+        last_trade_price_e6: 0,
+        last_trade_ts: 0,
+        ema_period_secs,
+        max_staleness_secs,
+        last_update_ts: 0,
+        update_count: 0,
+        bump,
+        created_at: current_ts,
+        reserved: [0u8; 32],
+    };
+    mark_price.validate_params()?;
 
-    // NEW-1: 从 Fund Program 的 InsuranceFundConfig 读取保险基金余额
-    // InsuranceFundConfig 结构:
-    // - discriminator: u64 (8 bytes)
-    // - fund: Pubkey (32 bytes)
-    // - bump: u8 (1 byte)
-    // - total_liquidation_income_e6: i64 (8 bytes)
-    // - total_adl_profit_e6: i64 (8 bytes)
-    // - total_shortfall_payout_e6: i64 (8 bytes)
-    // - adl_trigger_threshold_e6: i64 (8 bytes)
-    // - adl_trigger_count: u64 (8 bytes)
-    // - balance_1h_ago_e6: i64 (8 bytes)
-    // ... 
-    // 我们需要从 fund_vault 读取实际余额
-    let insurance_balance_e6 = read_insurance_fund_balance_from_vault(fund_vault)?;
-    let insurance_config_data = read_insurance_fund_config(insurance_config)?;
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = MarkPrice::SIZE;
+    let lamports = rent.minimum_balance(space);
 
-    msg!(
-        "NEW-1 ADL Check: shortfall={}, insurance_balance={}, threshold={}",
-        shortfall_e6,
-        insurance_balance_e6,
-        insurance_config_data.adl_trigger_threshold_e6
-    );
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            mark_price_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), mark_price_info.clone(), system_program.clone()],
+        &[&[MarkPrice::SEED_PREFIX, &[market_index], &[bump]]],
+    )?;
 
-    // P0-2 步骤2: 验证保险基金确实不足
-    if insurance_balance_e6 >= shortfall_e6 as i64 {
-        msg!("⚠️ Insurance fund sufficient, ADL not required");
-        return Err(LedgerError::ADLNotRequired.into());
-    }
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
 
-    // P0-2 步骤3: 计算需要 ADL 覆盖的金额
-    let adl_required = shortfall_e6 as i64 - insurance_balance_e6;
     msg!(
-        "NEW-1 ADL Required: {} (shortfall={}, insurance={})",
-        adl_required,
-        shortfall_e6,
-        insurance_balance_e6
-    );
-
-    // P0-2 步骤4: 验证目标仓位
-    // 收集剩余的账户作为目标仓位
-    let mut validated_targets: Vec<Pubkey> = Vec::new();
-    let mut total_available_pnl: i64 = 0;
-    let counterparty_side = bankrupt_side.opposite();
-
-    for target_info in account_info_iter {
-        // 尝试反序列化为 Position
-        if let Ok(position) = deserialize_account::<Position>(&target_info.data.borrow()) {
-            // 验证: 必须是同市场
-            if position.market_index != market_index {
-                msg!("⚠️ Position {} wrong market, skipping", target_info.key);
-                continue;
-            }
-
-            // 验证: 必须是反向方向
-            if position.side != counterparty_side {
-                msg!("⚠️ Position {} wrong side, skipping", target_info.key);
-                continue;
-            }
-
-            // 验证: 必须有盈利 (unrealized_pnl > 0)
-            if position.unrealized_pnl_e6 <= 0 {
-                msg!("⚠️ Position {} no profit, skipping", target_info.key);
-                continue;
-            }
+        "MarkPrice created: market={}, ema_period={}s, max_staleness={}s",
+        market_index,
+        ema_period_secs,
+        max_staleness_secs
+    );
+    Ok(())
+}
 
-            // 验证通过
-            validated_targets.push(*target_info.key);
-            total_available_pnl += position.unrealized_pnl_e6;
+fn process_update_mark_price(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let caller = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+    let price_feed_info = next_account_info(account_info_iter)?;
 
-            msg!(
-                "✅ ADL Target validated: {}, pnl={}",
-                target_info.key,
-                position.unrealized_pnl_e6
-            );
-        }
-    }
+    assert_signer(caller)?;
+    assert_writable(mark_price_info)?;
 
-    // P0-2: 验证是否有足够的目标仓位
-    if validated_targets.is_empty() {
-        msg!("❌ No valid ADL targets found");
-        return Err(LedgerError::NoOpposingPositionsForADL.into());
-    }
+    let current_ts = get_current_timestamp()?;
+    let index_price_e6 = load_price_feed(program_id, price_feed_info, market_index)?.checked_price(current_ts)?;
+    let mut mark_price = load_mark_price(program_id, mark_price_info, market_index)?;
+    let mark_price_e6 = mark_price.update(index_price_e6, current_ts)?;
+    mark_price.serialize(&mut &mut mark_price_info.data.borrow_mut()[..])?;
 
     msg!(
-        "NEW-1 ADL Targets: {} positions, total_pnl={}",
-        validated_targets.len(),
-        total_available_pnl
+        "MarkPrice updated: market={}, index={}, basis_ema={}, last_trade={}, mark={}",
+        market_index,
+        index_price_e6,
+        mark_price.basis_ema_e6,
+        mark_price.last_trade_price_e6,
+        mark_price_e6
     );
+    Ok(())
+}
 
-    // NEW-1 步骤5: CPI 调用 Fund Program SetADLInProgress
-    // 派生 Ledger Config PDA 用于 CPI 签名
-    let (_, ledger_config_bump) = Pubkey::find_program_address(
-        &[b"ledger_config"],
+fn process_check_position_health(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    user: Pubkey,
+    market_index: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let position_info = next_account_info(account_info_iter)?;
+    let market_config_info = next_account_info(account_info_iter)?;
+    let funding_state_info = next_account_info(account_info_iter)?;
+    let mark_price_info = next_account_info(account_info_iter)?;
+
+    let (position_pda, _) = Pubkey::find_program_address(
+        &[b"position", user.as_ref(), &[market_index]],
         program_id,
     );
-    let bump_slice = [ledger_config_bump];
-    let signer_seeds = &[&[b"ledger_config".as_ref(), bump_slice.as_ref()][..]];
-    
-    cpi::set_adl_in_progress(
-        fund_program.key,
-        ledger_config_info.clone(),
-        insurance_config.clone(),
-        true, // Set ADL in progress = true
-        signer_seeds,
-    )?;
-    
-    msg!("✅ NEW-1: CPI SetADLInProgress(true) - LP redemptions paused");
-
-    // P0-2 步骤6: 更新 ADL 状态
-    ledger_config.total_adl_count += 1;
-    ledger_config.last_update_ts = current_ts;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    if position_info.key != &position_pda || position_info.owner != program_id {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
+    if position.user != user || position.market_index != market_index || position.is_empty() {
+        return Err(LedgerError::PositionNotFound.into());
+    }
 
-    // P0-2 步骤7: 发出 ADL 触发事件
-    msg!("🚨 ADL_TRIGGERED_EVENT:");
-    msg!("  market_index: {}", market_index);
-    msg!("  shortfall_e6: {}", shortfall_e6);
-    msg!("  insurance_balance_e6: {}", insurance_balance_e6);
-    msg!("  adl_required_e6: {}", adl_required);
-    msg!("  bankrupt_side: {:?}", bankrupt_side);
-    msg!("  target_count: {}", validated_targets.len());
-    msg!("  total_available_pnl_e6: {}", total_available_pnl);
-    msg!("  timestamp: {}", current_ts);
-    msg!("  adl_count: {}", ledger_config.total_adl_count);
+    // 只在内存中计提资金费和刷新清算价格，不写回仓位
+    let current_ts = get_current_timestamp()?;
+    let market_config = load_market_config(program_id, market_config_info, market_index)?;
+    let funding_state = load_funding_state(program_id, funding_state_info, market_index)?;
+    position.accrue_funding(funding_state.cumulative_funding_index_e6)?;
+    position.refresh_liquidation_price(&market_config)?;
+    let mark_price_e6 = load_mark_price(program_id, mark_price_info, market_index)?.checked_price(current_ts)?;
 
-    // Emit structured ADL event
-    let adl_event = events::ADLEvent {
-        discriminator: events::event_discriminator::ADL,
-        sequence: ledger_config.total_adl_count,
-        timestamp: current_ts,
+    let health = PositionHealth {
+        user,
         market_index,
-        trigger_reason: events::ADLTriggerReason::Bankruptcy as u8,
-        shortfall_e6,
-        insurance_balance_before_e6: insurance_balance_e6,
-        insurance_balance_after_e6: insurance_balance_e6,
-        bankrupt_user: Pubkey::default(), // TriggerADL does not receive bankrupt user account
-        bankrupt_side: match bankrupt_side { Side::Long => 0, Side::Short => 1 },
-        bankrupt_size_e6: 0,
-        counterparty_user: if validated_targets.is_empty() { Pubkey::default() } else { validated_targets[0] },
-        counterparty_side: match bankrupt_side { Side::Long => 1, Side::Short => 0 },
-        counterparty_size_reduced_e6: 0,
-        counterparty_pnl_e6: total_available_pnl,
-        related_trade_sequence: 0,
+        mark_price_e6,
+        unrealized_pnl_e6: position.calculate_unrealized_pnl(mark_price_e6)?,
+        margin_ratio_e6: position.margin_ratio_e6(mark_price_e6)?,
+        maintenance_margin_rate_e6: market_config
+            .risk_tier_for(position.notional_value_e6()?)?
+            .maintenance_margin_rate_e6,
+        liquidation_price_e6: position.liquidation_price_e6,
+        is_liquidatable: position.should_liquidate(mark_price_e6),
     };
-    events::emit_adl_event(&adl_event);
+    set_return_data(&health.try_to_vec()?);
 
+    msg!(
+        "Position health: user={}, market={}, mark={}, upnl={}, margin_ratio={}, mmr={}, liq_price={}, liquidatable={}",
+        user,
+        market_index,
+        health.mark_price_e6,
+        health.unrealized_pnl_e6,
+        health.margin_ratio_e6,
+        health.maintenance_margin_rate_e6,
+        health.liquidation_price_e6,
+        health.is_liquidatable
+    );
     Ok(())
 }
 
-/// NEW-1: InsuranceFundConfig 数据 (用于读取)
-struct InsuranceFundConfigData {
-    #[allow(dead_code)]
-    discriminator: u64,
-    #[allow(dead_code)]
-    fund: Pubkey,
-    #[allow(dead_code)]
-    bump: u8,
-    #[allow(dead_code)]
-    total_liquidation_income_e6: i64,
-    #[allow(dead_code)]
-    total_adl_profit_e6: i64,
-    #[allow(dead_code)]
-    total_shortfall_payout_e6: i64,
-    adl_trigger_threshold_e6: i64,
-    #[allow(dead_code)]
-    adl_trigger_count: u64,
-    #[allow(dead_code)]
-    balance_1h_ago_e6: i64,
-    #[allow(dead_code)]
-    last_snapshot_ts: i64,
-    #[allow(dead_code)]
-    withdrawal_delay_secs: i64,
-    #[allow(dead_code)]
-    is_adl_in_progress: bool,
-}
+// ============================================================================
+// 手续费分配
+// ============================================================================
 
-/// NEW-1: 从 Fund Program 的 InsuranceFundConfig 读取配置
-fn read_insurance_fund_config(insurance_config: &AccountInfo) -> Result<InsuranceFundConfigData, ProgramError> {
-    let data = insurance_config.data.borrow();
-    
-    // InsuranceFundConfig 最小大小检查
-    // discriminator(8) + fund(32) + bump(1) + 6*i64(48) + u64(8) + i64(8) + i64(8) + bool(1) + pubkey(32) + i64(8)
-    // = 8 + 32 + 1 + 48 + 8 + 8 + 8 + 1 + 32 + 8 = 154 bytes minimum
-    if data.len() < 154 {
-        msg!("InsuranceFundConfig account too small: {}", data.len());
-        return Err(LedgerError::InvalidAccount.into());
+fn process_create_fee_schedule(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    insurance_bps: u16,
+    treasury_bps: u16,
+    referrer_bps: u16,
+    maker_rebate_bps: u16,
+    referee_discount_bps: u16,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let fee_schedule_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    assert_signer(admin)?;
+    assert_writable(fee_schedule_info)?;
+
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
-    
-    // 读取 discriminator
-    let discriminator = u64::from_le_bytes(data[0..8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    
-    // 验证 discriminator (0x1024_1024_0004 for InsuranceFundConfig)
-    // 这里我们跳过严格验证，因为可能有不同的 discriminator 值
-    
-    let mut offset = 8;
-    
-    // fund: Pubkey (32 bytes)
-    let fund = Pubkey::try_from(&data[offset..offset+32]).map_err(|_| LedgerError::InvalidAccount)?;
-    offset += 32;
-    
-    // bump: u8 (1 byte)
-    let bump = data[offset];
-    offset += 1;
-    
-    // total_liquidation_income_e6: i64
-    let total_liquidation_income_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // total_adl_profit_e6: i64
-    let total_adl_profit_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // total_shortfall_payout_e6: i64
-    let total_shortfall_payout_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // adl_trigger_threshold_e6: i64
-    let adl_trigger_threshold_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // adl_trigger_count: u64
-    let adl_trigger_count = u64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // balance_1h_ago_e6: i64
-    let balance_1h_ago_e6 = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // last_snapshot_ts: i64
-    let last_snapshot_ts = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // withdrawal_delay_secs: i64
-    let withdrawal_delay_secs = i64::from_le_bytes(data[offset..offset+8].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    offset += 8;
-    
-    // is_adl_in_progress: bool
-    let is_adl_in_progress = data[offset] != 0;
-    
-    Ok(InsuranceFundConfigData {
-        discriminator,
-        fund,
-        bump,
-        total_liquidation_income_e6,
-        total_adl_profit_e6,
-        total_shortfall_payout_e6,
-        adl_trigger_threshold_e6,
-        adl_trigger_count,
-        balance_1h_ago_e6,
-        last_snapshot_ts,
-        withdrawal_delay_secs,
-        is_adl_in_progress,
-    })
-}
 
-/// NEW-1: 从 Fund Vault (SPL Token Account) 读取实际余额
-fn read_insurance_fund_balance_from_vault(fund_vault: &AccountInfo) -> Result<i64, ProgramError> {
-    // SPL Token Account 结构:
-    // - mint: Pubkey (32 bytes)
-    // - owner: Pubkey (32 bytes)
-    // - amount: u64 (8 bytes) <- 我们需要这个
-    // - ...
-    
-    let data = fund_vault.data.borrow();
-    if data.len() < 72 {
-        msg!("Fund vault account too small: {}", data.len());
+    // 派生 PDA
+    let (fee_schedule_pda, bump) = Pubkey::find_program_address(&[FeeSchedule::SEED_PREFIX], program_id);
+    if fee_schedule_info.key != &fee_schedule_pda {
         return Err(LedgerError::InvalidAccount.into());
     }
-    
-    // 读取 amount (offset 64)
-    let amount = u64::from_le_bytes(data[64..72].try_into().map_err(|_| LedgerError::InvalidAccount)?);
-    
-    // 转换为 i64 (安全，因为余额不会超过 i64::MAX)
-    Ok(amount as i64)
-}
+    if fee_schedule_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
+    }
+
+    let current_ts = get_current_timestamp()?;
+    let fee_schedule = FeeSchedule {
+        discriminator: FeeSchedule::DISCRIMINATOR,
+        insurance_bps,
+        treasury_bps,
+        referrer_bps,
+        maker_rebate_bps,
+        total_insurance_e6: 0,
+        total_treasury_e6: 0,
+        total_referrer_e6: 0,
+        total_maker_rebate_e6: 0,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        referee_discount_bps,
+        reserved: [0u8; 62],
+    };
+    fee_schedule.validate_params()?;
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = FeeSchedule::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            fee_schedule_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), fee_schedule_info.clone(), system_program.clone()],
+        &[&[FeeSchedule::SEED_PREFIX, &[bump]]],
+    )?;
 
-// ============================================================================
-// 资金费率处理
-// ============================================================================
+    fee_schedule.serialize(&mut &mut fee_schedule_info.data.borrow_mut()[..])?;
 
-fn process_settle_funding(
+    msg!(
+        "FeeSchedule created: insurance={}bps, treasury={}bps, referrer={}bps, maker_rebate={}bps, referee_discount={}bps",
+        insurance_bps,
+        treasury_bps,
+        referrer_bps,
+        maker_rebate_bps,
+        referee_discount_bps
+    );
+    Ok(())
+}
+
+fn process_update_fee_schedule(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    user: Pubkey,
-    market_index: u8,
-    funding_rate_e6: i64,
-    index_price_e6: u64,
+    insurance_bps: u16,
+    treasury_bps: u16,
+    referrer_bps: u16,
+    maker_rebate_bps: u16,
+    referee_discount_bps: u16,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let position_info = next_account_info(account_info_iter)?;
-    let user_account_info = next_account_info(account_info_iter)?;
-    let vault_config_info = next_account_info(account_info_iter)?;
-    let _vault_program = next_account_info(account_info_iter)?;
+    let admin = next_account_info(account_info_iter)?;
+    let fee_schedule_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
-    assert_signer(relayer)?;
-    assert_writable(position_info)?;
-    assert_writable(user_account_info)?;
+    assert_signer(admin)?;
+    assert_writable(fee_schedule_info)?;
 
-    // 读取仓位
-    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-    if position.user != user || position.market_index != market_index {
-        return Err(LedgerError::PositionNotFound.into());
-    }
-    if position.is_empty() {
-        return Err(LedgerError::PositionNotFound.into());
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
+        return Err(LedgerError::InvalidAdmin.into());
     }
 
-    let current_ts = get_current_timestamp()?;
-
-    // 计算资金费
-    // funding_payment = position_value * funding_rate
-    // Long 支付 Short (funding_rate > 0)
-    // Short 支付 Long (funding_rate < 0)
-    let position_value = (position.size_e6 as i128)
-        .checked_mul(index_price_e6 as i128)
-        .ok_or(LedgerError::Overflow)?
-        .checked_div(1_000_000)
-        .ok_or(LedgerError::Overflow)? as i64;
-
-    let funding_payment = mul_e6(position_value, funding_rate_e6)?;
-
-    // 根据方向调整符号
-    let actual_payment = match position.side {
-        Side::Long => funding_payment,  // Long 支付正 funding
-        Side::Short => -funding_payment, // Short 收取正 funding
-    };
-
-    // 更新仓位
-    position.cumulative_funding_e6 = checked_add(position.cumulative_funding_e6, actual_payment)?;
-    position.last_funding_ts = current_ts;
-    position.last_update_ts = current_ts;
-    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
-
-    // CPI 调用 Vault 更新用户余额
-    // 从用户账户扣除/增加 funding_payment
-    // 读取 LedgerConfig 获取 vault_program
-    let ledger_config = deserialize_account::<LedgerConfig>(&vault_config_info.data.borrow())
-        .ok()
-        .map(|c| c.vault_program);
-    
-    // 资金费率结算通过更新用户持仓记录完成
-    // 实际的资金转移在平仓时一并结算
-    // TODO: 如果需要实时结算资金费率，需要添加对应的 Vault CPI
-    msg!("Funding payment recorded: {}", actual_payment);
-
-    // Emit FundingEvent
-    let side_u8 = match position.side { Side::Long => 0u8, Side::Short => 1u8 };
-    events::emit_funding_event(&events::FundingEvent {
-        discriminator: events::event_discriminator::FUNDING,
-        sequence: 0, // no LedgerConfig available for sequence here
-        timestamp: current_ts,
-        user,
-        market_index,
-        side: side_u8,
-        position_size_e6: position.size_e6,
-        funding_rate_e9: funding_rate_e6 * 1000, // e6 to e9
-        payment_e6: actual_payment,
-        mark_price_e6: index_price_e6,
-        period_start: position.last_funding_ts,
-        period_end: current_ts,
-    });
+    let mut fee_schedule = load_fee_schedule(program_id, fee_schedule_info)?;
+    fee_schedule.insurance_bps = insurance_bps;
+    fee_schedule.treasury_bps = treasury_bps;
+    fee_schedule.referrer_bps = referrer_bps;
+    fee_schedule.maker_rebate_bps = maker_rebate_bps;
+    fee_schedule.referee_discount_bps = referee_discount_bps;
+    fee_schedule.validate_params()?;
+    fee_schedule.last_update_ts = get_current_timestamp()?;
+    fee_schedule.serialize(&mut &mut fee_schedule_info.data.borrow_mut()[..])?;
 
     msg!(
-        "Funding settled: user={}, market={}, rate={}, payment={}",
-        user, market_index, funding_rate_e6, actual_payment
+        "FeeSchedule updated: insurance={}bps, treasury={}bps, referrer={}bps, maker_rebate={}bps, referee_discount={}bps",
+        insurance_bps,
+        treasury_bps,
+        referrer_bps,
+        maker_rebate_bps,
+        referee_discount_bps
     );
-
     Ok(())
 }
 
 // ============================================================================
-// 管理指令处理
+// 手续费档位
 // ============================================================================
 
-fn process_add_relayer(accounts: &[AccountInfo], relayer: Pubkey) -> ProgramResult {
+fn process_create_fee_tier_table(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tiers: Vec<FeeTier>,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let admin = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
+    let fee_tier_table_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
     assert_signer(admin)?;
-    assert_writable(relayer_config_info)?;
-
-    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    assert_writable(fee_tier_table_info)?;
 
-    if relayer_config.admin != *admin.key {
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
         return Err(LedgerError::InvalidAdmin.into());
     }
 
-    if relayer_config.authorized_relayers.len() >= MAX_RELAYERS {
+    // 派生 PDA
+    let (fee_tier_table_pda, bump) = Pubkey::find_program_address(&[FeeTierTable::SEED_PREFIX], program_id);
+    if fee_tier_table_info.key != &fee_tier_table_pda {
         return Err(LedgerError::InvalidAccount.into());
     }
-
-    if !relayer_config.authorized_relayers.contains(&relayer) {
-        relayer_config.authorized_relayers.push(relayer);
-        relayer_config.last_update_ts = get_current_timestamp()?;
-        relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
-        msg!("Added relayer: {}", relayer);
+    if fee_tier_table_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
     }
 
+    let current_ts = get_current_timestamp()?;
+    let fee_tier_table = FeeTierTable {
+        discriminator: FeeTierTable::DISCRIMINATOR,
+        tiers,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        reserved: [0u8; 32],
+    };
+    fee_tier_table.validate()?;
+
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = FeeTierTable::SIZE;
+    let lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            fee_tier_table_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), fee_tier_table_info.clone(), system_program.clone()],
+        &[&[FeeTierTable::SEED_PREFIX, &[bump]]],
+    )?;
+
+    fee_tier_table.serialize(&mut &mut fee_tier_table_info.data.borrow_mut()[..])?;
+
+    for (i, tier) in fee_tier_table.tiers.iter().enumerate() {
+        msg!(
+            "  Tier {}: volume>={}, maker={}, taker={}",
+            i, tier.min_volume_e6, tier.maker_fee_rate_e6, tier.taker_fee_rate_e6
+        );
+    }
+    msg!("FeeTierTable created: tiers={}", fee_tier_table.tiers.len());
     Ok(())
 }
 
-fn process_remove_relayer(accounts: &[AccountInfo], relayer: Pubkey) -> ProgramResult {
+fn process_update_fee_tier_table(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tiers: Vec<FeeTier>,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let admin = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
+    let fee_tier_table_info = next_account_info(account_info_iter)?;
+    let ledger_config_info = next_account_info(account_info_iter)?;
 
     assert_signer(admin)?;
-    assert_writable(relayer_config_info)?;
-
-    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    assert_writable(fee_tier_table_info)?;
 
-    if relayer_config.admin != *admin.key {
+    // 验证 Admin
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.admin != *admin.key {
         return Err(LedgerError::InvalidAdmin.into());
     }
 
-    relayer_config.authorized_relayers.retain(|&r| r != relayer);
-    relayer_config.last_update_ts = get_current_timestamp()?;
-    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+    let mut fee_tier_table = load_fee_tier_table(program_id, fee_tier_table_info)?;
+    fee_tier_table.tiers = tiers;
+    fee_tier_table.validate()?;
 
-    msg!("Removed relayer: {}", relayer);
+    fee_tier_table.last_update_ts = get_current_timestamp()?;
+    fee_tier_table.serialize(&mut &mut fee_tier_table_info.data.borrow_mut()[..])?;
+
+    for (i, tier) in fee_tier_table.tiers.iter().enumerate() {
+        msg!(
+            "  Tier {}: volume>={}, maker={}, taker={}",
+            i, tier.min_volume_e6, tier.maker_fee_rate_e6, tier.taker_fee_rate_e6
+        );
+    }
+    msg!("FeeTierTable updated: tiers={}", fee_tier_table.tiers.len());
     Ok(())
 }
 
-fn process_update_required_signatures(accounts: &[AccountInfo], required_signatures: u8) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let admin = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
+// ============================================================================
+// 推荐返佣
+// ============================================================================
 
-    assert_signer(admin)?;
-    assert_writable(relayer_config_info)?;
+fn process_create_referrer_code(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    code: ReferralCode,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let referrer = next_account_info(account_info_iter)?;
+    let referrer_stats_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
-    let mut relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
+    assert_signer(referrer)?;
+    assert_writable(referrer_stats_info)?;
 
-    if relayer_config.admin != *admin.key {
-        return Err(LedgerError::InvalidAdmin.into());
+    if code == [0u8; 16] {
+        return Err(LedgerError::InvalidReferralCode.into());
     }
 
-    if required_signatures == 0 || required_signatures as usize > relayer_config.authorized_relayers.len() {
-        return Err(LedgerError::InsufficientSignatures.into());
+    // 派生 PDA
+    let (referrer_stats_pda, bump) = Pubkey::find_program_address(&[ReferrerStats::SEED_PREFIX, &code], program_id);
+    if referrer_stats_info.key != &referrer_stats_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if referrer_stats_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
     }
 
-    relayer_config.required_signatures = required_signatures;
-    relayer_config.last_update_ts = get_current_timestamp()?;
-    relayer_config.serialize(&mut &mut relayer_config_info.data.borrow_mut()[..])?;
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = ReferrerStats::SIZE;
+    let lamports = rent.minimum_balance(space);
 
-    msg!("Updated required signatures to: {}", required_signatures);
+    invoke_signed(
+        &system_instruction::create_account(
+            referrer.key,
+            referrer_stats_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[referrer.clone(), referrer_stats_info.clone(), system_program.clone()],
+        &[&[ReferrerStats::SEED_PREFIX, &code, &[bump]]],
+    )?;
+
+    let current_ts = get_current_timestamp()?;
+    let referrer_stats = ReferrerStats {
+        discriminator: ReferrerStats::DISCRIMINATOR,
+        referrer: *referrer.key,
+        code,
+        referee_count: 0,
+        total_earned_e6: 0,
+        claimable_e6: 0,
+        total_claimed_e6: 0,
+        bump,
+        created_at: current_ts,
+        last_update_ts: current_ts,
+        reserved: [0u8; 32],
+    };
+    referrer_stats.serialize(&mut &mut referrer_stats_info.data.borrow_mut()[..])?;
+
+    msg!("ReferrerCode created: referrer={}", referrer.key);
     Ok(())
 }
 
-fn process_set_paused(accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+fn process_bind_referral(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    code: ReferralCode,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let admin = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
+    let user = next_account_info(account_info_iter)?;
+    let referral_info = next_account_info(account_info_iter)?;
+    let referrer_stats_info = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
-    assert_signer(admin)?;
-    assert_writable(ledger_config_info)?;
+    assert_signer(user)?;
+    assert_writable(referral_info)?;
+    assert_writable(referrer_stats_info)?;
 
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    let mut referrer_stats = load_referrer_stats(program_id, referrer_stats_info, &code)?;
+    if referrer_stats.referrer == *user.key {
+        return Err(LedgerError::SelfReferral.into());
+    }
 
-    if ledger_config.admin != *admin.key {
-        return Err(LedgerError::InvalidAdmin.into());
+    // 派生 PDA (已绑定则不可更改)
+    let (referral_pda, bump) = Pubkey::find_program_address(&[Referral::SEED_PREFIX, user.key.as_ref()], program_id);
+    if referral_info.key != &referral_pda {
+        return Err(LedgerError::InvalidAccount.into());
+    }
+    if referral_info.data_len() > 0 {
+        return Err(LedgerError::AlreadyInitialized.into());
     }
 
-    ledger_config.is_paused = paused;
-    ledger_config.last_update_ts = get_current_timestamp()?;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    // 创建账户
+    let rent = Rent::get()?;
+    let space = Referral::SIZE;
+    let lamports = rent.minimum_balance(space);
 
-    msg!("Ledger {}", if paused { "paused" } else { "resumed" });
+    invoke_signed(
+        &system_instruction::create_account(
+            user.key,
+            referral_info.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[user.clone(), referral_info.clone(), system_program.clone()],
+        &[&[Referral::SEED_PREFIX, user.key.as_ref(), &[bump]]],
+    )?;
+
+    let current_ts = get_current_timestamp()?;
+    let referral = Referral {
+        discriminator: Referral::DISCRIMINATOR,
+        user: *user.key,
+        referrer: referrer_stats.referrer,
+        code,
+        bump,
+        created_at: current_ts,
+        reserved: [0u8; 16],
+    };
+    referral.serialize(&mut &mut referral_info.data.borrow_mut()[..])?;
+
+    referrer_stats.referee_count = referrer_stats.referee_count.saturating_add(1);
+    referrer_stats.last_update_ts = current_ts;
+    referrer_stats.serialize(&mut &mut referrer_stats_info.data.borrow_mut()[..])?;
+
+    msg!("Referral bound: user={}, referrer={}", user.key, referral.referrer);
     Ok(())
 }
 
-fn process_update_admin(accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+fn process_claim_referral_rewards(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    code: ReferralCode,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let current_admin = next_account_info(account_info_iter)?;
+    let referrer = next_account_info(account_info_iter)?;
+    let referrer_stats_info = next_account_info(account_info_iter)?;
+    let referrer_account_info = next_account_info(account_info_iter)?;
+    let vault_config_info = next_account_info(account_info_iter)?;
     let ledger_config_info = next_account_info(account_info_iter)?;
+    let vault_program = next_account_info(account_info_iter)?;
 
-    assert_signer(current_admin)?;
-    assert_writable(ledger_config_info)?;
+    assert_signer(referrer)?;
+    assert_writable(referrer_stats_info)?;
+    assert_writable(referrer_account_info)?;
 
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    let ledger_config = load_ledger_config(program_id, ledger_config_info)?;
+    if ledger_config.is_paused {
+        return Err(LedgerError::LedgerPaused.into());
+    }
+    if vault_program.key != &ledger_config.vault_program {
+        return Err(LedgerError::InvalidVaultProgram.into());
+    }
 
-    if ledger_config.admin != *current_admin.key {
-        return Err(LedgerError::InvalidAdmin.into());
+    let mut referrer_stats = load_referrer_stats(program_id, referrer_stats_info, &code)?;
+    if referrer_stats.referrer != *referrer.key {
+        return Err(LedgerError::InvalidReferralCode.into());
+    }
+    // 分成只能记入推荐人自己的 Vault 账户
+    if cpi::read_user_account(referrer_account_info)?.wallet != *referrer.key {
+        return Err(LedgerError::InvalidAccount.into());
     }
 
-    ledger_config.admin = new_admin;
-    ledger_config.last_update_ts = get_current_timestamp()?;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    let current_ts = get_current_timestamp()?;
+    let amount_e6 = referrer_stats.claim(current_ts)?;
+    referrer_stats.serialize(&mut &mut referrer_stats_info.data.borrow_mut()[..])?;
 
-    msg!("Admin updated to: {}", new_admin);
+    // CPI: Vault 从已收取的手续费中划转到推荐人账户
+    let (_, ledger_config_bump) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+    cpi::credit_referral_reward(
+        vault_program.key,
+        vault_config_info.clone(),
+        referrer_account_info.clone(),
+        ledger_config_info.clone(),
+        amount_e6,
+        &[&[b"ledger_config", &[ledger_config_bump]]],
+    )?;
+
+    msg!(
+        "Referral rewards claimed: referrer={}, amount={}, total_claimed={}",
+        referrer.key,
+        amount_e6,
+        referrer_stats.total_claimed_e6
+    );
     Ok(())
 }
 
-fn process_update_vault_program(accounts: &[AccountInfo], new_vault_program: Pubkey) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let admin = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::TradeLeg;
+    use crate::state::tests::{test_market_config, test_position};
+    use solana_program::{
+        clock::Clock,
+        entrypoint::SUCCESS,
+        instruction::Instruction,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+    };
+    use std::{cell::RefCell, sync::Once};
 
-    assert_signer(admin)?;
-    assert_writable(ledger_config_info)?;
+    const TEST_TS: i64 = 1_700_000_000;
 
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    thread_local! {
+        /// 当前测试线程发出的 CPI 指令
+        static CPI_LOG: RefCell<Vec<Instruction>> = const { RefCell::new(Vec::new()) };
+        /// 当前测试线程输出的程序日志 (msg!)
+        static PROGRAM_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
 
-    if ledger_config.admin != *admin.key {
-        return Err(LedgerError::InvalidAdmin.into());
+    /// 链下 syscall: 固定时钟，CPI 只记录不执行，日志只记录不输出
+    struct TestSyscalls;
+
+    impl SyscallStubs for TestSyscalls {
+        fn sol_log(&self, message: &str) {
+            PROGRAM_LOG.with(|log| log.borrow_mut().push(message.to_string()));
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock { unix_timestamp: TEST_TS, ..Clock::default() };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            _account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            CPI_LOG.with(|log| log.borrow_mut().push(instruction.clone()));
+            Ok(())
+        }
     }
 
-    ledger_config.vault_program = new_vault_program;
-    ledger_config.last_update_ts = get_current_timestamp()?;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    fn init_syscalls() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscalls));
+        });
+        CPI_LOG.with(|log| log.borrow_mut().clear());
+        PROGRAM_LOG.with(|log| log.borrow_mut().clear());
+    }
 
-    msg!("Vault program updated to: {}", new_vault_program);
-    Ok(())
-}
+    /// 解码本线程日志中名为 event_name 的事件 (EVENT:<name>:<base64>)
+    fn logged_events<T: BorshDeserialize>(event_name: &str) -> Vec<T> {
+        let prefix = format!("{}:{}:", events::EVENT_PREFIX, event_name);
+        PROGRAM_LOG.with(|log| {
+            log.borrow()
+                .iter()
+                .filter_map(|line| line.strip_prefix(&prefix))
+                .map(|encoded| {
+                    let mut data = Vec::new();
+                    let mut bits = 0u32;
+                    let mut bit_count = 0;
+                    for c in encoded.bytes().take_while(|&c| c != b'=') {
+                        let value = match c {
+                            b'A'..=b'Z' => c - b'A',
+                            b'a'..=b'z' => c - b'a' + 26,
+                            b'0'..=b'9' => c - b'0' + 52,
+                            b'+' => 62,
+                            _ => 63,
+                        };
+                        bits = (bits << 6) | value as u32;
+                        bit_count += 6;
+                        if bit_count >= 8 {
+                            bit_count -= 8;
+                            data.push((bits >> bit_count) as u8);
+                        }
+                    }
+                    T::try_from_slice(&data).unwrap()
+                })
+                .collect()
+        })
+    }
 
-fn process_update_fund_program(accounts: &[AccountInfo], new_fund_program: Pubkey) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let admin = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
+    /// 取出本线程记录的、发往 program_id 的 CPI (tag, 参数字节)
+    fn take_cpis(program_id: &Pubkey) -> Vec<(u8, Vec<u8>)> {
+        CPI_LOG.with(|log| {
+            let (taken, rest) = log.borrow_mut().drain(..).partition(|ix| ix.program_id == *program_id);
+            *log.borrow_mut() = rest;
+            taken.into_iter().map(|ix: Instruction| (ix.data[0], ix.data[1..].to_vec())).collect()
+        })
+    }
 
-    assert_signer(admin)?;
-    assert_writable(ledger_config_info)?;
+    fn cpi_u64(args: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(args[offset..offset + 8].try_into().unwrap())
+    }
 
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
+    fn cpi_i64(args: &[u8], offset: usize) -> i64 {
+        i64::from_le_bytes(args[offset..offset + 8].try_into().unwrap())
+    }
 
-    if ledger_config.admin != *admin.key {
-        return Err(LedgerError::InvalidAdmin.into());
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        is_signer: bool,
     }
 
-    ledger_config.fund_program = new_fund_program;
-    ledger_config.last_update_ts = get_current_timestamp()?;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            Self { key, owner, lamports: 1_000_000_000, data, is_signer: false }
+        }
 
-    msg!("Fund program updated to: {}", new_fund_program);
-    Ok(())
-}
+        fn signer(key: Pubkey) -> Self {
+            Self { is_signer: true, ..Self::new(key, solana_program::system_program::id(), vec![]) }
+        }
 
-// ============================================================================
-// Admin 工具指令
-// ============================================================================
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                true,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
 
-/// 🔧 Admin 重置 Position（仅测试网使用）
-/// 
-/// 将 Position 的 size 和其他字段重置为 0，用于清理累积的测试仓位
-fn process_admin_reset_position(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    user: Pubkey,
-    market_index: u8,
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let admin = next_account_info(account_info_iter)?;
-    let position_info = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
+    /// 按链上账户大小序列化 (尾部零填充)
+    fn account_data<T: BorshSerialize>(value: &T, size: usize) -> Vec<u8> {
+        let mut data = value.try_to_vec().unwrap();
+        data.resize(size, 0);
+        data
+    }
 
-    assert_signer(admin)?;
-    assert_writable(position_info)?;
+    fn test_ledger_config(admin: Pubkey) -> LedgerConfig {
+        LedgerConfig {
+            discriminator: LedgerConfig::DISCRIMINATOR,
+            admin,
+            vault_program: Pubkey::new_unique(),
+            fund_program: Pubkey::new_unique(),
+            global_sequence: 0,
+            total_positions_opened: 0,
+            total_positions_closed: 0,
+            total_volume_e6: 0,
+            total_fees_collected_e6: 0,
+            total_liquidations: 0,
+            total_adl_count: 0,
+            is_paused: false,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0u8; 65],
+        }
+    }
 
-    // 验证 Admin
-    let ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-    if ledger_config.admin != *admin.key {
-        msg!("❌ Invalid admin: expected {}, got {}", ledger_config.admin, admin.key);
-        return Err(LedgerError::InvalidAdmin.into());
+    /// 位于 ["ledger_config"] PDA、由本程序持有的 LedgerConfig
+    fn ledger_config_account(program_id: &Pubkey, admin: Pubkey) -> TestAccount {
+        let (pda, _) = Pubkey::find_program_address(&[b"ledger_config"], program_id);
+        TestAccount::new(pda, *program_id, account_data(&test_ledger_config(admin), LedgerConfig::SIZE))
     }
 
-    // 验证 Position PDA
-    let (position_pda, _) = Pubkey::find_program_address(
-        &[b"position", user.as_ref(), &[market_index]],
-        program_id,
-    );
-    if position_info.key != &position_pda {
-        msg!("❌ Invalid Position PDA");
-        return Err(LedgerError::InvalidAccount.into());
+    /// 位于 seeds PDA、由本程序持有的账户
+    fn pda_account<T: BorshSerialize>(program_id: &Pubkey, seeds: &[&[u8]], value: &T, size: usize) -> TestAccount {
+        let (pda, _) = Pubkey::find_program_address(seeds, program_id);
+        TestAccount::new(pda, *program_id, account_data(value, size))
     }
 
-    // 读取并重置 Position
-    let mut position = deserialize_account::<Position>(&position_info.data.borrow())?;
-    
-    let reset_pre_side = position.side as u8;
-    let reset_pre_size = position.size_e6;
-    let reset_pre_entry = position.entry_price_e6;
-    let reset_pre_margin = position.margin_e6;
+    fn market_config_account(program_id: &Pubkey, config: &MarketConfig) -> TestAccount {
+        pda_account(program_id, &[MarketConfig::SEED_PREFIX, &[config.market_index]], config, MarketConfig::SIZE)
+    }
 
-    msg!("🔧 Resetting Position: user={}, market={}, current_size={}", 
-         user, market_index, position.size_e6);
-    
-    // 重置所有字段
-    position.size_e6 = 0;
-    position.margin_e6 = 0;
-    position.entry_price_e6 = 0;
-    position.liquidation_price_e6 = 0;
-    position.unrealized_pnl_e6 = 0;
-    let reset_ts = get_current_timestamp()?;
-    position.last_update_ts = reset_ts;
-    
-    position.serialize(&mut &mut position_info.data.borrow_mut()[..])?;
+    fn position_account(program_id: &Pubkey, position: &Position) -> TestAccount {
+        pda_account(
+            program_id,
+            &[Position::SEED_PREFIX, position.user.as_ref(), &[position.market_index]],
+            position,
+            Position::SIZE,
+        )
+    }
 
-    emit_position_event(&PositionEvent {
-        discriminator: event_discriminator::POSITION,
-        sequence: 0,
-        timestamp: reset_ts,
-        user,
-        market_index,
-        event_type: 5,
-        side_before: reset_pre_side,
-        size_before_e6: reset_pre_size,
-        entry_price_before_e6: reset_pre_entry,
-        margin_before_e6: reset_pre_margin,
-        side_after: reset_pre_side,
-        size_after_e6: 0,
-        entry_price_after_e6: 0,
-        margin_after_e6: 0,
-        size_delta_e6: -(reset_pre_size as i64),
-        realized_pnl_e6: 0,
-        fee_e6: 0,
-        related_trade_sequence: 0,
-    });
+    fn funding_state_account(program_id: &Pubkey, market_index: u8, cumulative_funding_index_e6: i64) -> TestAccount {
+        let funding_state = FundingState {
+            discriminator: FundingState::DISCRIMINATOR,
+            market_index,
+            cumulative_funding_index_e6,
+            funding_interval_secs: 3600,
+            last_funding_ts: 0,
+            last_funding_rate_e6: 0,
+            last_index_price_e6: 0,
+            update_count: 0,
+            bump: 255,
+            created_at: 0,
+            reserved: [0u8; 64],
+        };
+        pda_account(program_id, &[FundingState::SEED_PREFIX, &[market_index]], &funding_state, FundingState::SIZE)
+    }
 
-    msg!("✅ Position reset to zero");
-    Ok(())
-}
+    /// 由 Relayer 在 TEST_TS 发布的 PriceFeed
+    fn price_feed_account(program_id: &Pubkey, market_index: u8, price_e6: u64) -> TestAccount {
+        let price_feed = PriceFeed {
+            discriminator: PriceFeed::DISCRIMINATOR,
+            market_index,
+            price_e6,
+            conf_e6: 0,
+            publish_ts: TEST_TS,
+            max_staleness_secs: 60,
+            max_confidence_e6: 10_000,
+            signer_count: 1,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: TEST_TS,
+            reserved: [0u8; 64],
+        };
+        pda_account(program_id, &[PriceFeed::SEED_PREFIX, &[market_index]], &price_feed, PriceFeed::SIZE)
+    }
 
-// ============================================================================
-// Spot 交易指令处理
-// ============================================================================
+    /// 尚无成交价格的 MarkPrice (标记价格 = 指数价格)
+    fn mark_price_account(program_id: &Pubkey, market_index: u8) -> TestAccount {
+        let mark_price = MarkPrice {
+            discriminator: MarkPrice::DISCRIMINATOR,
+            market_index,
+            mark_price_e6: 0,
+            index_price_e6: 0,
+            basis_ema_e6: 0,
+            last_trade_price_e6: 0,
+            last_trade_ts: 0,
+            ema_period_secs: 300,
+            max_staleness_secs: 60,
+            last_update_ts: 0,
+            update_count: 0,
+            bump: 255,
+            created_at: 0,
+            reserved: [0u8; 32],
+        };
+        pda_account(program_id, &[MarkPrice::SEED_PREFIX, &[market_index]], &mark_price, MarkPrice::SIZE)
+    }
 
-use crate::state::{SpotTradeRecord, SpotSide, spot_fee_type};
-use crate::instruction::SpotTradeData;
+    /// 已由 UpdateFundingIndex 在 TEST_TS 计入一期费率的 FundingState
+    fn applied_funding_state_account(program_id: &Pubkey, market_index: u8, funding_rate_e6: i64, index_price_e6: u64) -> TestAccount {
+        let mut account = funding_state_account(program_id, market_index, 0);
+        let mut funding_state = deserialize_account::<FundingState>(&account.data).unwrap();
+        funding_state.apply_rate(funding_rate_e6, index_price_e6, TEST_TS).unwrap();
+        account.data = account_data(&funding_state, FundingState::SIZE);
+        account
+    }
 
-/// 记录单笔 Spot 成交
-fn process_record_spot_trade(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    user: Pubkey,
-    market_index: u16,
-    is_buy: bool,
-    base_amount_e6: u64,
-    quote_amount_e6: u64,
-    price_e6: u64,
-    fee_e6: u64,
-    is_taker: bool,
-    batch_id: u64,
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let spot_trade_info = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
+    /// 在 TEST_TS 刷新过的 MarkPrice
+    fn fresh_mark_price_account(program_id: &Pubkey, market_index: u8, mark_price_e6: u64) -> TestAccount {
+        let mut account = mark_price_account(program_id, market_index);
+        let mut mark_price = deserialize_account::<MarkPrice>(&account.data).unwrap();
+        mark_price.mark_price_e6 = mark_price_e6;
+        mark_price.index_price_e6 = mark_price_e6;
+        mark_price.last_update_ts = TEST_TS;
+        account.data = account_data(&mark_price, MarkPrice::SIZE);
+        account
+    }
 
-    assert_signer(relayer)?;
-    assert_writable(spot_trade_info)?;
-    assert_writable(ledger_config_info)?;
+    /// 在 TEST_TS 发布了 funding_rate_e6 / index_price_e6 的 PremiumSamples
+    fn premium_samples_account(program_id: &Pubkey, market_index: u8, funding_rate_e6: i64, index_price_e6: u64) -> TestAccount {
+        let premium_samples = PremiumSamples {
+            discriminator: PremiumSamples::DISCRIMINATOR,
+            market_index,
+            interest_rate_e6: 0,
+            premium_clamp_e6: 0,
+            head: 0,
+            count: 0,
+            last_funding_rate_e6: funding_rate_e6,
+            last_premium_twap_e6: 0,
+            last_index_price_e6: index_price_e6,
+            last_published_ts: TEST_TS,
+            published_count: 1,
+            bump: 255,
+            created_at: 0,
+            reserved: [0u8; 32],
+            samples: [PremiumSample::default(); PREMIUM_SAMPLE_CAPACITY],
+        };
+        pda_account(program_id, &[PremiumSamples::SEED_PREFIX, &[market_index]], &premium_samples, PremiumSamples::SIZE)
+    }
 
-    // 验证 Relayer 授权
-    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
-    if !relayer_config.is_authorized(relayer.key) {
-        msg!("❌ Unauthorized relayer: {}", relayer.key);
-        return Err(LedgerError::UnauthorizedRelayer.into());
+    fn relayer_config_account(program_id: &Pubkey, relayer: Pubkey) -> TestAccount {
+        let relayer_config = RelayerConfig {
+            discriminator: RelayerConfig::DISCRIMINATOR,
+            admin: Pubkey::new_unique(),
+            authorized_relayers: vec![relayer],
+            required_signatures: 1,
+            bump: 255,
+            last_update_ts: 0,
+        };
+        pda_account(program_id, &[b"relayer_config"], &relayer_config, RelayerConfig::SIZE)
     }
 
-    // 获取下一个序列号
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-    let sequence = ledger_config.next_sequence();
+    /// FeeSchedule: 保险基金 40% / 金库 30% / 推荐人 20% / Maker 返佣 10%
+    fn fee_schedule_account(program_id: &Pubkey) -> TestAccount {
+        let fee_schedule = FeeSchedule {
+            discriminator: FeeSchedule::DISCRIMINATOR,
+            insurance_bps: 4_000,
+            treasury_bps: 3_000,
+            referrer_bps: 2_000,
+            maker_rebate_bps: 1_000,
+            total_insurance_e6: 0,
+            total_treasury_e6: 0,
+            total_referrer_e6: 0,
+            total_maker_rebate_e6: 0,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            referee_discount_bps: 0,
+            reserved: [0u8; 62],
+        };
+        pda_account(program_id, &[FeeSchedule::SEED_PREFIX], &fee_schedule, FeeSchedule::SIZE)
+    }
 
-    // 派生 SpotTradeRecord PDA
-    let (spot_trade_pda, spot_trade_bump) = Pubkey::find_program_address(
-        &[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes()],
-        program_id,
-    );
+    /// 单档 FeeTierTable: maker 最高 0.02% (可为负)，taker 最高 0.05%
+    fn fee_tier_table_account(program_id: &Pubkey) -> TestAccount {
+        let fee_tier_table = FeeTierTable {
+            discriminator: FeeTierTable::DISCRIMINATOR,
+            tiers: vec![FeeTier { min_volume_e6: 0, maker_fee_rate_e6: 200, taker_fee_rate_e6: 500 }],
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0u8; 32],
+        };
+        pda_account(program_id, &[FeeTierTable::SEED_PREFIX], &fee_tier_table, FeeTierTable::SIZE)
+    }
 
-    if spot_trade_info.key != &spot_trade_pda {
-        msg!("❌ Invalid SpotTradeRecord PDA");
-        return Err(LedgerError::InvalidAccount.into());
+    fn user_stats_account(program_id: &Pubkey, user: Pubkey) -> TestAccount {
+        let user_stats = UserStats {
+            discriminator: UserStats::DISCRIMINATOR,
+            user,
+            total_trades: 0,
+            total_volume_e6: 0,
+            total_realized_pnl_e6: 0,
+            total_fees_paid_e6: 0,
+            total_funding_paid_e6: 0,
+            total_liquidations: 0,
+            first_trade_at: 0,
+            last_trade_at: 0,
+            bump: 255,
+            total_rebates_received_e6: 0,
+            last_volume_day: 0,
+            daily_volume_e6: [0; ROLLING_VOLUME_DAYS],
+        };
+        pda_account(program_id, &[UserStats::SEED_PREFIX, user.as_ref()], &user_stats, UserStats::SIZE)
     }
 
-    // 创建账户
-    let rent = Rent::get()?;
-    let space = SpotTradeRecord::SIZE;
-    let lamports = rent.minimum_balance(space);
+    /// 尚未创建的用户 PDA (UserStats / Referral 等)
+    fn empty_pda_account(program_id: &Pubkey, seeds: &[&[u8]]) -> TestAccount {
+        let (pda, _) = Pubkey::find_program_address(seeds, program_id);
+        TestAccount::new(pda, solana_program::system_program::id(), vec![])
+    }
 
-    invoke_signed(
-        &system_instruction::create_account(
-            relayer.key,
-            spot_trade_info.key,
-            lamports,
-            space as u64,
-            program_id,
-        ),
-        &[relayer.clone(), spot_trade_info.clone(), system_program.clone()],
-        &[&[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes(), &[spot_trade_bump]]],
-    )?;
+    fn liquidator_stats_account(program_id: &Pubkey, liquidator: Pubkey) -> TestAccount {
+        let stats = LiquidatorStats {
+            discriminator: LiquidatorStats::DISCRIMINATOR,
+            liquidator,
+            total_liquidations: 0,
+            partial_liquidations: 0,
+            total_liquidated_notional_e6: 0,
+            total_rewards_e6: 0,
+            last_liquidation_ts: 0,
+            created_at: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        };
+        pda_account(program_id, &[LiquidatorStats::SEED_PREFIX, liquidator.as_ref()], &stats, LiquidatorStats::SIZE)
+    }
 
-    // 初始化 SpotTradeRecord
-    let current_ts = get_current_timestamp()?;
-    let spot_trade = SpotTradeRecord {
-        discriminator: SpotTradeRecord::DISCRIMINATOR,
-        sequence,
-        user,
-        market_index,
-        side: if is_buy { SpotSide::Buy } else { SpotSide::Sell },
-        base_amount_e6,
-        quote_amount_e6,
-        price_e6,
-        fee_e6,
-        fee_type: if is_taker { spot_fee_type::TAKER } else { spot_fee_type::MAKER },
-        timestamp: current_ts,
-        batch_id,
-        bump: spot_trade_bump,
-        reserved: [0u8; 32],
-    };
+    /// Vault UserAccount (布局见 cpi::read_user_account)
+    fn vault_user_account(vault_program: &Pubkey, wallet: Pubkey) -> TestAccount {
+        let mut data = vec![0u8; 57];
+        data[8..40].copy_from_slice(wallet.as_ref());
+        TestAccount::new(Pubkey::new_unique(), *vault_program, data)
+    }
 
-    spot_trade.serialize(&mut &mut spot_trade_info.data.borrow_mut()[..])?;
+    fn unowned_account() -> TestAccount {
+        TestAccount::new(Pubkey::new_unique(), Pubkey::new_unique(), vec![])
+    }
 
-    // 更新 LedgerConfig 统计
-    ledger_config.total_volume_e6 = ledger_config.total_volume_e6.saturating_add(quote_amount_e6);
-    ledger_config.total_fees_collected_e6 = ledger_config.total_fees_collected_e6.saturating_add(fee_e6);
-    ledger_config.last_update_ts = current_ts;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    fn market_config_params(max_leverage: u8) -> MarketConfigParams {
+        let config = test_market_config();
+        MarketConfigParams {
+            max_leverage,
+            initial_margin_rate_e6: config.initial_margin_rate_e6,
+            maintenance_margin_rate_e6: config.maintenance_margin_rate_e6,
+            liquidation_penalty_rate_e6: config.liquidation_penalty_rate_e6,
+            partial_liquidation_buffer_e6: config.partial_liquidation_buffer_e6,
+            full_liquidation_margin_ratio_e6: config.full_liquidation_margin_ratio_e6,
+            min_liquidation_notional_e6: config.min_liquidation_notional_e6,
+            liquidator_reward_bps: config.liquidator_reward_bps,
+            tick_size_e6: config.tick_size_e6,
+            lot_size_e6: config.lot_size_e6,
+            price_band_bps: config.price_band_bps,
+            liquidation_price_band_bps: config.liquidation_price_band_bps,
+            funding_interval_secs: config.funding_interval_secs,
+            max_funding_rate_e6: config.max_funding_rate_e6,
+            is_active: config.is_active,
+        }
+    }
 
-    msg!("✅ SpotTradeRecord created: seq={}, user={}, market={}, side={}, base={}, quote={}, fee={}",
-         sequence, user, market_index, if is_buy { "Buy" } else { "Sell" },
-         base_amount_e6, quote_amount_e6, fee_e6);
+    #[test]
+    fn test_update_market_config_requires_verified_ledger_config() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let attacker = Pubkey::new_unique();
+        let mut admin_account = TestAccount::signer(attacker);
+        let mut market_account = market_config_account(&program_id, &test_market_config());
+
+        // 伪造: 非 PDA 地址上的 LedgerConfig，声明攻击者为 admin
+        let mut fake = ledger_config_account(&program_id, attacker);
+        fake.key = Pubkey::new_unique();
+        let accounts = [admin_account.info(), market_account.info(), fake.info()];
+        assert_eq!(
+            process_update_market_config(&program_id, &accounts, 0, market_config_params(50)),
+            Err(LedgerError::InvalidAccount.into())
+        );
 
-    emit_trade_event(&TradeEvent {
-        discriminator: event_discriminator::TRADE,
-        sequence,
-        timestamp: current_ts,
-        batch_id,
-        market_index: market_index as u8,
-        market_type: 1,
-        trade_type: 0,
-        maker: Pubkey::default(),
-        maker_order_id: [0u8; 16],
-        maker_side: 0,
-        maker_fee_e6: 0,
-        taker: user,
-        taker_order_id: [0u8; 16],
-        taker_side: if is_buy { 0 } else { 1 },
-        taker_fee_e6: fee_e6 as i64,
-        price_e6,
-        size_e6: base_amount_e6,
-        notional_e6: quote_amount_e6,
-        maker_realized_pnl_e6: 0,
-        taker_realized_pnl_e6: 0,
-        maker_margin_delta_e6: 0,
-        taker_margin_delta_e6: 0,
-    });
+        // 伪造: PDA 地址正确，但不属于本程序
+        let mut foreign = ledger_config_account(&program_id, attacker);
+        foreign.owner = Pubkey::new_unique();
+        let accounts = [admin_account.info(), market_account.info(), foreign.info()];
+        assert_eq!(
+            process_update_market_config(&program_id, &accounts, 0, market_config_params(50)),
+            Err(LedgerError::InvalidAccount.into())
+        );
+
+        // 真实 LedgerConfig，但签名者不是 admin
+        let mut genuine = ledger_config_account(&program_id, Pubkey::new_unique());
+        let accounts = [admin_account.info(), market_account.info(), genuine.info()];
+        assert_eq!(
+            process_update_market_config(&program_id, &accounts, 0, market_config_params(50)),
+            Err(LedgerError::InvalidAdmin.into())
+        );
+        assert_eq!(
+            deserialize_account::<MarketConfig>(&market_account.data).unwrap().max_leverage,
+            20
+        );
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_update_market_config_by_admin() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let mut admin_account = TestAccount::signer(admin);
+        let mut market_account = market_config_account(&program_id, &test_market_config());
+        let mut ledger_account = ledger_config_account(&program_id, admin);
+
+        let accounts = [admin_account.info(), market_account.info(), ledger_account.info()];
+        process_update_market_config(&program_id, &accounts, 0, market_config_params(10)).unwrap();
+
+        let updated = deserialize_account::<MarketConfig>(&market_account.data).unwrap();
+        assert_eq!(updated.max_leverage, 10);
+        assert_eq!(updated.last_update_ts, TEST_TS);
+    }
 
-/// 批量记录 Spot 成交
-fn process_batch_record_spot_trades(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    trades: Vec<SpotTradeData>,
-    batch_id: u64,
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let ledger_config_info = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
+    #[test]
+    fn test_set_paused_rejects_forged_ledger_config() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let attacker = Pubkey::new_unique();
+        let mut admin_account = TestAccount::signer(attacker);
+        let mut fake = ledger_config_account(&program_id, attacker);
+        fake.key = Pubkey::new_unique();
+
+        let accounts = [admin_account.info(), fake.info()];
+        assert_eq!(
+            process_set_paused(&program_id, &accounts, true),
+            Err(LedgerError::InvalidAccount.into())
+        );
+    }
 
-    assert_signer(relayer)?;
-    assert_writable(ledger_config_info)?;
+    /// Liquidate 的全部账户 (Vault / Fund / Token 账户仅作为 CPI 占位)
+    struct LiquidateAccounts {
+        liquidator: TestAccount,
+        position: TestAccount,
+        user_vault: TestAccount,
+        vault_config: TestAccount,
+        ledger_config: TestAccount,
+        user_stats: TestAccount,
+        vault_program: TestAccount,
+        vault_token: TestAccount,
+        fund_program: TestAccount,
+        insurance_fund: TestAccount,
+        insurance_config: TestAccount,
+        insurance_vault: TestAccount,
+        counterparty_vault: TestAccount,
+        token_program: TestAccount,
+        market_config: TestAccount,
+        price_feed: TestAccount,
+        liquidator_vault: TestAccount,
+        liquidator_stats: TestAccount,
+        system_program: TestAccount,
+        shortfall_record: TestAccount,
+        funding_state: TestAccount,
+        mark_price: TestAccount,
+    }
 
-    // 验证 Relayer 授权
-    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
-    if !relayer_config.is_authorized(relayer.key) {
-        return Err(LedgerError::UnauthorizedRelayer.into());
+    impl LiquidateAccounts {
+        fn new(program_id: &Pubkey, position: &Position, market_config: &MarketConfig, index_price_e6: u64) -> Self {
+            let ledger_config = ledger_config_account(program_id, Pubkey::new_unique());
+            let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+            let liquidator = Pubkey::new_unique();
+            let market_index = position.market_index;
+            Self {
+                liquidator: TestAccount::signer(liquidator),
+                position: position_account(program_id, position),
+                user_vault: vault_user_account(&config.vault_program, position.user),
+                vault_config: unowned_account(),
+                ledger_config,
+                user_stats: unowned_account(),
+                vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+                vault_token: unowned_account(),
+                fund_program: TestAccount::new(config.fund_program, Pubkey::new_unique(), vec![]),
+                insurance_fund: unowned_account(),
+                insurance_config: unowned_account(),
+                insurance_vault: unowned_account(),
+                counterparty_vault: unowned_account(),
+                token_program: unowned_account(),
+                market_config: market_config_account(program_id, market_config),
+                price_feed: price_feed_account(program_id, market_index, index_price_e6),
+                liquidator_vault: vault_user_account(&config.vault_program, liquidator),
+                liquidator_stats: liquidator_stats_account(program_id, liquidator),
+                system_program: unowned_account(),
+                shortfall_record: unowned_account(),
+                funding_state: funding_state_account(program_id, market_index, 0),
+                mark_price: mark_price_account(program_id, market_index),
+            }
+        }
+
+        fn ledger_config(&self) -> LedgerConfig {
+            deserialize_account::<LedgerConfig>(&self.ledger_config.data).unwrap()
+        }
+
+        fn position(&self) -> Position {
+            deserialize_account::<Position>(&self.position.data).unwrap()
+        }
+
+        fn liquidate(&mut self, program_id: &Pubkey, user: Pubkey, market_index: u8) -> ProgramResult {
+            let accounts = [
+                self.liquidator.info(),
+                self.position.info(),
+                self.user_vault.info(),
+                self.vault_config.info(),
+                self.ledger_config.info(),
+                self.user_stats.info(),
+                self.vault_program.info(),
+                self.vault_token.info(),
+                self.fund_program.info(),
+                self.insurance_fund.info(),
+                self.insurance_config.info(),
+                self.insurance_vault.info(),
+                self.counterparty_vault.info(),
+                self.token_program.info(),
+                self.market_config.info(),
+                self.price_feed.info(),
+                self.liquidator_vault.info(),
+                self.liquidator_stats.info(),
+                self.system_program.info(),
+                self.shortfall_record.info(),
+                self.funding_state.info(),
+                self.mark_price.info(),
+            ];
+            process_liquidate(program_id, &accounts, user, market_index)
+        }
     }
 
-    let mut ledger_config = deserialize_account::<LedgerConfig>(&ledger_config_info.data.borrow())?;
-    let current_ts = get_current_timestamp()?;
-    let rent = Rent::get()?;
-    let space = SpotTradeRecord::SIZE;
-    let lamports = rent.minimum_balance(space);
+    #[test]
+    fn test_split_liquidation_penalty() {
+        // $1,000 罚金，清算人 20%
+        assert_eq!(split_liquidation_penalty(1_000_000_000, 2_000), (800_000_000, 200_000_000));
+        // 未配置奖励: 全部进入保险基金
+        assert_eq!(split_liquidation_penalty(1_000_000_000, 0), (1_000_000_000, 0));
+        // 向下取整，余数归保险基金
+        assert_eq!(split_liquidation_penalty(3, 5_000), (2, 1));
+    }
 
-    for trade in trades.iter() {
-        let spot_trade_info = next_account_info(account_info_iter)?;
-        assert_writable(spot_trade_info)?;
+    #[test]
+    fn test_partial_liquidation_releases_funding_surplus() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let market_config = MarketConfig { partial_liquidation_buffer_e6: 5_000, ..test_market_config() };
 
-        let sequence = ledger_config.next_sequence();
+        // Long 10 BTC @ $50,000，保证金仅 $1,000，但应收未结算资金费 $48,000
+        // @ $46,000: 权益 $1,000 + $48,000 - $40,000 = $9,000，保证金率 ~1.96% < MMR 2.5%
+        let mut position = Position {
+            size_e6: 10_000_000,
+            margin_e6: 1_000_000_000,
+            unsettled_funding_e6: -48_000_000_000,
+            ..test_position()
+        };
+        position.refresh_liquidation_price(&market_config).unwrap();
+
+        let mut accounts = LiquidateAccounts::new(&program_id, &position, &market_config, 46_000_000_000);
+        accounts.liquidate(&program_id, position.user, 0).unwrap();
+
+        let liquidated = accounts.position();
+        assert!(liquidated.size_e6 > 0 && liquidated.size_e6 < 10_000_000);
+        // 盈余不计入仓位保证金
+        assert_eq!(liquidated.margin_e6, 1_000_000_000);
+
+        // Vault 锁定保证金: -资金费 (负数 = 收取) - (亏损 + 罚金) - 释放的盈余 = 不变
+        let cpis = take_cpis(&accounts.ledger_config().vault_program);
+        let funding = cpis.iter().find(|(tag, _)| *tag == 14).map(|(_, args)| cpi_i64(args, 0)).unwrap();
+        let consumed = cpis.iter().find(|(tag, _)| *tag == 7).map(|(_, args)| cpi_u64(args, 0)).unwrap();
+        let released = cpis.iter().find(|(tag, _)| *tag == 5).map(|(_, args)| cpi_u64(args, 0)).unwrap();
+        assert!(funding < 0);
+        assert!(released > 0);
+        assert_eq!(-funding - consumed as i64 - released as i64, 0);
+        assert_eq!(liquidated.unsettled_funding_e6 + funding, -48_000_000_000);
+    }
 
-        // 派生 PDA
-        let (spot_trade_pda, spot_trade_bump) = Pubkey::find_program_address(
-            &[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes()],
+    /// 以仓位的穿仓序号派生 ShortfallRecord PDA
+    fn shortfall_record_pda(program_id: &Pubkey, user: &Pubkey, market_index: u8, shortfall_index: u64) -> Pubkey {
+        Pubkey::find_program_address(
+            &[ShortfallRecord::SEED_PREFIX, user.as_ref(), &[market_index], &shortfall_index.to_le_bytes()],
             program_id,
+        )
+        .0
+    }
+
+    #[test]
+    fn test_shortfall_record_unique_per_liquidation() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let market_config = test_market_config();
+        // Long 1 BTC @ $50,000，保证金 $5,000: @ $44,000 亏损 $6,000，穿仓
+        let position = Position { liquidation_price_e6: 46_250_000_000, ..test_position() };
+        let user = position.user;
+        let mut accounts = LiquidateAccounts::new(&program_id, &position, &market_config, 44_000_000_000);
+
+        // 其他交易推进 global_sequence 不影响记录地址: 地址只由仓位的穿仓计数决定
+        let first_pda = shortfall_record_pda(&program_id, &user, 0, 0);
+        let mut ledger_config = accounts.ledger_config();
+        ledger_config.global_sequence = 1_000;
+        accounts.ledger_config.data = account_data(&ledger_config, LedgerConfig::SIZE);
+        accounts.shortfall_record = TestAccount::new(first_pda, program_id, vec![0u8; ShortfallRecord::SIZE]);
+        accounts.liquidate(&program_id, user, 0).unwrap();
+        let record = deserialize_account::<ShortfallRecord>(&accounts.shortfall_record.data).unwrap();
+        assert_eq!((record.shortfall_index, record.liquidation_sequence), (0, 1_000));
+        assert!(record.shortfall_e6 > 0);
+        assert_eq!(accounts.position().shortfall_count, 1);
+
+        // 同方向重新开仓后再次穿仓: 旧记录地址不再有效，新记录使用递增后的计数
+        let reopened = Position { shortfall_count: accounts.position().shortfall_count, ..position.clone() };
+        accounts.position.data = account_data(&reopened, Position::SIZE);
+        assert_eq!(
+            accounts.liquidate(&program_id, user, 0),
+            Err(LedgerError::InvalidAccount.into())
         );
+        accounts.position.data = account_data(&reopened, Position::SIZE);
+        let second_pda = shortfall_record_pda(&program_id, &user, 0, 1);
+        assert_ne!(first_pda, second_pda);
+        accounts.shortfall_record = TestAccount::new(second_pda, program_id, vec![0u8; ShortfallRecord::SIZE]);
+        accounts.liquidate(&program_id, user, 0).unwrap();
+        let record = deserialize_account::<ShortfallRecord>(&accounts.shortfall_record.data).unwrap();
+        assert_eq!(record.shortfall_index, 1);
+        assert_eq!(accounts.position().shortfall_count, 2);
+    }
 
-        if spot_trade_info.key != &spot_trade_pda {
-            msg!("❌ Invalid SpotTradeRecord PDA for sequence {}", sequence);
-            return Err(LedgerError::InvalidAccount.into());
-        }
+    /// ExecuteADL 的全部账户 (单个目标仓位)
+    struct ExecuteAdlAccounts {
+        admin: TestAccount,
+        ledger_config: TestAccount,
+        vault_config: TestAccount,
+        vault_program: TestAccount,
+        fund_program: TestAccount,
+        insurance_fund: TestAccount,
+        insurance_config: TestAccount,
+        adl_state: TestAccount,
+        market_config: TestAccount,
+        price_feed: TestAccount,
+        funding_state: TestAccount,
+        mark_price: TestAccount,
+        shortfall_record: TestAccount,
+        vault_token: TestAccount,
+        insurance_vault: TestAccount,
+        token_program: TestAccount,
+        target: TestAccount,
+        target_vault: TestAccount,
+    }
 
-        // 创建账户
-        invoke_signed(
-            &system_instruction::create_account(
-                relayer.key,
-                spot_trade_info.key,
-                lamports,
-                space as u64,
-                program_id,
-            ),
-            &[relayer.clone(), spot_trade_info.clone(), system_program.clone()],
-            &[&[SpotTradeRecord::SEED_PREFIX, &sequence.to_le_bytes(), &[spot_trade_bump]]],
-        )?;
+    impl ExecuteAdlAccounts {
+        fn execute(&mut self, program_id: &Pubkey) -> ProgramResult {
+            let accounts = [
+                self.admin.info(),
+                self.ledger_config.info(),
+                self.vault_config.info(),
+                self.vault_program.info(),
+                self.fund_program.info(),
+                self.insurance_fund.info(),
+                self.insurance_config.info(),
+                self.adl_state.info(),
+                self.market_config.info(),
+                self.price_feed.info(),
+                self.funding_state.info(),
+                self.mark_price.info(),
+                self.shortfall_record.info(),
+                self.vault_token.info(),
+                self.insurance_vault.info(),
+                self.token_program.info(),
+                self.target.info(),
+                self.target_vault.info(),
+            ];
+            process_execute_adl(program_id, &accounts, 0)
+        }
+    }
 
-        // 初始化
-        let spot_trade = SpotTradeRecord {
-            discriminator: SpotTradeRecord::DISCRIMINATOR,
-            sequence,
-            user: trade.user,
-            market_index: trade.market_index,
-            side: if trade.is_buy { SpotSide::Buy } else { SpotSide::Sell },
-            base_amount_e6: trade.base_amount_e6,
-            quote_amount_e6: trade.quote_amount_e6,
-            price_e6: trade.price_e6,
-            fee_e6: trade.fee_e6,
-            fee_type: if trade.is_taker { spot_fee_type::TAKER } else { spot_fee_type::MAKER },
-            timestamp: current_ts,
-            batch_id,
-            bump: spot_trade_bump,
+    #[test]
+    fn test_execute_adl_transfers_covered_amount_to_insurance_fund() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let ledger_config = ledger_config_account(&program_id, admin);
+        let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+
+        // Long 在破产价格 $45,000 穿仓 $2,000，保险基金 $1,500 -> ADL 需覆盖 $500
+        let record = ShortfallRecord {
+            discriminator: ShortfallRecord::DISCRIMINATOR,
+            user: Pubkey::new_unique(),
+            market_index: 0,
+            side: Side::Long,
+            size_e6: 1_000_000,
+            entry_price_e6: 50_000_000_000,
+            bankruptcy_price_e6: 45_000_000_000,
+            shortfall_e6: 2_000_000_000,
+            liquidation_sequence: 7,
+            shortfall_index: 0,
+            is_consumed: true,
+            created_at: 0,
+            consumed_at: TEST_TS,
+            bump: 255,
+            reserved: [0u8; 24],
+        };
+        let record_seeds: &[&[u8]] = &[ShortfallRecord::SEED_PREFIX, record.user.as_ref(), &[0], &0u64.to_le_bytes()];
+        let shortfall_record = pda_account(&program_id, record_seeds, &record, ShortfallRecord::SIZE);
+        let mut adl_state = ADLState {
+            discriminator: ADLState::DISCRIMINATOR,
+            market_index: 0,
+            is_active: false,
+            shortfall_record: Pubkey::default(),
+            bankrupt_user: Pubkey::default(),
+            bankrupt_side: Side::Long,
+            bankrupt_size_e6: 0,
+            bankruptcy_price_e6: 0,
+            shortfall_e6: 0,
+            insurance_balance_before_e6: 0,
+            adl_required_e6: 0,
+            covered_e6: 0,
+            counterparty_size_reduced_e6: 0,
+            target_count: 0,
+            triggered_at: 0,
+            completed_at: 0,
+            completed_count: 0,
+            bump: 255,
             reserved: [0u8; 32],
         };
+        adl_state.start(shortfall_record.key, &record, 1_500_000_000, TEST_TS).unwrap();
+
+        // 目标: Short 1 BTC @ $50,000；指数价格 $44,000，每 BTC 让出 $1,000
+        let target = Position { side: Side::Short, ..test_position() };
+        let mut accounts = ExecuteAdlAccounts {
+            admin: TestAccount::signer(admin),
+            vault_config: unowned_account(),
+            vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+            fund_program: TestAccount::new(config.fund_program, Pubkey::new_unique(), vec![]),
+            insurance_fund: unowned_account(),
+            insurance_config: unowned_account(),
+            adl_state: pda_account(&program_id, &[ADLState::SEED_PREFIX, &[0]], &adl_state, ADLState::SIZE),
+            market_config: market_config_account(&program_id, &test_market_config()),
+            price_feed: price_feed_account(&program_id, 0, 44_000_000_000),
+            funding_state: funding_state_account(&program_id, 0, 0),
+            mark_price: mark_price_account(&program_id, 0),
+            shortfall_record: unowned_account(),
+            vault_token: unowned_account(),
+            insurance_vault: unowned_account(),
+            token_program: unowned_account(),
+            target: position_account(&program_id, &target),
+            target_vault: vault_user_account(&config.vault_program, target.user),
+            ledger_config,
+        };
 
-        spot_trade.serialize(&mut &mut spot_trade_info.data.borrow_mut()[..])?;
+        // 非 ADLState 记录的穿仓不能被扣减
+        assert_eq!(accounts.execute(&program_id), Err(LedgerError::InvalidAccount.into()));
+
+        accounts.shortfall_record = shortfall_record;
+        accounts.execute(&program_id).unwrap();
+
+        let mark = deserialize_account::<MarkPrice>(&accounts.mark_price.data).unwrap();
+        assert_eq!(mark.mark_price_e6, 44_000_000_000);
+        let reduced = deserialize_account::<Position>(&accounts.target.data).unwrap();
+        assert_eq!(reduced.size_e6, 500_000);
+
+        // 让出的 $500 从 Vault 转入保险基金，并从穿仓记录中扣减
+        let vault_cpis = take_cpis(&config.vault_program);
+        let transferred = vault_cpis.iter().find(|(tag, _)| *tag == 15).map(|(_, args)| cpi_u64(args, 0)).unwrap();
+        assert_eq!(transferred, 500_000_000);
+        let fund_cpis = take_cpis(&config.fund_program);
+        let recorded = fund_cpis.iter().find(|(tag, _)| *tag == 17).map(|(_, args)| cpi_i64(args, 0)).unwrap();
+        assert_eq!(recorded, 500_000_000);
+
+        let record = deserialize_account::<ShortfallRecord>(&accounts.shortfall_record.data).unwrap();
+        assert_eq!(record.shortfall_e6, 1_500_000_000);
+        let adl_state = deserialize_account::<ADLState>(&accounts.adl_state.data).unwrap();
+        assert_eq!(adl_state.remaining_e6(), 0);
+    }
 
-        emit_trade_event(&TradeEvent {
-            discriminator: event_discriminator::TRADE,
-            sequence,
-            timestamp: current_ts,
-            batch_id,
-            market_index: trade.market_index as u8,
-            market_type: 1,
-            trade_type: 0,
-            maker: Pubkey::default(),
-            maker_order_id: [0u8; 16],
-            maker_side: 0,
-            maker_fee_e6: 0,
-            taker: trade.user,
-            taker_order_id: [0u8; 16],
-            taker_side: if trade.is_buy { 0 } else { 1 },
-            taker_fee_e6: trade.fee_e6 as i64,
-            price_e6: trade.price_e6,
-            size_e6: trade.base_amount_e6,
-            notional_e6: trade.quote_amount_e6,
-            maker_realized_pnl_e6: 0,
-            taker_realized_pnl_e6: 0,
-            maker_margin_delta_e6: 0,
-            taker_margin_delta_e6: 0,
-        });
+    /// TriggerADL 的全部账户 (单个目标仓位)
+    struct TriggerAdlAccounts {
+        admin: TestAccount,
+        ledger_config: TestAccount,
+        fund_program: TestAccount,
+        insurance_config: TestAccount,
+        fund_vault: TestAccount,
+        price_feed: TestAccount,
+        mark_price: TestAccount,
+        adl_state: TestAccount,
+        system_program: TestAccount,
+        shortfall_record: TestAccount,
+        target: TestAccount,
+    }
 
-        // 累加统计
-        ledger_config.total_volume_e6 = ledger_config.total_volume_e6.saturating_add(trade.quote_amount_e6);
-        ledger_config.total_fees_collected_e6 = ledger_config.total_fees_collected_e6.saturating_add(trade.fee_e6);
+    impl TriggerAdlAccounts {
+        fn execute(&mut self, program_id: &Pubkey) -> ProgramResult {
+            let accounts = [
+                self.admin.info(),
+                self.ledger_config.info(),
+                self.fund_program.info(),
+                self.insurance_config.info(),
+                self.fund_vault.info(),
+                self.price_feed.info(),
+                self.mark_price.info(),
+                self.adl_state.info(),
+                self.system_program.info(),
+                self.shortfall_record.info(),
+                self.target.info(),
+            ];
+            process_trigger_adl(program_id, &accounts, 0)
+        }
     }
 
-    ledger_config.last_update_ts = current_ts;
-    ledger_config.serialize(&mut &mut ledger_config_info.data.borrow_mut()[..])?;
+    #[test]
+    fn test_trigger_adl_ranks_targets_by_refreshed_mark_price() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let ledger_config = ledger_config_account(&program_id, admin);
+        let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+
+        // Short 在破产价格 $55,000 穿仓 $2,000，保险基金 $1,500
+        let record = ShortfallRecord {
+            discriminator: ShortfallRecord::DISCRIMINATOR,
+            user: Pubkey::new_unique(),
+            market_index: 0,
+            side: Side::Short,
+            size_e6: 1_000_000,
+            entry_price_e6: 50_000_000_000,
+            bankruptcy_price_e6: 55_000_000_000,
+            shortfall_e6: 2_000_000_000,
+            liquidation_sequence: 7,
+            shortfall_index: 0,
+            is_consumed: false,
+            created_at: 0,
+            consumed_at: 0,
+            bump: 255,
+            reserved: [0u8; 24],
+        };
+        let record_seeds: &[&[u8]] = &[ShortfallRecord::SEED_PREFIX, record.user.as_ref(), &[0], &0u64.to_le_bytes()];
+        let shortfall_record = pda_account(&program_id, record_seeds, &record, ShortfallRecord::SIZE);
+        let adl_state = pda_account(&program_id, &[ADLState::SEED_PREFIX, &[0]], &ADLState {
+            discriminator: ADLState::DISCRIMINATOR,
+            market_index: 0,
+            is_active: false,
+            shortfall_record: Pubkey::default(),
+            bankrupt_user: Pubkey::default(),
+            bankrupt_side: Side::Long,
+            bankrupt_size_e6: 0,
+            bankruptcy_price_e6: 0,
+            shortfall_e6: 0,
+            insurance_balance_before_e6: 0,
+            adl_required_e6: 0,
+            covered_e6: 0,
+            counterparty_size_reduced_e6: 0,
+            target_count: 0,
+            triggered_at: 0,
+            completed_at: 0,
+            completed_count: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        }, ADLState::SIZE);
+
+        // 指数价格 $50,000，最近成交 $52,000 且基差 EMA +$2,000 -> 标记价格 $52,000
+        let mut mark_price = mark_price_account(&program_id, 0);
+        let mut mark = deserialize_account::<MarkPrice>(&mark_price.data).unwrap();
+        mark.basis_ema_e6 = 2_000_000_000;
+        mark.last_trade_price_e6 = 52_000_000_000;
+        mark.last_trade_ts = TEST_TS;
+        mark.last_update_ts = TEST_TS;
+        mark.update_count = 1;
+        mark_price.data = account_data(&mark, MarkPrice::SIZE);
+
+        // 目标: Long 1 BTC @ $51,000，按指数价格亏损，按标记价格盈利
+        let target = Position { entry_price_e6: 51_000_000_000, ..test_position() };
+
+        let mut insurance_vault_data = vec![0u8; 165];
+        insurance_vault_data[64..72].copy_from_slice(&1_500_000_000u64.to_le_bytes());
+        let mut accounts = TriggerAdlAccounts {
+            admin: TestAccount::signer(admin),
+            fund_program: TestAccount::new(config.fund_program, Pubkey::new_unique(), vec![]),
+            insurance_config: TestAccount::new(Pubkey::new_unique(), Pubkey::new_unique(), vec![0u8; 200]),
+            fund_vault: TestAccount::new(Pubkey::new_unique(), Pubkey::new_unique(), insurance_vault_data),
+            price_feed: price_feed_account(&program_id, 0, 50_000_000_000),
+            mark_price,
+            adl_state,
+            system_program: unowned_account(),
+            shortfall_record,
+            target: position_account(&program_id, &target),
+            ledger_config,
+        };
+        accounts.execute(&program_id).unwrap();
+
+        let mark = deserialize_account::<MarkPrice>(&accounts.mark_price.data).unwrap();
+        assert_eq!(mark.mark_price_e6, 52_000_000_000);
+        let adl_state = deserialize_account::<ADLState>(&accounts.adl_state.data).unwrap();
+        assert!(adl_state.is_active);
+        assert_eq!(adl_state.adl_required_e6, 500_000_000);
+        assert_eq!(take_cpis(&config.fund_program).len(), 1);
+    }
 
-    msg!("✅ BatchRecordSpotTrades: {} trades recorded, batch_id={}", trades.len(), batch_id);
-    Ok(())
-}
+    fn funding_summary_account(program_id: &Pubkey, market_index: u8) -> TestAccount {
+        let summary = FundingSettlementSummary {
+            discriminator: FundingSettlementSummary::DISCRIMINATOR,
+            market_index,
+            epoch: 0,
+            funding_rate_e6: 0,
+            index_price_e6: 0,
+            accounts_settled: 0,
+            total_funding_paid_e6: 0,
+            net_funding_e6: 0,
+            first_settled_at: 0,
+            last_settled_at: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        };
+        pda_account(program_id, &[FundingSettlementSummary::SEED_PREFIX, &[market_index]], &summary, FundingSettlementSummary::SIZE)
+    }
 
-// ============================================================================
-// 订单事件存证
-// ============================================================================
+    /// SettleFunding 的全部账户
+    struct SettleFundingAccounts {
+        relayer: TestAccount,
+        position: TestAccount,
+        user_vault: TestAccount,
+        vault_config: TestAccount,
+        vault_program: TestAccount,
+        ledger_config: TestAccount,
+        user_stats: TestAccount,
+        market_config: TestAccount,
+        relayer_config: TestAccount,
+        premium_samples: TestAccount,
+        mark_price: TestAccount,
+        funding_state: TestAccount,
+    }
 
-fn process_record_order_events(
-    _program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    events: Vec<OrderEventInput>,
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let _ledger_config_info = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
+    impl SettleFundingAccounts {
+        /// 本期费率 0.01% @ $50,000 已发布并计入 FundingState 指数 ($5 / BTC)
+        fn new(program_id: &Pubkey, position: &Position) -> Self {
+            let ledger_config = ledger_config_account(program_id, Pubkey::new_unique());
+            let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+            let relayer = Pubkey::new_unique();
+            let market_index = position.market_index;
+            Self {
+                relayer: TestAccount::signer(relayer),
+                position: position_account(program_id, position),
+                user_vault: vault_user_account(&config.vault_program, position.user),
+                vault_config: unowned_account(),
+                vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+                ledger_config,
+                user_stats: user_stats_account(program_id, position.user),
+                market_config: market_config_account(program_id, &test_market_config()),
+                relayer_config: relayer_config_account(program_id, relayer),
+                premium_samples: premium_samples_account(program_id, market_index, 100, 50_000_000_000),
+                mark_price: fresh_mark_price_account(program_id, market_index, 50_000_000_000),
+                funding_state: applied_funding_state_account(program_id, market_index, 100, 50_000_000_000),
+            }
+        }
 
-    assert_signer(relayer)?;
+        fn settle(&mut self, program_id: &Pubkey, user: Pubkey) -> ProgramResult {
+            let accounts = [
+                self.relayer.info(),
+                self.position.info(),
+                self.user_vault.info(),
+                self.vault_config.info(),
+                self.vault_program.info(),
+                self.ledger_config.info(),
+                self.user_stats.info(),
+                self.market_config.info(),
+                self.relayer_config.info(),
+                self.premium_samples.info(),
+                self.mark_price.info(),
+                self.funding_state.info(),
+            ];
+            process_settle_funding(program_id, &accounts, user, 0, 100, 50_000_000_000)
+        }
 
-    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
-    if !relayer_config.is_authorized(relayer.key) {
-        msg!("❌ Unauthorized relayer for RecordOrderEvents: {}", relayer.key);
-        return Err(LedgerError::UnauthorizedRelayer.into());
+        /// 以同一组账户作为单个三元组调用 SettleFundingBatch
+        fn settle_batch(&mut self, program_id: &Pubkey, summary: &mut TestAccount, epoch: u64) -> ProgramResult {
+            let mut system_program = unowned_account();
+            let accounts = [
+                self.relayer.info(),
+                self.relayer_config.info(),
+                self.ledger_config.info(),
+                self.vault_config.info(),
+                self.vault_program.info(),
+                self.market_config.info(),
+                summary.info(),
+                system_program.info(),
+                self.premium_samples.info(),
+                self.funding_state.info(),
+                self.mark_price.info(),
+                self.position.info(),
+                self.user_vault.info(),
+                self.user_stats.info(),
+            ];
+            process_settle_funding_batch(program_id, &accounts, 0, 100, 50_000_000_000, epoch)
+        }
     }
 
-    for input in &events {
-        let order_event = OrderEvent {
-            discriminator: event_discriminator::ORDER,
-            sequence: 0,
-            timestamp: input.timestamp,
-            order_id: input.order_id,
-            client_order_id: input.client_order_id,
-            user: input.user,
-            market_index: input.market_index,
-            market_type: input.market_type,
-            side: input.side,
-            order_type: input.order_type,
-            time_in_force: 0,
-            reduce_only: false,
-            post_only: false,
-            price_e6: input.price_e6,
-            size_e6: input.size_e6,
-            filled_size_e6: 0,
-            remaining_size_e6: input.size_e6,
-            trigger_price_e6: 0,
-            avg_fill_price_e6: 0,
-            status: input.status,
-            status_reason: input.status_reason,
+    #[test]
+    fn test_settle_funding_charges_index_accrual_once() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = test_position();
+        let mut accounts = SettleFundingAccounts::new(&program_id, &position);
+
+        // Vault UserAccount 必须属于仓位用户
+        let vault_program = accounts.vault_program.key;
+        accounts.user_vault = vault_user_account(&vault_program, Pubkey::new_unique());
+        assert_eq!(accounts.settle(&program_id, position.user), Err(LedgerError::InvalidAccount.into()));
+        accounts.user_vault = vault_user_account(&vault_program, position.user);
+
+        // Long 1 BTC 按指数差额支付 $5
+        accounts.settle(&program_id, position.user).unwrap();
+        let settled = deserialize_account::<Position>(&accounts.position.data).unwrap();
+        assert_eq!(settled.margin_e6, 4_995_000_000);
+        assert_eq!(settled.unsettled_funding_e6, 0);
+        assert_eq!(settled.cumulative_funding_e6, 5_000_000);
+        assert_eq!(settled.last_funding_index_e6, 5_000_000);
+        assert_eq!(settled.last_funding_ts, TEST_TS);
+        let cpis = take_cpis(&vault_program);
+        assert_eq!(cpis.len(), 1);
+        assert_eq!(cpis[0].0, 14);
+        assert_eq!(cpi_i64(&cpis[0].1, 0), 5_000_000);
+        let stats = deserialize_account::<UserStats>(&accounts.user_stats.data).unwrap();
+        assert_eq!(stats.total_funding_paid_e6, 5_000_000);
+
+        // 之后按同一指数计提 (开/平仓、清算) 不再重复收取
+        let mut after = settled.clone();
+        assert_eq!(after.accrue_funding(5_000_000).unwrap(), 0);
+        let (_, pnl, _) = after.reduce(after.size_e6, 50_000_000_000).unwrap();
+        assert_eq!(pnl, 0);
+    }
+
+    #[test]
+    fn test_settle_funding_rejects_other_users_stats() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = test_position();
+        let mut single = SettleFundingAccounts::new(&program_id, &position);
+        let mut batch = SettleFundingAccounts::new(&program_id, &position);
+
+        // 传入其他用户的 UserStats 不能篡改其资金费统计
+        let other = Pubkey::new_unique();
+        single.user_stats = user_stats_account(&program_id, other);
+        assert_eq!(single.settle(&program_id, position.user), Err(LedgerError::InvalidAccount.into()));
+        let stats = deserialize_account::<UserStats>(&single.user_stats.data).unwrap();
+        assert_eq!(stats.total_funding_paid_e6, 0);
+
+        let mut summary_account = funding_summary_account(&program_id, 0);
+        batch.user_stats = user_stats_account(&program_id, other);
+        assert_eq!(
+            batch.settle_batch(&program_id, &mut summary_account, 1),
+            Err(LedgerError::InvalidAccount.into())
+        );
+        let stats = deserialize_account::<UserStats>(&batch.user_stats.data).unwrap();
+        assert_eq!(stats.total_funding_paid_e6, 0);
+    }
+
+    #[test]
+    fn test_settle_funding_enforces_interval_and_applied_rate() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = test_position();
+        let mut accounts = SettleFundingAccounts::new(&program_id, &position);
+        let vault_program = accounts.vault_program.key;
+
+        // 已发布但尚未计入指数的费率不能结算
+        accounts.funding_state = funding_state_account(&program_id, 0, 5_000_000);
+        assert_eq!(accounts.settle(&program_id, position.user), Err(LedgerError::FundingNotDue.into()));
+        accounts.funding_state = applied_funding_state_account(&program_id, 0, 200, 50_000_000_000);
+        assert_eq!(accounts.settle(&program_id, position.user), Err(LedgerError::InvalidFundingRate.into()));
+        accounts.funding_state = applied_funding_state_account(&program_id, 0, 100, 50_000_000_000);
+
+        accounts.settle(&program_id, position.user).unwrap();
+        assert_eq!(take_cpis(&vault_program).len(), 1);
+
+        // 同一周期内再次结算
+        assert_eq!(accounts.settle(&program_id, position.user), Err(LedgerError::FundingNotDue.into()));
+        assert!(take_cpis(&vault_program).is_empty());
+        assert_eq!(deserialize_account::<Position>(&accounts.position.data).unwrap().margin_e6, 4_995_000_000);
+    }
+
+    #[test]
+    fn test_settle_funding_batch_uses_index_and_mark_price() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = test_position();
+        let mut single = SettleFundingAccounts::new(&program_id, &position);
+        let vault_program = single.vault_program.key;
+        let mut summary_account = funding_summary_account(&program_id, 0);
+
+        // 过期的 MarkPrice 不能用于 FundingEvent
+        let mut stale_mark_price = deserialize_account::<MarkPrice>(&single.mark_price.data).unwrap();
+        stale_mark_price.last_update_ts = TEST_TS - stale_mark_price.max_staleness_secs - 1;
+        let stale_data = account_data(&stale_mark_price, MarkPrice::SIZE);
+        let fresh_data = std::mem::replace(&mut single.mark_price.data, stale_data);
+        assert_eq!(
+            single.settle_batch(&program_id, &mut summary_account, 1),
+            Err(LedgerError::StalePrice.into())
+        );
+        single.mark_price.data = fresh_data;
+        single.settle_batch(&program_id, &mut summary_account, 1).unwrap();
+
+        // 与单个结算相同: 只按指数差额收取一次
+        let settled = deserialize_account::<Position>(&single.position.data).unwrap();
+        assert_eq!(settled.margin_e6, 4_995_000_000);
+        assert_eq!(settled.last_funding_index_e6, 5_000_000);
+        let cpis = take_cpis(&vault_program);
+        assert_eq!(cpis.len(), 1);
+        assert_eq!(cpi_i64(&cpis[0].1, 0), 5_000_000);
+        let summary = deserialize_account::<FundingSettlementSummary>(&summary_account.data).unwrap();
+        assert_eq!(summary.accounts_settled, 1);
+        assert_eq!(summary.total_funding_paid_e6, 5_000_000);
+    }
+
+    #[test]
+    fn test_claim_referral_rewards_credits_through_vault() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let code: ReferralCode = *b"ALICE___________";
+        let stats = ReferrerStats {
+            discriminator: ReferrerStats::DISCRIMINATOR,
+            referrer,
+            code,
+            referee_count: 1,
+            total_earned_e6: 3_000_000,
+            claimable_e6: 3_000_000,
+            total_claimed_e6: 0,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0u8; 32],
         };
-        emit_order_event(&order_event);
+        let mut referrer_account = TestAccount::signer(referrer);
+        let mut stats_account = pda_account(&program_id, &[ReferrerStats::SEED_PREFIX, &code], &stats, ReferrerStats::SIZE);
+        let mut ledger_account = ledger_config_account(&program_id, Pubkey::new_unique());
+        let vault_program = deserialize_account::<LedgerConfig>(&ledger_account.data).unwrap().vault_program;
+        let mut vault_program_account = TestAccount::new(vault_program, Pubkey::new_unique(), vec![]);
+        let mut referrer_vault = vault_user_account(&vault_program, referrer);
+        let mut vault_config = unowned_account();
+
+        // 伪造: 非 PDA 地址上的 LedgerConfig
+        let mut fake = ledger_config_account(&program_id, Pubkey::new_unique());
+        fake.key = Pubkey::new_unique();
+        let accounts = [
+            referrer_account.info(),
+            stats_account.info(),
+            referrer_vault.info(),
+            vault_config.info(),
+            fake.info(),
+            vault_program_account.info(),
+        ];
+        assert_eq!(
+            process_claim_referral_rewards(&program_id, &accounts, code),
+            Err(LedgerError::InvalidAccount.into())
+        );
+
+        let accounts = [
+            referrer_account.info(),
+            stats_account.info(),
+            referrer_vault.info(),
+            vault_config.info(),
+            ledger_account.info(),
+            vault_program_account.info(),
+        ];
+        process_claim_referral_rewards(&program_id, &accounts, code).unwrap();
+
+        // 通过 Vault.CreditReferralReward 划转，而不是伪造的 ClosePositionSettle 盈亏
+        let cpis = take_cpis(&vault_program);
+        assert_eq!(cpis.len(), 1);
+        assert_eq!(cpis[0].0, 17);
+        assert_eq!(cpi_u64(&cpis[0].1, 0), 3_000_000);
+        let claimed = deserialize_account::<ReferrerStats>(&stats_account.data).unwrap();
+        assert_eq!((claimed.claimable_e6, claimed.total_claimed_e6), (0, 3_000_000));
     }
 
-    msg!("✅ RecordOrderEvents: {} events emitted", events.len());
-    Ok(())
-}
+    /// OpenPosition 的全部账户 (Vault / Fund 账户仅作为 CPI 占位)
+    struct OpenPositionAccounts {
+        relayer: TestAccount,
+        position: TestAccount,
+        user_vault: TestAccount,
+        vault_config: TestAccount,
+        ledger_config: TestAccount,
+        user_stats: TestAccount,
+        vault_program: TestAccount,
+        ledger_program: TestAccount,
+        system_program: TestAccount,
+        market_config: TestAccount,
+        price_feed: TestAccount,
+        funding_state: TestAccount,
+        mark_price: TestAccount,
+        fund_program: TestAccount,
+        insurance_fund: TestAccount,
+        insurance_config: TestAccount,
+        fee_schedule: TestAccount,
+        fee_tier_table: TestAccount,
+        referral: TestAccount,
+        referrer_stats: TestAccount,
+    }
 
-// ============================================================================
-// 资金费率事件存证
-// ============================================================================
+    impl OpenPositionAccounts {
+        /// position 为 None 时仓位 PDA 尚未写入 (零填充，CPI 创建账户在链下不执行)
+        fn new(program_id: &Pubkey, user: Pubkey, position: Option<&Position>, index_price_e6: u64) -> Self {
+            let ledger_config = ledger_config_account(program_id, Pubkey::new_unique());
+            let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+            let position = match position {
+                Some(position) => position_account(program_id, position),
+                None => {
+                    let (pda, _) = Pubkey::find_program_address(&[Position::SEED_PREFIX, user.as_ref(), &[0]], program_id);
+                    TestAccount::new(pda, *program_id, vec![0u8; Position::SIZE])
+                }
+            };
+            Self {
+                relayer: TestAccount::signer(Pubkey::new_unique()),
+                position,
+                user_vault: vault_user_account(&config.vault_program, user),
+                vault_config: unowned_account(),
+                ledger_config,
+                user_stats: empty_pda_account(program_id, &[UserStats::SEED_PREFIX, user.as_ref()]),
+                vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+                ledger_program: TestAccount::new(*program_id, Pubkey::new_unique(), vec![]),
+                system_program: unowned_account(),
+                market_config: market_config_account(program_id, &test_market_config()),
+                price_feed: price_feed_account(program_id, 0, index_price_e6),
+                funding_state: funding_state_account(program_id, 0, 0),
+                mark_price: mark_price_account(program_id, 0),
+                fund_program: TestAccount::new(config.fund_program, Pubkey::new_unique(), vec![]),
+                insurance_fund: unowned_account(),
+                insurance_config: unowned_account(),
+                fee_schedule: fee_schedule_account(program_id),
+                fee_tier_table: fee_tier_table_account(program_id),
+                referral: empty_pda_account(program_id, &[Referral::SEED_PREFIX, user.as_ref()]),
+                referrer_stats: unowned_account(),
+            }
+        }
 
-fn process_record_funding_events(
-    _program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    events: Vec<FundingEventInput>,
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let relayer = next_account_info(account_info_iter)?;
-    let _ledger_config_info = next_account_info(account_info_iter)?;
-    let relayer_config_info = next_account_info(account_info_iter)?;
+        fn position(&self) -> Position {
+            deserialize_account::<Position>(&self.position.data).unwrap()
+        }
 
-    assert_signer(relayer)?;
+        fn open(&mut self, program_id: &Pubkey, user: Pubkey, side: Side, size_e6: u64, price_e6: u64) -> ProgramResult {
+            let accounts = [
+                self.relayer.info(),
+                self.position.info(),
+                self.user_vault.info(),
+                self.vault_config.info(),
+                self.ledger_config.info(),
+                self.user_stats.info(),
+                self.vault_program.info(),
+                self.ledger_program.info(),
+                self.system_program.info(),
+                self.market_config.info(),
+                self.price_feed.info(),
+                self.funding_state.info(),
+                self.mark_price.info(),
+                self.fund_program.info(),
+                self.insurance_fund.info(),
+                self.insurance_config.info(),
+                self.fee_schedule.info(),
+                self.fee_tier_table.info(),
+                self.referral.info(),
+                self.referrer_stats.info(),
+            ];
+            let fill = FillParams { batch_id: 1, is_taker: true, fee_rate_e6: 500 };
+            process_open_position(program_id, &accounts, user, 0, side, size_e6, price_e6, 10, fill)
+        }
+    }
 
-    let relayer_config = deserialize_account::<RelayerConfig>(&relayer_config_info.data.borrow())?;
-    if !relayer_config.is_authorized(relayer.key) {
-        msg!("❌ Unauthorized relayer for RecordFundingEvents: {}", relayer.key);
-        return Err(LedgerError::UnauthorizedRelayer.into());
+    #[test]
+    fn test_open_position_charges_fee_outside_position_margin() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+
+        // Long 1 BTC @ $50,000，10x: 保证金 $5,000，手续费 0.05% = $25
+        let mut accounts = OpenPositionAccounts::new(&program_id, user, None, 50_000_000_000);
+        let vault_program = accounts.vault_program.key;
+        let fund_program = accounts.fund_program.key;
+        accounts.open(&program_id, user, Side::Long, 1_000_000, 50_000_000_000).unwrap();
+
+        // 手续费从可用余额扣除，不计入锁定保证金和仓位保证金
+        assert_eq!(accounts.position().margin_e6, 5_000_000_000);
+        let vault_cpis = take_cpis(&vault_program);
+        assert_eq!(vault_cpis.len(), 2);
+        assert_eq!((vault_cpis[0].0, cpi_u64(&vault_cpis[0].1, 0)), (4, 5_000_000_000));
+        assert_eq!((vault_cpis[1].0, cpi_u64(&vault_cpis[1].1, 0)), (18, 25_000_000));
+
+        // 保险基金 40% 计入 Fund
+        let fund_cpis = take_cpis(&fund_program);
+        assert_eq!(fund_cpis.len(), 1);
+        assert_eq!((fund_cpis[0].0, cpi_i64(&fund_cpis[0].1, 0)), (22, 10_000_000));
     }
 
-    for input in &events {
-        msg!(
-            "EVENT:FundingSettlement:market={},rate={},price={},accounts={},total_paid={},epoch={},ts={}",
-            input.market_index,
-            input.funding_rate_e6,
-            input.index_price_e6,
-            input.accounts_settled,
-            input.total_funding_paid_e6,
-            input.epoch,
-            input.timestamp,
+    #[test]
+    fn test_open_position_flip_charges_open_fee_outside_margin() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = Position { side: Side::Short, ..test_position() };
+        let user = position.user;
+
+        // 已有 Short 1 BTC，开 Long 3 BTC: 平掉 1 BTC，反向开 Long 2 BTC
+        let mut accounts = OpenPositionAccounts::new(&program_id, user, Some(&position), 50_000_000_000);
+        let vault_program = accounts.vault_program.key;
+        let fund_program = accounts.fund_program.key;
+        accounts.open(&program_id, user, Side::Long, 3_000_000, 50_000_000_000).unwrap();
+
+        let flipped = accounts.position();
+        assert_eq!((flipped.side, flipped.size_e6, flipped.margin_e6), (Side::Long, 2_000_000, 10_000_000_000));
+
+        // 平仓手续费 $25 由 ClosePositionSettle 扣除，开仓保证金 $10,000 锁定，开仓手续费 $50 单独扣除
+        let vault_cpis = take_cpis(&vault_program);
+        assert_eq!(vault_cpis.len(), 3);
+        assert_eq!((vault_cpis[0].0, cpi_u64(&vault_cpis[0].1, 16)), (6, 25_000_000));
+        assert_eq!((vault_cpis[1].0, cpi_u64(&vault_cpis[1].1, 0)), (4, 10_000_000_000));
+        assert_eq!((vault_cpis[2].0, cpi_u64(&vault_cpis[2].1, 0)), (18, 50_000_000));
+
+        let fund_cpis = take_cpis(&fund_program);
+        assert_eq!((fund_cpis[0].0, cpi_i64(&fund_cpis[0].1, 0)), (22, 30_000_000));
+    }
+
+    /// RemoveMargin / SetLeverage 的全部账户
+    struct AdjustMarginAccounts {
+        user: TestAccount,
+        position: TestAccount,
+        user_vault: TestAccount,
+        vault_config: TestAccount,
+        ledger_config: TestAccount,
+        relayer_config: TestAccount,
+        vault_program: TestAccount,
+        market_config: TestAccount,
+        funding_state: TestAccount,
+        price_feed: TestAccount,
+        mark_price: TestAccount,
+    }
+
+    impl AdjustMarginAccounts {
+        fn execute(&mut self, program_id: &Pubkey, adjustment: MarginAdjustment) -> ProgramResult {
+            let user = self.user.key;
+            let accounts = [
+                self.user.info(),
+                self.position.info(),
+                self.user_vault.info(),
+                self.vault_config.info(),
+                self.ledger_config.info(),
+                self.relayer_config.info(),
+                self.vault_program.info(),
+                self.market_config.info(),
+                self.funding_state.info(),
+                self.price_feed.info(),
+                self.mark_price.info(),
+            ];
+            process_adjust_margin(program_id, &accounts, user, 0, adjustment)
+        }
+    }
+
+    #[test]
+    fn test_remove_margin_accrues_funding_before_check() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let position = test_position();
+        let ledger_config = ledger_config_account(&program_id, Pubkey::new_unique());
+        let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+
+        // Long 1 BTC，保证金 $5,000，IMR 5% 需 $2,500；资金费指数 +$1,000 -> 应付 $1,000，最多可提 $1,500
+        let mut accounts = AdjustMarginAccounts {
+            user: TestAccount::signer(position.user),
+            position: position_account(&program_id, &position),
+            user_vault: vault_user_account(&config.vault_program, position.user),
+            vault_config: unowned_account(),
+            relayer_config: relayer_config_account(&program_id, Pubkey::new_unique()),
+            vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+            market_config: market_config_account(&program_id, &test_market_config()),
+            funding_state: funding_state_account(&program_id, 0, 1_000_000_000),
+            price_feed: price_feed_account(&program_id, 0, 50_000_000_000),
+            mark_price: mark_price_account(&program_id, 0),
+            ledger_config,
+        };
+
+        assert_eq!(
+            accounts.execute(&program_id, MarginAdjustment::Remove(2_000_000_000)),
+            Err(LedgerError::InsufficientMargin.into())
         );
+        accounts.execute(&program_id, MarginAdjustment::Remove(1_500_000_000)).unwrap();
+
+        let mark = deserialize_account::<MarkPrice>(&accounts.mark_price.data).unwrap();
+        assert_eq!(mark.mark_price_e6, 50_000_000_000);
+        let adjusted = deserialize_account::<Position>(&accounts.position.data).unwrap();
+        assert_eq!(adjusted.margin_e6, 3_500_000_000);
+        assert_eq!(adjusted.unsettled_funding_e6, 1_000_000_000);
+        assert_eq!(adjusted.last_funding_index_e6, 1_000_000_000);
+
+        let vault_cpis = take_cpis(&config.vault_program);
+        assert_eq!((vault_cpis[0].0, cpi_u64(&vault_cpis[0].1, 0)), (5, 1_500_000_000));
     }
 
-    msg!("✅ RecordFundingEvents: {} events emitted", events.len());
-    Ok(())
-}
+    /// ExecuteTradeBatch 的全部账户 (单笔成交，maker / taker 均为新开仓)
+    struct TradeBatchAccounts {
+        relayer: TestAccount,
+        trade_batch: TestAccount,
+        relayer_config: TestAccount,
+        ledger_config: TestAccount,
+        vault_config: TestAccount,
+        vault_program: TestAccount,
+        ledger_program: TestAccount,
+        system_program: TestAccount,
+        insurance_fund: TestAccount,
+        fund_program: TestAccount,
+        insurance_config: TestAccount,
+        fee_schedule: TestAccount,
+        fee_tier_table: TestAccount,
+        /// maker / taker 各 [Position, UserAccount (Vault), UserStats]
+        legs: Vec<TestAccount>,
+        /// MarketConfig / PriceFeed / FundingState / MarkPrice / 双方 Referral
+        markets: Vec<TestAccount>,
+    }
+
+    impl TradeBatchAccounts {
+        fn new(program_id: &Pubkey, batch_id: u64, fill: &MatchedTrade) -> Self {
+            let relayer = Pubkey::new_unique();
+            let ledger_config = ledger_config_account(program_id, Pubkey::new_unique());
+            let config = deserialize_account::<LedgerConfig>(&ledger_config.data).unwrap();
+            let trade_batch = TradeBatch {
+                discriminator: TradeBatch::DISCRIMINATOR,
+                batch_id,
+                data_hash: compute_batch_hash(program_id, batch_id, &vec![fill.clone()].try_to_vec().unwrap()),
+                signatures: vec![RelayerSignature { relayer, signed_at: TEST_TS }],
+                executed: false,
+                created_at: TEST_TS,
+                expires_at: TEST_TS + 60,
+                creator: relayer,
+                bump: 255,
+            };
+            let mut legs = Vec::new();
+            for user in [fill.maker.user, fill.taker.user] {
+                let (position_pda, _) =
+                    Pubkey::find_program_address(&[Position::SEED_PREFIX, user.as_ref(), &[fill.market_index]], program_id);
+                legs.push(TestAccount::new(position_pda, *program_id, vec![0u8; Position::SIZE]));
+                legs.push(vault_user_account(&config.vault_program, user));
+                legs.push(user_stats_account(program_id, user));
+            }
+            let markets = vec![
+                market_config_account(program_id, &test_market_config()),
+                price_feed_account(program_id, fill.market_index, fill.price_e6),
+                funding_state_account(program_id, fill.market_index, 0),
+                mark_price_account(program_id, fill.market_index),
+                empty_pda_account(program_id, &[Referral::SEED_PREFIX, fill.maker.user.as_ref()]),
+                empty_pda_account(program_id, &[Referral::SEED_PREFIX, fill.taker.user.as_ref()]),
+            ];
+            Self {
+                relayer: TestAccount::signer(relayer),
+                trade_batch: pda_account(program_id, &[b"trade_batch", &batch_id.to_le_bytes()], &trade_batch, TradeBatch::SIZE),
+                relayer_config: relayer_config_account(program_id, relayer),
+                vault_config: unowned_account(),
+                vault_program: TestAccount::new(config.vault_program, Pubkey::new_unique(), vec![]),
+                ledger_program: TestAccount::new(*program_id, Pubkey::new_unique(), vec![]),
+                system_program: unowned_account(),
+                insurance_fund: unowned_account(),
+                fund_program: TestAccount::new(config.fund_program, Pubkey::new_unique(), vec![]),
+                insurance_config: unowned_account(),
+                fee_schedule: fee_schedule_account(program_id),
+                fee_tier_table: fee_tier_table_account(program_id),
+                ledger_config,
+                legs,
+                markets,
+            }
+        }
+
+        fn execute(&mut self, program_id: &Pubkey, batch_id: u64, trades: Vec<MatchedTrade>) -> ProgramResult {
+            let mut accounts = vec![
+                self.relayer.info(),
+                self.trade_batch.info(),
+                self.relayer_config.info(),
+                self.ledger_config.info(),
+                self.vault_config.info(),
+                self.vault_program.info(),
+                self.ledger_program.info(),
+                self.system_program.info(),
+                self.insurance_fund.info(),
+                self.fund_program.info(),
+                self.insurance_config.info(),
+                self.fee_schedule.info(),
+                self.fee_tier_table.info(),
+            ];
+            accounts.extend(self.legs.iter_mut().map(|a| a.info()));
+            accounts.extend(self.markets.iter_mut().map(|a| a.info()));
+            process_execute_trade_batch(program_id, &accounts, batch_id, trades)
+        }
+    }
 
+    #[test]
+    fn test_trade_batch_caps_maker_rebate_at_rebate_pool_share() {
+        init_syscalls();
+        let program_id = Pubkey::new_unique();
+        let leg = |side: Side, fee_rate_e6: i64| TradeLeg {
+            user: Pubkey::new_unique(),
+            order_id: [0u8; 16],
+            trade_type: trade_data_type::OPEN,
+            side,
+            size_e6: 1_000_000,
+            leverage: 10,
+            fee_rate_e6,
+        };
+        // 1 BTC @ $50,000: maker 返佣 -0.02% = $10，taker 手续费 0.05% = $25，其中 Maker 返佣池 10% = $2.5
+        let fill = MatchedTrade {
+            market_index: 0,
+            price_e6: 50_000_000_000,
+            maker: leg(Side::Short, -200),
+            taker: leg(Side::Long, 500),
+        };
+        let mut accounts = TradeBatchAccounts::new(&program_id, 1, &fill);
+        let vault_program = accounts.vault_program.key;
+        accounts.execute(&program_id, 1, vec![fill.clone()]).unwrap();
+
+        // 返佣按返佣池上限 $2.5 通过 CreditFeeRebate 划给 maker
+        let vault_cpis = take_cpis(&vault_program);
+        let rebates: Vec<u64> = vault_cpis.iter().filter(|(tag, _)| *tag == 16).map(|(_, args)| cpi_u64(args, 0)).collect();
+        assert_eq!(rebates, vec![2_500_000]);
+        let maker_stats = deserialize_account::<UserStats>(&accounts.legs[2].data).unwrap();
+        assert_eq!(maker_stats.total_rebates_received_e6, 2_500_000);
+        let trade_events = logged_events::<TradeEvent>(events::TRADE_EVENT_NAME);
+        assert_eq!(trade_events.len(), 1);
+        assert_eq!((trade_events[0].maker_fee_e6, trade_events[0].taker_fee_e6), (-2_500_000, 25_000_000));
+
+        // 返佣池已全部发放，FeeSchedule 不再累计 Maker 返佣
+        let fee_schedule = deserialize_account::<FeeSchedule>(&accounts.fee_schedule.data).unwrap();
+        assert_eq!(fee_schedule.total_maker_rebate_e6, 0);
+        assert_eq!(fee_schedule.total_insurance_e6, 10_000_000);
+    }
+}
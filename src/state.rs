@@ -7,10 +7,11 @@
 //! - Position: 用户仓位 (PDA)
 //! - TradeRecord: 成交记录
 //! - UserStats: 用户统计
+//! - MarketConfig: 单市场风控参数
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::pubkey::Pubkey;
-use crate::utils::{mul_e6, div_e6, checked_sub, checked_add};
+use crate::utils::{mul_e6, div_e6, checked_div, checked_sub, checked_add, is_price_within_band};
 
 // ============================================================================
 // Side (仓位方向)
@@ -46,18 +47,51 @@ pub const MAX_RELAYERS: usize = 5;
 /// 最大签名数量
 pub const MAX_SIGNATURES: usize = 5;
 
-/// 最大杠杆倍数 (100x)
+/// 全局杠杆硬上限 (100x) - 各市场的 max_leverage 不得超过此值
 pub const MAX_LEVERAGE: u8 = 100;
 
-/// 默认清算阈值 (维持保证金率 2.5%)
-pub const DEFAULT_MAINTENANCE_MARGIN_RATE: i64 = 25_000; // 2.5% in e6
+/// 清算罚金率硬上限 (10%)
+pub const MAX_LIQUIDATION_PENALTY_RATE: i64 = 100_000; // 10% in e6
 
-/// 清算罚金率 (1%)
-pub const LIQUIDATION_PENALTY_RATE: i64 = 10_000; // 1% in e6
+/// 每个市场最多的风险档位数量
+pub const MAX_RISK_TIERS: usize = 8;
+
+/// 成交价格带宽硬上限 (bps, 10000 = 100%)
+pub const MAX_PRICE_BAND_BPS: u16 = 10_000;
+
+/// 单期资金费率硬上限 (10%)
+pub const MAX_FUNDING_RATE_E6: i64 = 100_000; // 10% in e6
+
+/// 每个市场溢价采样环形缓冲区容量
+pub const PREMIUM_SAMPLE_CAPACITY: usize = 60;
 
 /// 交易批次过期时间 (60 秒)
 pub const TRADE_BATCH_EXPIRY_SECONDS: i64 = 60;
 
+/// 手续费分配比例分母 (bps)
+pub const FEE_SPLIT_BPS_DENOMINATOR: u16 = 10_000;
+
+/// 保险基金分成下限 (10%)
+pub const MIN_INSURANCE_FEE_BPS: u16 = 1_000;
+
+/// 推荐人分成上限 (50%)
+pub const MAX_REFERRER_FEE_BPS: u16 = 5_000;
+
+/// Maker 返佣分成上限 (50%)
+pub const MAX_MAKER_REBATE_FEE_BPS: u16 = 5_000;
+
+/// 被推荐用户手续费折扣上限 (50%)
+pub const MAX_REFEREE_DISCOUNT_BPS: u16 = 5_000;
+
+/// 手续费档位数量上限
+pub const MAX_FEE_TIERS: usize = 8;
+
+/// 手续费档位使用的滚动成交量窗口 (30 个 UTC 日，每日一个桶)
+pub const ROLLING_VOLUME_DAYS: usize = 30;
+
+/// 滚动成交量分桶的单位 (1 天)
+pub const ROLLING_VOLUME_BUCKET_SECS: i64 = 24 * 60 * 60;
+
 // ============================================================================
 // LedgerConfig (全局配置)
 // ============================================================================
@@ -132,6 +166,13 @@ impl LedgerConfig {
         self.global_sequence = self.global_sequence.saturating_add(1);
         seq
     }
+
+    /// 连续分配 count 个序号，返回第一个
+    pub fn next_sequences(&mut self, count: u64) -> u64 {
+        let seq = self.global_sequence;
+        self.global_sequence = self.global_sequence.saturating_add(count);
+        seq
+    }
 }
 
 // ============================================================================
@@ -297,8 +338,14 @@ pub struct Position {
     pub last_update_ts: i64,
     /// Bump
     pub bump: u8,
+    /// 上次结算时的 FundingState 累计资金费指数 (e6)
+    pub last_funding_index_e6: i64,
+    /// 已计提但尚未与 Vault 结算的资金费 (e6，正数 = 应付)，平仓时计入已实现盈亏
+    pub unsettled_funding_e6: i64,
+    /// 本仓位已创建的 ShortfallRecord 数量 (占用原预留空间)，作为下一条记录的 PDA seed
+    pub shortfall_count: u64,
     /// 预留空间
-    pub reserved: [u8; 32],
+    pub reserved: [u8; 8],
 }
 
 impl Position {
@@ -319,7 +366,10 @@ impl Position {
         8 + // opened_at
         8 + // last_update_ts
         1 + // bump
-        32; // reserved
+        8 + // last_funding_index_e6
+        8 + // unsettled_funding_e6
+        8 + // shortfall_count
+        8; // reserved
 
     /// PDA Seeds prefix: ["position", user]
     /// 注意: market_index 需要在调用处传入
@@ -335,6 +385,57 @@ impl Position {
         Ok(result as u64)
     }
 
+    /// 扣除未结算资金费后的保证金 (e6)，清算相关计算均使用此值
+    pub fn funding_adjusted_margin_e6(&self) -> i64 {
+        (self.margin_e6 as i64).saturating_sub(self.unsettled_funding_e6)
+    }
+
+    /// 按 FundingState 累计指数计提资金费
+    /// payment = size * (index - last_index) / 1e6，Long 支付正值，Short 收取
+    ///
+    /// 返回本次计提的资金费 (正数 = 应付)。空仓位只同步指数。
+    /// 不修改 last_funding_ts (SettleFunding 的结算周期起点)
+    pub fn accrue_funding(&mut self, funding_index_e6: i64) -> Result<i64, ProgramError> {
+        let index_delta = checked_sub(funding_index_e6, self.last_funding_index_e6)?;
+        self.last_funding_index_e6 = funding_index_e6;
+        if self.is_empty() || index_delta == 0 {
+            return Ok(0);
+        }
+
+        let payment = mul_e6(index_delta, self.size_e6 as i64)?;
+        let payment = match self.side {
+            Side::Long => payment,
+            Side::Short => -payment,
+        };
+        self.unsettled_funding_e6 = checked_add(self.unsettled_funding_e6, payment)?;
+        self.cumulative_funding_e6 = checked_add(self.cumulative_funding_e6, payment)?;
+        Ok(payment)
+    }
+
+    /// 取出平仓部分对应的未结算资金费 (close_ratio e6)，仓位已清空时取出全部
+    /// 调用方将其从已实现盈亏中扣除
+    pub fn take_unsettled_funding(&mut self, close_ratio_e6: i64) -> Result<i64, ProgramError> {
+        let share = if self.size_e6 == 0 {
+            self.unsettled_funding_e6
+        } else {
+            mul_e6(self.unsettled_funding_e6, close_ratio_e6)?
+        };
+        self.unsettled_funding_e6 = checked_sub(self.unsettled_funding_e6, share)?;
+        Ok(share)
+    }
+
+    /// 将应付资金费 (正数 = 支付) 实时计入仓位保证金
+    /// 支付超过保证金的部分保留为未结算资金费，仍从清算计算的保证金中扣除
+    ///
+    /// 返回实际从保证金扣除 (负数 = 增加) 的金额，调用方需通过 Vault CPI 同步锁定保证金
+    pub fn settle_funding_to_margin(&mut self, payment_e6: i64) -> Result<i64, ProgramError> {
+        let settled = payment_e6.min(self.margin_e6 as i64);
+        let new_margin = checked_sub(self.margin_e6 as i64, settled)?;
+        self.margin_e6 = new_margin as u64;
+        self.unsettled_funding_e6 = checked_sub(payment_e6, settled)?;
+        Ok(settled)
+    }
+
     /// 计算未实现盈亏
     /// Long: (mark_price - entry_price) * size / 1e6
     /// Short: (entry_price - mark_price) * size / 1e6
@@ -352,12 +453,23 @@ impl Position {
     }
 
     /// 计算清算价格
-    /// Long: entry_price * (1 - 1/leverage + maintenance_margin_rate)
-    /// Short: entry_price * (1 + 1/leverage - maintenance_margin_rate)
-    pub fn calculate_liquidation_price(&self) -> Result<u64, ProgramError> {
+    /// Long: entry_price * (1 - margin/notional + maintenance_margin_rate)
+    /// Short: entry_price * (1 + margin/notional - maintenance_margin_rate)
+    ///
+    /// margin/notional 为按入场价计算的实际保证金率: 开仓时等于 1/leverage，
+    /// 追加/减少保证金后随之变化，未结算资金费从 margin 中扣除。无保证金记录时回退到 1/leverage。
+    ///
+    /// `mmr` 为该市场 MarketConfig 中的维持保证金率 (e6)
+    pub fn calculate_liquidation_price(&self, mmr: i64) -> Result<u64, ProgramError> {
         let entry = self.entry_price_e6 as i64;
-        let leverage_factor = div_e6(1_000_000, self.leverage as i64)?; // 1/leverage in e6
-        let mmr = DEFAULT_MAINTENANCE_MARGIN_RATE; // 2.5% in e6
+        let notional = (self.size_e6 as u128) * (self.entry_price_e6 as u128);
+        let margin = self.funding_adjusted_margin_e6().max(0) as u128;
+        let leverage_factor = if self.margin_e6 > 0 && notional > 0 {
+            // margin / notional in e6 = margin * 1e12 / (size * entry)
+            (margin * 1_000_000_000_000 / notional).min(i64::MAX as u128) as i64
+        } else {
+            checked_div(1_000_000, self.leverage as i64)? // 1/leverage in e6
+        };
 
         let factor = match self.side {
             Side::Long => {
@@ -376,6 +488,171 @@ impl Position {
         Ok(liq_price.max(0) as u64)
     }
 
+    /// 当前保证金率 = (margin - 未结算资金费 + 未实现盈亏) / 按 mark_price 计算的名义价值 (e6)
+    pub fn margin_ratio_e6(&self, mark_price_e6: u64) -> Result<i64, ProgramError> {
+        let notional = (self.size_e6 as u128) * (mark_price_e6 as u128) / 1_000_000;
+        if notional == 0 {
+            return Ok(i64::MAX);
+        }
+        let equity = checked_add(self.funding_adjusted_margin_e6(), self.calculate_unrealized_pnl(mark_price_e6)?)?;
+        let ratio = (equity as i128) * 1_000_000 / (notional as i128);
+        Ok(ratio.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+    }
+
+    /// 部分清算需要平掉的数量 (按 lot_size 向上取整)
+    ///
+    /// 以 mark_price 平掉比例 f，已实现亏损和罚金 (平仓名义价值 * penalty_rate) 留在仓位保证金中结算，
+    /// 使剩余仓位保证金率回到 target_ratio:
+    ///   (E - r·f·N) / ((1 - f)·N) >= t  =>  f >= (t·N - E) / ((t - r)·N)
+    ///
+    /// 返回 None 表示无法通过部分清算恢复 (需要全部清算)
+    pub fn partial_liquidation_size(
+        &self,
+        mark_price_e6: u64,
+        target_ratio_e6: i64,
+        penalty_rate_e6: i64,
+        lot_size_e6: u64,
+    ) -> Result<Option<u64>, ProgramError> {
+        if target_ratio_e6 <= penalty_rate_e6 || lot_size_e6 == 0 {
+            return Ok(None);
+        }
+        let notional = (self.size_e6 as i128) * (mark_price_e6 as i128) / 1_000_000;
+        let equity = (self.funding_adjusted_margin_e6() as i128) + (self.calculate_unrealized_pnl(mark_price_e6)? as i128);
+        if notional <= 0 || equity <= 0 {
+            return Ok(None);
+        }
+
+        // f (e6) = (t·N - E·1e6) / ((t - r)·N)，向上取整
+        let numerator = (target_ratio_e6 as i128) * notional - equity * 1_000_000;
+        if numerator <= 0 {
+            return Ok(Some(0));
+        }
+        let denominator = ((target_ratio_e6 - penalty_rate_e6) as i128) * notional;
+        let fraction_e6 = (numerator * 1_000_000 + denominator - 1) / denominator;
+
+        let size = self.size_e6 as i128;
+        let lot = lot_size_e6 as i128;
+        let close_size = (size * fraction_e6 + 999_999) / 1_000_000;
+        let close_size = (close_size + lot - 1) / lot * lot;
+        if close_size >= size {
+            return Ok(None);
+        }
+        Ok(Some(close_size as u64))
+    }
+
+    /// 在 mark_price 下仍满足初始保证金率 `imr` 时可提取的最大保证金
+    ///
+    /// 未实现盈利不计入，未实现亏损和未结算资金费从保证金中扣除
+    pub fn max_removable_margin(&self, mark_price_e6: u64, imr: i64) -> Result<u64, ProgramError> {
+        let pnl = self.calculate_unrealized_pnl(mark_price_e6)?;
+        let equity = checked_add(self.funding_adjusted_margin_e6(), pnl.min(0))?;
+        let notional = ((self.size_e6 as u128) * (mark_price_e6 as u128) / 1_000_000) as i64;
+        let required = mul_e6(notional, imr)?;
+        let removable = checked_sub(equity, required)?.max(0) as u64;
+        Ok(removable.min(self.margin_e6))
+    }
+
+    /// 按成交价减仓，实现已平部分的 PnL
+    ///
+    /// 返回 (实际平仓数量, 已实现 PnL, 释放保证金)。已实现 PnL 已扣除已平部分的未结算资金费。
+    /// 平仓数量超过仓位时按全部平仓处理，全部平仓会清空仓位字段；部分平仓后调用方需重新计算清算价格。
+    pub fn reduce(&mut self, size_e6: u64, price_e6: u64) -> Result<(u64, i64, u64), ProgramError> {
+        let close_size = size_e6.min(self.size_e6);
+        if close_size == 0 {
+            return Ok((0, 0, 0));
+        }
+
+        let close_ratio = div_e6(close_size as i64, self.size_e6 as i64)?;
+        let pnl = self.calculate_unrealized_pnl(price_e6)?;
+        let realized_pnl = mul_e6(pnl, close_ratio)?;
+
+        if close_size == self.size_e6 {
+            let margin_released = self.margin_e6;
+            self.size_e6 = 0;
+            self.margin_e6 = 0;
+            self.entry_price_e6 = 0;
+            self.liquidation_price_e6 = 0;
+            self.unrealized_pnl_e6 = 0;
+            let funding = self.take_unsettled_funding(close_ratio)?;
+            return Ok((close_size, checked_sub(realized_pnl, funding)?, margin_released));
+        }
+
+        let margin_released = (mul_e6(self.margin_e6 as i64, close_ratio)? as u64).min(self.margin_e6);
+        self.size_e6 -= close_size;
+        self.margin_e6 -= margin_released;
+        let funding = self.take_unsettled_funding(close_ratio)?;
+        Ok((close_size, checked_sub(realized_pnl, funding)?, margin_released))
+    }
+
+    /// 破产价格: 亏损恰好等于保证金 (扣除未结算资金费) 的价格
+    /// Long: entry - margin / size, Short: entry + margin / size
+    pub fn bankruptcy_price_e6(&self) -> u64 {
+        if self.size_e6 == 0 {
+            return 0;
+        }
+        let margin = self.funding_adjusted_margin_e6().max(0) as u128;
+        let margin_per_unit = (margin * 1_000_000 / self.size_e6 as u128) as u64;
+        match self.side {
+            Side::Long => self.entry_price_e6.saturating_sub(margin_per_unit),
+            Side::Short => self.entry_price_e6.saturating_add(margin_per_unit),
+        }
+    }
+
+    /// ADL 排序分数 (e6) = 盈利率 × 有效杠杆
+    /// 盈利率 = upnl / margin，有效杠杆 = 标记价格名义价值 / (margin + upnl)
+    /// 按标记价格未盈利的仓位返回 0 (不参与 ADL)
+    pub fn adl_score_e6(&self, mark_price_e6: u64) -> Result<u64, ProgramError> {
+        let pnl = self.calculate_unrealized_pnl(mark_price_e6)?;
+        if pnl <= 0 {
+            return Ok(0);
+        }
+        let pnl = pnl as i128;
+        let margin = self.margin_e6.max(1) as i128;
+        let notional = self.size_e6 as i128 * mark_price_e6 as i128 / 1_000_000;
+
+        let pnl_ratio_e6 = pnl * 1_000_000 / margin;
+        let effective_leverage_e6 = notional * 1_000_000 / (margin + pnl);
+        let score = pnl_ratio_e6 * effective_leverage_e6 / 1_000_000;
+        Ok(score.min(u64::MAX as i128) as u64)
+    }
+
+    /// ADL 减仓数量
+    /// 按破产价格平仓时，每单位仓位相对标记价格让出 |mark - bankruptcy| 的盈利用于覆盖穿仓。
+    /// 返回 (close_size, covered_e6)，close_size 为覆盖 remaining_e6 所需的最小数量
+    /// (按 lot_size 向上取整，不超过仓位大小)。
+    ///
+    /// 破产价格必须比标记价格对本仓位更不利 (Long: 低于 mark, Short: 高于 mark)，否则返回 InvalidPrice
+    pub fn adl_close_size(
+        &self,
+        remaining_e6: u64,
+        mark_price_e6: u64,
+        bankruptcy_price_e6: u64,
+        lot_size_e6: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        let haircut_per_unit = match self.side {
+            Side::Long => mark_price_e6.checked_sub(bankruptcy_price_e6),
+            Side::Short => bankruptcy_price_e6.checked_sub(mark_price_e6),
+        }
+        .filter(|&diff| diff > 0)
+        .ok_or(crate::error::LedgerError::InvalidPrice)? as u128;
+
+        let mut needed = (remaining_e6 as u128 * 1_000_000).div_ceil(haircut_per_unit);
+        if lot_size_e6 > 0 {
+            needed = needed.div_ceil(lot_size_e6 as u128) * lot_size_e6 as u128;
+        }
+        let close_size = needed.min(self.size_e6 as u128) as u64;
+        let covered = (close_size as u128 * haircut_per_unit / 1_000_000) as u64;
+        Ok((close_size, covered))
+    }
+
+    /// 按仓位当前名义价值所在的风险档位重新计算清算价格
+    /// 仓位大小变化后 (开仓/加仓/减仓) 必须调用
+    pub fn refresh_liquidation_price(&mut self, market_config: &MarketConfig) -> Result<(), ProgramError> {
+        let tier = market_config.risk_tier_for(self.notional_value_e6()?)?;
+        self.liquidation_price_e6 = self.calculate_liquidation_price(tier.maintenance_margin_rate_e6)?;
+        Ok(())
+    }
+
     /// 检查是否应该被清算
     pub fn should_liquidate(&self, mark_price_e6: u64) -> bool {
         match self.side {
@@ -386,7 +663,16 @@ impl Position {
 
     /// 更新入场价格 (加仓时)
     /// new_entry = (old_entry * old_size + new_price * add_size) / (old_size + add_size)
-    pub fn update_entry_price(&mut self, add_size_e6: u64, add_price_e6: u64) -> Result<(), ProgramError> {
+    ///
+    /// 混合杠杆: 加仓部分的保证金按本次杠杆计算，调用方需在调用前累加到 margin_e6；
+    /// leverage 更新为合并后的实际杠杆 (见 effective_leverage)。
+    /// 清算价格按加仓后名义价值所在的风险档位重新计算
+    pub fn update_entry_price(
+        &mut self,
+        add_size_e6: u64,
+        add_price_e6: u64,
+        market_config: &MarketConfig,
+    ) -> Result<(), ProgramError> {
         let old_notional = (self.size_e6 as u128)
             .checked_mul(self.entry_price_e6 as u128)
             .ok_or(crate::error::LedgerError::Overflow)?;
@@ -411,13 +697,26 @@ impl Position {
 
         self.entry_price_e6 = new_entry as u64;
         self.size_e6 = total_size as u64;
+        self.leverage = self.effective_leverage()?;
 
         // 重新计算清算价格
-        self.liquidation_price_e6 = self.calculate_liquidation_price()?;
+        self.refresh_liquidation_price(market_config)?;
 
         Ok(())
     }
 
+    /// 实际杠杆 = 入场名义价值 / 保证金 (四舍五入，范围 1..=255)
+    /// 无保证金记录时返回当前 leverage
+    pub fn effective_leverage(&self) -> Result<u8, ProgramError> {
+        if self.margin_e6 == 0 {
+            return Ok(self.leverage);
+        }
+        let notional = self.notional_value_e6()? as u128;
+        let margin = self.margin_e6 as u128;
+        let leverage = (notional + margin / 2) / margin;
+        Ok(leverage.clamp(1, u8::MAX as u128) as u8)
+    }
+
     /// 检查仓位是否为空
     pub fn is_empty(&self) -> bool {
         self.size_e6 == 0
@@ -522,6 +821,12 @@ pub struct UserStats {
     pub last_trade_at: i64,
     /// Bump
     pub bump: u8,
+    /// 累计收到的 maker 返佣 (e6, 占用原预留空间)
+    pub total_rebates_received_e6: u64,
+    /// 最近一次记录成交量的日序号 (unix_ts / 86400，占用原预留空间)
+    pub last_volume_day: i64,
+    /// 每日成交量 (e6)，日序号 d 存放在 d % 30，只有最近 30 天内的桶有效
+    pub daily_volume_e6: [u64; ROLLING_VOLUME_DAYS],
 }
 
 impl UserStats {
@@ -537,166 +842,1702 @@ impl UserStats {
         8 + // first_trade_at
         8 + // last_trade_at
         1 + // bump
-        32; // reserved
+        8 + // total_rebates_received_e6
+        8 + // last_volume_day
+        8 + // reserved
+        8 * ROLLING_VOLUME_DAYS; // daily_volume_e6
 
     /// PDA Seeds prefix
     pub const SEED_PREFIX: &'static [u8] = b"user_stats";
-}
 
-// ============================================================================
-// Tests
-// ============================================================================
+    /// 当前时刻的 30 天滚动成交量: 当日及前 29 天的每日成交量之和
+    pub fn rolling_volume_e6(&self, current_ts: i64) -> u64 {
+        let today = current_ts.div_euclid(ROLLING_VOLUME_BUCKET_SECS);
+        let first_day = (today - ROLLING_VOLUME_DAYS as i64 + 1).max(self.last_volume_day - ROLLING_VOLUME_DAYS as i64 + 1);
+        (first_day..=self.last_volume_day.min(today))
+            .map(|day| self.daily_volume_e6[Self::volume_bucket(day)])
+            .fold(0u64, u64::saturating_add)
+    }
 
-// ============================================================================
-// PredictionMarketPosition (预测市场仓位 PDA) - Phase 2 TODO
-// ============================================================================
+    /// 记录一笔成交的名义价值到当日的桶 (先清空上次记录之后已移出窗口的桶)
+    pub fn record_rolling_volume(&mut self, notional_e6: u64, current_ts: i64) {
+        let today = current_ts.div_euclid(ROLLING_VOLUME_BUCKET_SECS);
+        if today > self.last_volume_day {
+            let stale_from = (self.last_volume_day + 1).max(today - ROLLING_VOLUME_DAYS as i64 + 1);
+            for day in stale_from..=today {
+                self.daily_volume_e6[Self::volume_bucket(day)] = 0;
+            }
+            self.last_volume_day = today;
+        }
+        let bucket = &mut self.daily_volume_e6[Self::volume_bucket(self.last_volume_day)];
+        *bucket = bucket.saturating_add(notional_e6);
+    }
 
-/// 预测市场结果类型
-#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
-pub enum PredictionOutcome {
-    /// Yes 结果
-    Yes,
-    /// No 结果
-    No,
+    fn volume_bucket(day: i64) -> usize {
+        day.rem_euclid(ROLLING_VOLUME_DAYS as i64) as usize
+    }
 }
 
-/// 预测市场仓位状态
-#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
-pub enum PredictionMarketPositionStatus {
-    /// 活跃（可交易）
-    Active,
-    /// 已结算（等待领取）
-    Settled,
-    /// 已领取（关闭）
-    Claimed,
-}
+// ============================================================================
+// MarketConfig (单市场风控参数 PDA)
+// ============================================================================
 
-/// 预测市场仓位 (PDA)
-/// PDA Seeds: ["prediction_market_position", user, event_id]
-/// 
-/// 预测市场仓位与永续合约仓位的主要区别：
-/// 1. 没有杠杆，1:1 保证金
-/// 2. 结算价格只有 0 或 1（对应 No/Yes 赢）
-/// 3. 有明确的结算时间
-/// 4. 份额代替数量
-/// 
-/// TODO: Phase 2 实现
+/// MarketConfig (单市场风控参数)
+/// PDA Seeds: ["market_config", market_index]
+///
+/// 取代全局的杠杆/维持保证金率/清算罚金常量，使 BTC 与小市值币种
+/// 可以配置不同的风险参数。
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
-pub struct PredictionMarketPosition {
+pub struct MarketConfig {
     /// 账户鉴别器
     pub discriminator: [u8; 8],
-    /// 用户钱包
-    pub user: Pubkey,
-    /// 事件ID (SHA256 hash of event name, e.g., "US_ELECTION_2024")
-    pub event_id: [u8; 32],
-    /// 预测结果 (Yes/No)
-    pub outcome: PredictionOutcome,
-    /// 持有份额 (e6) - 1 份 = 1 USDC 赢时的收益
-    pub shares_e6: u64,
-    /// 平均买入价格 (e6) - 0.00 ~ 1.00 USD
-    pub avg_price_e6: u64,
-    /// 锁定保证金 (e6) = shares * avg_price
-    pub margin_e6: u64,
-    /// 仓位状态
-    pub status: PredictionMarketPositionStatus,
-    /// 结算价格 (e6) - 0 或 1_000_000
-    pub settlement_price_e6: u64,
-    /// 实现盈亏 (e6) - 结算后计算
-    pub realized_pnl_e6: i64,
-    /// 创建时间
-    pub created_at: i64,
-    /// 结算时间 (0 = 未结算)
-    pub settled_at: i64,
-    /// 领取时间 (0 = 未领取)
-    pub claimed_at: i64,
+    /// 市场索引
+    pub market_index: u8,
+    /// 最大杠杆倍数 (不超过 MAX_LEVERAGE)
+    pub max_leverage: u8,
+    /// 初始保证金率 (e6) - 开仓/加仓时的最低保证金要求
+    pub initial_margin_rate_e6: i64,
+    /// 维持保证金率 (e6) - 低于此值触发清算
+    pub maintenance_margin_rate_e6: i64,
+    /// 清算罚金率 (e6)
+    pub liquidation_penalty_rate_e6: i64,
+    /// 部分清算目标保证金率 = 维持保证金率 + buffer (e6)
+    pub partial_liquidation_buffer_e6: i64,
+    /// 保证金率低于此值时直接全部清算 (e6)
+    pub full_liquidation_margin_ratio_e6: i64,
+    /// 部分清算后剩余名义价值低于此值时全部清算 (e6)
+    pub min_liquidation_notional_e6: u64,
+    /// 清算罚金中支付给清算人的比例 (bps)，其余进入保险基金
+    pub liquidator_reward_bps: u16,
+    /// 最小价格变动单位 (e6)
+    pub tick_size_e6: u64,
+    /// 最小数量变动单位 (e6)
+    pub lot_size_e6: u64,
+    /// 普通成交价格相对参考价格 (PriceFeed) 的最大偏离 (bps)
+    pub price_band_bps: u16,
+    /// 清算 / ADL 成交价格相对参考价格的最大偏离 (bps)
+    pub liquidation_price_band_bps: u16,
+    /// 资金费结算周期 (秒): 同一仓位两次 SettleFunding 的最小间隔
+    pub funding_interval_secs: i64,
+    /// 单期资金费率绝对值上限 (e6)
+    pub max_funding_rate_e6: i64,
+    /// 是否允许开仓 (false 时仅允许平仓/清算)
+    pub is_active: bool,
     /// Bump
     pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 最后更新时间
+    pub last_update_ts: i64,
+    /// 风险档位 (按 max_notional_e6 升序，为空时使用上面的基础参数)
+    pub risk_tiers: Vec<RiskTier>,
     /// 预留空间
-    pub reserved: [u8; 32],
+    pub reserved: [u8; 64],
 }
 
-impl PredictionMarketPosition {
-    pub const DISCRIMINATOR: [u8; 8] = *b"pm_pos__";
+/// 风险档位: 仓位名义价值 <= max_notional_e6 时适用的杠杆和维持保证金率
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RiskTier {
+    /// 档位名义价值上限 (e6, 含)
+    pub max_notional_e6: u64,
+    /// 档位最大杠杆
+    pub max_leverage: u8,
+    /// 档位维持保证金率 (e6)
+    pub maintenance_margin_rate_e6: i64,
+}
+
+impl RiskTier {
+    pub const SIZE: usize = 8 + 1 + 8;
+}
+
+impl MarketConfig {
+    pub const DISCRIMINATOR: [u8; 8] = *b"mktcfg__";
     pub const SIZE: usize = 8 + // discriminator
-        32 + // user
-        32 + // event_id
-        1 + // outcome
-        8 + // shares_e6
-        8 + // avg_price_e6
-        8 + // margin_e6
-        1 + // status
-        8 + // settlement_price_e6
-        8 + // realized_pnl_e6
-        8 + // created_at
-        8 + // settled_at
-        8 + // claimed_at
+        1 + // market_index
+        1 + // max_leverage
+        8 + // initial_margin_rate_e6
+        8 + // maintenance_margin_rate_e6
+        8 + // liquidation_penalty_rate_e6
+        8 + // partial_liquidation_buffer_e6
+        8 + // full_liquidation_margin_ratio_e6
+        8 + // min_liquidation_notional_e6
+        2 + // liquidator_reward_bps
+        8 + // tick_size_e6
+        8 + // lot_size_e6
+        2 + // price_band_bps
+        2 + // liquidation_price_band_bps
+        8 + // funding_interval_secs
+        8 + // max_funding_rate_e6
+        1 + // is_active
         1 + // bump
-        32; // reserved
+        8 + // created_at
+        8 + // last_update_ts
+        4 + (RiskTier::SIZE * MAX_RISK_TIERS) + // risk_tiers (Vec)
+        64; // reserved
+
+    /// PDA Seeds prefix: ["market_config", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"market_config";
+
+    /// 验证风控参数的合法性
+    /// - 0 < max_leverage <= MAX_LEVERAGE
+    /// - 0 < maintenance_margin_rate < initial_margin_rate <= 100%
+    /// - 0 <= liquidation_penalty_rate <= MAX_LIQUIDATION_PENALTY_RATE
+    /// - tick_size / lot_size 非零
+    /// - funding_interval_secs > 0, 0 < max_funding_rate <= MAX_FUNDING_RATE_E6
+    pub fn validate(&self) -> Result<(), crate::error::LedgerError> {
+        if self.max_leverage == 0 || self.max_leverage > MAX_LEVERAGE {
+            return Err(crate::error::LedgerError::InvalidLeverage);
+        }
+        if self.maintenance_margin_rate_e6 <= 0
+            || self.maintenance_margin_rate_e6 >= self.initial_margin_rate_e6
+            || self.initial_margin_rate_e6 > 1_000_000
+        {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.liquidation_penalty_rate_e6 < 0
+            || self.liquidation_penalty_rate_e6 > MAX_LIQUIDATION_PENALTY_RATE
+        {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.partial_liquidation_buffer_e6 < 0
+            || self.full_liquidation_margin_ratio_e6 < 0
+            || self.full_liquidation_margin_ratio_e6 > self.maintenance_margin_rate_e6
+        {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.liquidator_reward_bps > 10_000 {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.tick_size_e6 == 0 || self.lot_size_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.price_band_bps == 0 || self.price_band_bps > MAX_PRICE_BAND_BPS {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.liquidation_price_band_bps == 0 || self.liquidation_price_band_bps > MAX_PRICE_BAND_BPS {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        if self.funding_interval_secs <= 0
+            || self.max_funding_rate_e6 <= 0
+            || self.max_funding_rate_e6 > MAX_FUNDING_RATE_E6
+        {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        self.validate_risk_tiers()
+    }
 
-    /// PDA Seeds prefix: ["prediction_market_position", user, event_id]
-    pub const SEED_PREFIX: &'static [u8] = b"prediction_market_position";
+    /// 验证风险档位
+    /// - 名义价值上限严格递增
+    /// - 杠杆不递增且不超过市场 max_leverage，维持保证金率不递减
+    /// - 每档 mmr < 1/max_leverage，否则开仓即被清算
+    pub fn validate_risk_tiers(&self) -> Result<(), crate::error::LedgerError> {
+        if self.risk_tiers.len() > MAX_RISK_TIERS {
+            return Err(crate::error::LedgerError::InvalidMarketConfig);
+        }
+        let mut prev: Option<&RiskTier> = None;
+        for tier in self.risk_tiers.iter() {
+            if tier.max_leverage == 0 || tier.max_leverage > self.max_leverage {
+                return Err(crate::error::LedgerError::InvalidMarketConfig);
+            }
+            if tier.maintenance_margin_rate_e6 <= 0
+                || (tier.max_leverage as i64) * tier.maintenance_margin_rate_e6 >= 1_000_000
+            {
+                return Err(crate::error::LedgerError::InvalidMarketConfig);
+            }
+            if let Some(p) = prev {
+                if tier.max_notional_e6 <= p.max_notional_e6
+                    || tier.max_leverage > p.max_leverage
+                    || tier.maintenance_margin_rate_e6 < p.maintenance_margin_rate_e6
+                {
+                    return Err(crate::error::LedgerError::InvalidMarketConfig);
+                }
+            }
+            prev = Some(tier);
+        }
+        Ok(())
+    }
 
-    /// 检查仓位是否为空（可清理）
-    pub fn is_empty(&self) -> bool {
-        self.shares_e6 == 0 || 
-        self.status == PredictionMarketPositionStatus::Claimed
+    /// 查找名义价值对应的风险档位
+    /// 未配置档位时返回由基础参数构成的单一档位；超出最高档位返回 PositionSizeExceedsMax
+    pub fn risk_tier_for(&self, notional_e6: u64) -> Result<RiskTier, crate::error::LedgerError> {
+        if self.risk_tiers.is_empty() {
+            return Ok(RiskTier {
+                max_notional_e6: u64::MAX,
+                max_leverage: self.max_leverage,
+                maintenance_margin_rate_e6: self.maintenance_margin_rate_e6,
+            });
+        }
+        self.risk_tiers
+            .iter()
+            .find(|t| notional_e6 <= t.max_notional_e6)
+            .copied()
+            .ok_or(crate::error::LedgerError::PositionSizeExceedsMax)
     }
 
-    /// 检查是否已结算
-    pub fn is_settled(&self) -> bool {
-        self.status == PredictionMarketPositionStatus::Settled ||
-        self.status == PredictionMarketPositionStatus::Claimed
+    /// 名义价值对应的初始保证金率: max(市场 IMR, 1 / 档位最大杠杆)
+    pub fn initial_margin_rate_for(&self, notional_e6: u64) -> Result<i64, crate::error::LedgerError> {
+        let tier = self.risk_tier_for(notional_e6)?;
+        Ok(self.initial_margin_rate_e6.max(1_000_000 / tier.max_leverage as i64))
     }
 
-    /// 计算未实现盈亏（基于当前市场价格）
-    /// 
-    /// 如果用户持有 Yes 份额:
-    ///   PnL = shares * (current_price - avg_price)
-    /// 
-    /// 如果用户持有 No 份额:
-    ///   PnL = shares * ((1 - current_price) - avg_price)
-    ///   Note: No 份额的 avg_price 是买入 No 的价格
-    pub fn calculate_unrealized_pnl(&self, current_price_e6: u64) -> i64 {
-        if self.shares_e6 == 0 {
-            return 0;
+    /// 检查开仓/加仓后的仓位是否满足所在档位的杠杆限制
+    pub fn check_position_tier(&self, leverage: u8, notional_e6: u64) -> Result<(), crate::error::LedgerError> {
+        let tier = self.risk_tier_for(notional_e6)?;
+        if leverage > tier.max_leverage {
+            return Err(crate::error::LedgerError::LeverageExceedsMax);
         }
+        Ok(())
+    }
 
-        let shares = self.shares_e6 as i64;
-        let avg_price = self.avg_price_e6 as i64;
-        let current = current_price_e6 as i64;
+    /// 检查杠杆是否满足本市场限制
+    /// 同时要求 1/leverage >= initial_margin_rate
+    pub fn check_leverage(&self, leverage: u8) -> Result<(), crate::error::LedgerError> {
+        if leverage == 0 {
+            return Err(crate::error::LedgerError::InvalidLeverage);
+        }
+        if leverage > self.max_leverage {
+            return Err(crate::error::LedgerError::LeverageExceedsMax);
+        }
+        if (leverage as i64) * self.initial_margin_rate_e6 > 1_000_000 {
+            return Err(crate::error::LedgerError::LeverageExceedsMax);
+        }
+        Ok(())
+    }
 
-        // PnL = shares * (current - avg) / 1e6
-        let price_diff = current - avg_price;
-        (shares as i128 * price_diff as i128 / 1_000_000) as i64
+    /// 检查普通成交价格是否在参考价格的 price_band_bps 范围内
+    pub fn check_trade_price(&self, price_e6: u64, reference_price_e6: u64) -> Result<(), crate::error::LedgerError> {
+        if !is_price_within_band(price_e6, reference_price_e6, self.price_band_bps) {
+            return Err(crate::error::LedgerError::PriceOutOfBand);
+        }
+        Ok(())
     }
 
-    /// 计算结算盈亏
-    /// 
-    /// 如果预测正确（赢）: PnL = shares * (1 - avg_price)
-    /// 如果预测错误（输）: PnL = -shares * avg_price (损失全部保证金)
-    pub fn calculate_settlement_pnl(&self, winning_outcome: PredictionOutcome) -> i64 {
-        if self.shares_e6 == 0 {
-            return 0;
+    /// 检查清算 / ADL 成交价格是否在参考价格的 liquidation_price_band_bps 范围内
+    pub fn check_liquidation_price(&self, price_e6: u64, reference_price_e6: u64) -> Result<(), crate::error::LedgerError> {
+        if !is_price_within_band(price_e6, reference_price_e6, self.liquidation_price_band_bps) {
+            return Err(crate::error::LedgerError::PriceOutOfBand);
         }
+        Ok(())
+    }
 
-        let shares = self.shares_e6 as i64;
-        let avg_price = self.avg_price_e6 as i64;
+    /// 检查资金费率绝对值是否超过 max_funding_rate_e6
+    pub fn check_funding_rate(&self, funding_rate_e6: i64) -> Result<(), crate::error::LedgerError> {
+        if funding_rate_e6.unsigned_abs() > self.max_funding_rate_e6 as u64 {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        Ok(())
+    }
 
-        if self.outcome == winning_outcome {
-            // 赢了：收益 = 份额 * (1 - 买入价格)
-            let profit_per_share = 1_000_000 - avg_price;
-            (shares as i128 * profit_per_share as i128 / 1_000_000) as i64
-        } else {
-            // 输了：损失 = 份额 * 买入价格 (即全部保证金)
-            -((shares as i128 * avg_price as i128 / 1_000_000) as i64)
+    /// 检查距上次结算是否已满一个资金费周期
+    pub fn check_funding_due(&self, last_funding_ts: i64, current_ts: i64) -> Result<(), crate::error::LedgerError> {
+        if current_ts < last_funding_ts.saturating_add(self.funding_interval_secs) {
+            return Err(crate::error::LedgerError::FundingNotDue);
+        }
+        Ok(())
+    }
+
+    /// 检查成交价格/数量是否符合 tick_size / lot_size
+    pub fn check_order(&self, size_e6: u64, price_e6: u64) -> Result<(), crate::error::LedgerError> {
+        if size_e6.checked_rem(self.lot_size_e6) != Some(0) {
+            return Err(crate::error::LedgerError::InvalidTradeAmount);
+        }
+        if price_e6.checked_rem(self.tick_size_e6) != Some(0) {
+            return Err(crate::error::LedgerError::InvalidPrice);
         }
+        Ok(())
     }
 }
 
-/// 预测市场事件配置 (全局 PDA)
-/// PDA Seeds: ["prediction_market_event", event_id]
-/// 
-/// TODO: Phase 2 实现
+// ============================================================================
+// LiquidatorStats (清算人统计 PDA)
+// ============================================================================
+
+/// 清算人统计
+/// 首次执行清算时自动创建
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct LiquidatorStats {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 清算人钱包
+    pub liquidator: Pubkey,
+    /// 执行的清算次数 (含部分清算)
+    pub total_liquidations: u64,
+    /// 其中部分清算次数
+    pub partial_liquidations: u64,
+    /// 累计清算名义价值 (e6)
+    pub total_liquidated_notional_e6: u64,
+    /// 累计获得的清算奖励 (e6)
+    pub total_rewards_e6: u64,
+    /// 最后一次清算时间
+    pub last_liquidation_ts: i64,
+    /// 创建时间
+    pub created_at: i64,
+    /// Bump
+    pub bump: u8,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl LiquidatorStats {
+    pub const DISCRIMINATOR: [u8; 8] = *b"liqstats";
+    pub const SIZE: usize = 8 + // discriminator
+        32 + // liquidator
+        8 + // total_liquidations
+        8 + // partial_liquidations
+        8 + // total_liquidated_notional_e6
+        8 + // total_rewards_e6
+        8 + // last_liquidation_ts
+        8 + // created_at
+        1 + // bump
+        32; // reserved
+
+    /// PDA Seeds prefix: ["liquidator_stats", liquidator]
+    pub const SEED_PREFIX: &'static [u8] = b"liquidator_stats";
+
+    /// 记录一次清算
+    pub fn record(&mut self, notional_e6: u64, reward_e6: u64, is_partial: bool, current_ts: i64) {
+        self.total_liquidations = self.total_liquidations.saturating_add(1);
+        if is_partial {
+            self.partial_liquidations = self.partial_liquidations.saturating_add(1);
+        }
+        self.total_liquidated_notional_e6 = self.total_liquidated_notional_e6.saturating_add(notional_e6);
+        self.total_rewards_e6 = self.total_rewards_e6.saturating_add(reward_e6);
+        self.last_liquidation_ts = current_ts;
+    }
+}
+
+// ============================================================================
+// FundingState (每个市场的累计资金费指数 PDA)
+// ============================================================================
+
+/// 资金费率状态
+/// 每个结算周期更新一次累计指数，仓位在开/平/清算/ADL 时按指数差额计提资金费
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct FundingState {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 累计资金费指数 (e6): 每 1 单位 Long 仓位累计支付的资金费
+    pub cumulative_funding_index_e6: i64,
+    /// 结算周期 (秒)
+    pub funding_interval_secs: i64,
+    /// 上次更新时间
+    pub last_funding_ts: i64,
+    /// 上次资金费率 (e6)
+    pub last_funding_rate_e6: i64,
+    /// 上次指数价格 (e6)
+    pub last_index_price_e6: u64,
+    /// 更新次数
+    pub update_count: u64,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 预留空间
+    pub reserved: [u8; 64],
+}
+
+impl FundingState {
+    pub const DISCRIMINATOR: [u8; 8] = *b"fundstat";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        8 + // cumulative_funding_index_e6
+        8 + // funding_interval_secs
+        8 + // last_funding_ts
+        8 + // last_funding_rate_e6
+        8 + // last_index_price_e6
+        8 + // update_count
+        1 + // bump
+        8 + // created_at
+        64; // reserved
+
+    /// PDA Seeds prefix: ["funding_state", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"funding_state";
+
+    /// 验证参数
+    pub fn validate_params(&self) -> Result<(), crate::error::LedgerError> {
+        if self.funding_interval_secs <= 0 {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        Ok(())
+    }
+
+    /// 按本周期资金费率更新累计指数
+    /// index += rate * index_price / 1e6，距上次更新不足一个周期返回 FundingNotDue
+    pub fn apply_rate(
+        &mut self,
+        funding_rate_e6: i64,
+        index_price_e6: u64,
+        current_ts: i64,
+    ) -> Result<(), crate::error::LedgerError> {
+        if index_price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        if self.last_funding_ts > 0
+            && current_ts < self.last_funding_ts.saturating_add(self.funding_interval_secs)
+        {
+            return Err(crate::error::LedgerError::FundingNotDue);
+        }
+        let index_delta = (funding_rate_e6 as i128) * (index_price_e6 as i128) / 1_000_000;
+        let index_delta = i64::try_from(index_delta).map_err(|_| crate::error::LedgerError::Overflow)?;
+        self.cumulative_funding_index_e6 = self
+            .cumulative_funding_index_e6
+            .checked_add(index_delta)
+            .ok_or(crate::error::LedgerError::Overflow)?;
+        self.last_funding_rate_e6 = funding_rate_e6;
+        self.last_index_price_e6 = index_price_e6;
+        self.last_funding_ts = current_ts;
+        self.update_count = self.update_count.saturating_add(1);
+        Ok(())
+    }
+
+    /// 检查费率和指数价格是否为最近一次计入指数的值 (已在 UpdateFundingIndex 中检查上限和周期)
+    pub fn check_applied_rate(&self, funding_rate_e6: i64, index_price_e6: u64) -> Result<(), crate::error::LedgerError> {
+        if self.update_count == 0 {
+            return Err(crate::error::LedgerError::FundingNotDue);
+        }
+        if funding_rate_e6 != self.last_funding_rate_e6 || index_price_e6 != self.last_index_price_e6 {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// PremiumSamples (溢价采样环形缓冲区 PDA)
+// ============================================================================
+
+/// 单个溢价采样
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PremiumSample {
+    /// 溢价率 (e6) = (mark - index) / index
+    pub premium_e6: i64,
+    /// 指数价格 (e6)
+    pub index_price_e6: u64,
+    /// 采样时间 (链上时钟)
+    pub timestamp: i64,
+}
+
+impl PremiumSample {
+    pub const SIZE: usize = 8 + 8 + 8;
+}
+
+/// 溢价采样与资金费率发布
+/// Relayer 持续提交 mark / index 采样，每个周期由 PublishFundingRate 计算:
+/// rate = clamp(TWAP(premium) + clamp(interest - TWAP(premium), ±premium_clamp), ±max_funding_rate)
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct PremiumSamples {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 每周期利率分量 (e6)
+    pub interest_rate_e6: i64,
+    /// 利率与溢价差额的钳制范围 (e6)
+    pub premium_clamp_e6: i64,
+    /// 下一个写入位置
+    pub head: u16,
+    /// 有效采样数量
+    pub count: u16,
+    /// 最新发布的资金费率 (e6)
+    pub last_funding_rate_e6: i64,
+    /// 最新发布时的溢价 TWAP (e6)
+    pub last_premium_twap_e6: i64,
+    /// 最新发布时的平均指数价格 (e6)
+    pub last_index_price_e6: u64,
+    /// 最新发布时间
+    pub last_published_ts: i64,
+    /// 发布次数
+    pub published_count: u64,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 预留空间
+    pub reserved: [u8; 32],
+    /// 采样环形缓冲区
+    pub samples: [PremiumSample; PREMIUM_SAMPLE_CAPACITY],
+}
+
+impl PremiumSamples {
+    pub const DISCRIMINATOR: [u8; 8] = *b"premsmpl";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        8 + // interest_rate_e6
+        8 + // premium_clamp_e6
+        2 + // head
+        2 + // count
+        8 + // last_funding_rate_e6
+        8 + // last_premium_twap_e6
+        8 + // last_index_price_e6
+        8 + // last_published_ts
+        8 + // published_count
+        1 + // bump
+        8 + // created_at
+        32 + // reserved
+        PremiumSample::SIZE * PREMIUM_SAMPLE_CAPACITY; // samples
+
+    /// PDA Seeds prefix: ["premium_samples", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"premium_samples";
+
+    /// 验证参数: |interest| 和 premium_clamp 不超过 MAX_FUNDING_RATE_E6
+    pub fn validate_params(&self) -> Result<(), crate::error::LedgerError> {
+        if self.interest_rate_e6.abs() > MAX_FUNDING_RATE_E6
+            || self.premium_clamp_e6 < 0
+            || self.premium_clamp_e6 > MAX_FUNDING_RATE_E6
+        {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        Ok(())
+    }
+
+    /// 写入一个采样，缓冲区满时覆盖最旧的采样
+    pub fn push(&mut self, mark_price_e6: u64, index_price_e6: u64, timestamp: i64) -> Result<(), crate::error::LedgerError> {
+        if mark_price_e6 == 0 || index_price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        let premium = (mark_price_e6 as i128 - index_price_e6 as i128) * 1_000_000 / index_price_e6 as i128;
+        let premium_e6 = i64::try_from(premium).map_err(|_| crate::error::LedgerError::Overflow)?;
+
+        self.samples[self.head as usize] = PremiumSample { premium_e6, index_price_e6, timestamp };
+        self.head = ((self.head as usize + 1) % PREMIUM_SAMPLE_CAPACITY) as u16;
+        self.count = (self.count + 1).min(PREMIUM_SAMPLE_CAPACITY as u16);
+        Ok(())
+    }
+
+    /// 按时间顺序 (旧 -> 新) 返回有效采样
+    pub fn ordered_samples(&self) -> impl Iterator<Item = &PremiumSample> {
+        let start = (self.head as usize + PREMIUM_SAMPLE_CAPACITY - self.count as usize) % PREMIUM_SAMPLE_CAPACITY;
+        (0..self.count as usize).map(move |i| &self.samples[(start + i) % PREMIUM_SAMPLE_CAPACITY])
+    }
+
+    /// since_ts 之后 (不含) 采样的时间加权平均溢价和平均指数价格
+    /// 每个采样的权重为其持续时间 (到下一个采样或 now)，总权重为 0 时退化为算术平均
+    pub fn twap_since(&self, since_ts: i64, now: i64) -> Option<(i64, u64)> {
+        let window: Vec<&PremiumSample> = self.ordered_samples().filter(|s| s.timestamp > since_ts).collect();
+        if window.is_empty() {
+            return None;
+        }
+
+        let mut weighted_premium: i128 = 0;
+        let mut premium_sum: i128 = 0;
+        let mut index_sum: u128 = 0;
+        let mut total_weight: i128 = 0;
+        for (i, sample) in window.iter().enumerate() {
+            let end = window.get(i + 1).map_or(now, |next| next.timestamp);
+            let weight = end.saturating_sub(sample.timestamp).max(0) as i128;
+            weighted_premium += sample.premium_e6 as i128 * weight;
+            total_weight += weight;
+            premium_sum += sample.premium_e6 as i128;
+            index_sum += sample.index_price_e6 as u128;
+        }
+
+        let n = window.len() as i128;
+        let premium_twap = if total_weight > 0 {
+            weighted_premium / total_weight
+        } else {
+            premium_sum / n
+        };
+        Some((premium_twap as i64, (index_sum / n as u128) as u64))
+    }
+
+    /// 资金费率 = 溢价 TWAP + clamp(利率 - 溢价 TWAP, ±premium_clamp)，再钳制到 ±max_funding_rate
+    pub fn compute_funding_rate(&self, premium_twap_e6: i64, max_funding_rate_e6: i64) -> i64 {
+        let clamp = self.premium_clamp_e6;
+        let interest_component = self.interest_rate_e6.saturating_sub(premium_twap_e6).clamp(-clamp, clamp);
+        premium_twap_e6
+            .saturating_add(interest_component)
+            .clamp(-max_funding_rate_e6, max_funding_rate_e6)
+    }
+
+    /// 检查 Relayer 提交的费率和指数价格是否与最近一次发布且未过期的结果一致
+    pub fn check_published(
+        &self,
+        funding_rate_e6: i64,
+        index_price_e6: u64,
+        funding_interval_secs: i64,
+        current_ts: i64,
+    ) -> Result<(), crate::error::LedgerError> {
+        if self.published_count == 0
+            || current_ts > self.last_published_ts.saturating_add(funding_interval_secs)
+        {
+            return Err(crate::error::LedgerError::FundingNotDue);
+        }
+        if funding_rate_e6 != self.last_funding_rate_e6 || index_price_e6 != self.last_index_price_e6 {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// FundingSettlementSummary (批量资金费结算汇总 PDA)
+// ============================================================================
+
+/// 每个市场当前资金费轮次 (epoch) 的链上汇总，字段对应 FundingEventInput
+/// 同一轮次可拆分为多笔 SettleFundingBatch 交易，新轮次开始时重置
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct FundingSettlementSummary {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 轮次标识
+    pub epoch: u64,
+    /// 本轮资金费率 (e6)
+    pub funding_rate_e6: i64,
+    /// 本轮指数价格 (e6)
+    pub index_price_e6: u64,
+    /// 本轮已结算仓位数
+    pub accounts_settled: u32,
+    /// Long 仓位支付的资金费合计 (e6，正数 = Long 支付 Short)
+    pub total_funding_paid_e6: i64,
+    /// 全部仓位资金费净额 (e6，多空不平衡部分)
+    pub net_funding_e6: i64,
+    /// 本轮首笔结算时间
+    pub first_settled_at: i64,
+    /// 本轮最后结算时间
+    pub last_settled_at: i64,
+    /// Bump
+    pub bump: u8,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl FundingSettlementSummary {
+    pub const DISCRIMINATOR: [u8; 8] = *b"fundsumm";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        8 + // epoch
+        8 + // funding_rate_e6
+        8 + // index_price_e6
+        4 + // accounts_settled
+        8 + // total_funding_paid_e6
+        8 + // net_funding_e6
+        8 + // first_settled_at
+        8 + // last_settled_at
+        1 + // bump
+        32; // reserved
+
+    /// PDA Seeds prefix: ["funding_summary", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"funding_summary";
+
+    /// 开始 / 继续一个轮次
+    /// - 新轮次 (epoch 更大或尚未结算过): 重置汇总
+    /// - 同一轮次: 费率和指数价格必须一致，否则 InvalidFundingRate
+    /// - 旧轮次: FundingNotDue
+    pub fn begin_epoch(
+        &mut self,
+        epoch: u64,
+        funding_rate_e6: i64,
+        index_price_e6: u64,
+        current_ts: i64,
+    ) -> Result<(), crate::error::LedgerError> {
+        if epoch > self.epoch || self.accounts_settled == 0 {
+            self.epoch = epoch;
+            self.funding_rate_e6 = funding_rate_e6;
+            self.index_price_e6 = index_price_e6;
+            self.accounts_settled = 0;
+            self.total_funding_paid_e6 = 0;
+            self.net_funding_e6 = 0;
+            self.first_settled_at = current_ts;
+        } else if epoch < self.epoch {
+            return Err(crate::error::LedgerError::FundingNotDue);
+        } else if funding_rate_e6 != self.funding_rate_e6 || index_price_e6 != self.index_price_e6 {
+            return Err(crate::error::LedgerError::InvalidFundingRate);
+        }
+        self.last_settled_at = current_ts;
+        Ok(())
+    }
+
+    /// 记录一个仓位的本期资金费
+    pub fn record(&mut self, side: Side, payment_e6: i64) {
+        self.accounts_settled = self.accounts_settled.saturating_add(1);
+        if side == Side::Long {
+            self.total_funding_paid_e6 = self.total_funding_paid_e6.saturating_add(payment_e6);
+        }
+        self.net_funding_e6 = self.net_funding_e6.saturating_add(payment_e6);
+    }
+}
+
+// ============================================================================
+// ShortfallRecord (穿仓记录 PDA)
+// ============================================================================
+
+/// 穿仓记录
+/// Liquidate 发生穿仓时创建，TriggerADL 消费 (每条记录只能触发一次 ADL)
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct ShortfallRecord {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 穿仓用户
+    pub user: Pubkey,
+    /// 市场索引
+    pub market_index: u8,
+    /// 穿仓仓位方向
+    pub side: Side,
+    /// 穿仓仓位大小 (e6)
+    pub size_e6: u64,
+    /// 入场价格 (e6)
+    pub entry_price_e6: u64,
+    /// 破产价格 (e6): 亏损等于保证金的价格
+    pub bankruptcy_price_e6: u64,
+    /// 尚未覆盖的穿仓金额 (e6)，ExecuteADL 覆盖后递减
+    pub shortfall_e6: u64,
+    /// 关联的 LiquidationEvent sequence
+    pub liquidation_sequence: u64,
+    /// 该仓位的第几条穿仓记录 (创建时的 Position.shortfall_count)，PDA seed
+    pub shortfall_index: u64,
+    /// 是否已被 TriggerADL 消费
+    pub is_consumed: bool,
+    /// 创建时间
+    pub created_at: i64,
+    /// 消费时间
+    pub consumed_at: i64,
+    /// Bump
+    pub bump: u8,
+    /// 预留空间
+    pub reserved: [u8; 24],
+}
+
+impl ShortfallRecord {
+    pub const DISCRIMINATOR: [u8; 8] = *b"shortfal";
+    pub const SIZE: usize = 8 + // discriminator
+        32 + // user
+        1 + // market_index
+        1 + // side
+        8 + // size_e6
+        8 + // entry_price_e6
+        8 + // bankruptcy_price_e6
+        8 + // shortfall_e6
+        8 + // liquidation_sequence
+        8 + // shortfall_index
+        1 + // is_consumed
+        8 + // created_at
+        8 + // consumed_at
+        1 + // bump
+        24; // reserved
+
+    /// PDA Seeds prefix: ["shortfall", user, market_index, shortfall_index (LE)]
+    /// shortfall_index 取自 Position.shortfall_count，只随该仓位的穿仓递增，清算人可在发送交易前确定地址
+    pub const SEED_PREFIX: &'static [u8] = b"shortfall";
+
+    /// 标记为已消费
+    pub fn consume(&mut self, current_ts: i64) -> Result<(), crate::error::LedgerError> {
+        if self.is_consumed {
+            return Err(crate::error::LedgerError::ShortfallAlreadyConsumed);
+        }
+        self.is_consumed = true;
+        self.consumed_at = current_ts;
+        Ok(())
+    }
+
+    /// 扣减 ADL 已覆盖的金额，返回实际扣减的部分 (不超过剩余穿仓)
+    pub fn apply_coverage(&mut self, covered_e6: u64) -> u64 {
+        let applied = covered_e6.min(self.shortfall_e6);
+        self.shortfall_e6 -= applied;
+        applied
+    }
+}
+
+// ============================================================================
+// ADLState (每个市场的 ADL 状态 PDA)
+// ============================================================================
+
+/// ADL 状态
+/// TriggerADL 时创建/启动，ExecuteADL 累计覆盖金额，CompleteADL 结束
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct ADLState {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 当前是否有进行中的 ADL
+    pub is_active: bool,
+    /// 本轮 ADL 消费的 ShortfallRecord
+    pub shortfall_record: Pubkey,
+    /// 穿仓用户
+    pub bankrupt_user: Pubkey,
+    /// 穿仓方向
+    pub bankrupt_side: Side,
+    /// 穿仓仓位大小 (e6)
+    pub bankrupt_size_e6: u64,
+    /// 穿仓仓位的破产价格 (e6)，ExecuteADL 按此价格减仓
+    pub bankruptcy_price_e6: u64,
+    /// 穿仓金额 (e6)
+    pub shortfall_e6: u64,
+    /// 触发时保险基金余额 (e6)
+    pub insurance_balance_before_e6: i64,
+    /// 需要 ADL 覆盖的金额 = shortfall - 保险基金余额 (e6)
+    pub adl_required_e6: u64,
+    /// ExecuteADL 已覆盖金额 (e6)
+    pub covered_e6: u64,
+    /// 对手方累计减仓数量 (e6)
+    pub counterparty_size_reduced_e6: u64,
+    /// 被减仓的目标数
+    pub target_count: u32,
+    /// 触发时间
+    pub triggered_at: i64,
+    /// 完成时间
+    pub completed_at: i64,
+    /// 已完成的 ADL 轮次
+    pub completed_count: u64,
+    /// Bump
+    pub bump: u8,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl ADLState {
+    pub const DISCRIMINATOR: [u8; 8] = *b"adlstate";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        1 + // is_active
+        32 + // shortfall_record
+        32 + // bankrupt_user
+        1 + // bankrupt_side
+        8 + // bankrupt_size_e6
+        8 + // bankruptcy_price_e6
+        8 + // shortfall_e6
+        8 + // insurance_balance_before_e6
+        8 + // adl_required_e6
+        8 + // covered_e6
+        8 + // counterparty_size_reduced_e6
+        4 + // target_count
+        8 + // triggered_at
+        8 + // completed_at
+        8 + // completed_count
+        1 + // bump
+        32; // reserved
+
+    /// PDA Seeds prefix: ["adl_state", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"adl_state";
+
+    /// 按 ShortfallRecord 启动新一轮 ADL
+    pub fn start(
+        &mut self,
+        shortfall_record_key: Pubkey,
+        record: &ShortfallRecord,
+        insurance_balance_e6: i64,
+        current_ts: i64,
+    ) -> Result<(), crate::error::LedgerError> {
+        if self.is_active {
+            return Err(crate::error::LedgerError::ADLInProgress);
+        }
+        self.is_active = true;
+        self.shortfall_record = shortfall_record_key;
+        self.bankrupt_user = record.user;
+        self.bankrupt_side = record.side;
+        self.bankrupt_size_e6 = record.size_e6;
+        self.bankruptcy_price_e6 = record.bankruptcy_price_e6;
+        self.shortfall_e6 = record.shortfall_e6;
+        self.insurance_balance_before_e6 = insurance_balance_e6;
+        self.adl_required_e6 = record.shortfall_e6.saturating_sub(insurance_balance_e6.max(0) as u64);
+        self.covered_e6 = 0;
+        self.counterparty_size_reduced_e6 = 0;
+        self.target_count = 0;
+        self.triggered_at = current_ts;
+        self.completed_at = 0;
+        Ok(())
+    }
+
+    /// 尚未覆盖的金额 (e6)
+    pub fn remaining_e6(&self) -> u64 {
+        self.adl_required_e6.saturating_sub(self.covered_e6)
+    }
+
+    /// 记录一个目标的减仓结果
+    pub fn record_reduction(&mut self, covered_e6: u64, size_reduced_e6: u64) {
+        self.covered_e6 = self.covered_e6.saturating_add(covered_e6);
+        self.counterparty_size_reduced_e6 = self.counterparty_size_reduced_e6.saturating_add(size_reduced_e6);
+        self.target_count = self.target_count.saturating_add(1);
+    }
+
+    /// 结束本轮 ADL，要求穿仓已完全覆盖
+    pub fn complete(&mut self, current_ts: i64) -> Result<(), crate::error::LedgerError> {
+        if !self.is_active {
+            return Err(crate::error::LedgerError::ADLNotTriggered);
+        }
+        if self.remaining_e6() > 0 {
+            return Err(crate::error::LedgerError::ADLShortfallNotCovered);
+        }
+        self.is_active = false;
+        self.completed_at = current_ts;
+        self.completed_count = self.completed_count.saturating_add(1);
+        Ok(())
+    }
+}
+
+// ============================================================================
+// PriceFeed (Ledger 信任的预言机价格 PDA)
+// ============================================================================
+
+/// 单市场预言机价格
+///
+/// 由达到 RelayerConfig.required_signatures 的 Relayer 联合签名更新，
+/// 价格取各 Relayer 报价的中位数。清算等风控操作只使用此价格，
+/// 不信任调用方传入的价格。
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct PriceFeed {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 价格 (e6)
+    pub price_e6: u64,
+    /// 置信区间 (e6, 价格 ± conf)
+    pub conf_e6: u64,
+    /// 报价时间 (Relayer 提交的价格时间戳)
+    pub publish_ts: i64,
+    /// 最大允许延迟 (秒)
+    pub max_staleness_secs: i64,
+    /// 最大允许置信区间 / 价格 (e6, e.g., 10_000 = 1%)
+    pub max_confidence_e6: i64,
+    /// 最近一次更新的签名 Relayer 数量
+    pub signer_count: u8,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 最后更新时间 (链上时间)
+    pub last_update_ts: i64,
+    /// 预留空间
+    pub reserved: [u8; 64],
+}
+
+impl PriceFeed {
+    pub const DISCRIMINATOR: [u8; 8] = *b"prcfeed_";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        8 + // price_e6
+        8 + // conf_e6
+        8 + // publish_ts
+        8 + // max_staleness_secs
+        8 + // max_confidence_e6
+        1 + // signer_count
+        1 + // bump
+        8 + // created_at
+        8 + // last_update_ts
+        64; // reserved
+
+    /// PDA Seeds prefix: ["price_feed", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"price_feed";
+
+    /// 验证参数
+    pub fn validate_params(&self) -> Result<(), crate::error::LedgerError> {
+        if self.max_staleness_secs <= 0 {
+            return Err(crate::error::LedgerError::InvalidPriceFeed);
+        }
+        if self.max_confidence_e6 <= 0 || self.max_confidence_e6 > 1_000_000 {
+            return Err(crate::error::LedgerError::InvalidPriceFeed);
+        }
+        Ok(())
+    }
+
+    /// 获取可信价格
+    /// - 从未更新 -> InvalidPrice
+    /// - 超过 max_staleness_secs -> StalePrice
+    /// - conf / price 超过 max_confidence_e6 -> PriceConfidenceTooWide
+    pub fn checked_price(&self, current_ts: i64) -> Result<u64, crate::error::LedgerError> {
+        if self.price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        if current_ts.saturating_sub(self.publish_ts) > self.max_staleness_secs {
+            return Err(crate::error::LedgerError::StalePrice);
+        }
+        let conf_ratio = (self.conf_e6 as u128) * 1_000_000 / (self.price_e6 as u128);
+        if conf_ratio > self.max_confidence_e6 as u128 {
+            return Err(crate::error::LedgerError::PriceConfidenceTooWide);
+        }
+        Ok(self.price_e6)
+    }
+
+    /// 聚合多个 Relayer 报价
+    /// 返回 (中位数, 报价离散度 = (max - min) / 2)
+    pub fn aggregate_quorum(prices_e6: &[u64]) -> Option<(u64, u64)> {
+        if prices_e6.is_empty() || prices_e6.contains(&0) {
+            return None;
+        }
+        let mut sorted = prices_e6.to_vec();
+        sorted.sort_unstable();
+        let n = sorted.len();
+        let median = if n % 2 == 1 {
+            sorted[n / 2]
+        } else {
+            ((sorted[n / 2 - 1] as u128 + sorted[n / 2] as u128) / 2) as u64
+        };
+        let spread = (sorted[n - 1] - sorted[0]) / 2;
+        Some((median, spread))
+    }
+}
+
+// ============================================================================
+// MarkPrice (链上派生的标记价格 PDA)
+// ============================================================================
+
+/// 三个价格的中位数
+fn median_of_three(a: u64, b: u64, c: u64) -> u64 {
+    a.max(b).min(a.min(b).max(c))
+}
+
+/// 单市场标记价格
+///
+/// mark = median(index, index + EMA(basis), last_trade)，basis = last_trade - index。
+/// 由任何人调用 UpdateMarkPrice 刷新 (指数价格取 PriceFeed)，成交价由开/平仓和批量成交写入。
+/// 清算、资金费结算和健康度检查使用此价格。
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct MarkPrice {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 市场索引
+    pub market_index: u8,
+    /// 标记价格 (e6)
+    pub mark_price_e6: u64,
+    /// 最近一次更新使用的指数价格 (e6)
+    pub index_price_e6: u64,
+    /// 基差 (last_trade - index) 的时间加权 EMA (e6)
+    pub basis_ema_e6: i64,
+    /// 最新成交价格 (e6, 0 = 尚无成交)
+    pub last_trade_price_e6: u64,
+    /// 最新成交时间
+    pub last_trade_ts: i64,
+    /// EMA 周期 (秒)，超过一个周期没有成交时成交价退化为指数价格
+    pub ema_period_secs: i64,
+    /// 最大允许延迟 (秒)
+    pub max_staleness_secs: i64,
+    /// 最后更新时间 (链上时间)
+    pub last_update_ts: i64,
+    /// 更新次数
+    pub update_count: u64,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl MarkPrice {
+    pub const DISCRIMINATOR: [u8; 8] = *b"markprce";
+    pub const SIZE: usize = 8 + // discriminator
+        1 + // market_index
+        8 + // mark_price_e6
+        8 + // index_price_e6
+        8 + // basis_ema_e6
+        8 + // last_trade_price_e6
+        8 + // last_trade_ts
+        8 + // ema_period_secs
+        8 + // max_staleness_secs
+        8 + // last_update_ts
+        8 + // update_count
+        1 + // bump
+        8 + // created_at
+        32; // reserved
+
+    /// PDA Seeds prefix: ["mark_price", market_index]
+    pub const SEED_PREFIX: &'static [u8] = b"mark_price";
+
+    /// 验证参数
+    pub fn validate_params(&self) -> Result<(), crate::error::LedgerError> {
+        if self.ema_period_secs <= 0 || self.max_staleness_secs <= 0 {
+            return Err(crate::error::LedgerError::InvalidMarkPrice);
+        }
+        Ok(())
+    }
+
+    /// 记录最新成交价格
+    pub fn record_trade(&mut self, price_e6: u64, current_ts: i64) -> Result<(), crate::error::LedgerError> {
+        if price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        self.last_trade_price_e6 = price_e6;
+        self.last_trade_ts = current_ts;
+        Ok(())
+    }
+
+    /// 按新的指数价格更新基差 EMA 和标记价格，返回新的标记价格
+    ///
+    /// EMA 权重 = min(距上次更新秒数, ema_period) / ema_period，首次更新只记录指数价格
+    pub fn update(&mut self, index_price_e6: u64, current_ts: i64) -> Result<u64, crate::error::LedgerError> {
+        if index_price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        let trade_price_e6 = if self.last_trade_price_e6 > 0
+            && current_ts.saturating_sub(self.last_trade_ts) <= self.ema_period_secs
+        {
+            self.last_trade_price_e6
+        } else {
+            index_price_e6
+        };
+
+        let basis = trade_price_e6 as i128 - index_price_e6 as i128;
+        let elapsed = if self.update_count == 0 {
+            0
+        } else {
+            current_ts.saturating_sub(self.last_update_ts).clamp(0, self.ema_period_secs)
+        };
+        let ema = self.basis_ema_e6 as i128
+            + (basis - self.basis_ema_e6 as i128) * elapsed as i128 / self.ema_period_secs as i128;
+        self.basis_ema_e6 = i64::try_from(ema).map_err(|_| crate::error::LedgerError::Overflow)?;
+
+        let basis_price_e6 = u64::try_from((index_price_e6 as i128 + ema).max(1))
+            .map_err(|_| crate::error::LedgerError::Overflow)?;
+        self.mark_price_e6 = median_of_three(index_price_e6, basis_price_e6, trade_price_e6);
+        self.index_price_e6 = index_price_e6;
+        self.last_update_ts = current_ts;
+        self.update_count = self.update_count.saturating_add(1);
+        Ok(self.mark_price_e6)
+    }
+
+    /// 获取标记价格
+    /// - 从未更新 -> InvalidPrice
+    /// - 超过 max_staleness_secs 未更新 -> StalePrice
+    pub fn checked_price(&self, current_ts: i64) -> Result<u64, crate::error::LedgerError> {
+        if self.mark_price_e6 == 0 {
+            return Err(crate::error::LedgerError::InvalidPrice);
+        }
+        if current_ts.saturating_sub(self.last_update_ts) > self.max_staleness_secs {
+            return Err(crate::error::LedgerError::StalePrice);
+        }
+        Ok(self.mark_price_e6)
+    }
+}
+
+/// CheckPositionHealth 的返回数据 (set_return_data, Borsh 编码)
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct PositionHealth {
+    /// 用户
+    pub user: Pubkey,
+    /// 市场索引
+    pub market_index: u8,
+    /// 标记价格 (e6)
+    pub mark_price_e6: u64,
+    /// 按标记价格计算的未实现盈亏 (e6)
+    pub unrealized_pnl_e6: i64,
+    /// 保证金率 (e6, 已扣除未结算资金费)
+    pub margin_ratio_e6: i64,
+    /// 当前风险档位的维持保证金率 (e6)
+    pub maintenance_margin_rate_e6: i64,
+    /// 清算价格 (e6)
+    pub liquidation_price_e6: u64,
+    /// 按标记价格是否可被清算
+    pub is_liquidatable: bool,
+}
+
+// ============================================================================
+// FeeSchedule (手续费分配 PDA)
+// ============================================================================
+
+/// 全局手续费分配比例
+///
+/// 每笔手续费按 bps 拆分给保险基金、协议金库、推荐人和 Maker 返佣池，四项之和必须为 10000。
+/// 保险基金部分通过 Fund.AddTradingFee 划转，推荐人部分记入 ReferrerStats，其余部分记入本账户的累计额等待分发。
+/// 用户没有推荐人时推荐人部分归协议金库。取整余数归保险基金。
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct FeeSchedule {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 保险基金分成 (bps)
+    pub insurance_bps: u16,
+    /// 协议金库分成 (bps)
+    pub treasury_bps: u16,
+    /// 推荐人分成 (bps)
+    pub referrer_bps: u16,
+    /// Maker 返佣池分成 (bps)
+    pub maker_rebate_bps: u16,
+    /// 累计划入保险基金 (e6)
+    pub total_insurance_e6: u64,
+    /// 累计划入协议金库 (e6)
+    pub total_treasury_e6: u64,
+    /// 累计划入推荐人分成 (e6)
+    pub total_referrer_e6: u64,
+    /// 累计划入 Maker 返佣池 (e6)
+    pub total_maker_rebate_e6: u64,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 最后更新时间
+    pub last_update_ts: i64,
+    /// 被推荐用户的手续费折扣 (bps，按费率折扣)
+    pub referee_discount_bps: u16,
+    /// 预留空间
+    pub reserved: [u8; 62],
+}
+
+/// 手续费档位: 30 天滚动成交量 >= min_volume_e6 时适用的费率
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeTier {
+    /// 档位成交量下限 (e6, 含)
+    pub min_volume_e6: u64,
+    /// Maker 费率上限 (e6, 负值 = 返佣)
+    pub maker_fee_rate_e6: i64,
+    /// Taker 费率上限 (e6)
+    pub taker_fee_rate_e6: i64,
+}
+
+impl FeeTier {
+    pub const SIZE: usize = 8 + 8 + 8;
+}
+
+/// 全局手续费档位表 (PDA)
+///
+/// Relayer 提交的费率不得高于用户按 30 天滚动成交量所在档位的费率
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct FeeTierTable {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 档位 (按 min_volume_e6 升序，第一档下限为 0)
+    pub tiers: Vec<FeeTier>,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 最后更新时间
+    pub last_update_ts: i64,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl FeeTierTable {
+    pub const DISCRIMINATOR: [u8; 8] = *b"feetiers";
+    pub const SIZE: usize = 8 + // discriminator
+        4 + (FeeTier::SIZE * MAX_FEE_TIERS) + // tiers (Vec)
+        1 + // bump
+        8 + // created_at
+        8 + // last_update_ts
+        32; // reserved
+
+    /// PDA Seeds: ["fee_tier_table"]
+    pub const SEED_PREFIX: &'static [u8] = b"fee_tier_table";
+
+    /// 验证档位
+    /// - 1 ~ MAX_FEE_TIERS 个，第一档下限为 0，下限严格递增
+    /// - 0 <= taker <= 1%，-1% <= maker <= taker
+    /// - 费率随成交量不递增
+    pub fn validate(&self) -> Result<(), crate::error::LedgerError> {
+        if self.tiers.is_empty() || self.tiers.len() > MAX_FEE_TIERS || self.tiers[0].min_volume_e6 != 0 {
+            return Err(crate::error::LedgerError::InvalidFeeTier);
+        }
+        let mut prev: Option<&FeeTier> = None;
+        for tier in self.tiers.iter() {
+            if tier.taker_fee_rate_e6 < 0
+                || tier.taker_fee_rate_e6 > 10_000
+                || tier.maker_fee_rate_e6 < -10_000
+                || tier.maker_fee_rate_e6 > tier.taker_fee_rate_e6
+            {
+                return Err(crate::error::LedgerError::InvalidFeeTier);
+            }
+            if let Some(p) = prev {
+                if tier.min_volume_e6 <= p.min_volume_e6
+                    || tier.maker_fee_rate_e6 > p.maker_fee_rate_e6
+                    || tier.taker_fee_rate_e6 > p.taker_fee_rate_e6
+                {
+                    return Err(crate::error::LedgerError::InvalidFeeTier);
+                }
+            }
+            prev = Some(tier);
+        }
+        Ok(())
+    }
+
+    /// 查找滚动成交量对应的档位 (下限 <= volume 的最高档)
+    pub fn tier_for(&self, rolling_volume_e6: u64) -> Result<FeeTier, crate::error::LedgerError> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|t| rolling_volume_e6 >= t.min_volume_e6)
+            .copied()
+            .ok_or(crate::error::LedgerError::InvalidFeeTier)
+    }
+
+    /// 校验费率不高于所在档位的费率
+    pub fn check_fee_rate(
+        &self,
+        rolling_volume_e6: u64,
+        is_taker: bool,
+        fee_rate_e6: i64,
+    ) -> Result<(), crate::error::LedgerError> {
+        let tier = self.tier_for(rolling_volume_e6)?;
+        let tier_rate_e6 = if is_taker { tier.taker_fee_rate_e6 } else { tier.maker_fee_rate_e6 };
+        if fee_rate_e6 > tier_rate_e6 {
+            return Err(crate::error::LedgerError::FeeRateAboveTier);
+        }
+        Ok(())
+    }
+}
+
+/// 一笔手续费的分配结果
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FeeSplit {
+    pub insurance_e6: u64,
+    pub treasury_e6: u64,
+    pub referrer_e6: u64,
+    pub maker_rebate_e6: u64,
+}
+
+impl FeeSplit {
+    /// 累加另一笔分配结果
+    pub fn accumulate(&mut self, other: &FeeSplit) -> Result<(), crate::error::LedgerError> {
+        let add = |a: u64, b: u64| a.checked_add(b).ok_or(crate::error::LedgerError::Overflow);
+        self.insurance_e6 = add(self.insurance_e6, other.insurance_e6)?;
+        self.treasury_e6 = add(self.treasury_e6, other.treasury_e6)?;
+        self.referrer_e6 = add(self.referrer_e6, other.referrer_e6)?;
+        self.maker_rebate_e6 = add(self.maker_rebate_e6, other.maker_rebate_e6)?;
+        Ok(())
+    }
+
+    /// 非零的分配项 (FeeEvent 类型, 金额)，按固定顺序
+    pub fn recipients(&self) -> Vec<(crate::events::FeeType, u64)> {
+        use crate::events::FeeType;
+        [
+            (FeeType::InsuranceContribution, self.insurance_e6),
+            (FeeType::TreasuryShare, self.treasury_e6),
+            (FeeType::ReferrerShare, self.referrer_e6),
+            (FeeType::MakerRebateShare, self.maker_rebate_e6),
+        ]
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .collect()
+    }
+}
+
+impl FeeSchedule {
+    pub const DISCRIMINATOR: [u8; 8] = *b"feesched";
+    pub const SIZE: usize = 8 + // discriminator
+        2 + // insurance_bps
+        2 + // treasury_bps
+        2 + // referrer_bps
+        2 + // maker_rebate_bps
+        8 + // total_insurance_e6
+        8 + // total_treasury_e6
+        8 + // total_referrer_e6
+        8 + // total_maker_rebate_e6
+        1 + // bump
+        8 + // created_at
+        8 + // last_update_ts
+        2 + // referee_discount_bps
+        62; // reserved
+
+    /// PDA Seeds: ["fee_schedule"]
+    pub const SEED_PREFIX: &'static [u8] = b"fee_schedule";
+
+    /// 验证分配比例: 之和为 10000，且分配比例和被推荐用户折扣在硬上限内
+    pub fn validate_params(&self) -> Result<(), crate::error::LedgerError> {
+        let total = self.insurance_bps as u32
+            + self.treasury_bps as u32
+            + self.referrer_bps as u32
+            + self.maker_rebate_bps as u32;
+        if total != FEE_SPLIT_BPS_DENOMINATOR as u32
+            || self.insurance_bps < MIN_INSURANCE_FEE_BPS
+            || self.referrer_bps > MAX_REFERRER_FEE_BPS
+            || self.maker_rebate_bps > MAX_MAKER_REBATE_FEE_BPS
+            || self.referee_discount_bps > MAX_REFEREE_DISCOUNT_BPS
+        {
+            return Err(crate::error::LedgerError::InvalidFeeSchedule);
+        }
+        Ok(())
+    }
+
+    /// 按比例拆分一笔手续费 (向下取整，余数归保险基金)
+    /// 没有推荐人时推荐人部分归协议金库
+    pub fn split(&self, fee_e6: u64, has_referrer: bool) -> FeeSplit {
+        let share = |bps: u16| (fee_e6 as u128 * bps as u128 / FEE_SPLIT_BPS_DENOMINATOR as u128) as u64;
+        let (treasury_e6, referrer_e6) = if has_referrer {
+            (share(self.treasury_bps), share(self.referrer_bps))
+        } else {
+            (share(self.treasury_bps + self.referrer_bps), 0)
+        };
+        let maker_rebate_e6 = share(self.maker_rebate_bps);
+        FeeSplit {
+            insurance_e6: fee_e6 - treasury_e6 - referrer_e6 - maker_rebate_e6,
+            treasury_e6,
+            referrer_e6,
+            maker_rebate_e6,
+        }
+    }
+
+    /// 被推荐用户的实际费率 (只对正费率打折，maker 返佣不变)
+    pub fn referee_fee_rate_e6(&self, fee_rate_e6: i64) -> i64 {
+        if fee_rate_e6 <= 0 {
+            return fee_rate_e6;
+        }
+        fee_rate_e6 - fee_rate_e6 * self.referee_discount_bps as i64 / FEE_SPLIT_BPS_DENOMINATOR as i64
+    }
+
+    /// 累计已分配的手续费
+    pub fn record(&mut self, split: &FeeSplit) -> Result<(), crate::error::LedgerError> {
+        let add = |a: u64, b: u64| a.checked_add(b).ok_or(crate::error::LedgerError::Overflow);
+        self.total_insurance_e6 = add(self.total_insurance_e6, split.insurance_e6)?;
+        self.total_treasury_e6 = add(self.total_treasury_e6, split.treasury_e6)?;
+        self.total_referrer_e6 = add(self.total_referrer_e6, split.referrer_e6)?;
+        self.total_maker_rebate_e6 = add(self.total_maker_rebate_e6, split.maker_rebate_e6)?;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Referral / ReferrerStats (推荐关系 PDA)
+// ============================================================================
+
+/// 推荐码 (非全零)
+pub type ReferralCode = [u8; 16];
+
+/// 用户与推荐码的绑定 (一次性，不可更改)
+/// PDA Seeds: ["referral", user]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct Referral {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 被推荐用户
+    pub user: Pubkey,
+    /// 推荐人
+    pub referrer: Pubkey,
+    /// 推荐码
+    pub code: ReferralCode,
+    /// Bump
+    pub bump: u8,
+    /// 绑定时间
+    pub created_at: i64,
+    /// 预留空间
+    pub reserved: [u8; 16],
+}
+
+impl Referral {
+    pub const DISCRIMINATOR: [u8; 8] = *b"referral";
+    pub const SIZE: usize = 8 + // discriminator
+        32 + // user
+        32 + // referrer
+        16 + // code
+        1 + // bump
+        8 + // created_at
+        16; // reserved
+
+    /// PDA Seeds prefix: ["referral", user]
+    pub const SEED_PREFIX: &'static [u8] = b"referral";
+}
+
+/// 推荐人统计 (每个推荐码一个)，累计手续费分成并通过 Vault 领取
+/// PDA Seeds: ["referrer_stats", code]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct ReferrerStats {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 推荐人
+    pub referrer: Pubkey,
+    /// 推荐码
+    pub code: ReferralCode,
+    /// 绑定的被推荐用户数
+    pub referee_count: u64,
+    /// 累计获得的手续费分成 (e6)
+    pub total_earned_e6: u64,
+    /// 待领取 (e6)
+    pub claimable_e6: u64,
+    /// 累计已领取 (e6)
+    pub total_claimed_e6: u64,
+    /// Bump
+    pub bump: u8,
+    /// 创建时间
+    pub created_at: i64,
+    /// 最后更新时间
+    pub last_update_ts: i64,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl ReferrerStats {
+    pub const DISCRIMINATOR: [u8; 8] = *b"refstats";
+    pub const SIZE: usize = 8 + // discriminator
+        32 + // referrer
+        16 + // code
+        8 + // referee_count
+        8 + // total_earned_e6
+        8 + // claimable_e6
+        8 + // total_claimed_e6
+        1 + // bump
+        8 + // created_at
+        8 + // last_update_ts
+        32; // reserved
+
+    /// PDA Seeds prefix: ["referrer_stats", code]
+    pub const SEED_PREFIX: &'static [u8] = b"referrer_stats";
+
+    /// 记入一笔手续费分成
+    pub fn credit(&mut self, amount_e6: u64, current_ts: i64) -> Result<(), crate::error::LedgerError> {
+        self.total_earned_e6 = self.total_earned_e6.checked_add(amount_e6).ok_or(crate::error::LedgerError::Overflow)?;
+        self.claimable_e6 = self.claimable_e6.checked_add(amount_e6).ok_or(crate::error::LedgerError::Overflow)?;
+        self.last_update_ts = current_ts;
+        Ok(())
+    }
+
+    /// 领取全部待领取分成，返回领取金额
+    pub fn claim(&mut self, current_ts: i64) -> Result<u64, crate::error::LedgerError> {
+        let amount_e6 = self.claimable_e6;
+        if amount_e6 == 0 {
+            return Err(crate::error::LedgerError::NoReferralRewards);
+        }
+        self.claimable_e6 = 0;
+        self.total_claimed_e6 = self.total_claimed_e6.checked_add(amount_e6).ok_or(crate::error::LedgerError::Overflow)?;
+        self.last_update_ts = current_ts;
+        Ok(amount_e6)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+// ============================================================================
+// PredictionMarketPosition (预测市场仓位 PDA) - Phase 2 TODO
+// ============================================================================
+
+/// 预测市场结果类型
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionOutcome {
+    /// Yes 结果
+    Yes,
+    /// No 结果
+    No,
+}
+
+/// 预测市场仓位状态
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredictionMarketPositionStatus {
+    /// 活跃（可交易）
+    Active,
+    /// 已结算（等待领取）
+    Settled,
+    /// 已领取（关闭）
+    Claimed,
+}
+
+/// 预测市场仓位 (PDA)
+/// PDA Seeds: ["prediction_market_position", user, event_id]
+/// 
+/// 预测市场仓位与永续合约仓位的主要区别：
+/// 1. 没有杠杆，1:1 保证金
+/// 2. 结算价格只有 0 或 1（对应 No/Yes 赢）
+/// 3. 有明确的结算时间
+/// 4. 份额代替数量
+/// 
+/// TODO: Phase 2 实现
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct PredictionMarketPosition {
+    /// 账户鉴别器
+    pub discriminator: [u8; 8],
+    /// 用户钱包
+    pub user: Pubkey,
+    /// 事件ID (SHA256 hash of event name, e.g., "US_ELECTION_2024")
+    pub event_id: [u8; 32],
+    /// 预测结果 (Yes/No)
+    pub outcome: PredictionOutcome,
+    /// 持有份额 (e6) - 1 份 = 1 USDC 赢时的收益
+    pub shares_e6: u64,
+    /// 平均买入价格 (e6) - 0.00 ~ 1.00 USD
+    pub avg_price_e6: u64,
+    /// 锁定保证金 (e6) = shares * avg_price
+    pub margin_e6: u64,
+    /// 仓位状态
+    pub status: PredictionMarketPositionStatus,
+    /// 结算价格 (e6) - 0 或 1_000_000
+    pub settlement_price_e6: u64,
+    /// 实现盈亏 (e6) - 结算后计算
+    pub realized_pnl_e6: i64,
+    /// 创建时间
+    pub created_at: i64,
+    /// 结算时间 (0 = 未结算)
+    pub settled_at: i64,
+    /// 领取时间 (0 = 未领取)
+    pub claimed_at: i64,
+    /// Bump
+    pub bump: u8,
+    /// 预留空间
+    pub reserved: [u8; 32],
+}
+
+impl PredictionMarketPosition {
+    pub const DISCRIMINATOR: [u8; 8] = *b"pm_pos__";
+    pub const SIZE: usize = 8 + // discriminator
+        32 + // user
+        32 + // event_id
+        1 + // outcome
+        8 + // shares_e6
+        8 + // avg_price_e6
+        8 + // margin_e6
+        1 + // status
+        8 + // settlement_price_e6
+        8 + // realized_pnl_e6
+        8 + // created_at
+        8 + // settled_at
+        8 + // claimed_at
+        1 + // bump
+        32; // reserved
+
+    /// PDA Seeds prefix: ["prediction_market_position", user, event_id]
+    pub const SEED_PREFIX: &'static [u8] = b"prediction_market_position";
+
+    /// 检查仓位是否为空（可清理）
+    pub fn is_empty(&self) -> bool {
+        self.shares_e6 == 0 || 
+        self.status == PredictionMarketPositionStatus::Claimed
+    }
+
+    /// 检查是否已结算
+    pub fn is_settled(&self) -> bool {
+        self.status == PredictionMarketPositionStatus::Settled ||
+        self.status == PredictionMarketPositionStatus::Claimed
+    }
+
+    /// 计算未实现盈亏（基于当前市场价格）
+    /// 
+    /// 如果用户持有 Yes 份额:
+    ///   PnL = shares * (current_price - avg_price)
+    /// 
+    /// 如果用户持有 No 份额:
+    ///   PnL = shares * ((1 - current_price) - avg_price)
+    ///   Note: No 份额的 avg_price 是买入 No 的价格
+    pub fn calculate_unrealized_pnl(&self, current_price_e6: u64) -> i64 {
+        if self.shares_e6 == 0 {
+            return 0;
+        }
+
+        let shares = self.shares_e6 as i64;
+        let avg_price = self.avg_price_e6 as i64;
+        let current = current_price_e6 as i64;
+
+        // PnL = shares * (current - avg) / 1e6
+        let price_diff = current - avg_price;
+        (shares as i128 * price_diff as i128 / 1_000_000) as i64
+    }
+
+    /// 计算结算盈亏
+    /// 
+    /// 如果预测正确（赢）: PnL = shares * (1 - avg_price)
+    /// 如果预测错误（输）: PnL = -shares * avg_price (损失全部保证金)
+    pub fn calculate_settlement_pnl(&self, winning_outcome: PredictionOutcome) -> i64 {
+        if self.shares_e6 == 0 {
+            return 0;
+        }
+
+        let shares = self.shares_e6 as i64;
+        let avg_price = self.avg_price_e6 as i64;
+
+        if self.outcome == winning_outcome {
+            // 赢了：收益 = 份额 * (1 - 买入价格)
+            let profit_per_share = 1_000_000 - avg_price;
+            (shares as i128 * profit_per_share as i128 / 1_000_000) as i64
+        } else {
+            // 输了：损失 = 份额 * 买入价格 (即全部保证金)
+            -((shares as i128 * avg_price as i128 / 1_000_000) as i64)
+        }
+    }
+}
+
+/// 预测市场事件配置 (全局 PDA)
+/// PDA Seeds: ["prediction_market_event", event_id]
+/// 
+/// TODO: Phase 2 实现
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
 pub struct PredictionMarketEvent {
     /// 账户鉴别器
@@ -811,19 +2652,19 @@ pub mod spot_fee_type {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
-    #[test]
-    fn test_position_calculate_unrealized_pnl() {
-        let mut pos = Position {
+    /// 测试用仓位: Long 1 BTC @ $50,000，保证金 $5,000 (10x)
+    pub(crate) fn test_position() -> Position {
+        Position {
             discriminator: Position::DISCRIMINATOR,
             user: Pubkey::new_unique(),
             market_index: 0,
             side: Side::Long,
-            size_e6: 1_000_000, // 1 BTC
-            entry_price_e6: 50_000_000_000, // $50,000
-            margin_e6: 5_000_000_000, // $5,000
+            size_e6: 1_000_000,
+            entry_price_e6: 50_000_000_000,
+            margin_e6: 5_000_000_000,
             leverage: 10,
             liquidation_price_e6: 0,
             unrealized_pnl_e6: 0,
@@ -833,8 +2674,44 @@ mod tests {
             opened_at: 0,
             last_update_ts: 0,
             bump: 255,
-            reserved: [0; 32],
-        };
+            last_funding_index_e6: 0,
+            unsettled_funding_e6: 0,
+            shortfall_count: 0,
+            reserved: [0; 8],
+        }
+    }
+
+    /// 测试用市场配置: 20x，IMR 5%，MMR 2.5%，罚金 1%，无风险档位
+    pub(crate) fn test_market_config() -> MarketConfig {
+        MarketConfig {
+            discriminator: MarketConfig::DISCRIMINATOR,
+            market_index: 0,
+            max_leverage: 20,
+            initial_margin_rate_e6: 50_000,
+            maintenance_margin_rate_e6: 25_000,
+            liquidation_penalty_rate_e6: 10_000,
+            partial_liquidation_buffer_e6: 0,
+            full_liquidation_margin_ratio_e6: 0,
+            min_liquidation_notional_e6: 0,
+            liquidator_reward_bps: 0,
+            tick_size_e6: 1,
+            lot_size_e6: 1,
+            price_band_bps: 200,
+            liquidation_price_band_bps: 1_000,
+            funding_interval_secs: 3600,
+            max_funding_rate_e6: 7_500,
+            is_active: true,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            risk_tiers: vec![],
+            reserved: [0; 64],
+        }
+    }
+
+    #[test]
+    fn test_position_calculate_unrealized_pnl() {
+        let mut pos = test_position();
 
         // Mark price = $55,000 -> PnL = +$5,000
         let pnl = pos.calculate_unrealized_pnl(55_000_000_000).unwrap();
@@ -854,23 +2731,8 @@ mod tests {
     #[test]
     fn test_position_should_liquidate() {
         let mut pos = Position {
-            discriminator: Position::DISCRIMINATOR,
-            user: Pubkey::new_unique(),
-            market_index: 0,
-            side: Side::Long,
-            size_e6: 1_000_000,
-            entry_price_e6: 50_000_000_000, // $50,000
-            margin_e6: 5_000_000_000,
-            leverage: 10,
             liquidation_price_e6: 45_000_000_000, // $45,000
-            unrealized_pnl_e6: 0,
-            last_funding_ts: 0,
-            cumulative_funding_e6: 0,
-            open_order_count: 0,
-            opened_at: 0,
-            last_update_ts: 0,
-            bump: 255,
-            reserved: [0; 32],
+            ..test_position()
         };
 
         // Long: 价格低于清算价 -> 应该清算
@@ -884,6 +2746,895 @@ mod tests {
         assert!(!pos.should_liquidate(54_000_000_000));
     }
 
+    #[test]
+    fn test_position_calculate_liquidation_price() {
+        let mut pos = test_position();
+
+        // Long 10x, MMR 0.5%: $50,000 * (1 - 0.1 + 0.005) = $45,250
+        assert_eq!(pos.calculate_liquidation_price(5_000).unwrap(), 45_250_000_000);
+        // 更高的 MMR -> 更早被清算
+        assert_eq!(pos.calculate_liquidation_price(50_000).unwrap(), 47_500_000_000);
+
+        // Short 10x, MMR 0.5%: $50,000 * (1 + 0.1 - 0.005) = $54,750
+        pos.side = Side::Short;
+        assert_eq!(pos.calculate_liquidation_price(5_000).unwrap(), 54_750_000_000);
+
+        // 追加保证金至 $10,000 (实际 5x): $50,000 * (1 + 0.2 - 0.005) = $59,750
+        pos.margin_e6 = 10_000_000_000;
+        assert_eq!(pos.calculate_liquidation_price(5_000).unwrap(), 59_750_000_000);
+    }
+
+    #[test]
+    fn test_position_partial_liquidation_size() {
+        let pos = Position {
+            size_e6: 10_000_000,            // 10 BTC
+            margin_e6: 50_000_000_000,      // $50,000 (10x)
+            ..test_position()
+        };
+
+        // @ $46,000: 权益 $10,000，名义价值 $460,000 -> 保证金率 ~2.17%
+        let mark = 46_000_000_000;
+        assert_eq!(pos.margin_ratio_e6(mark).unwrap(), 21_739);
+
+        // 目标 3%，罚金 1%: f >= (13,800 - 10,000) / (0.02 * 460,000) = 41.3%
+        let close = pos.partial_liquidation_size(mark, 30_000, 10_000, 100_000).unwrap().unwrap();
+        assert_eq!(close, 4_200_000);
+
+        // 部分清算后剩余仓位满足目标保证金率
+        let remaining_notional = (10_000_000 - close) as i128 * 46_000;
+        let penalty = close as i128 * 46_000 / 100;
+        let remaining_equity = 10_000_000_000i128 - penalty;
+        assert!(remaining_equity * 1_000_000 >= 30_000 * remaining_notional);
+
+        // 目标保证金率不高于罚金率时无法部分清算
+        assert_eq!(pos.partial_liquidation_size(mark, 10_000, 10_000, 1).unwrap(), None);
+        // 已穿仓
+        assert_eq!(pos.partial_liquidation_size(40_000_000_000, 30_000, 10_000, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_position_max_removable_margin() {
+        let mut pos = Position {
+            margin_e6: 10_000_000_000,      // $10,000 (5x)
+            leverage: 5,
+            ..test_position()
+        };
+
+        // IMR 10% @ $50,000: 需要 $5,000，可提取 $5,000
+        assert_eq!(pos.max_removable_margin(50_000_000_000, 100_000).unwrap(), 5_000_000_000);
+        // 浮盈不计入: @ $55,000 需要 $5,500，可提取 $4,500
+        assert_eq!(pos.max_removable_margin(55_000_000_000, 100_000).unwrap(), 4_500_000_000);
+        // 浮亏扣除: @ $46,000 权益 $6,000，需要 $4,600，可提取 $1,400
+        assert_eq!(pos.max_removable_margin(46_000_000_000, 100_000).unwrap(), 1_400_000_000);
+        // 权益不足时不可提取
+        pos.margin_e6 = 4_000_000_000;
+        assert_eq!(pos.max_removable_margin(50_000_000_000, 100_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_market_config_checks() {
+        let mut config = MarketConfig {
+            market_index: 3,
+            tick_size_e6: 10_000,
+            lot_size_e6: 1_000,
+            ..test_market_config()
+        };
+        assert!(config.validate().is_ok());
+
+        // 杠杆限制
+        assert!(config.check_leverage(20).is_ok());
+        assert_eq!(config.check_leverage(0), Err(crate::error::LedgerError::InvalidLeverage));
+        assert_eq!(config.check_leverage(21), Err(crate::error::LedgerError::LeverageExceedsMax));
+
+        // tick / lot 对齐
+        assert!(config.check_order(5_000, 50_000_000_000).is_ok());
+        assert_eq!(config.check_order(5_500, 50_000_000_000), Err(crate::error::LedgerError::InvalidTradeAmount));
+        assert_eq!(config.check_order(5_000, 50_000_005_000), Err(crate::error::LedgerError::InvalidPrice));
+
+        // MMR 必须小于 IMR
+        config.maintenance_margin_rate_e6 = 50_000;
+        assert!(config.validate().is_err());
+        config.maintenance_margin_rate_e6 = 25_000;
+
+        // 初始保证金率限制有效杠杆: IMR 10% -> 最多 10x
+        config.initial_margin_rate_e6 = 100_000;
+        assert!(config.check_leverage(10).is_ok());
+        assert_eq!(config.check_leverage(11), Err(crate::error::LedgerError::LeverageExceedsMax));
+
+        // 价格带宽: 普通成交 2%，清算 / ADL 成交 10%
+        let reference = 50_000_000_000;
+        assert!(config.check_trade_price(50_900_000_000, reference).is_ok());
+        assert_eq!(config.check_trade_price(52_000_000_000, reference), Err(crate::error::LedgerError::PriceOutOfBand));
+        assert!(config.check_liquidation_price(52_000_000_000, reference).is_ok());
+        assert_eq!(config.check_liquidation_price(44_000_000_000, reference), Err(crate::error::LedgerError::PriceOutOfBand));
+
+        config.price_band_bps = 0;
+        assert!(config.validate().is_err());
+        config.price_band_bps = 200;
+
+        // 资金费率上限 0.75% / 周期 1 小时
+        assert!(config.check_funding_rate(-7_500).is_ok());
+        assert_eq!(config.check_funding_rate(7_501), Err(crate::error::LedgerError::InvalidFundingRate));
+        assert_eq!(config.check_funding_rate(i64::MIN), Err(crate::error::LedgerError::InvalidFundingRate));
+        assert!(config.check_funding_due(1_000, 4_600).is_ok());
+        assert_eq!(config.check_funding_due(1_000, 4_599), Err(crate::error::LedgerError::FundingNotDue));
+
+        config.max_funding_rate_e6 = MAX_FUNDING_RATE_E6 + 1;
+        assert!(config.validate().is_err());
+        config.max_funding_rate_e6 = 7_500;
+        config.funding_interval_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_market_config_risk_tiers() {
+        let mut config = MarketConfig {
+            max_leverage: 50,
+            initial_margin_rate_e6: 20_000,
+            maintenance_margin_rate_e6: 10_000,
+            price_band_bps: 100,
+            liquidation_price_band_bps: 500,
+            ..test_market_config()
+        };
+
+        // 无档位: 使用基础参数
+        let tier = config.risk_tier_for(u64::MAX).unwrap();
+        assert_eq!(tier.max_leverage, 50);
+        assert_eq!(tier.maintenance_margin_rate_e6, 10_000);
+
+        config.risk_tiers = vec![
+            RiskTier { max_notional_e6: 100_000_000_000, max_leverage: 50, maintenance_margin_rate_e6: 10_000 },
+            RiskTier { max_notional_e6: 1_000_000_000_000, max_leverage: 20, maintenance_margin_rate_e6: 25_000 },
+            RiskTier { max_notional_e6: 10_000_000_000_000, max_leverage: 5, maintenance_margin_rate_e6: 100_000 },
+        ];
+        assert!(config.validate().is_ok());
+
+        // $50k -> 第一档, $500k -> 第二档
+        assert_eq!(config.risk_tier_for(50_000_000_000).unwrap().maintenance_margin_rate_e6, 10_000);
+        assert_eq!(config.risk_tier_for(500_000_000_000).unwrap().maintenance_margin_rate_e6, 25_000);
+        // 超出最高档
+        assert_eq!(
+            config.risk_tier_for(20_000_000_000_000),
+            Err(crate::error::LedgerError::PositionSizeExceedsMax)
+        );
+
+        // 鲸鱼仓位不能使用高杠杆
+        assert!(config.check_position_tier(50, 50_000_000_000).is_ok());
+        assert_eq!(
+            config.check_position_tier(50, 500_000_000_000),
+            Err(crate::error::LedgerError::LeverageExceedsMax)
+        );
+
+        // 档位必须单调: 高档位的 MMR 不能更低
+        config.risk_tiers[2].maintenance_margin_rate_e6 = 5_000;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_position_reduce() {
+        let mut pos = Position {
+            size_e6: 2_000_000,              // 2 BTC
+            margin_e6: 10_000_000_000,       // $10,000
+            ..test_position()
+        };
+
+        // 按 $51,000 平 0.5 BTC: PnL = $500，释放 1/4 保证金
+        let (closed, pnl, released) = pos.reduce(500_000, 51_000_000_000).unwrap();
+        assert_eq!(closed, 500_000);
+        assert_eq!(pnl, 500_000_000);
+        assert_eq!(released, 2_500_000_000);
+        assert_eq!(pos.size_e6, 1_500_000);
+        assert_eq!(pos.margin_e6, 7_500_000_000);
+
+        // 超额平仓按全部平仓处理
+        let (closed, pnl, released) = pos.reduce(3_000_000, 49_000_000_000).unwrap();
+        assert_eq!(closed, 1_500_000);
+        assert_eq!(pnl, -1_500_000_000);
+        assert_eq!(released, 7_500_000_000);
+        assert!(pos.is_empty());
+        assert_eq!(pos.margin_e6, 0);
+        assert_eq!(pos.entry_price_e6, 0);
+    }
+
+    #[test]
+    fn test_position_accrue_funding() {
+        let mut pos = Position {
+            size_e6: 2_000_000,              // 2 BTC
+            margin_e6: 10_000_000_000,       // $10,000
+            last_funding_index_e6: 1_000_000,
+            ..test_position()
+        };
+        let liq_before = pos.calculate_liquidation_price(5_000).unwrap();
+
+        // 指数上涨 $5 / BTC: Long 2 BTC 应付 $10
+        assert_eq!(pos.accrue_funding(6_000_000).unwrap(), 10_000_000);
+        assert_eq!(pos.unsettled_funding_e6, 10_000_000);
+        assert_eq!(pos.cumulative_funding_e6, 10_000_000);
+        assert_eq!(pos.last_funding_index_e6, 6_000_000);
+        assert_eq!(pos.last_funding_ts, 0);
+        assert_eq!(pos.funding_adjusted_margin_e6(), 9_990_000_000);
+        // 未结算资金费减少有效保证金，清算价格上移
+        assert!(pos.calculate_liquidation_price(5_000).unwrap() > liq_before);
+
+        // 同一指数重复计提无效果
+        assert_eq!(pos.accrue_funding(6_000_000).unwrap(), 0);
+
+        // 平一半: 已实现 PnL 扣除一半资金费
+        let (_, pnl, _) = pos.reduce(1_000_000, 50_000_000_000).unwrap();
+        assert_eq!(pnl, -5_000_000);
+        assert_eq!(pos.unsettled_funding_e6, 5_000_000);
+
+        // Short 收取资金费
+        pos.side = Side::Short;
+        assert_eq!(pos.accrue_funding(8_000_000).unwrap(), -2_000_000);
+        assert_eq!(pos.unsettled_funding_e6, 3_000_000);
+
+        // 全部平仓取出剩余资金费
+        let (_, pnl, _) = pos.reduce(1_000_000, 50_000_000_000).unwrap();
+        assert_eq!(pnl, -3_000_000);
+        assert_eq!(pos.unsettled_funding_e6, 0);
+        assert_eq!(pos.cumulative_funding_e6, 8_000_000);
+
+        // 空仓位只同步指数
+        assert_eq!(pos.accrue_funding(9_000_000).unwrap(), 0);
+        assert_eq!(pos.last_funding_index_e6, 9_000_000);
+    }
+
+    #[test]
+    fn test_position_settle_funding_to_margin() {
+        let mut pos = Position {
+            margin_e6: 100_000_000,          // $100
+            leverage: 100,
+            ..test_position()
+        };
+
+        // 支付 $30
+        assert_eq!(pos.settle_funding_to_margin(30_000_000).unwrap(), 30_000_000);
+        assert_eq!(pos.margin_e6, 70_000_000);
+        assert_eq!(pos.unsettled_funding_e6, 0);
+
+        // 收取 $10
+        assert_eq!(pos.settle_funding_to_margin(-10_000_000).unwrap(), -10_000_000);
+        assert_eq!(pos.margin_e6, 80_000_000);
+
+        // 支付超过保证金: 超出部分保留为未结算
+        assert_eq!(pos.settle_funding_to_margin(100_000_000).unwrap(), 80_000_000);
+        assert_eq!(pos.margin_e6, 0);
+        assert_eq!(pos.unsettled_funding_e6, 20_000_000);
+        assert_eq!(pos.funding_adjusted_margin_e6(), -20_000_000);
+    }
+
+    #[test]
+    fn test_funding_settlement_summary_epochs() {
+        let mut summary = FundingSettlementSummary {
+            discriminator: FundingSettlementSummary::DISCRIMINATOR,
+            market_index: 0,
+            epoch: 0,
+            funding_rate_e6: 0,
+            index_price_e6: 0,
+            accounts_settled: 0,
+            total_funding_paid_e6: 0,
+            net_funding_e6: 0,
+            first_settled_at: 0,
+            last_settled_at: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        };
+        assert_eq!(summary.try_to_vec().unwrap().len(), FundingSettlementSummary::SIZE);
+
+        summary.begin_epoch(7, 100, 50_000_000_000, 1_000).unwrap();
+        summary.record(Side::Long, 10_000_000);
+        summary.record(Side::Short, -9_000_000);
+
+        // 同一轮次拆分到下一笔交易
+        summary.begin_epoch(7, 100, 50_000_000_000, 1_010).unwrap();
+        summary.record(Side::Long, 5_000_000);
+        assert_eq!(summary.accounts_settled, 3);
+        assert_eq!(summary.total_funding_paid_e6, 15_000_000);
+        assert_eq!(summary.net_funding_e6, 6_000_000);
+        assert_eq!(summary.first_settled_at, 1_000);
+        assert_eq!(summary.last_settled_at, 1_010);
+
+        // 同一轮次费率不一致 / 旧轮次
+        assert_eq!(
+            summary.begin_epoch(7, 200, 50_000_000_000, 1_020),
+            Err(crate::error::LedgerError::InvalidFundingRate)
+        );
+        assert_eq!(
+            summary.begin_epoch(6, 100, 50_000_000_000, 1_020),
+            Err(crate::error::LedgerError::FundingNotDue)
+        );
+
+        // 新轮次重置
+        summary.begin_epoch(8, -50, 51_000_000_000, 4_600).unwrap();
+        assert_eq!(summary.accounts_settled, 0);
+        assert_eq!(summary.total_funding_paid_e6, 0);
+        assert_eq!(summary.first_settled_at, 4_600);
+    }
+
+    #[test]
+    fn test_premium_samples_funding_rate() {
+        let mut samples = PremiumSamples {
+            discriminator: PremiumSamples::DISCRIMINATOR,
+            market_index: 0,
+            interest_rate_e6: 100,  // 0.01% / 周期
+            premium_clamp_e6: 500,  // ±0.05%
+            head: 0,
+            count: 0,
+            last_funding_rate_e6: 0,
+            last_premium_twap_e6: 0,
+            last_index_price_e6: 0,
+            last_published_ts: 0,
+            published_count: 0,
+            bump: 255,
+            created_at: 0,
+            reserved: [0u8; 32],
+            samples: [PremiumSample::default(); PREMIUM_SAMPLE_CAPACITY],
+        };
+        assert!(samples.validate_params().is_ok());
+        assert_eq!(samples.try_to_vec().unwrap().len(), PremiumSamples::SIZE);
+        assert!(samples.twap_since(0, 100).is_none());
+
+        // 溢价 +0.2% 持续 100 秒，+0.1% 持续 300 秒
+        samples.push(50_100_000_000, 50_000_000_000, 100).unwrap();
+        samples.push(50_050_000_000, 50_000_000_000, 200).unwrap();
+        let (twap, index) = samples.twap_since(0, 500).unwrap();
+        assert_eq!(twap, (2_000 * 100 + 1_000 * 300) / 400);
+        assert_eq!(index, 50_000_000_000);
+        // 只使用上次发布之后的采样
+        assert_eq!(samples.twap_since(100, 500).unwrap().0, 1_000);
+
+        // 溢价 0.125% 超过利率: 利率分量钳制到 -0.05%
+        assert_eq!(samples.compute_funding_rate(twap, 7_500), 1_250 - 500);
+        // 溢价接近利率时等于利率
+        assert_eq!(samples.compute_funding_rate(300, 7_500), 100);
+        // 最终费率钳制到 max_funding_rate
+        assert_eq!(samples.compute_funding_rate(-20_000, 7_500), -7_500);
+
+        // 环形缓冲区满后覆盖最旧的采样
+        for i in 0..PREMIUM_SAMPLE_CAPACITY as i64 {
+            samples.push(50_000_000_000, 50_000_000_000, 1_000 + i).unwrap();
+        }
+        assert_eq!(samples.count as usize, PREMIUM_SAMPLE_CAPACITY);
+        assert_eq!(samples.ordered_samples().next().unwrap().timestamp, 1_000);
+        assert_eq!(samples.twap_since(0, 2_000).unwrap().0, 0);
+        assert_eq!(samples.push(0, 50_000_000_000, 3_000), Err(crate::error::LedgerError::InvalidPrice));
+
+        // 已发布费率的校验
+        assert_eq!(samples.check_published(0, 0, 3600, 0), Err(crate::error::LedgerError::FundingNotDue));
+        samples.last_funding_rate_e6 = 750;
+        samples.last_index_price_e6 = 50_000_000_000;
+        samples.last_published_ts = 3_600;
+        samples.published_count = 1;
+        assert!(samples.check_published(750, 50_000_000_000, 3600, 7_200).is_ok());
+        assert_eq!(
+            samples.check_published(800, 50_000_000_000, 3600, 7_200),
+            Err(crate::error::LedgerError::InvalidFundingRate)
+        );
+        assert_eq!(
+            samples.check_published(750, 50_000_000_000, 3600, 7_201),
+            Err(crate::error::LedgerError::FundingNotDue)
+        );
+    }
+
+    #[test]
+    fn test_funding_state_apply_rate() {
+        let mut state = FundingState {
+            discriminator: FundingState::DISCRIMINATOR,
+            market_index: 0,
+            cumulative_funding_index_e6: 0,
+            funding_interval_secs: 3600,
+            last_funding_ts: 0,
+            last_funding_rate_e6: 0,
+            last_index_price_e6: 0,
+            update_count: 0,
+            bump: 255,
+            created_at: 0,
+            reserved: [0u8; 64],
+        };
+        assert!(state.validate_params().is_ok());
+        assert_eq!(state.try_to_vec().unwrap().len(), FundingState::SIZE);
+        assert_eq!(
+            state.check_applied_rate(0, 0),
+            Err(crate::error::LedgerError::FundingNotDue)
+        );
+
+        // 0.01% * $50,000 = $5 / BTC
+        state.apply_rate(100, 50_000_000_000, 1_000).unwrap();
+        assert_eq!(state.cumulative_funding_index_e6, 5_000_000);
+        assert_eq!(state.update_count, 1);
+
+        // 未满一个周期
+        assert_eq!(
+            state.apply_rate(100, 50_000_000_000, 4_599),
+            Err(crate::error::LedgerError::FundingNotDue)
+        );
+
+        // 负费率降低指数
+        state.apply_rate(-200, 50_000_000_000, 4_600).unwrap();
+        assert_eq!(state.cumulative_funding_index_e6, -5_000_000);
+        assert_eq!(state.last_funding_rate_e6, -200);
+        assert_eq!(state.last_funding_ts, 4_600);
+        assert!(state.check_applied_rate(-200, 50_000_000_000).is_ok());
+        assert_eq!(
+            state.check_applied_rate(100, 50_000_000_000),
+            Err(crate::error::LedgerError::InvalidFundingRate)
+        );
+
+        assert_eq!(state.apply_rate(100, 0, 9_000), Err(crate::error::LedgerError::InvalidPrice));
+        state.funding_interval_secs = 0;
+        assert!(state.validate_params().is_err());
+    }
+
+    #[test]
+    fn test_position_adl_score() {
+        let mut pos = test_position();
+
+        // mark $55,000: 盈利率 100%，有效杠杆 55,000 / 10,000 = 5.5
+        assert_eq!(pos.adl_score_e6(55_000_000_000).unwrap(), 5_500_000);
+
+        // 同样盈利但保证金更多: 盈利率和有效杠杆都更低，排在后面
+        pos.margin_e6 = 10_000_000_000;
+        let lower = pos.adl_score_e6(55_000_000_000).unwrap();
+        assert!(lower < 5_500_000);
+        assert_eq!(lower, 1_833_333);
+
+        // 未盈利不参与排序
+        assert_eq!(pos.adl_score_e6(50_000_000_000).unwrap(), 0);
+        assert_eq!(pos.adl_score_e6(45_000_000_000).unwrap(), 0);
+
+        // 破产价格: Long 保证金 $10,000 / 1 BTC -> $40,000
+        assert_eq!(pos.bankruptcy_price_e6(), 40_000_000_000);
+        pos.side = Side::Short;
+        assert_eq!(pos.bankruptcy_price_e6(), 60_000_000_000);
+    }
+
+    #[test]
+    fn test_position_adl_close_size() {
+        let pos = Position {
+            side: Side::Short,
+            size_e6: 2_000_000,              // 2 BTC
+            margin_e6: 10_000_000_000,
+            ..test_position()
+        };
+        let mark = 45_000_000_000;
+        let bankruptcy = 46_000_000_000; // 每 BTC 让出 $1,000
+
+        // 覆盖 $1,500 需要 1.5 BTC
+        assert_eq!(
+            pos.adl_close_size(1_500_000_000, mark, bankruptcy, 100_000).unwrap(),
+            (1_500_000, 1_500_000_000)
+        );
+        // lot_size = 1 BTC 向上取整，超额覆盖
+        assert_eq!(
+            pos.adl_close_size(1_200_000_000, mark, bankruptcy, 1_000_000).unwrap(),
+            (2_000_000, 2_000_000_000)
+        );
+        // 不超过仓位大小
+        assert_eq!(
+            pos.adl_close_size(5_000_000_000, mark, bankruptcy, 100_000).unwrap(),
+            (2_000_000, 2_000_000_000)
+        );
+        // 破产价格对 Short 比标记价格更有利: 无法覆盖
+        assert!(pos.adl_close_size(1_000_000_000, mark, 44_000_000_000, 100_000).is_err());
+        assert!(pos.adl_close_size(1_000_000_000, mark, mark, 100_000).is_err());
+    }
+
+    #[test]
+    fn test_adl_state_lifecycle() {
+        let mut state = ADLState {
+            discriminator: ADLState::DISCRIMINATOR,
+            market_index: 0,
+            is_active: false,
+            shortfall_record: Pubkey::default(),
+            bankrupt_user: Pubkey::default(),
+            bankrupt_side: Side::Long,
+            bankrupt_size_e6: 0,
+            bankruptcy_price_e6: 0,
+            shortfall_e6: 0,
+            insurance_balance_before_e6: 0,
+            adl_required_e6: 0,
+            covered_e6: 0,
+            counterparty_size_reduced_e6: 0,
+            target_count: 0,
+            triggered_at: 0,
+            completed_at: 0,
+            completed_count: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        };
+        assert_eq!(state.try_to_vec().unwrap().len(), ADLState::SIZE);
+        assert!(state.complete(10).is_err());
+
+        let mut record = ShortfallRecord {
+            discriminator: ShortfallRecord::DISCRIMINATOR,
+            user: Pubkey::new_unique(),
+            market_index: 0,
+            side: Side::Short,
+            size_e6: 1_000_000,
+            entry_price_e6: 50_000_000_000,
+            bankruptcy_price_e6: 55_000_000_000,
+            shortfall_e6: 5_000_000_000,
+            liquidation_sequence: 42,
+            shortfall_index: 0,
+            is_consumed: false,
+            created_at: 90,
+            consumed_at: 0,
+            bump: 255,
+            reserved: [0u8; 24],
+        };
+        assert_eq!(record.try_to_vec().unwrap().len(), ShortfallRecord::SIZE);
+        record.consume(100).unwrap();
+        assert!(record.consume(101).is_err());
+
+        // 穿仓 $5,000，保险基金 $2,000 -> ADL 需覆盖 $3,000
+        let record_key = Pubkey::new_unique();
+        state.start(record_key, &record, 2_000_000_000, 100).unwrap();
+        assert_eq!(state.adl_required_e6, 3_000_000_000);
+        assert_eq!(state.bankrupt_user, record.user);
+        assert_eq!(state.bankruptcy_price_e6, 55_000_000_000);
+        assert!(state.start(record_key, &record, 0, 101).is_err());
+
+        state.record_reduction(1_000_000_000, 500_000);
+        assert_eq!(state.remaining_e6(), 2_000_000_000);
+        assert!(state.complete(200).is_err());
+        assert_eq!(record.apply_coverage(1_000_000_000), 1_000_000_000);
+        assert_eq!(record.shortfall_e6, 4_000_000_000);
+
+        state.record_reduction(2_500_000_000, 800_000);
+        assert_eq!(state.remaining_e6(), 0);
+        // 覆盖超过剩余穿仓时只扣减到 0
+        assert_eq!(record.apply_coverage(5_000_000_000), 4_000_000_000);
+        assert_eq!(record.shortfall_e6, 0);
+        assert_eq!(state.counterparty_size_reduced_e6, 1_300_000);
+        assert_eq!(state.target_count, 2);
+        state.complete(300).unwrap();
+        assert!(!state.is_active);
+        assert_eq!(state.completed_at, 300);
+        assert_eq!(state.completed_count, 1);
+    }
+
+    #[test]
+    fn test_liquidator_stats_record() {
+        let mut stats = LiquidatorStats {
+            discriminator: LiquidatorStats::DISCRIMINATOR,
+            liquidator: Pubkey::new_unique(),
+            total_liquidations: 0,
+            partial_liquidations: 0,
+            total_liquidated_notional_e6: 0,
+            total_rewards_e6: 0,
+            last_liquidation_ts: 0,
+            created_at: 0,
+            bump: 255,
+            reserved: [0u8; 32],
+        };
+        stats.record(10_000_000_000, 40_000_000, true, 100);
+        stats.record(5_000_000_000, 0, false, 200);
+
+        assert_eq!(stats.total_liquidations, 2);
+        assert_eq!(stats.partial_liquidations, 1);
+        assert_eq!(stats.total_liquidated_notional_e6, 15_000_000_000);
+        assert_eq!(stats.total_rewards_e6, 40_000_000);
+        assert_eq!(stats.last_liquidation_ts, 200);
+        assert_eq!(stats.try_to_vec().unwrap().len(), LiquidatorStats::SIZE);
+    }
+
+    #[test]
+    fn test_price_feed_checked_price() {
+        let mut feed = PriceFeed {
+            discriminator: PriceFeed::DISCRIMINATOR,
+            market_index: 0,
+            price_e6: 0,
+            conf_e6: 0,
+            publish_ts: 1_000,
+            max_staleness_secs: 30,
+            max_confidence_e6: 10_000, // 1%
+            signer_count: 0,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0; 64],
+        };
+        assert!(feed.validate_params().is_ok());
+
+        // 从未更新
+        assert_eq!(feed.checked_price(1_000), Err(crate::error::LedgerError::InvalidPrice));
+
+        feed.price_e6 = 50_000_000_000;
+        feed.conf_e6 = 25_000_000; // 0.05%
+        assert_eq!(feed.checked_price(1_030), Ok(50_000_000_000));
+        assert_eq!(feed.checked_price(1_031), Err(crate::error::LedgerError::StalePrice));
+
+        feed.conf_e6 = 600_000_000; // 1.2%
+        assert_eq!(feed.checked_price(1_000), Err(crate::error::LedgerError::PriceConfidenceTooWide));
+    }
+
+    #[test]
+    fn test_price_feed_aggregate_quorum() {
+        assert_eq!(PriceFeed::aggregate_quorum(&[]), None);
+        assert_eq!(PriceFeed::aggregate_quorum(&[100, 0]), None);
+        assert_eq!(PriceFeed::aggregate_quorum(&[100]), Some((100, 0)));
+        // 单个离群报价不影响中位数
+        assert_eq!(PriceFeed::aggregate_quorum(&[101, 1, 100]), Some((100, 50)));
+        assert_eq!(PriceFeed::aggregate_quorum(&[100, 104, 102, 98]), Some((101, 3)));
+    }
+
+    #[test]
+    fn test_mark_price_update() {
+        let mut mark = MarkPrice {
+            discriminator: MarkPrice::DISCRIMINATOR,
+            market_index: 0,
+            mark_price_e6: 0,
+            index_price_e6: 0,
+            basis_ema_e6: 0,
+            last_trade_price_e6: 0,
+            last_trade_ts: 0,
+            ema_period_secs: 100,
+            max_staleness_secs: 30,
+            last_update_ts: 0,
+            update_count: 0,
+            bump: 255,
+            created_at: 0,
+            reserved: [0; 32],
+        };
+        assert!(mark.validate_params().is_ok());
+        assert_eq!(mark.try_to_vec().unwrap().len(), MarkPrice::SIZE);
+        assert_eq!(mark.checked_price(1_000), Err(crate::error::LedgerError::InvalidPrice));
+        assert_eq!(mark.record_trade(0, 1_000), Err(crate::error::LedgerError::InvalidPrice));
+
+        // 尚无成交: 标记价格 = 指数价格
+        assert_eq!(mark.update(100_000_000, 1_000), Ok(100_000_000));
+
+        // 成交价 102，半个 EMA 周期后基差 EMA = 1，mark = median(100, 101, 102)
+        mark.record_trade(102_000_000, 1_010).unwrap();
+        assert_eq!(mark.update(100_000_000, 1_050), Ok(101_000_000));
+        assert_eq!(mark.basis_ema_e6, 1_000_000);
+        assert_eq!(mark.checked_price(1_080), Ok(101_000_000));
+        assert_eq!(mark.checked_price(1_081), Err(crate::error::LedgerError::StalePrice));
+
+        // 单笔离群成交只按经过时间影响 EMA
+        mark.record_trade(150_000_000, 1_050).unwrap();
+        assert_eq!(mark.update(100_000_000, 1_050), Ok(101_000_000));
+        assert_eq!(mark.update(100_000_000, 1_060), Ok(105_900_000));
+
+        // 超过一个 EMA 周期没有成交，基差回归 0
+        assert_eq!(mark.update(100_000_000, 1_200), Ok(100_000_000));
+        assert_eq!(mark.basis_ema_e6, 0);
+        assert_eq!(mark.update_count, 5);
+
+        mark.ema_period_secs = 0;
+        assert_eq!(mark.validate_params(), Err(crate::error::LedgerError::InvalidMarkPrice));
+    }
+
+    #[test]
+    fn test_fee_tier_table() {
+        let mut table = FeeTierTable {
+            discriminator: FeeTierTable::DISCRIMINATOR,
+            tiers: vec![
+                FeeTier { min_volume_e6: 0, maker_fee_rate_e6: 200, taker_fee_rate_e6: 500 },
+                FeeTier { min_volume_e6: 1_000_000_000_000, maker_fee_rate_e6: 0, taker_fee_rate_e6: 400 },
+                FeeTier { min_volume_e6: 10_000_000_000_000, maker_fee_rate_e6: -100, taker_fee_rate_e6: 300 },
+            ],
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0u8; 32],
+        };
+        assert!(table.validate().is_ok());
+        assert_eq!(table.tier_for(999_999_999_999).unwrap().taker_fee_rate_e6, 500);
+        assert_eq!(table.tier_for(1_000_000_000_000).unwrap().taker_fee_rate_e6, 400);
+        assert_eq!(table.tier_for(u64::MAX).unwrap().maker_fee_rate_e6, -100);
+
+        // 费率不得高于档位
+        assert!(table.check_fee_rate(0, true, 500).is_ok());
+        assert!(table.check_fee_rate(0, true, 100).is_ok());
+        assert_eq!(table.check_fee_rate(1_000_000_000_000, true, 500), Err(crate::error::LedgerError::FeeRateAboveTier));
+        assert_eq!(table.check_fee_rate(10_000_000_000_000, false, 0), Err(crate::error::LedgerError::FeeRateAboveTier));
+        assert!(table.check_fee_rate(10_000_000_000_000, false, -100).is_ok());
+
+        // 费率随成交量递增 / 第一档下限非 0 均无效
+        table.tiers[1].taker_fee_rate_e6 = 600;
+        assert_eq!(table.validate(), Err(crate::error::LedgerError::InvalidFeeTier));
+        table.tiers[1].taker_fee_rate_e6 = 400;
+        table.tiers[0].min_volume_e6 = 1;
+        assert_eq!(table.validate(), Err(crate::error::LedgerError::InvalidFeeTier));
+    }
+
+    #[test]
+    fn test_user_stats_rolling_volume() {
+        let mut stats = UserStats {
+            discriminator: UserStats::DISCRIMINATOR,
+            user: Pubkey::new_unique(),
+            total_trades: 0,
+            total_volume_e6: 0,
+            total_realized_pnl_e6: 0,
+            total_fees_paid_e6: 0,
+            total_funding_paid_e6: 0,
+            total_liquidations: 0,
+            first_trade_at: 0,
+            last_trade_at: 0,
+            bump: 255,
+            total_rebates_received_e6: 0,
+            last_volume_day: 0,
+            daily_volume_e6: [0; ROLLING_VOLUME_DAYS],
+        };
+        let day = ROLLING_VOLUME_BUCKET_SECS;
+        let start = 1_700_000_000;
+        stats.record_rolling_volume(3_000_000, start);
+        assert_eq!(stats.rolling_volume_e6(start), 3_000_000);
+
+        // 第 15 天仍在窗口内，不衰减
+        let later = start + 15 * day;
+        assert_eq!(stats.rolling_volume_e6(later), 3_000_000);
+        stats.record_rolling_volume(1_000_000, later);
+        stats.record_rolling_volume(500_000, later + 60);
+        assert_eq!(stats.rolling_volume_e6(later), 4_500_000);
+
+        // 第 30 天首日的成交移出窗口，第 15 天的仍计入
+        assert_eq!(stats.rolling_volume_e6(start + 29 * day), 4_500_000);
+        assert_eq!(stats.rolling_volume_e6(start + 30 * day), 1_500_000);
+        stats.record_rolling_volume(2_000_000, start + 30 * day);
+        assert_eq!(stats.rolling_volume_e6(start + 30 * day), 3_500_000);
+
+        // 桶被复用前已清空: 45 天后只剩第 30 天的成交
+        assert_eq!(stats.rolling_volume_e6(start + 45 * day), 2_000_000);
+        stats.record_rolling_volume(100_000, start + 45 * day);
+        assert_eq!(stats.rolling_volume_e6(start + 45 * day), 2_100_000);
+
+        // 整个窗口无成交后归零
+        assert_eq!(stats.rolling_volume_e6(start + 75 * day), 0);
+        assert_eq!(stats.try_to_vec().unwrap().len() + 8, UserStats::SIZE);
+    }
+
+    #[test]
+    fn test_referrer_stats_claim() {
+        let mut stats = ReferrerStats {
+            discriminator: ReferrerStats::DISCRIMINATOR,
+            referrer: Pubkey::new_unique(),
+            code: *b"ALICE___________",
+            referee_count: 1,
+            total_earned_e6: 0,
+            claimable_e6: 0,
+            total_claimed_e6: 0,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            reserved: [0u8; 32],
+        };
+        assert_eq!(stats.try_to_vec().unwrap().len(), ReferrerStats::SIZE);
+        assert_eq!(stats.claim(10), Err(crate::error::LedgerError::NoReferralRewards));
+
+        stats.credit(2_000_000, 20).unwrap();
+        stats.credit(500_000, 30).unwrap();
+        assert_eq!(stats.claim(40), Ok(2_500_000));
+        assert_eq!((stats.claimable_e6, stats.total_claimed_e6, stats.total_earned_e6), (0, 2_500_000, 2_500_000));
+
+        stats.credit(100_000, 50).unwrap();
+        assert_eq!(stats.claim(60), Ok(100_000));
+        assert_eq!(stats.total_claimed_e6, stats.total_earned_e6);
+    }
+
+    #[test]
+    fn test_fee_schedule_split() {
+        let mut schedule = FeeSchedule {
+            discriminator: FeeSchedule::DISCRIMINATOR,
+            insurance_bps: 4_000,
+            treasury_bps: 3_000,
+            referrer_bps: 2_000,
+            maker_rebate_bps: 1_000,
+            total_insurance_e6: 0,
+            total_treasury_e6: 0,
+            total_referrer_e6: 0,
+            total_maker_rebate_e6: 0,
+            bump: 255,
+            created_at: 0,
+            last_update_ts: 0,
+            referee_discount_bps: 1_000,
+            reserved: [0u8; 62],
+        };
+        assert!(schedule.validate_params().is_ok());
+
+        // 取整余数归保险基金
+        let split = schedule.split(1_001, true);
+        assert_eq!(split, FeeSplit { insurance_e6: 401, treasury_e6: 300, referrer_e6: 200, maker_rebate_e6: 100 });
+        schedule.record(&split).unwrap();
+        schedule.record(&split).unwrap();
+        assert_eq!(schedule.total_insurance_e6, 802);
+        assert_eq!(schedule.total_maker_rebate_e6, 200);
+
+        // 没有推荐人时推荐人部分归协议金库
+        let no_referrer = schedule.split(1_001, false);
+        assert_eq!(no_referrer, FeeSplit { insurance_e6: 401, treasury_e6: 500, referrer_e6: 0, maker_rebate_e6: 100 });
+        assert_eq!(no_referrer.recipients().len(), 3);
+
+        // 被推荐用户折扣只作用于正费率
+        assert_eq!(schedule.referee_fee_rate_e6(500), 450);
+        assert_eq!(schedule.referee_fee_rate_e6(-200), -200);
+        schedule.referee_discount_bps = 5_001;
+        assert_eq!(schedule.validate_params(), Err(crate::error::LedgerError::InvalidFeeSchedule));
+        schedule.referee_discount_bps = 1_000;
+
+        // 之和必须为 10000
+        schedule.treasury_bps = 2_999;
+        assert_eq!(schedule.validate_params(), Err(crate::error::LedgerError::InvalidFeeSchedule));
+
+        // 硬上限
+        schedule.insurance_bps = 1_000;
+        schedule.treasury_bps = 2_000;
+        schedule.referrer_bps = 2_000;
+        schedule.maker_rebate_bps = 5_000;
+        assert!(schedule.validate_params().is_ok());
+        schedule.treasury_bps = 3_000;
+        schedule.insurance_bps = 0;
+        assert_eq!(schedule.validate_params(), Err(crate::error::LedgerError::InvalidFeeSchedule));
+        schedule.insurance_bps = 1_000;
+        schedule.treasury_bps = 0;
+        schedule.referrer_bps = 3_000;
+        schedule.maker_rebate_bps = 6_000;
+        assert_eq!(schedule.validate_params(), Err(crate::error::LedgerError::InvalidFeeSchedule));
+    }
+
+    #[test]
+    fn test_position_refresh_liquidation_price_uses_tier() {
+        let config = MarketConfig {
+            initial_margin_rate_e6: 20_000,
+            maintenance_margin_rate_e6: 5_000,
+            price_band_bps: 100,
+            liquidation_price_band_bps: 500,
+            risk_tiers: vec![
+                RiskTier { max_notional_e6: 100_000_000_000, max_leverage: 20, maintenance_margin_rate_e6: 5_000 },
+                RiskTier { max_notional_e6: 10_000_000_000_000, max_leverage: 10, maintenance_margin_rate_e6: 50_000 },
+            ],
+            ..test_market_config()
+        };
+        let mut pos = test_position();
+
+        pos.refresh_liquidation_price(&config).unwrap();
+        assert_eq!(pos.liquidation_price_e6, 45_250_000_000); // MMR 0.5%
+
+        // 加仓至 3 BTC ($150k, 10x) -> 第二档 MMR 5%
+        pos.margin_e6 += 10_000_000_000;
+        pos.update_entry_price(2_000_000, 50_000_000_000, &config).unwrap();
+        assert_eq!(pos.size_e6, 3_000_000);
+        assert_eq!(pos.liquidation_price_e6, 47_500_000_000);
+    }
+
+    #[test]
+    fn test_position_mixed_leverage_increase() {
+        let config = MarketConfig {
+            initial_margin_rate_e6: 20_000,
+            maintenance_margin_rate_e6: 5_000,
+            price_band_bps: 100,
+            liquidation_price_band_bps: 500,
+            ..test_market_config()
+        };
+        let mut pos = test_position();
+
+        // 以 20x 再加 1 BTC: 保证金 $5,000 + $2,500，实际杠杆 $100,000 / $7,500 ≈ 13x
+        pos.margin_e6 += 2_500_000_000;
+        pos.update_entry_price(1_000_000, 50_000_000_000, &config).unwrap();
+        assert_eq!(pos.leverage, 13);
+        // 清算价格按实际保证金率 7.5%: $50,000 * (1 - 0.075 + 0.005) = $46,500
+        assert_eq!(pos.liquidation_price_e6, 46_500_000_000);
+
+        // 20x 仓位以 5x 小额加仓进入第二档 (最高 10x): 本笔杠杆合规，但混合杠杆 12x 超限
+        let mut config = config;
+        config.risk_tiers = vec![
+            RiskTier { max_notional_e6: 60_000_000_000, max_leverage: 20, maintenance_margin_rate_e6: 5_000 },
+            RiskTier { max_notional_e6: 10_000_000_000_000, max_leverage: 10, maintenance_margin_rate_e6: 10_000 },
+        ];
+        let mut pos = Position { margin_e6: 2_500_000_000, leverage: 20, ..test_position() };
+        pos.margin_e6 += 3_000_000_000;
+        pos.update_entry_price(300_000, 50_000_000_000, &config).unwrap();
+        assert_eq!(pos.leverage, 12);
+        let notional = pos.notional_value_e6().unwrap();
+        assert!(config.check_position_tier(5, notional).is_ok());
+        assert_eq!(
+            config.check_position_tier(pos.leverage, notional),
+            Err(crate::error::LedgerError::LeverageExceedsMax)
+        );
+    }
+
     #[test]
     fn test_trade_batch_add_signature() {
         let mut batch = TradeBatch {
@@ -113,6 +113,13 @@ pub fn div_e6(a: i64, b: i64) -> Result<i64, ProgramError> {
     i64::try_from(result).map_err(|_| LedgerError::Overflow.into())
 }
 
+/// 检查价格是否在参考价格的 ±band_bps 范围内
+/// |price - reference| * 10000 <= reference * band_bps
+pub fn is_price_within_band(price_e6: u64, reference_price_e6: u64, band_bps: u16) -> bool {
+    let diff = price_e6.abs_diff(reference_price_e6) as u128;
+    diff * 10_000 <= (reference_price_e6 as u128) * (band_bps as u128)
+}
+
 /// 计算数据哈希 (SHA256)
 /// 
 /// 注意: 这是基础版本，仅用于简单的数据完整性校验。
@@ -197,6 +204,18 @@ mod tests {
         assert_eq!(result, 50_000_000); // 50.0 in e6
     }
 
+    #[test]
+    fn test_is_price_within_band() {
+        let reference = 50_000_000_000; // $50,000
+        // 100 bps = 1% -> [$49,500, $50,500]
+        assert!(is_price_within_band(50_500_000_000, reference, 100));
+        assert!(is_price_within_band(49_500_000_000, reference, 100));
+        assert!(!is_price_within_band(50_500_000_001, reference, 100));
+        assert!(!is_price_within_band(49_499_999_999, reference, 100));
+        assert!(!is_price_within_band(1, reference, 100));
+        assert!(is_price_within_band(reference, reference, 0));
+    }
+
     #[test]
     fn test_compute_hash() {
         let data = b"test data";